@@ -0,0 +1,66 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/* Time source of the protocol timers. Waits block for at most the poll interval of real time, after
+ * which the timers compare against the clock again, so a manual clock drives them as well. */
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn poll_interval(&self) -> Duration {
+        Duration::MAX
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/* Clock standing still until advanced, for tests of timer behavior without waiting */
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock { start: Instant::now(), elapsed: Mutex::new(Duration::ZERO) }
+    }
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+    fn poll_interval(&self) -> Duration {
+        Duration::from_millis(5)
+    }
+}
+
+/* Real time to wait for the deadline on the clock before looking again, None once it passed */
+pub(crate) fn wait_slice(clock: &dyn Clock, deadline: Instant) -> Option<Duration> {
+    match deadline.saturating_duration_since(clock.now()) {
+        Duration::ZERO => None,
+        remaining => Some(remaining.min(clock.poll_interval())),
+    }
+}
+
+/* Blocks until the duration passed on the clock */
+pub(crate) fn sleep(clock: &dyn Clock, duration: Duration) {
+    let deadline = clock.now() + duration;
+    while let Some(timeout) = wait_slice(clock, deadline) {
+        thread::sleep(timeout);
+    }
+}
@@ -1,60 +1,578 @@
 use crate::message::{
+    decoder::FrameDecoder,
+    diag_message::{DiagMessage, DiagNackCode},
     header::{DoIPHeader, NackCode},
+    alive_check::AliveCheckResponse,
+    diag_power_mode::{DiagnosticPowerMode, DiagnosticPowerModeRequest},
+    entity_status::{EntityStatusRequest, EntityStatusResponse},
     message_factory,
-    vehicle_identification::{FurtherAction, VehicleIdentificationResponse},
+    routing_activation::{RoutingActivationCode, RoutingActivationRequest},
+    vehicle_identification::{
+        VehicleIdentificationRequest, VehicleIdentificationRequestEID, VehicleIdentificationRequestVIN,
+        VehicleIdentificationResponse,
+    },
     Message, MessageVariant,
 };
-use rand::Rng;
+use crate::clock::{self, Clock, SystemClock};
+use crate::logging::{self, debug, debug_span, info, info_span, trace, warn, Span};
+use crate::message::dump::HexBytes;
+use crate::network;
+use crate::pcap::{PcapCapture, Transport};
+use crate::recorder::{Direction, FrameRecorder};
+use crate::transport::{DatagramSocket, DoIPStream};
+use crate::timing::{TimingError, TimingParameters};
+#[cfg(feature = "tls")]
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, StreamOwned};
 use std::{
-    io::{self},
-    net::{TcpListener, TcpStream, UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr},
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Arc,
     thread::{self},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+/* Vehicle identification request sent during discovery */
+#[derive(Clone, Debug, PartialEq)]
+pub enum IdentificationRequest {
+    All,
+    ByEid([u8; 6]),
+    ByVin([u8; 17]),
+}
+impl IdentificationRequest {
+    pub(crate) fn from_message(message: &MessageVariant) -> Option<Self> {
+        match message {
+            MessageVariant::VehicleIDReqVariant(_) => Some(IdentificationRequest::All),
+            MessageVariant::VehicleIDReqByEIDVariant(req) => Some(IdentificationRequest::ByEid(req.eid)),
+            MessageVariant::VehicleIDReqByVINVariant(req) => Some(IdentificationRequest::ByVin(req.vin)),
+            _ => None,
+        }
+    }
+    fn serialize(&self) -> Vec<u8> {
+        match self {
+            IdentificationRequest::All => VehicleIdentificationRequest::new().serialize(),
+            IdentificationRequest::ByEid(eid) => VehicleIdentificationRequestEID::new(eid).serialize(),
+            IdentificationRequest::ByVin(vin) => VehicleIdentificationRequestVIN::new(vin).serialize(),
+        }
+    }
+}
+
+/* Vehicle identification response together with the address it came from */
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiscoveredEntity {
+    pub address: SocketAddr,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub response: VehicleIdentificationResponse,
+}
+
 #[derive(Default)]
 pub struct DoIPClient {
+    bind_address: Option<IpAddr>,
+    interface: Option<String>,
+    local_port: u16,
+    timing: TimingParameters,
+    /* System clock if unset */
+    clock: Option<Arc<dyn Clock>>,
 }
 impl DoIPClient {
     const DOIP_PORT: u16 = 13200;
-    pub fn start(&self) {
-        let handle = thread::spawn(move||{
-            DoIPClient::identification_handler();}
-            );
-        handle.join().unwrap();
+    /* IPv6 all-nodes multicast group, the IPv6 counterpart of the broadcast address */
+    pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 1);
+    /* Sends a vehicle identification request, e.g. to the broadcast address or to the all-nodes group
+     * with the interface as scope, and collects the responses arriving within the timeout.
+     * Frames other than identification responses are ignored. */
+    pub fn discover<A: ToSocketAddrs>(
+        destination: A,
+        request: &IdentificationRequest,
+        timeout: Duration,
+    ) -> io::Result<Vec<DiscoveredEntity>> {
+        DoIPClient::default().identify(destination, request, timeout)
+    }
+    /* Local address for discovery and connections, the unspecified address of the peer's IP version
+     * if unset */
+    pub fn set_bind_address(&mut self, address: IpAddr) -> &mut Self {
+        self.bind_address = Some(address);
+        self
+    }
+    /* Keeps discovery and connections on the named network interface, also as IPv6 multicast scope */
+    pub fn set_interface(&mut self, name: &str) -> &mut Self {
+        self.interface = Some(name.to_string());
+        self
+    }
+    /* Local UDP and TCP port, ephemeral if 0 */
+    pub fn set_local_port(&mut self, port: u16) -> &mut Self {
+        self.local_port = port;
+        self
+    }
+    /* Timeouts of the sessions opened by connect */
+    pub fn set_timing(&mut self, timing: TimingParameters) -> Result<&mut Self, TimingError> {
+        timing.validate()?;
+        self.timing = timing;
+        Ok(self)
+    }
+    /* Time source of discovery and session timeouts */
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = Some(clock);
+        self
+    }
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock))
     }
-    fn init_udp_socket() -> io::Result<UdpSocket> {
-        let socket: UdpSocket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DoIPClient::DOIP_PORT))?;
-        socket.set_broadcast(true)?;
-        socket.set_write_timeout(Some(Duration::from_secs(5)))?;
+    fn local_address(&self, peer: &SocketAddr) -> Option<SocketAddr> {
+        let unspecified = match peer {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        match (self.bind_address, self.local_port) {
+            (None, 0) => None,
+            (address, port) => Some(SocketAddr::new(address.unwrap_or(unspecified), port)),
+        }
+    }
+    fn resolve<A: ToSocketAddrs>(address: A) -> io::Result<SocketAddr> {
+        address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no destination address"))
+    }
+    /* Same as discover, from the configured address, port and interface */
+    pub fn identify<A: ToSocketAddrs>(
+        &self,
+        destination: A,
+        request: &IdentificationRequest,
+        timeout: Duration,
+    ) -> io::Result<Vec<DiscoveredEntity>> {
+        let mut destination = DoIPClient::resolve(destination)?;
+        let local = self.local_address(&destination).unwrap_or_else(|| match destination {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        });
+        let socket = network::udp_socket(local, self.interface.as_deref())?;
+        match &mut destination {
+            SocketAddr::V4(_) => socket.set_broadcast(true)?,
+            SocketAddr::V6(destination) => {
+                if let (0, Some(name)) = (destination.scope_id(), &self.interface) {
+                    destination.set_scope_id(network::interface_index(name)?);
+                }
+            }
+        }
+        self.identify_over(&socket, destination, request, timeout)
+    }
+    /* Same as identify, on the given socket, e.g. an in-memory one of transport::memory */
+    pub fn identify_over(
+        &self,
+        socket: &dyn DatagramSocket,
+        destination: SocketAddr,
+        request: &IdentificationRequest,
+        timeout: Duration,
+    ) -> io::Result<Vec<DiscoveredEntity>> {
+        socket.send_to(&request.serialize(), destination)?;
+        let clock = self.clock();
+        let deadline = clock.now() + timeout;
+        let mut buff: [u8; 512] = [0; 512];
+        let mut entities = Vec::new();
+        loop {
+            let Some(timeout) = clock::wait_slice(&*clock, deadline) else {
+                return Ok(entities);
+            };
+            socket.set_read_timeout(Some(timeout))?;
+            match socket.recv_from(&mut buff) {
+                Ok((len, address)) => {
+                    if let Ok(MessageVariant::VehicleIDResVariant(response)) = message_factory(&buff[..len]) {
+                        entities.push(DiscoveredEntity { address, response });
+                    }
+                }
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    /* Connects from the configured address, port and interface and activates routing */
+    pub fn connect<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        source_address: u16,
+        activation_type: u8,
+    ) -> Result<DoIPClientSession, SessionError> {
+        let addr = DoIPClient::resolve(addr)?;
+        let stream = network::tcp_connect(addr, self.local_address(&addr), self.interface.as_deref())?;
+        stream.set_nodelay(true)?;
+        let (timing, clock) = (self.timing, self.clock.clone());
+        DoIPClientSession::open(Box::new(stream), source_address, activation_type, None, timing, clock)
+    }
+    /* Same as connect, over an established stream, e.g. an in-memory one of transport::memory */
+    pub fn connect_over<S: DoIPStream + 'static>(
+        &self,
+        stream: S,
+        source_address: u16,
+        activation_type: u8,
+    ) -> Result<DoIPClientSession, SessionError> {
+        let (timing, clock) = (self.timing, self.clock.clone());
+        DoIPClientSession::open(Box::new(stream), source_address, activation_type, None, timing, clock)
+    }
+    /* Listens for vehicle announcements and logs them, blocks while listening. The sockets use the
+     * configured address, interface and local port, the DoIP port if 0. Without a bind address they
+     * listen on IPv4 and on IPv6, where announcements go to the all-nodes group. */
+    pub fn start(&self) -> io::Result<()> {
+        let port = match self.local_port {
+            0 => DoIPClient::DOIP_PORT,
+            port => port,
+        };
+        let mut sockets = Vec::new();
+        match self.bind_address {
+            Some(address) => sockets.push(self.announcement_socket(SocketAddr::new(address, port))?),
+            None => {
+                sockets.push(self.announcement_socket((Ipv4Addr::UNSPECIFIED, port).into())?);
+                match self.announcement_socket((Ipv6Addr::UNSPECIFIED, port).into()) {
+                    Ok(socket) => sockets.push(socket),
+                    Err(err) => warn!(%err, "IPv6 announcements unavailable"),
+                }
+            }
+        }
+        let handlers: Vec<_> = sockets
+            .into_iter()
+            .map(|socket| thread::spawn(move || DoIPClient::identification_handler(&socket)))
+            .collect();
+        for handler in handlers {
+            handler.join().map_err(|_| io::Error::other("announcement listener panicked"))??;
+        }
+        Ok(())
+    }
+    fn announcement_socket(&self, address: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = network::udp_socket(address, self.interface.as_deref())?;
+        match address.ip() {
+            IpAddr::V4(_) => socket.set_broadcast(true)?,
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                let interface = match &self.interface {
+                    Some(name) => network::interface_index(name)?,
+                    None => 0,
+                };
+                socket.join_multicast_v6(&DoIPClient::ALL_NODES, interface)?;
+            }
+            IpAddr::V6(_) => (),
+        }
         Ok(socket)
     }
     fn parse_identification_response(buff: &[u8], len: usize) -> Result<MessageVariant, NackCode> {
-        message_factory(&buff[..])
+        message_factory(&buff[..len])
     }
-    fn identification_handler() {
-        let mut header_buff: [u8; DoIPHeader::length() + 17] = [0; DoIPHeader::length() + 17];
-        let socket = DoIPClient::init_udp_socket().expect("UDP socket setup failed");
+    fn identification_handler(socket: &UdpSocket) -> io::Result<()> {
+        let mut header_buff: [u8; DoIPHeader::length() + 33] = [0; DoIPHeader::length() + 33];
         loop {
-            if let Ok((len, _)) = socket.recv_from(&mut header_buff) {
-                match DoIPClient::parse_identification_response(&header_buff, len) {
-                    Ok(message) => {
-                        match message {
-                            MessageVariant::VehicleIDResVariant(response) => {
-                                println!("{:?}", response);
-                            },
-                            _ => println!("Invalid message recieved on udp port")
-                        }
+            let (len, _) = socket.recv_from(&mut header_buff)?;
+            match DoIPClient::parse_identification_response(&header_buff, len) {
+                Ok(MessageVariant::VehicleIDResVariant(response)) => {
+                    info!(response = ?response, "vehicle announcement");
+                }
+                Ok(_) => debug!("unexpected message on the UDP port"),
+                Err(code) => debug!(code = ?code, "invalid identification message"),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Io(io::Error),
+    Timeout,
+    ConnectionClosed,
+    /* Frame received from the entity could not be decoded */
+    Decode(NackCode),
+    /* Generic header negative acknowledge sent by the entity */
+    HeaderNack(NackCode),
+    RoutingActivationDenied(RoutingActivationCode),
+    DiagnosticNack(DiagNackCode),
+}
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Io(err) => write!(f, "I/O error: {}", err),
+            SessionError::Timeout => write!(f, "timeout while waiting for the DoIP entity"),
+            SessionError::ConnectionClosed => write!(f, "connection closed by the DoIP entity"),
+            SessionError::Decode(code) => write!(f, "invalid frame received: {:?}", code),
+            SessionError::HeaderNack(code) => write!(f, "header negative acknowledge: {:?}", code),
+            SessionError::RoutingActivationDenied(code) => {
+                write!(f, "routing activation denied: {:?}", code)
+            }
+            SessionError::DiagnosticNack(code) => {
+                write!(f, "diagnostic message negative acknowledge: {:?}", code)
+            }
+        }
+    }
+}
+impl std::error::Error for SessionError {}
+impl From<io::Error> for SessionError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => SessionError::Timeout,
+            io::ErrorKind::UnexpectedEof => SessionError::ConnectionClosed,
+            _ => SessionError::Io(err),
+        }
+    }
+}
+
+/* Routed TCP connection to a DoIP entity, used to exchange diagnostic messages */
+pub struct DoIPClientSession {
+    stream: Box<dyn DoIPStream>,
+    decoder: FrameDecoder,
+    source_address: u16,
+    entity_address: u16,
+    pending: VecDeque<DiagMessage>,
+    last_activity: Instant,
+    clock: Arc<dyn Clock>,
+    recorder: Option<FrameRecorder>,
+    capture: Option<PcapCapture>,
+    timing: TimingParameters,
+    span: Span,
+}
+impl DoIPClientSession {
+    pub const DOIP_PORT: u16 = 13200;
+    pub const DOIP_TLS_PORT: u16 = 3496;
 
+    pub fn connect<A: ToSocketAddrs>(addr: A, source_address: u16) -> Result<Self, SessionError> {
+        DoIPClientSession::connect_with_activation_type(
+            addr,
+            source_address,
+            RoutingActivationRequest::ACTIVATION_TYPE_DEFAULT,
+        )
+    }
+    pub fn connect_with_activation_type<A: ToSocketAddrs>(
+        addr: A,
+        source_address: u16,
+        activation_type: u8,
+    ) -> Result<Self, SessionError> {
+        let stream = DoIPClientSession::connect_tcp(addr)?;
+        let timing = TimingParameters::default();
+        DoIPClientSession::open(Box::new(stream), source_address, activation_type, None, timing, None)
+    }
+    /* Connects to the TLS port of the entity, verifying its certificate against server_name */
+    #[cfg(feature = "tls")]
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        source_address: u16,
+        activation_type: u8,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self, SessionError> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let tls = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+        let stream = StreamOwned::new(tls, DoIPClientSession::connect_tcp(addr)?);
+        let timing = TimingParameters::default();
+        DoIPClientSession::open(Box::new(stream), source_address, activation_type, None, timing, None)
+    }
+    /* Connects and records every frame exchanged from routing activation on */
+    pub fn connect_recorded<A: ToSocketAddrs>(
+        addr: A,
+        source_address: u16,
+        recorder: FrameRecorder,
+    ) -> Result<Self, SessionError> {
+        DoIPClientSession::open(
+            Box::new(DoIPClientSession::connect_tcp(addr)?),
+            source_address,
+            RoutingActivationRequest::ACTIVATION_TYPE_DEFAULT,
+            Some(recorder),
+            Default::default(),
+            None,
+        )
+    }
+    fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+    fn open(
+        stream: Box<dyn DoIPStream>,
+        source_address: u16,
+        activation_type: u8,
+        recorder: Option<FrameRecorder>,
+        timing: TimingParameters,
+        clock: Option<Arc<dyn Clock>>,
+    ) -> Result<Self, SessionError> {
+        let clock = clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let peer = stream.peer_addr()?;
+        let span = info_span!("session", peer = %peer, tls = stream.is_tls(), sa = source_address);
+        let mut session = DoIPClientSession {
+            stream,
+            decoder: FrameDecoder::new(),
+            source_address,
+            entity_address: 0,
+            pending: VecDeque::new(),
+            last_activity: clock.now(),
+            clock,
+            recorder,
+            capture: None,
+            timing,
+            span,
+        };
+        session.activate_routing(activation_type)?;
+        Ok(session)
+    }
+    pub fn source_address(&self) -> u16 {
+        self.source_address
+    }
+    pub fn entity_address(&self) -> u16 {
+        self.entity_address
+    }
+    pub fn local_address(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+    /* Time since the last diagnostic message was sent or received */
+    pub fn idle_time(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.last_activity)
+    }
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+    pub fn set_recorder(&mut self, recorder: Option<FrameRecorder>) {
+        self.recorder = recorder;
+    }
+    /* Timeouts of the requests from now on */
+    pub fn set_timing(&mut self, timing: TimingParameters) -> Result<(), TimingError> {
+        timing.validate()?;
+        self.timing = timing;
+        Ok(())
+    }
+    /* Writes the traffic of the session to a pcap capture */
+    pub fn set_capture(&mut self, capture: Option<PcapCapture>) {
+        self.capture = capture;
+    }
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Direction::TesterToEntity, frame);
+        }
+        self.capture_frame(Direction::TesterToEntity, frame);
+        self.stream.write_all(frame)
+    }
+    fn capture_frame(&self, direction: Direction, frame: &[u8]) {
+        let payload_type = logging::payload_type(frame);
+        trace!(direction = ?direction, payload_type = ?payload_type, frame = %HexBytes(frame), "frame");
+        let (Some(capture), Ok(local), Ok(peer)) =
+            (&self.capture, self.stream.local_addr(), self.stream.peer_addr())
+        else {
+            return;
+        };
+        match direction {
+            Direction::TesterToEntity => capture.record(Transport::Tcp, local, peer, frame),
+            Direction::EntityToTester => capture.record(Transport::Tcp, peer, local, frame),
+        }
+    }
+    fn activate_routing(&mut self, activation_type: u8) -> Result<(), SessionError> {
+        let _session = self.span.clone().entered();
+        let request = RoutingActivationRequest::new(self.source_address, activation_type);
+        self.write_frame(&request.serialize())?;
+        let deadline = self.clock.now() + self.timing.a_doip_ctrl;
+        loop {
+            if let MessageVariant::RoutingActivationResponseVariant(response) =
+                self.read_message(deadline)?
+            {
+                let code = response.routing_activation_response_code;
+                debug!(activation_type, code = ?code, "routing activation");
+                if code != RoutingActivationCode::RoutingActivated {
+                    return Err(SessionError::RoutingActivationDenied(code));
+                }
+                self.entity_address = response.entity_logical_address;
+                return Ok(());
+            }
+        }
+    }
+    pub fn entity_status(&mut self) -> Result<EntityStatusResponse, SessionError> {
+        self.write_frame(&EntityStatusRequest::default().serialize())?;
+        let deadline = self.clock.now() + self.timing.a_doip_ctrl;
+        loop {
+            match self.read_message(deadline)? {
+                MessageVariant::EntityStatusResponseVariant(response) => return Ok(response),
+                MessageVariant::DiagnoticMessageVariant(message) => self.pending.push_back(message),
+                _ => (),
+            }
+        }
+    }
+    pub fn power_mode(&mut self) -> Result<DiagnosticPowerMode, SessionError> {
+        self.write_frame(&DiagnosticPowerModeRequest::default().serialize())?;
+        let deadline = self.clock.now() + self.timing.a_doip_ctrl;
+        loop {
+            match self.read_message(deadline)? {
+                MessageVariant::DiagnosticPowerModeResponseVariant(response) => {
+                    return Ok(response.power_mode)
+                }
+                MessageVariant::DiagnoticMessageVariant(message) => self.pending.push_back(message),
+                _ => (),
+            }
+        }
+    }
+    /* Sends a diagnostic message and waits for the entity to acknowledge it */
+    pub fn send_diagnostic(&mut self, target_address: u16, user_data: &[u8]) -> Result<(), SessionError> {
+        let _session = self.span.clone().entered();
+        let _request = debug_span!("diagnostic_request", ta = target_address).entered();
+        let message = DiagMessage::new(self.source_address, target_address, user_data);
+        self.write_frame(&message.serialize())?;
+        self.last_activity = self.clock.now();
+        let deadline = self.clock.now() + self.timing.a_doip_diagnostic_message;
+        loop {
+            match self.read_message(deadline)? {
+                MessageVariant::DiagnosticMessageAckVariant(ack)
+                    if ack.source_address == target_address => return Ok(()),
+                MessageVariant::DiagnosticMessageNAckVariant(nack)
+                    if nack.source_address == target_address => {
+                    debug!(nack = ?nack.nack_code, "diagnostic message rejected");
+                    return Err(SessionError::DiagnosticNack(nack.nack_code))
+                }
+                MessageVariant::DiagnoticMessageVariant(message) => {
+                    self.last_activity = self.clock.now();
+                    self.pending.push_back(message)
+                }
+                _ => (),
+            }
+        }
+    }
+    /* Waits for the next diagnostic message addressed to this tester */
+    pub fn receive_diagnostic(&mut self, timeout: Duration) -> Result<DiagMessage, SessionError> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if let MessageVariant::DiagnoticMessageVariant(message) = self.read_message(deadline)? {
+                self.last_activity = self.clock.now();
+                return Ok(message);
+            }
+        }
+    }
+    fn read_message(&mut self, deadline: Instant) -> Result<MessageVariant, SessionError> {
+        let _session = self.span.clone().entered();
+        let mut buff: [u8; 4096] = [0; 4096];
+        loop {
+            if let Some(frame) = self.decoder.next_frame().map_err(SessionError::Decode)? {
+                if let Some(recorder) = &self.recorder {
+                    recorder.record(Direction::EntityToTester, &frame);
+                }
+                self.capture_frame(Direction::EntityToTester, &frame);
+                match message_factory(&frame) {
+                    Ok(MessageVariant::HeaderNackMessageVariant(nack)) => {
+                        return Err(SessionError::HeaderNack(nack.nack_code))
                     }
-                    Err(code) => {
-                        eprintln!("Identification message parsing failed: {:?}", code);
+                    Ok(MessageVariant::AliveCheckRequestVariant(_)) => {
+                        debug!("alive check request");
+                        let response = AliveCheckResponse::new(self.source_address);
+                        self.write_frame(&response.serialize())?;
+                        continue;
                     }
+                    Ok(message) => return Ok(message),
+                    Err(code) => return Err(SessionError::Decode(code)),
                 }
-            } else {
-                eprintln!("Error during recv_from");
             }
-        } // loop
+            let Some(timeout) = clock::wait_slice(&*self.clock, deadline) else {
+                return Err(SessionError::Timeout);
+            };
+            self.stream.set_read_timeout(Some(timeout))?;
+            match self.stream.read(&mut buff) {
+                Ok(0) => return Err(SessionError::ConnectionClosed),
+                Ok(len) => self.decoder.push(&buff[..len]),
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
     }
 }
 
@@ -62,8 +580,179 @@ impl DoIPClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pcap::PcapReader;
+    use crate::test_util::{FakeEntity, SharedBuffer};
+    use std::net::SocketAddr;
 
     #[test]
     fn build_client() {
     }
+    #[test]
+    fn session_routes_diagnostic_messages() {
+        let entity = FakeEntity::spawn(0x1000, |request: &DiagMessage| {
+            vec![(Duration::ZERO, [&[request.user_data[0] + 0x40], &request.user_data[1..]].concat())]
+        });
+        let mut session = DoIPClientSession::connect(entity.address(), 0x0E80).unwrap();
+        assert_eq!(session.entity_address(), 0x1000);
+        session.send_diagnostic(0x1000, &[0x3E, 0x00]).unwrap();
+        let response = session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        assert_eq!(response.source_address, 0x1000);
+        assert_eq!(response.user_data, vec![0x7E, 0x00]);
+        assert_eq!(entity.received(), vec![DiagMessage::new(0x0E80, 0x1000, &[0x3E, 0x00])]);
+    }
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn trace_session_with_addresses() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| vec![(Duration::ZERO, vec![0x7E, 0x00])]);
+        let recorder = crate::test_util::TraceRecorder::default();
+        recorder.capture(|| {
+            let mut session = DoIPClientSession::connect(entity.address(), 0x0E80).unwrap();
+            session.send_diagnostic(0x1000, &[0x3E, 0x00]).unwrap();
+            session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        });
+        let events = recorder.events();
+        let frames: Vec<_> = events.iter().filter(|event| event.message() == Some("frame")).collect();
+        assert!(frames.iter().all(|event| event.field("peer") == Some(&entity.address().to_string())));
+        assert!(frames.iter().all(|event| event.field("sa") == Some("3712")));
+        let payload_types: Vec<_> = frames.iter().filter_map(|event| event.field("payload_type")).collect();
+        assert_eq!(
+            payload_types[..3],
+            ["Some(RoutingActivationReq)", "Some(RoutingActivationRes)", "Some(DiagMessage)"]
+        );
+        let request = frames.iter().find(|event| event.field("ta").is_some()).unwrap();
+        assert_eq!(request.spans, ["session", "diagnostic_request"]);
+        assert_eq!(request.field("ta"), Some("4096"));
+        assert_eq!(request.field("direction"), Some("TesterToEntity"));
+    }
+    #[test]
+    fn session_records_frames() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| vec![(Duration::ZERO, vec![0x7E, 0x00])]);
+        let recorder = FrameRecorder::new();
+        let mut session =
+            DoIPClientSession::connect_recorded(entity.address(), 0x0E80, recorder.clone()).unwrap();
+        session.send_diagnostic(0x1000, &[0x3E, 0x00]).unwrap();
+        session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        let directions: Vec<(Direction, u16)> = recorder
+            .recording()
+            .frames
+            .iter()
+            .map(|frame| (frame.direction, u16::from_be_bytes([frame.frame[2], frame.frame[3]])))
+            .collect();
+        assert_eq!(
+            directions,
+            vec![
+                (Direction::TesterToEntity, 0x0005),
+                (Direction::EntityToTester, 0x0006),
+                (Direction::TesterToEntity, 0x8001),
+                (Direction::EntityToTester, 0x8002),
+                (Direction::EntityToTester, 0x8001),
+            ]
+        );
+    }
+    #[test]
+    fn session_captures_frames() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| vec![(Duration::ZERO, vec![0x7E, 0x00])]);
+        let buffer = SharedBuffer::default();
+        let mut session = DoIPClientSession::connect(entity.address(), 0x0E80).unwrap();
+        session.set_capture(Some(PcapCapture::new(buffer.clone()).unwrap()));
+        session.send_diagnostic(0x1000, &[0x3E, 0x00]).unwrap();
+        session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        let contents = buffer.contents();
+        let mut reader = PcapReader::new(&contents[..]).unwrap();
+        reader.set_ports(&[entity.address().port()]);
+        let sources: Vec<SocketAddr> = reader.map(|message| message.unwrap().source).collect();
+        assert_eq!(sources.len(), 3);
+        assert_eq!(sources[1..], [entity.address(), entity.address()]);
+    }
+    #[test]
+    fn discover_entities() {
+        let entity = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = entity.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let mut buff = [0; 64];
+            let (len, tester) = entity.recv_from(&mut buff).unwrap();
+            assert!(matches!(message_factory(&buff[..len]), Ok(MessageVariant::VehicleIDReqByVINVariant(_))));
+            let response = VehicleIdentificationResponse::new(
+                b"WVWZZZ1JZXW000001",
+                0x1000,
+                &[1; 6],
+                &[2; 6],
+                Default::default(),
+            );
+            entity.send_to(&[0x02, 0xFD], tester).unwrap();
+            entity.send_to(&response.serialize(), tester).unwrap();
+        });
+        let request = IdentificationRequest::ByVin(*b"WVWZZZ1JZXW000001");
+        let entities = DoIPClient::discover(address, &request, Duration::from_millis(300)).unwrap();
+        responder.join().unwrap();
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].address, address);
+        assert_eq!(entities[0].response.logical_address, 0x1000);
+    }
+    #[test]
+    fn connect_from_bound_address() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| Vec::new());
+        let mut client = DoIPClient::default();
+        client.set_bind_address(Ipv4Addr::LOCALHOST.into());
+        let session = client.connect(entity.address(), 0x0E80, 0).unwrap();
+        assert_eq!(session.local_address().unwrap().ip(), Ipv4Addr::LOCALHOST);
+        assert_eq!(session.entity_address(), 0x1000);
+        client.set_interface("nodoip0");
+        assert!(client.identify(entity.address(), &IdentificationRequest::All, Duration::ZERO).is_err());
+    }
+    #[test]
+    fn listen_for_announcements_on_configured_socket() {
+        let client = DoIPClient::default();
+        let socket = client.announcement_socket((Ipv6Addr::UNSPECIFIED, 0).into()).unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv6());
+        let taken = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let mut client = DoIPClient::default();
+        client.set_bind_address(Ipv4Addr::LOCALHOST.into()).set_local_port(port);
+        assert_eq!(client.start().unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        client.set_interface("nodoip0");
+        assert!(client.start().is_err());
+    }
+    #[test]
+    fn session_requests_entity_status_and_power_mode() {
+        let address = crate::doip_server::DoIPServerBuilder::new().get_server().serve_loopback();
+        let mut session = DoIPClientSession::connect(address, 0x0E80).unwrap();
+        assert_eq!(session.entity_status().unwrap().open_sockets, 1);
+        assert_eq!(session.power_mode().unwrap(), DiagnosticPowerMode::Ready);
+    }
+    #[test]
+    fn session_reports_unknown_target() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| Vec::new());
+        let mut session = DoIPClientSession::connect(entity.address(), 0x0E80).unwrap();
+        assert!(matches!(
+            session.send_diagnostic(0x2000, &[0x3E, 0x00]),
+            Err(SessionError::DiagnosticNack(DiagNackCode::UnknownTargetAddress))
+        ));
+    }
+    #[test]
+    fn time_out_routing_activation_after_a_doip_ctrl() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let timing = TimingParameters { a_doip_ctrl: Duration::from_millis(100), ..Default::default() };
+        let mut client = DoIPClient::default();
+        client.set_timing(timing).unwrap();
+        let start = Instant::now();
+        let result = client.connect(listener.local_addr().unwrap(), 0x0E80, 0);
+        assert!(matches!(result, Err(SessionError::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let timing = TimingParameters { a_doip_ctrl: Duration::from_secs(10), ..Default::default() };
+        assert!(client.set_timing(timing).is_err());
+    }
+    #[test]
+    fn time_out_on_manual_clock() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let clock = Arc::new(crate::clock::ManualClock::new());
+        let mut client = DoIPClient::default();
+        client.set_clock(clock.clone());
+        let connection = thread::spawn(move || client.connect(address, 0x0E80, 0));
+        thread::sleep(Duration::from_millis(100));
+        assert!(!connection.is_finished());
+        clock.advance(Duration::from_secs(2));
+        assert!(matches!(connection.join().unwrap(), Err(SessionError::Timeout)));
+    }
 }
@@ -1,228 +1,906 @@
 use crate::message::{
+    alive_check::AliveCheckRequest,
+    diag_message::{DiagMessage, DiagMessageAck, DiagMessageNAck, DiagNackCode},
+    diag_power_mode::{DiagnosticPowerMode, DiagnosticPowerModeResponse},
     entity_status::{EntityStatusResponse, NodeType},
     header::{DoIPHeader, NackCode},
     header_nack::HeaderNackMessage,
     message_factory,
+    routing_activation::{RoutingActivationCode, RoutingActivationRequest, RoutingActivationResponse},
     vehicle_identification::{FurtherAction, VehicleIdentificationResponse},
-    Message, MessageVariant, routing_activation::{RoutingActivationResponse, RoutingActivationCode},
+    Message, MessageVariant,
 };
-use rand::Rng;
+use crate::clock::{self, Clock, SystemClock};
+use crate::doip_client::IdentificationRequest;
+use crate::logging::{self, debug, debug_span, info, info_span, trace, warn};
+use crate::message::dump::HexBytes;
+use crate::network;
+use crate::pcap::{PcapCapture, Transport};
+use crate::server_event::{ServerEvent, ServerObserver};
+use crate::transport::{DatagramSocket, DoIPStream, StreamListener};
+use crate::timing::{TimingError, TimingParameters};
+use rand::{Rng, RngCore};
+#[cfg(feature = "tls")]
+use crate::tls::{CertificateIdentity, TesterAuthorization};
+#[cfg(feature = "tls")]
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use std::{
-    io::{self, Read, Write},
-    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError, TryLockError,
+    },
     thread::{self},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+/* Diagnostic response sent back to the tester on behalf of the ECU at source_address */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiagnosticResponse {
+    pub source_address: u16,
+    pub user_data: Vec<u8>,
+    /* Time to wait before sending the response */
+    pub delay: Duration,
+}
+impl DiagnosticResponse {
+    pub fn new(source_address: u16, user_data: Vec<u8>) -> Self {
+        DiagnosticResponse { source_address, user_data, delay: Duration::ZERO }
+    }
+}
+
+/* Application layer behind the entity, answering routed diagnostic requests */
+pub trait DiagnosticHandler: Send {
+    fn is_target_known(&self, target_address: u16) -> bool;
+    fn handle_request(
+        &mut self,
+        source_address: u16,
+        target_address: u16,
+        request: &[u8],
+    ) -> Vec<DiagnosticResponse>;
+}
+
 #[derive(Default)]
 pub struct DoIPServer {
     vin: [u8; 17],
     eid: [u8; 6],
     gid: [u8; 6],
     logical_address: u16,
+    node_type: NodeType,
     max_sockets: u8,
-    open_sockets: u8,
     max_data_size: u32,
-    client_source_address: Option<u16>
+    /* Largest user data of a diagnostic message the ECUs take, rejected with DiagnosticMessageTooLarge.
+     * Larger frames than max_data_size get the header NACK before, so it matters only below that. */
+    max_diagnostic_size: u32,
+    timing: TimingParameters,
+    /* System clock and thread RNG if unset */
+    clock: Option<Arc<dyn Clock>>,
+    rng: Mutex<Option<Box<dyn RngCore + Send>>>,
+    diagnostic_handler: Option<Arc<Mutex<dyn DiagnosticHandler>>>,
+    capture: Option<PcapCapture>,
+    observers: Vec<Arc<Mutex<dyn ServerObserver>>>,
+    /* Interface index for IPv6 announcements, 0 for the one given by name or the system's choice */
+    ipv6_interface: u32,
+    /* Single local address to serve on, IPv4 and IPv6 on all addresses if unset */
+    bind_address: Option<IpAddr>,
+    interface: Option<String>,
+    tcp_port: u16,
+    udp_port: u16,
+    #[cfg(feature = "tls")]
+    tls_port: u16,
+    /* Broadcast and all-nodes multicast if unset */
+    announcement_destinations: Option<Vec<SocketAddr>>,
+    #[cfg(feature = "tls")]
+    tls_config: Option<Arc<ServerConfig>>,
+    #[cfg(feature = "tls")]
+    tester_authorizations: Vec<TesterAuthorization>,
+    /* Activation types only granted on TLS connections */
+    tls_activation_types: HashSet<u8>,
+    open_sockets: AtomicU8,
+    /* Connections with routing activated by their source address */
+    registered_addresses: Mutex<HashMap<u16, Arc<AliveCheck>>>,
+    lifecycle: Lifecycle,
+    /* Threads serving tester connections, which close them on shutdown */
+    connections: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+/* Running server returned by DoIPServer::start, with the addresses its sockets are bound to */
+pub struct ServerHandle {
+    pub tcp_address: SocketAddr,
+    pub tls_address: Option<SocketAddr>,
+    pub udp_addresses: Vec<SocketAddr>,
+    server: Arc<DoIPServer>,
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+impl ServerHandle {
+    /* Waits until the listeners run and the identification sockets take requests */
+    pub fn wait_ready(&self, timeout: Duration) -> bool {
+        self.server.lifecycle.wait_ready(timeout)
+    }
+    /* Stops announcing and accepting testers, closes all tester connections and joins the server threads */
+    pub fn shutdown(&self) {
+        self.server.lifecycle.shut_down();
+        self.join();
+    }
+    /* Waits until the server was shut down */
+    pub fn join(&self) {
+        for thread in self.threads.lock().unwrap().drain(..) {
+            let _ = thread.join();
+        }
+        let connections: Vec<_> = self.server.connections.lock().unwrap().drain(..).collect();
+        for thread in connections {
+            let _ = thread.join();
+        }
+    }
+}
+/* Startup and shutdown state shared by the threads of a running server */
+#[derive(Default)]
+struct Lifecycle {
+    state: Mutex<LifecycleState>,
+    changed: Condvar,
+}
+#[derive(Default)]
+struct LifecycleState {
+    /* Threads still starting up, i.e. binding and setting up their sockets */
+    starting: usize,
+    shut_down: bool,
+}
+impl Lifecycle {
+    fn starting(&self, threads: usize) {
+        self.state.lock().unwrap().starting += threads;
+    }
+    fn started(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.starting = state.starting.saturating_sub(1);
+        self.changed.notify_all();
+    }
+    fn wait_ready(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.starting > 0 && !state.shut_down)
+            .unwrap();
+        state.starting == 0
+    }
+    fn shut_down(&self) {
+        self.state.lock().unwrap().shut_down = true;
+        self.changed.notify_all();
+    }
+    fn is_shut_down(&self) -> bool {
+        self.state.lock().unwrap().shut_down
+    }
+    /* Sleeps on the clock unless shut down meanwhile, returns whether the server is still running */
+    fn sleep(&self, clock: &dyn Clock, duration: Duration) -> bool {
+        let deadline = clock.now() + duration;
+        let mut state = self.state.lock().unwrap();
+        while !state.shut_down {
+            let Some(timeout) = clock::wait_slice(clock, deadline) else {
+                return true;
+            };
+            state = self.changed.wait_timeout(state, timeout).unwrap().0;
+        }
+        false
+    }
+}
+/* Alive check of a connection with routing activated, requested by a connection finding the sockets
+ * exhausted and carried out by the thread serving the checked one */
+#[derive(Default)]
+struct AliveCheck {
+    state: Mutex<AliveCheckState>,
+    changed: Condvar,
+}
+#[derive(Default)]
+struct AliveCheckState {
+    /* Request not sent yet */
+    requested: bool,
+    /* Response not received yet */
+    pending: bool,
+    /* Silent until the deadline, the connection is to be closed */
+    failed: bool,
+}
+impl AliveCheck {
+    fn request(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.requested = true;
+        state.pending = true;
+    }
+    /* Whether a request is to be sent, true once per alive check */
+    fn take_request(&self) -> bool {
+        std::mem::take(&mut self.state.lock().unwrap().requested)
+    }
+    fn answered(&self) {
+        self.state.lock().unwrap().pending = false;
+        self.changed.notify_all();
+    }
+    /* Waits for the response until the deadline on the clock, fails the connection without one */
+    fn wait_response(&self, clock: &dyn Clock, deadline: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.pending {
+            let Some(timeout) = clock::wait_slice(clock, deadline) else {
+                state.failed = true;
+                return false;
+            };
+            state = self.changed.wait_timeout(state, timeout).unwrap().0;
+        }
+        true
+    }
+    fn has_failed(&self) -> bool {
+        self.state.lock().unwrap().failed
+    }
+}
+struct ConnectionState {
+    peer: SocketAddr,
+    source_address: Option<u16>,
+    close: bool,
+    alive_check: Arc<AliveCheck>,
 }
-enum ConnectionState {
-    initialized,
-    registered,
+/* Kind of tester connections accepted on a listener */
+#[derive(Clone)]
+enum Security {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(Arc<ServerConfig>),
 }
 impl DoIPServer {
     const DOIP_PORT: u16 = 13200;
-    const A_DO_IP_ANNOUNCE_NUM: u8 = 3;
-    const A_DO_IP_ANNOUNCE_INTERVAL: Duration = Duration::from_millis(500);
-    const T_TCP_GENERAL_INACTIVITY: Duration = Duration::from_secs(5 * 60);
-    const T_TCP_INITIAL_INACTIVITY: Duration = Duration::from_secs(2);
-    const T_TCP_ALIVE_CHECK: Duration = Duration::from_millis(500);
-    fn handle_message(&mut self, stream: &mut TcpStream, message: &MessageVariant) {
-        match message {
-            MessageVariant::RoutingActivationRequestVariant(req) => {
-                if self.client_source_address.is_some_and(|addr| 
-                   {req.source_address == addr})
-                {
-                    let response = RoutingActivationResponse::new(
-                        req.source_address,
-                        self.logical_address,
-                        RoutingActivationCode::RoutingActivated
-                    );
-                    stream.write_all(&response.serialize()).unwrap();
+    const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 1);
+    #[cfg(feature = "tls")]
+    const DOIP_TLS_PORT: u16 = 3496;
+    /* Interval at which idle listeners check for shutdown */
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+    /* Interval at which a diagnostic message waits for the handler busy with another connection */
+    const HANDLER_POLL_INTERVAL: Duration = Duration::from_millis(1);
+    /* Time after which a tester not taking frames any more is given up */
+    const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+    fn activate_routing(
+        &self,
+        stream: &dyn DoIPStream,
+        connection: &mut ConnectionState,
+        req: &RoutingActivationRequest,
+    ) -> RoutingActivationCode {
+        if ![
+            RoutingActivationRequest::ACTIVATION_TYPE_DEFAULT,
+            RoutingActivationRequest::ACTIVATION_TYPE_WWH_OBD,
+            RoutingActivationRequest::ACTIVATION_TYPE_CENTRAL_SECURITY,
+        ]
+        .contains(&req.activation_type)
+        {
+            connection.close = true;
+            return RoutingActivationCode::DeniedActivationTypeUnsupported;
+        }
+        if self.tls_activation_types.contains(&req.activation_type) && !stream.is_tls() {
+            return RoutingActivationCode::DeniedRequiresTls;
+        }
+        #[cfg(feature = "tls")]
+        if stream.is_tls() && !self.tester_authorizations.is_empty() {
+            if let Some(code) = self.authorize_tester(stream.peer_certificate(), req) {
+                connection.close = code == RoutingActivationCode::DeniedUnknownSourceAddress;
+                return code;
+            }
+        }
+        if self.open_sockets.load(Ordering::SeqCst) > self.max_sockets && !self.free_socket(connection) {
+            connection.close = true;
+            return RoutingActivationCode::DeniedNoSocketAvailable;
+        }
+        match connection.source_address {
+            Some(addr) if addr == req.source_address => RoutingActivationCode::RoutingActivated,
+            Some(_) => {
+                connection.close = true;
+                RoutingActivationCode::DeniedDifferentSA
+            }
+            None => {
+                let mut registered_addresses = self.registered_addresses.lock().unwrap();
+                if registered_addresses.contains_key(&req.source_address) {
+                    connection.close = true;
+                    return RoutingActivationCode::DeniedSAInUse;
                 }
-                else {
-                    let response = RoutingActivationResponse::new(
-                        req.source_address,
-                        self.logical_address,
-                        RoutingActivationCode::DeniedDifferentSA
-                    );
-                    stream.write_all(&response.serialize()).unwrap();
-                    //close socket TODO
+                registered_addresses.insert(req.source_address, connection.alive_check.clone());
+                connection.source_address = Some(req.source_address);
+                RoutingActivationCode::RoutingActivated
+            }
+        }
+    }
+    /* Alive checks the other connections with routing activated, those silent for T_TCP_Alive_Check get
+     * closed. Returns whether a socket is freed that way. */
+    fn free_socket(&self, connection: &ConnectionState) -> bool {
+        let checks: Vec<Arc<AliveCheck>> = self
+            .registered_addresses
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|check| !Arc::ptr_eq(check, &connection.alive_check))
+            .cloned()
+            .collect();
+        for check in &checks {
+            check.request();
+        }
+        let deadline = self.clock().now() + self.timing.t_tcp_alive_check;
+        let mut freed = false;
+        for check in checks {
+            freed |= !check.wait_response(self.clock(), deadline);
+        }
+        debug!(freed, "alive check on socket exhaustion");
+        freed
+    }
+    /* Checks the request against the activations granted to the tester certificate */
+    #[cfg(feature = "tls")]
+    fn authorize_tester(
+        &self,
+        certificate: Option<&[u8]>,
+        req: &RoutingActivationRequest,
+    ) -> Option<RoutingActivationCode> {
+        let granted: Vec<&TesterAuthorization> = match certificate {
+            Some(certificate) => {
+                let grants = self.tester_authorizations.iter();
+                grants.filter(|grant| grant.identity.matches(certificate)).collect()
+            }
+            None => Vec::new(),
+        };
+        if granted.is_empty() {
+            return Some(RoutingActivationCode::DeniedMissingAuthentication);
+        }
+        let granted: Vec<&TesterAuthorization> = granted
+            .into_iter()
+            .filter(|grant| grant.source_addresses.contains(&req.source_address))
+            .collect();
+        if granted.is_empty() {
+            return Some(RoutingActivationCode::DeniedUnknownSourceAddress);
+        }
+        if !granted.iter().any(|grant| grant.activation_types.contains(&req.activation_type)) {
+            return Some(RoutingActivationCode::DeniedMissingAuthentication);
+        }
+        None
+    }
+    fn handle_diagnostic_message(
+        &self,
+        stream: &mut dyn DoIPStream,
+        connection: &mut ConnectionState,
+        msg: &DiagMessage,
+    ) -> io::Result<()> {
+        let (sa, ta) = (msg.source_address, msg.target_address);
+        let _request = debug_span!("diagnostic_request", sa, ta).entered();
+        let routed = |nack| ServerEvent::DiagnosticMessage {
+            peer: connection.peer,
+            source_address: msg.source_address,
+            target_address: msg.target_address,
+            user_data: msg.user_data.clone(),
+            nack,
+        };
+        let reject = |stream: &mut dyn DoIPStream, code| {
+            debug!(nack = ?code, "diagnostic message rejected");
+            self.notify(routed(Some(code)));
+            let nack = DiagMessageNAck::new(msg.target_address, msg.source_address, code, &[]);
+            self.send(stream, &nack.serialize())
+        };
+        if connection.source_address != Some(msg.source_address) {
+            connection.close = true;
+            return reject(stream, DiagNackCode::InvalidSourceAddress);
+        }
+        let Some(handler) = &self.diagnostic_handler else {
+            return reject(stream, DiagNackCode::UnknownTargetAddress);
+        };
+        /* The handler is shared by all connections, it is locked only while it is called and never
+         * during a write or a response delay. The acknowledgement is due within A_Processing_Time, so
+         * the target counts as unreachable while the handler stays busy with another connection. */
+        let deadline = self.clock().now() + self.timing.a_processing_time;
+        let known = match self.lock_handler(handler, deadline) {
+            Some(handler) => handler.is_target_known(msg.target_address),
+            None => return reject(stream, DiagNackCode::TargetUnreachable),
+        };
+        if !known {
+            return reject(stream, DiagNackCode::UnknownTargetAddress);
+        }
+        if msg.user_data.len() > self.max_diagnostic_size as usize {
+            return reject(stream, DiagNackCode::DiagnosticMessageTooLarge);
+        }
+        debug!(length = msg.user_data.len(), "diagnostic message routed");
+        self.notify(routed(None));
+        let ack = DiagMessageAck::new(msg.target_address, msg.source_address, &[]);
+        self.send(stream, &ack.serialize())?;
+        let responses =
+            handler.lock().unwrap_or_else(PoisonError::into_inner).handle_request(sa, ta, &msg.user_data);
+        for response in responses {
+            if !self.lifecycle.sleep(self.clock(), response.delay) {
+                break;
+            }
+            let message = DiagMessage::new(response.source_address, msg.source_address, &response.user_data);
+            self.send(stream, &message.serialize())?;
+        }
+        Ok(())
+    }
+    fn lock_handler<'a>(
+        &self,
+        handler: &'a Mutex<dyn DiagnosticHandler + 'static>,
+        deadline: Instant,
+    ) -> Option<MutexGuard<'a, dyn DiagnosticHandler + 'static>> {
+        loop {
+            match handler.try_lock() {
+                Ok(locked) => return Some(locked),
+                Err(TryLockError::Poisoned(err)) => return Some(err.into_inner()),
+                Err(TryLockError::WouldBlock) => {
+                    let timeout = clock::wait_slice(self.clock(), deadline)?;
+                    thread::sleep(timeout.min(DoIPServer::HANDLER_POLL_INTERVAL));
                 }
             }
-            MessageVariant::AliveCheckRespnseVariant(resp) => {
-                self.client_source_address = Some(resp.source_address);
-            },
-            MessageVariant::EntityStatusRequestVariant(req) => {
+        }
+    }
+    fn handle_message(
+        &self,
+        stream: &mut dyn DoIPStream,
+        connection: &mut ConnectionState,
+        message: &MessageVariant,
+    ) -> io::Result<()> {
+        match message {
+            MessageVariant::RoutingActivationRequestVariant(req) => {
+                let code = self.activate_routing(stream, connection, req);
+                let (sa, activation_type) = (req.source_address, req.activation_type);
+                info!(sa, activation_type, code = ?code, "routing activation");
+                self.notify(ServerEvent::RoutingActivation {
+                    peer: connection.peer,
+                    source_address: req.source_address,
+                    activation_type: req.activation_type,
+                    code,
+                });
+                let response = RoutingActivationResponse::new(req.source_address, self.logical_address, code);
+                self.send(stream, &response.serialize())?;
+            }
+            MessageVariant::EntityStatusRequestVariant(_) => {
                 let response = EntityStatusResponse::new(
-                    NodeType::Node,
+                    self.node_type,
                     self.max_sockets,
-                    self.open_sockets,
+                    self.open_sockets.load(Ordering::SeqCst),
                     self.max_data_size,
                 );
-                stream.write_all(&response.serialize()).unwrap();
+                self.send(stream, &response.serialize())?;
+            }
+            MessageVariant::DiagnosticPowerModeRequestVariant(_) => {
+                let response = DiagnosticPowerModeResponse::new(DiagnosticPowerMode::Ready);
+                self.send(stream, &response.serialize())?;
+            }
+            MessageVariant::DiagnoticMessageVariant(msg) => {
+                self.handle_diagnostic_message(stream, connection, msg)?
+            }
+            MessageVariant::AliveCheckRespnseVariant(res) => {
+                let source_address = res.source_address;
+                debug!(sa = source_address, "alive check response");
+                connection.alive_check.answered();
+                self.notify(ServerEvent::AliveCheck { peer: connection.peer, source_address });
             }
-            MessageVariant::DiagnoticMessageVariant(msg) => todo!(),
-            MessageVariant::DiagnosticPowerModeRequestVariant(req) => todo!(),
             _ => (),
         }
+        Ok(())
     }
-    fn handle_connection(&self, stream: &mut TcpStream) {
-        let mut buff: Vec<u8> = Vec::<u8>::with_capacity(8);
-        let mut connection_state = ConnectionState::initialized;
-        loop {
-            buff.resize(8, 0);
-            match stream.read_exact(&mut buff) {
+    fn send_header_nack(
+        &self,
+        stream: &mut dyn DoIPStream,
+        connection: &ConnectionState,
+        code: NackCode,
+    ) -> io::Result<()> {
+        debug!(code = ?code, "header negative acknowledge");
+        self.notify(ServerEvent::HeaderNack { peer: connection.peer, code });
+        self.send(stream, &HeaderNackMessage::new(code).serialize())
+    }
+    fn notify(&self, event: ServerEvent) {
+        for observer in &self.observers {
+            observer.lock().unwrap().on_event(&event);
+        }
+    }
+    fn send(&self, stream: &mut dyn DoIPStream, frame: &[u8]) -> io::Result<()> {
+        self.capture_frame(stream, true, frame);
+        stream.write_all(frame)
+    }
+    fn capture_frame(&self, stream: &dyn DoIPStream, outgoing: bool, frame: &[u8]) {
+        let payload_type = logging::payload_type(frame);
+        trace!(outgoing, payload_type = ?payload_type, frame = %HexBytes(frame), "frame");
+        let (Some(capture), Ok(local), Ok(peer)) =
+            (&self.capture, stream.local_addr(), stream.peer_addr())
+        else {
+            return;
+        };
+        if outgoing {
+            capture.record(Transport::Tcp, local, peer, frame);
+        } else {
+            capture.record(Transport::Tcp, peer, local, frame);
+        }
+    }
+    fn accept(&self, stream: Box<dyn DoIPStream>, security: &Security) {
+        match security {
+            Security::Plain => self.handle_connection(&mut { stream }),
+            #[cfg(feature = "tls")]
+            Security::Tls(config) => {
+                /* The handshake takes place on the first read, bounded by the initial inactivity timer */
+                if let Ok(tls) = ServerConnection::new(config.clone()) {
+                    self.handle_connection(&mut StreamOwned::new(tls, stream));
+                }
+            }
+        }
+    }
+    fn handle_connection(&self, stream: &mut dyn DoIPStream) {
+        let Ok(peer) = stream.peer_addr() else {
+            return;
+        };
+        let _connection = info_span!("connection", peer = %peer, tls = stream.is_tls()).entered();
+        debug!("connection opened");
+        if stream.set_write_timeout(Some(DoIPServer::WRITE_TIMEOUT)).is_err() {
+            return;
+        }
+        let alive_check = Arc::new(AliveCheck::default());
+        let mut connection = ConnectionState { peer, source_address: None, close: false, alive_check };
+        self.open_sockets.fetch_add(1, Ordering::SeqCst);
+        self.notify(ServerEvent::ConnectionOpened { peer, tls: stream.is_tls() });
+        self.serve_connection(stream, &mut connection);
+        let _ = stream.shutdown();
+        if let Some(source_address) = connection.source_address {
+            self.registered_addresses.lock().unwrap().remove(&source_address);
+        }
+        self.open_sockets.fetch_sub(1, Ordering::SeqCst);
+        debug!(sa = ?connection.source_address, "connection closed");
+        self.notify(ServerEvent::ConnectionClosed { peer, source_address: connection.source_address });
+    }
+    fn serve_connection(&self, stream: &mut dyn DoIPStream, connection: &mut ConnectionState) {
+        let mut buff: Vec<u8> = vec![0; DoIPHeader::length()];
+        while !connection.close {
+            let inactivity_timeout = match connection.source_address {
+                Some(_) => self.timing.t_tcp_general_inactivity,
+                None => self.timing.t_tcp_initial_inactivity,
+            };
+            let deadline = self.clock().now() + inactivity_timeout;
+            buff.resize(DoIPHeader::length(), 0);
+            match self.read_until(stream, connection, &mut buff, deadline) {
                 Ok(_) => (),
-                Err(_) => {
-                    eprintln!("Error during socket read, closing");
+                Err(err) => {
+                    debug!(%err, "read failed, closing connection");
                     return;
                 }
             }
-            let payload_len = match DoIPHeader::from_buffer(&buff) {
-                Ok(header) => header.payload_length,
-                Err(code) => {
-                    stream
-                        .write_all(&HeaderNackMessage::new(code).serialize())
-                        .unwrap();
-                    if code == NackCode::IncorrectPattern || code == NackCode::InvalidPayloadLength
-                    {
+            let payload_len = DoIPHeader::get_payload_len(&buff);
+            let header_error = match DoIPHeader::from_buffer(&buff) {
+                Ok(_) if payload_len > self.max_data_size => Some(NackCode::MessageTooLong),
+                Ok(_) => None,
+                Err(code) => Some(code),
+            };
+            if let Some(code) = header_error {
+                self.capture_frame(stream, false, &buff);
+                if self.send_header_nack(stream, connection, code).is_err()
+                    || code == NackCode::IncorrectPattern
+                    || code == NackCode::InvalidPayloadLength
+                {
+                    return;
+                }
+                /* Discard the payload of the rejected message */
+                let mut remaining = payload_len as usize;
+                let mut discarded = [0; 4096];
+                while remaining > 0 {
+                    let len = remaining.min(discarded.len());
+                    if self.read_until(stream, connection, &mut discarded[..len], deadline).is_err() {
                         return;
                     }
-                    DoIPHeader::get_payload_len(&buff)
+                    remaining -= len;
                 }
-            };
-            let mut payload_buff: Vec<u8> = Vec::<u8>::with_capacity(payload_len as usize);
-            payload_buff.resize(payload_len as usize, 0);
-            match stream.read_exact(&mut payload_buff) {
+                continue;
+            }
+            let mut payload_buff: Vec<u8> = vec![0; payload_len as usize];
+            match self.read_until(stream, connection, &mut payload_buff, deadline) {
                 Ok(_) => (),
-                Err(_) => {
-                    eprintln!("Error during socket read, closing");
+                Err(err) => {
+                    debug!(%err, "read failed, closing connection");
                     return;
                 }
             }
             buff.extend_from_slice(&payload_buff);
-            match message_factory(&buff) {
-                Ok(message) =>self.handle_message(stream, &message),
+            self.capture_frame(stream, false, &buff);
+            let result = match message_factory(&buff) {
+                Ok(message) => self.handle_message(stream, connection, &message),
                 Err(code) => {
-                    stream
-                        .write_all(&HeaderNackMessage::new(code).serialize())
-                        .unwrap();
-                    if code == NackCode::InvalidPayloadLength {
-                        return;
-                    }
+                    connection.close = code == NackCode::InvalidPayloadLength;
+                    self.send_header_nack(stream, connection, code)
                 }
+            };
+            if let Err(err) = result {
+                debug!(%err, "write failed, closing connection");
+                return;
             }
         }
     }
-    pub fn start(&self) {
-        let announcement_message: VehicleIdentificationResponse =
-            VehicleIdentificationResponse::new(
-                &self.vin,
-                self.logical_address,
-                &self.eid,
-                &self.gid,
-                FurtherAction::NoFurtherAction,
-            );
-        let handle = thread::spawn(move || {
-            DoIPServer::identification_handler(announcement_message);
-        });
-        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, DoIPServer::DOIP_PORT)).unwrap();
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => self.handle_connection(&mut stream),
-                Err(_) => eprint!("Invalid stream received"),
+    /* Fills the buffer unless the tester stays silent until the deadline on the clock, fails an alive
+     * check or the server shuts down. Alive check requests go out meanwhile. */
+    fn read_until(
+        &self,
+        stream: &mut dyn DoIPStream,
+        connection: &ConnectionState,
+        buff: &mut [u8],
+        deadline: Instant,
+    ) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buff.len() {
+            if self.lifecycle.is_shut_down() || connection.alive_check.has_failed() {
+                return Err(io::ErrorKind::ConnectionAborted.into());
             }
+            if connection.alive_check.take_request() {
+                debug!("alive check request");
+                self.send(stream, &AliveCheckRequest::default().serialize())?;
+            }
+            let Some(timeout) = clock::wait_slice(self.clock(), deadline) else {
+                return Err(io::ErrorKind::TimedOut.into());
+            };
+            stream.set_read_timeout(Some(timeout.min(DoIPServer::POLL_INTERVAL)))?;
+            match stream.read(&mut buff[filled..]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => filled += len,
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_deref().unwrap_or(&SystemClock)
+    }
+    /* Binds all sockets and serves them on background threads until shut down through the handle */
+    pub fn start(self) -> io::Result<ServerHandle> {
+        let interface = self.interface.as_deref();
+        let scope_id = match (self.ipv6_interface, interface) {
+            (0, Some(name)) => network::interface_index(name)?,
+            (index, _) => index,
+        };
+        let mut udp_sockets = Vec::new();
+        match self.bind_address {
+            Some(address) => udp_sockets.push(self.init_udp_socket(address)?),
+            None => {
+                udp_sockets.push(self.init_udp_socket(Ipv4Addr::UNSPECIFIED.into())?);
+                match self.init_udp_socket(Ipv6Addr::UNSPECIFIED.into()) {
+                    Ok(socket) => udp_sockets.push(socket),
+                    Err(err) => warn!(%err, "IPv6 identification unavailable"),
+                }
+            }
+        }
+        let plain = (network::tcp_listener(self.bind_address, self.tcp_port, interface)?, Security::Plain);
+        #[cfg(not(feature = "tls"))]
+        let listeners = vec![plain];
+        #[cfg(feature = "tls")]
+        let listeners = match &self.tls_config {
+            Some(config) => {
+                let listener = network::tcp_listener(self.bind_address, self.tls_port, interface)?;
+                vec![plain, (listener, Security::Tls(config.clone()))]
+            }
+            None => vec![plain],
+        };
+        let listeners = listeners.into_iter().map(|(listener, security)| (Box::new(listener) as _, security));
+        let udp_sockets = udp_sockets.into_iter().map(|socket| Box::new(socket) as _);
+        self.run(listeners.collect(), udp_sockets.collect(), scope_id)
+    }
+    /* Serves plain tester connections from the listener and identification requests on the socket, e.g.
+     * the in-memory ones of transport::memory, on background threads until shut down through the handle */
+    pub fn serve(
+        self,
+        listener: Box<dyn StreamListener>,
+        socket: Option<Box<dyn DatagramSocket>>,
+    ) -> io::Result<ServerHandle> {
+        let scope_id = self.ipv6_interface;
+        self.run(vec![(listener, Security::Plain)], socket.into_iter().collect(), scope_id)
+    }
+    fn run(
+        self,
+        listeners: Vec<(Box<dyn StreamListener>, Security)>,
+        udp_sockets: Vec<Box<dyn DatagramSocket>>,
+        scope_id: u32,
+    ) -> io::Result<ServerHandle> {
+        let mut identification = Vec::new();
+        let mut udp_addresses = Vec::new();
+        for socket in udp_sockets {
+            let local = socket.local_addr()?;
+            /* Testers listen on the port they send their requests to, the DoIP port if it is ephemeral */
+            let port = match self.udp_port {
+                0 => DoIPServer::DOIP_PORT,
+                port => port,
+            };
+            let announcements: Vec<SocketAddr> = match &self.announcement_destinations {
+                Some(destinations) => {
+                    let same_version = |destination: &&SocketAddr| destination.is_ipv4() == local.is_ipv4();
+                    destinations.iter().filter(same_version).copied().collect()
+                }
+                None if local.is_ipv4() => vec![(Ipv4Addr::BROADCAST, port).into()],
+                None => vec![SocketAddrV6::new(DoIPServer::ALL_NODES, port, 0, scope_id).into()],
+            };
+            identification.push((socket, announcements));
+            udp_addresses.push(local);
+        }
+        let tcp_address = listeners[0].0.local_addr()?;
+        let tls_address = match listeners.get(1) {
+            Some((listener, _)) => Some(listener.local_addr()?),
+            None => None,
+        };
+        let server = Arc::new(self);
+        server.lifecycle.starting(identification.len() + listeners.len());
+        let mut threads = Vec::new();
+        for (socket, announcements) in identification {
+            let server = server.clone();
+            threads.push(thread::spawn(move || server.identification_handler(&*socket, &announcements)));
+        }
+        for (listener, security) in listeners {
+            let server = server.clone();
+            threads.push(thread::spawn(move || DoIPServer::listen(server, listener, security)));
         }
-        handle.join().unwrap();
+        let threads = Mutex::new(threads);
+        Ok(ServerHandle { tcp_address, tls_address, udp_addresses, server, threads })
+    }
+    fn listen(server: Arc<DoIPServer>, listener: Box<dyn StreamListener>, security: Security) {
+        if let Err(err) = listener.set_nonblocking(true) {
+            warn!(%err, "listener setup failed");
+            return;
+        }
+        server.lifecycle.started();
+        while !server.lifecycle.is_shut_down() {
+            match listener.accept() {
+                Ok(stream) => {
+                    let (connection_server, security) = (server.clone(), security.clone());
+                    let thread = thread::spawn(move || connection_server.accept(stream, &security));
+                    let mut connections = server.connections.lock().unwrap();
+                    connections.retain(|thread| !thread.is_finished());
+                    connections.push(thread);
+                }
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::WouldBlock {
+                        warn!(%err, "accepting tester connection failed");
+                    }
+                    server.lifecycle.sleep(&SystemClock, DoIPServer::POLL_INTERVAL);
+                }
+            }
+        }
+    }
+    /* Serves tester connections on an ephemeral loopback port, without UDP */
+    #[cfg(test)]
+    pub(crate) fn serve_loopback(self) -> std::net::SocketAddr {
+        self.serve_loopback_with(Security::Plain)
+    }
+    /* Same as serve_loopback, with TLS on the loopback port */
+    #[cfg(all(test, feature = "tls"))]
+    pub(crate) fn serve_loopback_tls(mut self) -> std::net::SocketAddr {
+        let config = self.tls_config.take().expect("TLS configuration missing");
+        self.serve_loopback_with(Security::Tls(config))
+    }
+    #[cfg(test)]
+    fn serve_loopback_with(self, security: Security) -> std::net::SocketAddr {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || DoIPServer::listen(Arc::new(self), Box::new(listener), security));
+        address
     }
     fn announce_on_upd_socket(
-        socket: &UdpSocket,
+        &self,
+        socket: &dyn DatagramSocket,
         response: &VehicleIdentificationResponse,
+        destinations: &[SocketAddr],
     ) -> io::Result<()> {
-        for _ in 0..DoIPServer::A_DO_IP_ANNOUNCE_NUM {
-            DoIPServer::send_announcement(&socket, response)?;
-            thread::sleep(DoIPServer::A_DO_IP_ANNOUNCE_INTERVAL);
+        for destination in destinations {
+            self.send_announcement(socket, response, *destination)?;
         }
         Ok(())
     }
     fn send_announcement(
-        socket: &UdpSocket,
+        &self,
+        socket: &dyn DatagramSocket,
         response: &VehicleIdentificationResponse,
+        destination: SocketAddr,
     ) -> io::Result<()> {
-        socket.send_to(
-            &response.serialize(),
-            (Ipv4Addr::BROADCAST, DoIPServer::DOIP_PORT),
-        )?;
+        socket.send_to(&response.serialize(), destination)?;
+        debug!(destination = %destination, "vehicle announcement sent");
+        self.notify(ServerEvent::AnnouncementSent { destination });
         Ok(())
     }
-    fn announce_wait_random() {
-        let a_do_ip_announce_wait: Duration =
-            Duration::from_millis(rand::thread_rng().gen_range(0..=500));
-        thread::sleep(a_do_ip_announce_wait);
+    fn announce_wait_random(&self) -> Duration {
+        let range = Duration::ZERO..=self.timing.a_doip_announce_wait;
+        match self.rng.lock().unwrap().as_mut() {
+            Some(rng) => rng.gen_range(range),
+            None => rand::thread_rng().gen_range(range),
+        }
     }
-    fn init_udp_socket() -> io::Result<UdpSocket> {
-        let socket: UdpSocket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DoIPServer::DOIP_PORT))?;
-        socket.set_broadcast(true)?;
-        socket.set_write_timeout(Some(Duration::from_secs(5)))?;
+    /* Broadcasts need SO_BROADCAST; all-nodes multicast reaches IPv6 sockets without joining */
+    fn init_udp_socket(&self, address: IpAddr) -> io::Result<UdpSocket> {
+        let socket = network::udp_socket(SocketAddr::new(address, self.udp_port), self.interface.as_deref())?;
+        if address.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
+        socket.set_write_timeout(Some(DoIPServer::WRITE_TIMEOUT))?;
         Ok(socket)
     }
     fn is_id_req_addr_us(
-        message: &MessageVariant,
+        request: &IdentificationRequest,
         response: &VehicleIdentificationResponse,
     ) -> bool {
-        match message {
-            MessageVariant::VehicleIDReqVariant(_) => true,
-            MessageVariant::VehicleIDReqByEIDVariant(request) => request.eid == response.eid,
-            MessageVariant::VehicleIDReqByVINVariant(request) => request.vin == response.vin,
-            _ => false,
+        match request {
+            IdentificationRequest::All => true,
+            IdentificationRequest::ByEid(eid) => *eid == response.eid,
+            IdentificationRequest::ByVin(vin) => *vin == response.vin,
         }
     }
-    fn identification_handler(response: VehicleIdentificationResponse) -> ! {
+    fn identification_handler(&self, socket: &dyn DatagramSocket, announcements: &[SocketAddr]) {
+        let response = VehicleIdentificationResponse::new(
+            &self.vin,
+            self.logical_address,
+            &self.eid,
+            &self.gid,
+            FurtherAction::NoFurtherAction,
+        );
         let mut header_buff: [u8; 40] = [0; 40];
-        let socket = DoIPServer::init_udp_socket().expect("UDP socket setup failed");
-        DoIPServer::announce_wait_random();
-        DoIPServer::announce_on_upd_socket(&socket, &response).expect("Announcement failed");
-        loop {
-            if let Ok((len, addr)) = socket.recv_from(&mut header_buff) {
-                match message_factory(&header_buff) {
+        if let Err(err) = socket.set_read_timeout(Some(DoIPServer::POLL_INTERVAL)) {
+            warn!(%err, "identification socket setup failed");
+            return;
+        }
+        self.lifecycle.started();
+        /* Announcements go out between identification requests, so these are answered right away */
+        let clock = self.clock();
+        let mut pending_announcements = self.timing.a_doip_announce_num;
+        let mut next_announcement = clock.now() + self.announce_wait_random();
+        while !self.lifecycle.is_shut_down() {
+            let mut timeout = DoIPServer::POLL_INTERVAL;
+            if pending_announcements > 0 {
+                match clock::wait_slice(clock, next_announcement) {
+                    Some(wait) => timeout = timeout.min(wait),
+                    None => {
+                        pending_announcements -= 1;
+                        next_announcement = clock.now() + self.timing.a_doip_announce_interval;
+                        if let Err(err) = self.announce_on_upd_socket(socket, &response, announcements) {
+                            warn!(%err, "vehicle announcement failed");
+                            pending_announcements = 0;
+                        }
+                        continue;
+                    }
+                }
+            }
+            if let Err(err) = socket.set_read_timeout(Some(timeout)) {
+                warn!(%err, "identification socket setup failed");
+                return;
+            }
+            match socket.recv_from(&mut header_buff) {
+                Ok((len, addr)) => match message_factory(&header_buff[..len]) {
                     Ok(message) => {
-                        if DoIPServer::is_id_req_addr_us(&message, &response) {
-                            if let Err(r) = socket.send_to(&response.serialize(), addr) {
-                                eprintln!("Error during sending announcement: {}", r);
+                        let Some(request) = IdentificationRequest::from_message(&message) else {
+                            continue;
+                        };
+                        let answered = DoIPServer::is_id_req_addr_us(&request, &response);
+                        debug!(peer = %addr, request = ?request, answered, "vehicle identification request");
+                        self.notify(ServerEvent::IdentificationRequest { peer: addr, request, answered });
+                        if answered {
+                            if let Err(err) = socket.send_to(&response.serialize(), addr) {
+                                warn!(peer = %addr, %err, "vehicle identification response failed");
                             }
                         }
                     }
                     Err(code) => {
-                        eprintln!("Identification message parsing failed: {:?}", code);
+                        debug!(peer = %addr, code = ?code, "invalid identification request");
                     }
+                },
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => (),
+                Err(err) => {
+                    warn!(%err, "identification socket receive failed");
+                    self.lifecycle.sleep(&SystemClock, DoIPServer::POLL_INTERVAL);
                 }
-            } else {
-                eprintln!("Error during recv_from");
             }
-        } // loop
+        }
     }
 }
 
-#[derive(Default)]
 pub struct DoIPServerBuilder {
     server: DoIPServer,
 }
+impl Default for DoIPServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl DoIPServerBuilder {
     pub fn new() -> Self {
-        let mut server: DoIPServer = Default::default();
-        server.max_sockets = 10;
-        server.open_sockets = 0;
-        server.max_data_size = u32::max_value();
+        let server = DoIPServer {
+            max_sockets: 10,
+            max_data_size: u32::MAX,
+            max_diagnostic_size: u32::MAX,
+            tcp_port: DoIPServer::DOIP_PORT,
+            udp_port: DoIPServer::DOIP_PORT,
+            #[cfg(feature = "tls")]
+            tls_port: DoIPServer::DOIP_TLS_PORT,
+            ..Default::default()
+        };
         DoIPServerBuilder { server }
     }
     pub fn set_vin(&mut self, vin: &[u8; 17]) -> &mut Self {
@@ -241,6 +919,123 @@ impl DoIPServerBuilder {
         self.server.logical_address = address;
         self
     }
+    pub fn set_node_type(&mut self, node_type: NodeType) -> &mut Self {
+        self.server.node_type = node_type;
+        self
+    }
+    pub fn set_max_sockets(&mut self, max_sockets: u8) -> &mut Self {
+        self.server.max_sockets = max_sockets;
+        self
+    }
+    pub fn set_max_data_size(&mut self, max_data_size: u32) -> &mut Self {
+        self.server.max_data_size = max_data_size;
+        self
+    }
+    pub fn set_max_diagnostic_size(&mut self, max_diagnostic_size: u32) -> &mut Self {
+        self.server.max_diagnostic_size = max_diagnostic_size;
+        self
+    }
+    pub fn set_timing(&mut self, timing: TimingParameters) -> Result<&mut Self, TimingError> {
+        timing.validate()?;
+        self.server.timing = timing;
+        Ok(self)
+    }
+    /* Time source of announcements, inactivity timers and response delays */
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.server.clock = Some(clock);
+        self
+    }
+    /* Source of the random wait before the first vehicle announcement */
+    pub fn set_rng<R: RngCore + Send + 'static>(&mut self, rng: R) -> &mut Self {
+        self.server.rng = Mutex::new(Some(Box::new(rng)));
+        self
+    }
+    /* Sends IPv6 announcements to the all-nodes group on the interface with this index */
+    pub fn set_ipv6_interface(&mut self, interface_index: u32) -> &mut Self {
+        self.server.ipv6_interface = interface_index;
+        self
+    }
+    /* Serves on this address only; note that sockets bound to a unicast address miss broadcasts */
+    pub fn set_bind_address(&mut self, address: IpAddr) -> &mut Self {
+        self.server.bind_address = Some(address);
+        self
+    }
+    /* Keeps all traffic on the named network interface */
+    pub fn set_interface(&mut self, name: &str) -> &mut Self {
+        self.server.interface = Some(name.to_string());
+        self
+    }
+    /* Port 0 selects an ephemeral port, see ServerHandle for the one bound */
+    pub fn set_tcp_port(&mut self, port: u16) -> &mut Self {
+        self.server.tcp_port = port;
+        self
+    }
+    /* Port of identification requests and of the default announcement destinations */
+    pub fn set_udp_port(&mut self, port: u16) -> &mut Self {
+        self.server.udp_port = port;
+        self
+    }
+    #[cfg(feature = "tls")]
+    pub fn set_tls_port(&mut self, port: u16) -> &mut Self {
+        self.server.tls_port = port;
+        self
+    }
+    /* Sends vehicle announcements to these addresses, e.g. a directed broadcast or single testers,
+     * instead of the broadcast and all-nodes addresses. An empty list disables announcements. */
+    pub fn set_announcement_destinations(&mut self, destinations: &[SocketAddr]) -> &mut Self {
+        self.server.announcement_destinations = Some(destinations.to_vec());
+        self
+    }
+    /* Reports protocol activity to the observer, e.g. a closure */
+    pub fn add_observer<O: ServerObserver + 'static>(&mut self, observer: O) -> &mut Self {
+        self.server.observers.push(Arc::new(Mutex::new(observer)));
+        self
+    }
+    /* Reports protocol activity through the returned channel */
+    pub fn subscribe(&mut self) -> mpsc::Receiver<ServerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.add_observer(sender);
+        receiver
+    }
+    /* Writes the traffic of all tester connections to a pcap capture */
+    pub fn set_capture(&mut self, capture: PcapCapture) -> &mut Self {
+        self.server.capture = Some(capture);
+        self
+    }
+    /* Accepts TLS protected tester connections on port 3496 in addition to plain TCP */
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, config: Arc<ServerConfig>) -> &mut Self {
+        self.server.tls_config = Some(config);
+        self
+    }
+    /* Grants the holder of a matching client certificate routing activation with the given source
+     * addresses and activation types. Once any grant exists, TLS testers without one are denied. */
+    #[cfg(feature = "tls")]
+    pub fn authorize_tester(
+        &mut self,
+        identity: CertificateIdentity,
+        source_addresses: &[u16],
+        activation_types: &[u8],
+    ) -> &mut Self {
+        self.server.tester_authorizations.push(TesterAuthorization {
+            identity,
+            source_addresses: source_addresses.to_vec(),
+            activation_types: activation_types.to_vec(),
+        });
+        self
+    }
+    /* Denies routing activation of the given types on connections without TLS */
+    pub fn set_tls_activation_types(&mut self, activation_types: &[u8]) -> &mut Self {
+        self.server.tls_activation_types = activation_types.iter().copied().collect();
+        self
+    }
+    pub fn set_diagnostic_handler<H>(&mut self, handler: H) -> &mut Self
+    where
+        H: DiagnosticHandler + 'static,
+    {
+        self.server.diagnostic_handler = Some(Arc::new(Mutex::new(handler)));
+        self
+    }
     pub fn get_server(self) -> DoIPServer {
         self.server
     }
@@ -249,6 +1044,681 @@ impl DoIPServerBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::doip_client::{DoIPClient, DoIPClientSession, IdentificationRequest, SessionError};
+    use crate::message::{
+        decoder::FrameDecoder, diag_power_mode::DiagnosticPowerModeRequest,
+        entity_status::EntityStatusRequest, header::PayloadType,
+    };
+    use crate::pcap::{CapturedMessage, PcapReader};
+    use crate::message::vehicle_identification::{
+        VehicleIdentificationRequest, VehicleIdentificationRequestEID,
+    };
+    use crate::test_util::SharedBuffer;
+    use crate::clock::ManualClock;
+    use rand::rngs::mock::StepRng;
+    use crate::transport::{memory::MemoryNetwork, DatagramSocket};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    struct EchoHandler;
+    impl DiagnosticHandler for EchoHandler {
+        fn is_target_known(&self, target_address: u16) -> bool {
+            target_address == 0x1001
+        }
+        fn handle_request(&mut self, _: u16, target_address: u16, request: &[u8]) -> Vec<DiagnosticResponse> {
+            vec![DiagnosticResponse::new(target_address, [&[request[0] + 0x40], &request[1..]].concat())]
+        }
+    }
+
+    fn serve() -> std::net::SocketAddr {
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_max_sockets(2).set_diagnostic_handler(EchoHandler);
+        builder.get_server().serve_loopback()
+    }
+    fn exchange(stream: &mut TcpStream, request: &[u8]) -> MessageVariant {
+        stream.write_all(request).unwrap();
+        let mut decoder = FrameDecoder::new();
+        let mut buff = [0; 256];
+        loop {
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                return message_factory(&frame).unwrap();
+            }
+            let len = stream.read(&mut buff).unwrap();
+            decoder.push(&buff[..len]);
+        }
+    }
+
+    #[test]
+    fn route_diagnostic_message_to_handler() {
+        let mut session = DoIPClientSession::connect(serve(), 0x0E80).unwrap();
+        assert_eq!(session.entity_address(), 0x1000);
+        session.send_diagnostic(0x1001, &[0x22, 0xF1, 0x90]).unwrap();
+        let response = session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        assert_eq!(response, DiagMessage::new(0x1001, 0x0E80, &[0x62, 0xF1, 0x90]));
+        assert!(matches!(
+            session.send_diagnostic(0x1002, &[0x3E, 0x00]),
+            Err(SessionError::DiagnosticNack(DiagNackCode::UnknownTargetAddress))
+        ));
+    }
+    #[test]
+    fn reject_diagnostic_message_too_large() {
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_max_diagnostic_size(4).set_diagnostic_handler(EchoHandler);
+        let mut stream = TcpStream::connect(builder.get_server().serve_loopback()).unwrap();
+        let activation = [0x03, 0xFC, 0x00, 0x05, 0, 0, 0, 7, 0x0E, 0x80, 0x00, 0, 0, 0, 0];
+        let activated = exchange(&mut stream, &activation);
+        assert!(matches!(activated, MessageVariant::RoutingActivationResponseVariant(_)));
+        let request = [0x03, 0xFC, 0x80, 0x01, 0, 0, 0, 9, 0x0E, 0x80, 0x10, 0x01, 0x2E, 0xF1, 0x90, 0, 1];
+        stream.write_all(&request).unwrap();
+        let mut nack = [0; 13];
+        stream.read_exact(&mut nack).unwrap();
+        assert_eq!(nack, [0x03, 0xFC, 0x80, 0x03, 0, 0, 0, 5, 0x10, 0x01, 0x0E, 0x80, 0x04]);
+        /* Within the limit the message is routed */
+        let request = [0x03, 0xFC, 0x80, 0x01, 0, 0, 0, 8, 0x0E, 0x80, 0x10, 0x01, 0x22, 0xF1, 0x90, 0x00];
+        assert!(matches!(exchange(&mut stream, &request), MessageVariant::DiagnosticMessageAckVariant(_)));
+    }
+    #[test]
+    fn delayed_response_does_not_block_other_connections() {
+        /* ECU 0x1002 answers after a delay on the server clock, 0x1001 right away */
+        struct DelayingHandler;
+        impl DiagnosticHandler for DelayingHandler {
+            fn is_target_known(&self, target_address: u16) -> bool {
+                matches!(target_address, 0x1001 | 0x1002)
+            }
+            fn handle_request(&mut self, _: u16, target: u16, request: &[u8]) -> Vec<DiagnosticResponse> {
+                let mut response = EchoHandler.handle_request(0, target, request);
+                if target == 0x1002 {
+                    response[0].delay = Duration::from_secs(10);
+                }
+                response
+            }
+        }
+        let clock = Arc::new(ManualClock::new());
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_clock(clock.clone()).set_diagnostic_handler(DelayingHandler);
+        let address = builder.get_server().serve_loopback();
+        let mut slow = DoIPClientSession::connect(address, 0x0E80).unwrap();
+        let mut fast = DoIPClientSession::connect(address, 0x0E81).unwrap();
+        slow.send_diagnostic(0x1002, &[0x22, 0xF1, 0x90]).unwrap();
+        assert!(matches!(slow.receive_diagnostic(Duration::from_millis(100)), Err(SessionError::Timeout)));
+
+        fast.send_diagnostic(0x1001, &[0x3E, 0x00]).unwrap();
+        let response = fast.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        assert_eq!(response, DiagMessage::new(0x1001, 0x0E81, &[0x7E, 0x00]));
+
+        clock.advance(Duration::from_secs(10));
+        let response = slow.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        assert_eq!(response, DiagMessage::new(0x1002, 0x0E80, &[0x62, 0xF1, 0x90]));
+    }
+    #[test]
+    fn acknowledge_within_processing_time() {
+        /* Holds the handler until released, as an ECU taking long to answer */
+        struct BlockingHandler(mpsc::Sender<()>, Mutex<mpsc::Receiver<()>>);
+        impl DiagnosticHandler for BlockingHandler {
+            fn is_target_known(&self, target_address: u16) -> bool {
+                target_address == 0x1001
+            }
+            fn handle_request(&mut self, _: u16, target: u16, request: &[u8]) -> Vec<DiagnosticResponse> {
+                let _ = self.0.send(());
+                let _ = self.1.lock().unwrap().recv();
+                EchoHandler.handle_request(0, target, request)
+            }
+        }
+        let (release, released) = mpsc::channel();
+        let (enter, entered) = mpsc::channel();
+        let timing = TimingParameters { a_processing_time: Duration::from_millis(100), ..Default::default() };
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_timing(timing).unwrap();
+        builder.set_diagnostic_handler(BlockingHandler(enter, Mutex::new(released)));
+        let address = builder.get_server().serve_loopback();
+        let mut busy = DoIPClientSession::connect(address, 0x0E80).unwrap();
+        let mut other = DoIPClientSession::connect(address, 0x0E81).unwrap();
+        busy.send_diagnostic(0x1001, &[0x3E, 0x00]).unwrap();
+        /* The acknowledgement precedes the handler call */
+        entered.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(
+            other.send_diagnostic(0x1001, &[0x3E, 0x00]),
+            Err(SessionError::DiagnosticNack(DiagNackCode::TargetUnreachable))
+        ));
+        release.send(()).unwrap();
+        assert_eq!(busy.receive_diagnostic(Duration::from_secs(1)).unwrap().user_data, vec![0x7E, 0x00]);
+        other.send_diagnostic(0x1001, &[0x3E, 0x00]).unwrap();
+        release.send(()).unwrap();
+        assert!(other.receive_diagnostic(Duration::from_secs(1)).is_ok());
+    }
+    #[test]
+    fn free_socket_of_silent_tester() {
+        let timing = TimingParameters { t_tcp_alive_check: Duration::from_millis(100), ..Default::default() };
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_max_sockets(1).set_diagnostic_handler(EchoHandler);
+        builder.set_timing(timing).unwrap();
+        let address = builder.get_server().serve_loopback();
+        let mut silent = DoIPClientSession::connect(address, 0x0E80).unwrap();
+        let mut session = DoIPClientSession::connect(address, 0x0E81).unwrap();
+        session.send_diagnostic(0x1001, &[0x3E, 0x00]).unwrap();
+        assert!(session.receive_diagnostic(Duration::from_secs(1)).is_ok());
+        assert!(silent.send_diagnostic(0x1001, &[0x3E, 0x00]).is_err());
+    }
+    #[test]
+    fn keep_socket_of_tester_answering_alive_check() {
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_max_sockets(1).set_diagnostic_handler(EchoHandler);
+        let address = builder.get_server().serve_loopback();
+        let mut active = DoIPClientSession::connect(address, 0x0E80).unwrap();
+        /* Waiting for a response, the session answers alive check requests */
+        let tester = thread::spawn(move || {
+            assert!(matches!(active.receive_diagnostic(Duration::from_secs(1)), Err(SessionError::Timeout)));
+            active
+        });
+        assert!(matches!(
+            DoIPClientSession::connect(address, 0x0E81),
+            Err(SessionError::RoutingActivationDenied(RoutingActivationCode::DeniedNoSocketAvailable))
+        ));
+        let mut active = tester.join().unwrap();
+        active.send_diagnostic(0x1001, &[0x3E, 0x00]).unwrap();
+        assert!(active.receive_diagnostic(Duration::from_secs(1)).is_ok());
+    }
+    #[test]
+    fn require_tls_for_activation_type() {
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_tls_activation_types(&[0xE0]);
+        let address = builder.get_server().serve_loopback();
+        assert!(matches!(
+            DoIPClientSession::connect_with_activation_type(address, 0x0E80, 0xE0),
+            Err(SessionError::RoutingActivationDenied(RoutingActivationCode::DeniedRequiresTls))
+        ));
+        assert!(DoIPClientSession::connect(address, 0x0E80).is_ok());
+    }
+    #[cfg(feature = "tls")]
+    #[test]
+    fn route_diagnostic_message_over_tls() {
+        use crate::test_util::TestPki;
+        use crate::tls::{TlsClientConfig, TlsServerConfig};
+        let pki = TestPki::new();
+        let mut builder = DoIPServerBuilder::new();
+        builder
+            .set_logical_address(0x1000)
+            .set_diagnostic_handler(EchoHandler)
+            .set_tls(TlsServerConfig::new(pki.issue("localhost")).build().unwrap())
+            .set_tls_activation_types(&[0xE0]);
+        let address = builder.get_server().serve_loopback_tls();
+        let config = TlsClientConfig::new(pki.ca()).unwrap().build().unwrap();
+        let mut session = DoIPClientSession::connect_tls(address, 0x0E80, 0xE0, "localhost", config).unwrap();
+        session.send_diagnostic(0x1001, &[0x22, 0xF1, 0x90]).unwrap();
+        let response = session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        assert_eq!(response.user_data, [0x62, 0xF1, 0x90]);
+        let config = TlsClientConfig::new(TestPki::new().ca()).unwrap().build().unwrap();
+        assert!(DoIPClientSession::connect_tls(address, 0x0E81, 0, "localhost", config).is_err());
+    }
+    #[cfg(feature = "tls")]
+    #[test]
+    fn verify_tester_certificate() {
+        use crate::test_util::TestPki;
+        use crate::tls::{TlsClientConfig, TlsServerConfig};
+        let pki = TestPki::new();
+        let mut tls = TlsServerConfig::new(pki.issue("localhost"));
+        tls.set_client_roots(pki.ca()).unwrap();
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_tls(tls.build().unwrap());
+        let address = builder.get_server().serve_loopback_tls();
+        let mut client = TlsClientConfig::new(pki.ca()).unwrap();
+        let anonymous = client.build().unwrap();
+        assert!(DoIPClientSession::connect_tls(address, 0x0E80, 0, "localhost", anonymous).is_err());
+        let authenticated = client.set_identity(pki.issue("tester")).build().unwrap();
+        assert!(DoIPClientSession::connect_tls(address, 0x0E80, 0, "localhost", authenticated).is_ok());
+    }
+    #[cfg(feature = "tls")]
+    #[test]
+    fn authorize_tester_certificates() {
+        use crate::test_util::TestPki;
+        use crate::tls::{certificate_fingerprint, TlsClientConfig, TlsIdentity, TlsServerConfig};
+        let pki = TestPki::new();
+        let backend = pki.issue("backend");
+        let mut tls = TlsServerConfig::new(pki.issue("localhost"));
+        tls.set_client_roots(pki.ca()).unwrap();
+        let mut builder = DoIPServerBuilder::new();
+        builder
+            .set_logical_address(0x1000)
+            .set_tls(tls.build().unwrap())
+            .authorize_tester(CertificateIdentity::Subject("CN=workshop".to_string()), &[0x0E80], &[0x00])
+            .authorize_tester(
+                CertificateIdentity::Fingerprint(certificate_fingerprint(&backend.cert_chain[0])),
+                &[0x0F00],
+                &[0x00, 0xE0],
+            );
+        let address = builder.get_server().serve_loopback_tls();
+        let connect = |identity: TlsIdentity, source_address, activation_type| {
+            let config = TlsClientConfig::new(pki.ca()).unwrap().set_identity(identity).build().unwrap();
+            DoIPClientSession::connect_tls(address, source_address, activation_type, "localhost", config)
+        };
+        let denied = |result: Result<DoIPClientSession, SessionError>| match result {
+            Err(SessionError::RoutingActivationDenied(code)) => code,
+            _ => panic!("routing activation not denied"),
+        };
+        assert!(connect(pki.issue("workshop"), 0x0E80, 0x00).is_ok());
+        assert_eq!(
+            denied(connect(pki.issue("workshop"), 0x0E80, 0xE0)),
+            RoutingActivationCode::DeniedMissingAuthentication
+        );
+        assert_eq!(
+            denied(connect(pki.issue("workshop"), 0x0E81, 0x00)),
+            RoutingActivationCode::DeniedUnknownSourceAddress
+        );
+        assert_eq!(
+            denied(connect(pki.issue("intruder"), 0x0E80, 0x00)),
+            RoutingActivationCode::DeniedMissingAuthentication
+        );
+        assert!(connect(backend, 0x0F00, 0xE0).is_ok());
+    }
+    #[test]
+    fn accept_ipv4_and_ipv6_testers() {
+        let listener = network::tcp_listener(None, 0, None).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000);
+        let server = Arc::new(builder.get_server());
+        thread::spawn(move || DoIPServer::listen(server, Box::new(listener), Security::Plain));
+        assert!(DoIPClientSession::connect((Ipv6Addr::LOCALHOST, port), 0x0E80).is_ok());
+        assert!(DoIPClientSession::connect((Ipv4Addr::LOCALHOST, port), 0x0E81).is_ok());
+    }
+    #[test]
+    fn identify_over_ipv6() {
+        let socket = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let entity = socket.local_addr().unwrap();
+        let tester = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let announcement = tester.local_addr().unwrap();
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_vin(b"WDD00000000000001").set_logical_address(0x1000);
+        let server = builder.get_server();
+        thread::spawn(move || server.identification_handler(&socket, &[announcement]));
+        tester.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut buff = [0; 64];
+        let (len, source) = tester.recv_from(&mut buff).unwrap();
+        assert_eq!(source, entity);
+        assert!(matches!(message_factory(&buff[..len]), Ok(MessageVariant::VehicleIDResVariant(_))));
+        let request = IdentificationRequest::All;
+        let entities = DoIPClient::discover(entity, &request, Duration::from_secs(3)).unwrap();
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].address, entity);
+        assert_eq!(entities[0].response.logical_address, 0x1000);
+    }
+    #[test]
+    fn spawn_on_ephemeral_ports() {
+        let tester = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut builder = DoIPServerBuilder::new();
+        builder
+            .set_logical_address(0x1000)
+            .set_bind_address(Ipv4Addr::LOCALHOST.into())
+            .set_tcp_port(0)
+            .set_udp_port(0)
+            .set_announcement_destinations(&[tester.local_addr().unwrap()]);
+        let handle = builder.get_server().start().unwrap();
+        assert_ne!(handle.tcp_address.port(), 0);
+        assert_eq!(handle.udp_addresses.len(), 1);
+        tester.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut buff = [0; 64];
+        let (len, source) = tester.recv_from(&mut buff).unwrap();
+        assert_eq!(source, handle.udp_addresses[0]);
+        assert!(matches!(message_factory(&buff[..len]), Ok(MessageVariant::VehicleIDResVariant(_))));
+        let session = DoIPClientSession::connect(handle.tcp_address, 0x0E80).unwrap();
+        assert_eq!(session.entity_address(), 0x1000);
+    }
+    #[test]
+    fn shut_down_gracefully() {
+        let mut builder = DoIPServerBuilder::new();
+        builder
+            .set_logical_address(0x1000)
+            .set_bind_address(Ipv4Addr::LOCALHOST.into())
+            .set_tcp_port(0)
+            .set_udp_port(0)
+            .set_announcement_destinations(&[]);
+        let handle = builder.get_server().start().unwrap();
+        assert!(handle.wait_ready(Duration::from_secs(3)));
+        let mut session = DoIPClientSession::connect(handle.tcp_address, 0x0E80).unwrap();
+        handle.shutdown();
+        assert!(session.receive_diagnostic(Duration::from_secs(1)).is_err());
+        assert!(TcpStream::connect(handle.tcp_address).is_err());
+    }
+    #[test]
+    fn announce_to_configured_udp_port() {
+        let network = MemoryNetwork::new();
+        let entity: SocketAddr = (Ipv4Addr::new(192, 168, 0, 10), 13401).into();
+        let tester = network.bind((Ipv4Addr::new(192, 168, 0, 1), 13401).into()).unwrap();
+        let other = network.bind((Ipv4Addr::new(192, 168, 0, 1), DoIPServer::DOIP_PORT).into()).unwrap();
+        let mut builder = DoIPServerBuilder::new();
+        let timing = TimingParameters { a_doip_announce_num: 1, ..Default::default() };
+        builder.set_udp_port(13401).set_timing(timing).unwrap();
+        let listener = Box::new(network.listen(entity).unwrap());
+        let socket = Box::new(network.bind(entity).unwrap());
+        let handle = builder.get_server().serve(listener, Some(socket)).unwrap();
+        let mut buff = [0; 64];
+        tester.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let (len, _) = tester.recv_from(&mut buff).unwrap();
+        assert!(matches!(message_factory(&buff[..len]), Ok(MessageVariant::VehicleIDResVariant(_))));
+        other.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(other.recv_from(&mut buff).is_err());
+        handle.shutdown();
+    }
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn trace_connection_with_addresses() {
+        use crate::test_util::{TraceRecorder, TracedEvent};
+        let network = MemoryNetwork::new();
+        let entity: SocketAddr = (Ipv4Addr::new(192, 168, 0, 10), DoIPServer::DOIP_PORT).into();
+        let listener = network.listen(entity).unwrap();
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_diagnostic_handler(EchoHandler);
+        let server = builder.get_server();
+        let tester = thread::spawn(move || {
+            let stream = network.connect(entity).unwrap();
+            let mut session = DoIPClient::default().connect_over(stream, 0x0E80, 0).unwrap();
+            session.send_diagnostic(0x1001, &[0x3E, 0x00]).unwrap();
+            session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+            session.local_address().unwrap()
+        });
+        /* The connection is served on this thread, the default of the recorder */
+        let recorder = TraceRecorder::default();
+        let mut stream = listener.accept().unwrap();
+        recorder.capture(|| server.handle_connection(&mut *stream));
+        let peer = tester.join().unwrap().to_string();
+        let events = recorder.events();
+        assert!(events.iter().all(|event| event.field("peer") == Some(&peer)));
+        let is_routed = |event: &&TracedEvent| event.message() == Some("diagnostic message routed");
+        let routed = events.iter().find(is_routed).unwrap();
+        assert_eq!(routed.spans, ["connection", "diagnostic_request"]);
+        assert_eq!((routed.field("sa"), routed.field("ta")), (Some("3712"), Some("4097")));
+        let incoming = events
+            .iter()
+            .filter(|event| event.message() == Some("frame") && event.field("outgoing") == Some("false"));
+        let payload_types: Vec<_> = incoming.filter_map(|event| event.field("payload_type")).collect();
+        assert_eq!(payload_types, ["Some(RoutingActivationReq)", "Some(DiagMessage)"]);
+    }
+    #[test]
+    fn serve_over_memory_transport() {
+        let network = MemoryNetwork::new();
+        let entity: SocketAddr = (Ipv4Addr::new(192, 168, 0, 10), DoIPServer::DOIP_PORT).into();
+        let tester = network.bind((Ipv4Addr::new(192, 168, 0, 1), DoIPServer::DOIP_PORT).into()).unwrap();
+        let mut builder = DoIPServerBuilder::new();
+        let timing = TimingParameters { a_doip_announce_num: 1, ..Default::default() };
+        builder.set_logical_address(0x1000).set_diagnostic_handler(EchoHandler).set_timing(timing).unwrap();
+        let listener = Box::new(network.listen(entity).unwrap());
+        let socket = Box::new(network.bind(entity).unwrap());
+        let handle = builder.get_server().serve(listener, Some(socket)).unwrap();
+        assert!(handle.wait_ready(Duration::from_secs(3)));
+        let mut buff = [0; 64];
+        tester.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let (len, source) = tester.recv_from(&mut buff).unwrap();
+        assert_eq!(source, entity);
+        assert!(matches!(message_factory(&buff[..len]), Ok(MessageVariant::VehicleIDResVariant(_))));
+        let client = DoIPClient::default();
+        let timeout = Duration::from_millis(200);
+        let entities = client.identify_over(&tester, entity, &IdentificationRequest::All, timeout);
+        assert_eq!(entities.unwrap().len(), 1);
+        let stream = network.connect(entity).unwrap();
+        let mut session = client.connect_over(stream, 0x0E80, 0).unwrap();
+        assert_eq!(session.entity_address(), 0x1000);
+        session.send_diagnostic(0x1001, &[0x22, 0xF1, 0x90]).unwrap();
+        let response = session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        assert_eq!(response.user_data, [0x62, 0xF1, 0x90]);
+        handle.shutdown();
+        assert!(session.receive_diagnostic(Duration::from_secs(1)).is_err());
+        assert!(network.connect(entity).is_err());
+    }
+    #[test]
+    fn reject_unknown_interface() {
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_interface("nodoip0").set_tcp_port(0).set_udp_port(0);
+        assert!(builder.get_server().start().is_err());
+    }
+    #[test]
+    fn capture_tester_traffic() {
+        let buffer = SharedBuffer::default();
+        let mut builder = DoIPServerBuilder::new();
+        builder
+            .set_logical_address(0x1000)
+            .set_diagnostic_handler(EchoHandler)
+            .set_capture(PcapCapture::new(buffer.clone()).unwrap());
+        let address = builder.get_server().serve_loopback();
+        let mut session = DoIPClientSession::connect(address, 0x0E80).unwrap();
+        session.send_diagnostic(0x1001, &[0x22, 0xF1, 0x90]).unwrap();
+        session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        let contents = buffer.contents();
+        let mut reader = PcapReader::new(&contents[..]).unwrap();
+        reader.set_ports(&[address.port()]);
+        let messages: Vec<CapturedMessage> = reader.map(Result::unwrap).collect();
+        assert_eq!(messages.len(), 5);
+        assert!(matches!(messages[0].message, MessageVariant::RoutingActivationRequestVariant(_)));
+        assert_eq!(messages[0].destination, address);
+        assert!(matches!(&messages[4].message, MessageVariant::DiagnoticMessageVariant(response)
+            if response.user_data == [0x62, 0xF1, 0x90]));
+        assert_eq!(messages[4].source, address);
+    }
+    #[test]
+    fn deny_source_address_in_use() {
+        let address = serve();
+        let _session = DoIPClientSession::connect(address, 0x0E80).unwrap();
+        assert!(matches!(
+            DoIPClientSession::connect(address, 0x0E80),
+            Err(SessionError::RoutingActivationDenied(RoutingActivationCode::DeniedSAInUse))
+        ));
+        assert!(DoIPClientSession::connect(address, 0x0E81).is_ok());
+    }
+    #[test]
+    fn keep_source_address_of_routed_connection() {
+        let mut stream = TcpStream::connect(serve()).unwrap();
+        let activate = |stream: &mut TcpStream, source_address| {
+            match exchange(stream, &RoutingActivationRequest::new(source_address, 0).serialize()) {
+                MessageVariant::RoutingActivationResponseVariant(response) => {
+                    response.routing_activation_response_code
+                }
+                _ => panic!("expected routing activation response"),
+            }
+        };
+        assert_eq!(activate(&mut stream, 0x0E80), RoutingActivationCode::RoutingActivated);
+        assert_eq!(activate(&mut stream, 0x0E80), RoutingActivationCode::RoutingActivated);
+        assert_eq!(activate(&mut stream, 0x0E81), RoutingActivationCode::DeniedDifferentSA);
+        assert_eq!(stream.read(&mut [0; 8]).unwrap(), 0);
+    }
+    #[test]
+    fn reject_diagnostic_message_before_routing_activation() {
+        let mut stream = TcpStream::connect(serve()).unwrap();
+        let request = DiagMessage::new(0x0E80, 0x1001, &[0x3E, 0x00]).serialize();
+        match exchange(&mut stream, &request) {
+            MessageVariant::DiagnosticMessageNAckVariant(nack) => {
+                assert_eq!(nack.nack_code, DiagNackCode::InvalidSourceAddress)
+            }
+            _ => panic!("expected diagnostic message negative acknowledge"),
+        }
+    }
+    #[test]
+    fn report_entity_status_and_power_mode() {
+        let mut stream = TcpStream::connect(serve()).unwrap();
+        let request = EntityStatusRequest::default().serialize();
+        match exchange(&mut stream, &request) {
+            MessageVariant::EntityStatusResponseVariant(status) => {
+                assert_eq!(status.max_sockets, 2);
+                assert_eq!(status.open_sockets, 1);
+            }
+            _ => panic!("expected entity status response"),
+        }
+        let request = DiagnosticPowerModeRequest::default().serialize();
+        assert!(matches!(
+            exchange(&mut stream, &request),
+            MessageVariant::DiagnosticPowerModeResponseVariant(DiagnosticPowerModeResponse {
+                power_mode: DiagnosticPowerMode::Ready
+            })
+        ));
+    }
+    #[test]
+    fn nack_unknown_payload_type_and_keep_connection() {
+        let mut stream = TcpStream::connect(serve()).unwrap();
+        let mut request = DoIPHeader::new(PayloadType::AliveCheckReq, 2).serialize();
+        request[2..4].copy_from_slice(&[0x70, 0x00]);
+        request.extend_from_slice(&[0, 0]);
+        assert!(matches!(
+            exchange(&mut stream, &request),
+            MessageVariant::HeaderNackMessageVariant(HeaderNackMessage { nack_code: NackCode::UnknownPayloadType })
+        ));
+        let request = RoutingActivationRequest::new(0x0E80, 0).serialize();
+        assert!(matches!(exchange(&mut stream, &request), MessageVariant::RoutingActivationResponseVariant(_)));
+    }
+    #[test]
+    fn close_connection_after_initial_inactivity() {
+        let initial_inactivity = Duration::from_millis(100);
+        let timing = TimingParameters { t_tcp_initial_inactivity: initial_inactivity, ..Default::default() };
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_timing(timing).unwrap();
+        let mut stream = TcpStream::connect(builder.get_server().serve_loopback()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(stream.read(&mut [0; 8]).unwrap(), 0);
+    }
+    #[test]
+    fn close_inactive_connection_on_manual_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_clock(clock.clone());
+        let mut stream = TcpStream::connect(builder.get_server().serve_loopback()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(stream.read(&mut [0; 8]).is_err());
+        clock.advance(Duration::from_secs(2));
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(stream.read(&mut [0; 8]).unwrap(), 0);
+    }
+    #[test]
+    fn switch_to_general_inactivity_after_routing_activation() {
+        let clock = Arc::new(ManualClock::new());
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_clock(clock.clone());
+        let mut stream = TcpStream::connect(builder.get_server().serve_loopback()).unwrap();
+        let response = exchange(&mut stream, &RoutingActivationRequest::new(0x0E80, 0).serialize());
+        assert!(matches!(response, MessageVariant::RoutingActivationResponseVariant(_)));
+        clock.advance(Duration::from_secs(3));
+        stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(stream.read(&mut [0; 8]).is_err());
+        clock.advance(Duration::from_secs(5 * 60));
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(stream.read(&mut [0; 8]).unwrap(), 0);
+    }
+    #[test]
+    fn announce_on_manual_clock() {
+        let tester = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let mut builder = DoIPServerBuilder::new();
+        builder
+            .set_bind_address(Ipv4Addr::LOCALHOST.into())
+            .set_tcp_port(0)
+            .set_udp_port(0)
+            .set_announcement_destinations(&[tester.local_addr().unwrap()])
+            .set_clock(clock.clone())
+            .set_rng(StepRng::new(0, 0));
+        let handle = builder.get_server().start().unwrap();
+        /* Ready once bound, the announcements follow while requests get answered */
+        assert!(handle.wait_ready(Duration::from_secs(1)));
+        let mut buff = [0; 64];
+        tester.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert!(tester.recv_from(&mut buff).is_ok());
+        tester.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(tester.recv_from(&mut buff).is_err());
+        let request = VehicleIdentificationRequest::new().serialize();
+        tester.send_to(&request, handle.udp_addresses[0]).unwrap();
+        tester.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let (len, _) = tester.recv_from(&mut buff).unwrap();
+        assert!(matches!(message_factory(&buff[..len]), Ok(MessageVariant::VehicleIDResVariant(_))));
+        /* Lets the server wait on the clock again before advancing it */
+        let advance = || {
+            thread::sleep(Duration::from_millis(50));
+            clock.advance(Duration::from_millis(500));
+        };
+        for _ in 0..2 {
+            advance();
+            tester.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            assert!(tester.recv_from(&mut buff).is_ok());
+        }
+        advance();
+        tester.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(tester.recv_from(&mut buff).is_err());
+        handle.shutdown();
+    }
+    #[test]
+    fn report_connection_events() {
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_diagnostic_handler(EchoHandler);
+        let events = builder.subscribe();
+        let address = builder.get_server().serve_loopback();
+        let mut session = DoIPClientSession::connect(address, 0x0E80).unwrap();
+        let peer = session.local_address().unwrap();
+        session.send_diagnostic(0x1001, &[0x3E, 0x00]).unwrap();
+        session.receive_diagnostic(Duration::from_secs(1)).unwrap();
+        assert!(session.send_diagnostic(0x1002, &[0x3E, 0x00]).is_err());
+        drop(session);
+        let events: Vec<ServerEvent> = events.iter().take(5).collect();
+        let diagnostic = |target_address, nack| ServerEvent::DiagnosticMessage {
+            peer,
+            source_address: 0x0E80,
+            target_address,
+            user_data: vec![0x3E, 0x00],
+            nack,
+        };
+        assert_eq!(
+            events,
+            vec![
+                ServerEvent::ConnectionOpened { peer, tls: false },
+                ServerEvent::RoutingActivation {
+                    peer,
+                    source_address: 0x0E80,
+                    activation_type: 0,
+                    code: RoutingActivationCode::RoutingActivated,
+                },
+                diagnostic(0x1001, None),
+                diagnostic(0x1002, Some(DiagNackCode::UnknownTargetAddress)),
+                ServerEvent::ConnectionClosed { peer, source_address: Some(0x0E80) },
+            ]
+        );
+    }
+    #[test]
+    fn report_header_nack_to_observer() {
+        let nacks = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = DoIPServerBuilder::new();
+        let observed = nacks.clone();
+        builder.add_observer(move |event: &ServerEvent| {
+            if let ServerEvent::HeaderNack { code, .. } = event {
+                observed.lock().unwrap().push(*code);
+            }
+        });
+        let mut stream = TcpStream::connect(builder.get_server().serve_loopback()).unwrap();
+        let mut request = DoIPHeader::new(PayloadType::AliveCheckReq, 0).serialize();
+        request[2..4].copy_from_slice(&[0x70, 0x00]);
+        exchange(&mut stream, &request);
+        assert_eq!(*nacks.lock().unwrap(), vec![NackCode::UnknownPayloadType]);
+    }
+    #[test]
+    fn report_identification_events() {
+        let tester = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let announcement = tester.local_addr().unwrap();
+        let mut builder = DoIPServerBuilder::new();
+        builder
+            .set_logical_address(0x1000)
+            .set_bind_address(Ipv4Addr::LOCALHOST.into())
+            .set_tcp_port(0)
+            .set_udp_port(0)
+            .set_announcement_destinations(&[announcement])
+            .set_timing(TimingParameters { a_doip_announce_num: 1, ..Default::default() })
+            .unwrap();
+        let events = builder.subscribe();
+        let handle = builder.get_server().start().unwrap();
+        let request = VehicleIdentificationRequestEID::new(&[9; 6]).serialize();
+        tester.send_to(&request, handle.udp_addresses[0]).unwrap();
+        /* The request may be answered before the announcement went out */
+        let timeout = Duration::from_secs(5);
+        let events: Vec<ServerEvent> = (0..2).map(|_| events.recv_timeout(timeout).unwrap()).collect();
+        assert!(events.contains(&ServerEvent::AnnouncementSent { destination: announcement }));
+        assert!(events.contains(&ServerEvent::IdentificationRequest {
+            peer: announcement,
+            request: IdentificationRequest::ByEid([9; 6]),
+            answered: false,
+        }));
+        handle.shutdown();
+    }
 
     #[test]
     fn build_server() {
@@ -256,7 +1726,6 @@ mod tests {
         let eid: [u8; 6] = [0; 6];
         let gid: [u8; 6] = [0; 6];
         let logical_address: u16 = 0;
-        let port: u16 = 0;
         let mut server_builder: DoIPServerBuilder = DoIPServerBuilder::default();
         server_builder
             .set_vin(&vin)
@@ -264,5 +1733,7 @@ mod tests {
             .set_gid(&gid)
             .set_logical_address(logical_address);
         let server = server_builder.get_server();
+        assert_eq!(server.logical_address, logical_address);
+        assert_eq!((server.max_sockets, server.max_data_size), (10, u32::MAX));
     }
 }
@@ -0,0 +1,345 @@
+use crate::flash::{crc32, FlashBlock};
+use crate::message::hex::{self, HexError};
+use std::{fmt, fs, io, path::Path};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    IntelHex,
+    SRecord,
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Syntax { line: usize, reason: &'static str },
+    Checksum { line: usize },
+    UnsupportedRecord { line: usize, record_type: u8 },
+    Overlap { address: u32 },
+    UnknownFormat,
+}
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "I/O error: {}", err),
+            ImageError::Syntax { line, reason } => write!(f, "line {}: {}", line, reason),
+            ImageError::Checksum { line } => write!(f, "line {}: checksum mismatch", line),
+            ImageError::UnsupportedRecord { line, record_type } => {
+                write!(f, "line {}: unsupported record type {}", line, record_type)
+            }
+            ImageError::Overlap { address } => write!(f, "overlapping data at 0x{:08X}", address),
+            ImageError::UnknownFormat => write!(f, "unknown image format"),
+        }
+    }
+}
+impl std::error::Error for ImageError {}
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> Self {
+        ImageError::Io(err)
+    }
+}
+
+/* Gap between two consecutive segments */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Gap {
+    pub address: u32,
+    pub length: u32,
+}
+
+/* Memory content of a flash image as address-ordered, non-overlapping segments */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FirmwareImage {
+    segments: Vec<FlashBlock>,
+    start_address: Option<u32>,
+}
+impl FirmwareImage {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ImageError> {
+        let text = fs::read_to_string(path.as_ref())?;
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        let format = match extension.as_deref() {
+            Some("hex" | "ihex" | "ihx") => ImageFormat::IntelHex,
+            Some("s19" | "s28" | "s37" | "srec" | "mot") => ImageFormat::SRecord,
+            _ => FirmwareImage::detect_format(&text).ok_or(ImageError::UnknownFormat)?,
+        };
+        FirmwareImage::parse(&text, format)
+    }
+    pub fn detect_format(text: &str) -> Option<ImageFormat> {
+        match text.trim_start().chars().next() {
+            Some(':') => Some(ImageFormat::IntelHex),
+            Some('S') => Some(ImageFormat::SRecord),
+            _ => None,
+        }
+    }
+    pub fn parse(text: &str, format: ImageFormat) -> Result<Self, ImageError> {
+        match format {
+            ImageFormat::IntelHex => FirmwareImage::from_intel_hex(text),
+            ImageFormat::SRecord => FirmwareImage::from_srecord(text),
+        }
+    }
+    pub fn from_intel_hex(text: &str) -> Result<Self, ImageError> {
+        let mut chunks = Vec::new();
+        let mut start_address = None;
+        let mut base_address: u32 = 0;
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = line
+                .strip_prefix(':')
+                .ok_or(ImageError::Syntax { line: line_number, reason: "missing start code" })?;
+            let bytes = decode_hex(record, line_number)?;
+            if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+                return Err(ImageError::Syntax { line: line_number, reason: "invalid record length" });
+            }
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+                return Err(ImageError::Checksum { line: line_number });
+            }
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0x00 => chunks.push((base_address.wrapping_add(offset), data.to_vec())),
+                0x01 => break,
+                0x02 if data.len() == 2 => base_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+                0x04 if data.len() == 2 => base_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+                0x03 | 0x05 if data.len() == 4 => {
+                    start_address = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+                }
+                0x02..=0x05 => {
+                    return Err(ImageError::Syntax { line: line_number, reason: "invalid record length" })
+                }
+                record_type => return Err(ImageError::UnsupportedRecord { line: line_number, record_type }),
+            }
+        }
+        let mut image = FirmwareImage::from_chunks(chunks)?;
+        image.start_address = start_address;
+        Ok(image)
+    }
+    pub fn from_srecord(text: &str) -> Result<Self, ImageError> {
+        let mut chunks = Vec::new();
+        let mut start_address = None;
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = line
+                .strip_prefix('S')
+                .ok_or(ImageError::Syntax { line: line_number, reason: "missing start code" })?;
+            let record_type = record
+                .chars()
+                .next()
+                .and_then(|digit| digit.to_digit(10))
+                .ok_or(ImageError::Syntax { line: line_number, reason: "invalid record type" })?
+                as u8;
+            let bytes = decode_hex(&record[1..], line_number)?;
+            if bytes.is_empty() || bytes.len() != 1 + bytes[0] as usize {
+                return Err(ImageError::Syntax { line: line_number, reason: "invalid record length" });
+            }
+            let (checksum, fields) = bytes.split_last().unwrap();
+            if !fields.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != *checksum {
+                return Err(ImageError::Checksum { line: line_number });
+            }
+            let address_length = match record_type {
+                0 | 1 | 5 | 9 => 2,
+                2 | 6 | 8 => 3,
+                3 | 7 => 4,
+                record_type => return Err(ImageError::UnsupportedRecord { line: line_number, record_type }),
+            };
+            let fields = &fields[1..];
+            if fields.len() < address_length {
+                return Err(ImageError::Syntax { line: line_number, reason: "invalid record length" });
+            }
+            let address = fields[..address_length]
+                .iter()
+                .fold(0u32, |address, byte| (address << 8) | *byte as u32);
+            match record_type {
+                1..=3 => chunks.push((address, fields[address_length..].to_vec())),
+                7..=9 => start_address = Some(address),
+                _ => (),
+            }
+        }
+        let mut image = FirmwareImage::from_chunks(chunks)?;
+        image.start_address = start_address;
+        Ok(image)
+    }
+    /* Sorts data chunks by address and merges adjacent ones, overlapping data is rejected */
+    pub fn from_chunks(mut chunks: Vec<(u32, Vec<u8>)>) -> Result<Self, ImageError> {
+        chunks.retain(|(_, data)| !data.is_empty());
+        chunks.sort_by_key(|(address, _)| *address);
+        let mut segments: Vec<FlashBlock> = Vec::new();
+        for (address, data) in chunks {
+            match segments.last_mut() {
+                Some(last) if segment_end(last) == address as u64 => last.data.extend(data),
+                Some(last) if segment_end(last) > address as u64 => {
+                    return Err(ImageError::Overlap { address })
+                }
+                _ => segments.push(FlashBlock { address, data }),
+            }
+        }
+        Ok(FirmwareImage { segments, start_address: None })
+    }
+    pub fn segments(&self) -> &[FlashBlock] {
+        &self.segments
+    }
+    pub fn start_address(&self) -> Option<u32> {
+        self.start_address
+    }
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+    pub fn gaps(&self) -> Vec<Gap> {
+        self.segments
+            .windows(2)
+            .map(|pair| Gap {
+                address: segment_end(&pair[0]) as u32,
+                length: pair[1].address - segment_end(&pair[0]) as u32,
+            })
+            .collect()
+    }
+    /* Merges segments separated by at most max_gap bytes, filling the gaps with fill_byte */
+    pub fn fill_gaps(&mut self, fill_byte: u8, max_gap: u32) {
+        let mut segments: Vec<FlashBlock> = Vec::with_capacity(self.segments.len());
+        for segment in self.segments.drain(..) {
+            match segments.last_mut() {
+                Some(last) if segment.address as u64 - segment_end(last) <= max_gap as u64 => {
+                    let gap = (segment.address as u64 - segment_end(last)) as usize;
+                    last.data.resize(last.data.len() + gap, fill_byte);
+                    last.data.extend(segment.data);
+                }
+                _ => segments.push(segment),
+            }
+        }
+        self.segments = segments;
+    }
+    /* CRC-32 over the data of all segments in address order */
+    pub fn crc32(&self) -> u32 {
+        let data: Vec<u8> = self.segments.iter().flat_map(|segment| segment.data.iter().copied()).collect();
+        crc32(&data)
+    }
+    /* Smallest addressAndLengthFormatIdentifier able to describe every segment */
+    pub fn address_and_length_format(&self) -> u8 {
+        let max_address = self.segments.iter().map(|segment| segment.address).max().unwrap_or(0);
+        let max_length = self.segments.iter().map(|segment| segment.data.len() as u32).max().unwrap_or(0);
+        (byte_count(max_length) << 4) | byte_count(max_address)
+    }
+}
+
+fn segment_end(segment: &FlashBlock) -> u64 {
+    segment.address as u64 + segment.data.len() as u64
+}
+
+fn byte_count(value: u32) -> u8 {
+    (4 - value.leading_zeros() / 8).max(1) as u8
+}
+
+fn decode_hex(text: &str, line: usize) -> Result<Vec<u8>, ImageError> {
+    hex::decode(text).map_err(|err| {
+        let reason = match err {
+            HexError::OddLength => "odd number of hex digits",
+            HexError::InvalidDigit(_) => "invalid hex digit",
+        };
+        ImageError::Syntax { line, reason }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTEL_HEX: &str = "\
+:020000040800F2
+:10000000000102030405060708090A0B0C0D0E0F78
+:0800100010111213141516174C
+:020000040801F1
+:04000000AABBCCDDEE
+:04000005080001ED01
+:00000001FF
+";
+
+    #[test]
+    fn parse_intel_hex() {
+        let image = FirmwareImage::from_intel_hex(INTEL_HEX).unwrap();
+        assert_eq!(
+            image.segments(),
+            &[
+                FlashBlock::new(0x0800_0000, &(0..24).collect::<Vec<u8>>()),
+                FlashBlock::new(0x0801_0000, &[0xAA, 0xBB, 0xCC, 0xDD]),
+            ]
+        );
+        assert_eq!(image.start_address(), Some(0x0800_01ED));
+        assert_eq!(image.len(), 28);
+        assert_eq!(image.gaps(), vec![Gap { address: 0x0800_0018, length: 0xFFE8 }]);
+    }
+    #[test]
+    fn parse_intel_hex_checksum_error() {
+        let text = INTEL_HEX.replace(":0800100010111213141516174C", ":0800100010111213141516174D");
+        assert!(matches!(
+            FirmwareImage::from_intel_hex(&text),
+            Err(ImageError::Checksum { line: 3 })
+        ));
+    }
+    #[test]
+    fn parse_srecord() {
+        let text = "\
+S00600004844521B
+S1130000000102030405060708090A0B0C0D0E0F74
+S10B0010101112131415161748
+S309080100001122334443
+S9030000FC
+";
+        let image = FirmwareImage::from_srecord(text).unwrap();
+        assert_eq!(
+            image.segments(),
+            &[
+                FlashBlock::new(0x0000, &(0..24).collect::<Vec<u8>>()),
+                FlashBlock::new(0x0801_0000, &[0x11, 0x22, 0x33, 0x44]),
+            ]
+        );
+        assert_eq!(image.start_address(), Some(0));
+        assert_eq!(image.address_and_length_format(), 0x14);
+    }
+    #[test]
+    fn parse_srecord_checksum_error() {
+        let text = "S1130000000102030405060708090A0B0C0D0E0F75\n";
+        assert!(matches!(FirmwareImage::from_srecord(text), Err(ImageError::Checksum { line: 1 })));
+    }
+    #[test]
+    fn overlapping_data_is_rejected() {
+        let chunks = vec![(0x100, vec![0; 16]), (0x108, vec![1; 4])];
+        assert!(matches!(
+            FirmwareImage::from_chunks(chunks),
+            Err(ImageError::Overlap { address: 0x108 })
+        ));
+    }
+    #[test]
+    fn fill_small_gaps() {
+        let chunks = vec![(0x200, vec![2; 2]), (0x100, vec![1; 2]), (0x104, vec![3; 2])];
+        let mut image = FirmwareImage::from_chunks(chunks).unwrap();
+        assert_eq!(image.gaps().len(), 2);
+        image.fill_gaps(0xFF, 0x10);
+        assert_eq!(
+            image.segments(),
+            &[
+                FlashBlock::new(0x100, &[1, 1, 0xFF, 0xFF, 3, 3]),
+                FlashBlock::new(0x200, &[2, 2]),
+            ]
+        );
+        assert_eq!(image.crc32(), crc32(&[1, 1, 0xFF, 0xFF, 3, 3, 2, 2]));
+        assert_eq!(image.address_and_length_format(), 0x12);
+    }
+    #[test]
+    fn detect_format() {
+        assert_eq!(FirmwareImage::detect_format(":00000001FF"), Some(ImageFormat::IntelHex));
+        assert_eq!(FirmwareImage::detect_format("S9030000FC"), Some(ImageFormat::SRecord));
+        assert_eq!(FirmwareImage::detect_format("{}"), None);
+    }
+}
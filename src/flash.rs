@@ -0,0 +1,537 @@
+use crate::doip_client::SessionError;
+use crate::firmware_image::FirmwareImage;
+use crate::uds::{
+    client::{UdsClient, UdsError},
+    security::SeedKeyProvider,
+    ServiceId, EXTENDED_SESSION, PROGRAMMING_SESSION,
+};
+use std::fmt;
+
+/* Contiguous piece of memory to be programmed */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlashBlock {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+impl FlashBlock {
+    pub fn new(address: u32, data: &[u8]) -> Self {
+        FlashBlock { address, data: data.to_vec() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlashConfig {
+    pub enter_extended_session: bool,
+    pub security_level: u8,
+    pub erase_routine: u16,
+    pub check_routine: u16,
+    pub data_format_identifier: u8,
+    /* High nibble: length of the memory size, low nibble: length of the memory address */
+    pub address_and_length_format: u8,
+    pub transfer_retries: u32,
+    pub reset_type: u8,
+}
+impl Default for FlashConfig {
+    fn default() -> Self {
+        FlashConfig {
+            enter_extended_session: true,
+            security_level: 0x11,
+            erase_routine: 0xFF00,
+            check_routine: 0x0202,
+            data_format_identifier: 0x00,
+            address_and_length_format: 0x44,
+            transfer_retries: 2,
+            reset_type: 0x01,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FlashStep {
+    ExtendedSession,
+    ProgrammingSession,
+    SecurityAccess,
+    Erase { block: usize },
+    RequestDownload { block: usize },
+    TransferData { block: usize },
+    RequestTransferExit { block: usize },
+    CheckMemory,
+    EcuReset,
+}
+
+/* Progress callbacks, all of them default to doing nothing */
+pub trait FlashProgress {
+    fn on_step(&mut self, _step: FlashStep) {}
+    fn on_transfer(&mut self, _block: usize, _transferred: usize, _total: usize) {}
+    fn on_retry(&mut self, _block: usize, _sequence_counter: u8, _error: &UdsError) {}
+}
+impl FlashProgress for () {}
+
+#[derive(Debug)]
+pub enum FlashError {
+    Uds { step: FlashStep, error: UdsError },
+    InvalidResponse { step: FlashStep, response: Vec<u8> },
+    InvalidAddressAndLengthFormat(u8),
+    /* Block address or length wider than the fields of the address and length format */
+    BlockOutOfFormat { address: u32, length: usize, format: u8 },
+    CheckFailed(Vec<u8>),
+}
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashError::Uds { step, error } => write!(f, "{:?} failed: {}", step, error),
+            FlashError::InvalidResponse { step, response } => {
+                write!(f, "{:?} failed: invalid response {:02X?}", step, response)
+            }
+            FlashError::InvalidAddressAndLengthFormat(format) => {
+                write!(f, "invalid address and length format identifier 0x{:02X}", format)
+            }
+            FlashError::BlockOutOfFormat { address, length, format } => write!(
+                f,
+                "block of {} bytes at 0x{:08X} exceeds address and length format 0x{:02X}",
+                length, address, format
+            ),
+            FlashError::CheckFailed(response) => {
+                write!(f, "memory check failed: {:02X?}", response)
+            }
+        }
+    }
+}
+impl std::error::Error for FlashError {}
+
+/* Software download sequence on top of a UDS client */
+pub struct Flasher<'a> {
+    client: &'a mut UdsClient,
+    seed_key: &'a dyn SeedKeyProvider,
+    config: FlashConfig,
+}
+impl<'a> Flasher<'a> {
+    pub fn new(client: &'a mut UdsClient, seed_key: &'a dyn SeedKeyProvider) -> Self {
+        Flasher { client, seed_key, config: FlashConfig::default() }
+    }
+    pub fn set_config(&mut self, config: FlashConfig) -> &mut Self {
+        self.config = config;
+        self
+    }
+    pub fn flash(&mut self, blocks: &[FlashBlock], progress: &mut dyn FlashProgress) -> Result<(), FlashError> {
+        self.flash_with_format(blocks, self.config.address_and_length_format, progress)
+    }
+    /* Downloads every segment of the image, addressed with the smallest matching format */
+    pub fn flash_image(&mut self, image: &FirmwareImage, progress: &mut dyn FlashProgress) -> Result<(), FlashError> {
+        self.flash_with_format(image.segments(), image.address_and_length_format(), progress)
+    }
+    fn flash_with_format(
+        &mut self,
+        blocks: &[FlashBlock],
+        address_and_length_format: u8,
+        progress: &mut dyn FlashProgress,
+    ) -> Result<(), FlashError> {
+        let memory_format = MemoryFormat::new(address_and_length_format)?;
+        /* Every block is checked against the format before the ECU is touched */
+        let records = blocks.iter().map(|block| memory_format.record(block)).collect::<Result<Vec<_>, _>>()?;
+        if self.config.enter_extended_session {
+            progress.on_step(FlashStep::ExtendedSession);
+            step(FlashStep::ExtendedSession, self.client.diagnostic_session_control(EXTENDED_SESSION))?;
+        }
+        progress.on_step(FlashStep::ProgrammingSession);
+        step(FlashStep::ProgrammingSession, self.client.diagnostic_session_control(PROGRAMMING_SESSION))?;
+        progress.on_step(FlashStep::SecurityAccess);
+        step(
+            FlashStep::SecurityAccess,
+            self.client.security_access(self.config.security_level, self.seed_key),
+        )?;
+        for (index, (block, record)) in blocks.iter().zip(&records).enumerate() {
+            self.erase(index, record, progress)?;
+            let max_block_length = self.request_download(index, record, progress)?;
+            self.transfer_data(index, block, max_block_length, progress)?;
+            progress.on_step(FlashStep::RequestTransferExit { block: index });
+            step(
+                FlashStep::RequestTransferExit { block: index },
+                self.client.request(&[ServiceId::RequestTransferExit as u8]),
+            )?;
+        }
+        self.check_memory(blocks, progress)?;
+        progress.on_step(FlashStep::EcuReset);
+        step(FlashStep::EcuReset, self.client.ecu_reset(self.config.reset_type))?;
+        Ok(())
+    }
+    /* The record is the memory address and size of the block with their format identifier */
+    fn erase(
+        &mut self,
+        index: usize,
+        record: &[u8],
+        progress: &mut dyn FlashProgress,
+    ) -> Result<(), FlashError> {
+        let current = FlashStep::Erase { block: index };
+        progress.on_step(current);
+        step(current, self.client.routine_control(0x01, self.config.erase_routine, record))?;
+        Ok(())
+    }
+    /* Returns the maximum number of data bytes per TransferData request */
+    fn request_download(
+        &mut self,
+        index: usize,
+        record: &[u8],
+        progress: &mut dyn FlashProgress,
+    ) -> Result<usize, FlashError> {
+        let current = FlashStep::RequestDownload { block: index };
+        progress.on_step(current);
+        let mut request = vec![ServiceId::RequestDownload as u8, self.config.data_format_identifier];
+        request.extend_from_slice(record);
+        let response = step(current, self.client.request(&request))?;
+        let length_bytes = (response.get(1).copied().unwrap_or(0) >> 4) as usize;
+        if length_bytes == 0 || length_bytes > 4 || response.len() < 2 + length_bytes {
+            return Err(FlashError::InvalidResponse { step: current, response });
+        }
+        let max_block_length = response[2..2 + length_bytes]
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        /* The block length counts the service id and the block sequence counter */
+        if max_block_length <= 2 {
+            return Err(FlashError::InvalidResponse { step: current, response });
+        }
+        Ok(max_block_length - 2)
+    }
+    fn transfer_data(
+        &mut self,
+        index: usize,
+        block: &FlashBlock,
+        chunk_length: usize,
+        progress: &mut dyn FlashProgress,
+    ) -> Result<(), FlashError> {
+        let current = FlashStep::TransferData { block: index };
+        progress.on_step(current);
+        let mut sequence_counter: u8 = 1;
+        let mut transferred = 0;
+        for chunk in block.data.chunks(chunk_length) {
+            let mut request = vec![ServiceId::TransferData as u8, sequence_counter];
+            request.extend_from_slice(chunk);
+            let mut retries = 0;
+            loop {
+                match self.client.request(&request) {
+                    Ok(response) if response.get(1) == Some(&sequence_counter) => break,
+                    Ok(response) => return Err(FlashError::InvalidResponse { step: current, response }),
+                    Err(error) if retries < self.config.transfer_retries && is_transient(&error) => {
+                        retries += 1;
+                        progress.on_retry(index, sequence_counter, &error);
+                    }
+                    Err(error) => return Err(FlashError::Uds { step: current, error }),
+                }
+            }
+            transferred += chunk.len();
+            progress.on_transfer(index, transferred, block.data.len());
+            sequence_counter = sequence_counter.wrapping_add(1);
+        }
+        Ok(())
+    }
+    fn check_memory(&mut self, blocks: &[FlashBlock], progress: &mut dyn FlashProgress) -> Result<(), FlashError> {
+        progress.on_step(FlashStep::CheckMemory);
+        let crc = blocks.iter().fold(CRC32_INIT, |crc, block| crc32_update(crc, &block.data));
+        let record = (!crc).to_be_bytes();
+        let response = step(
+            FlashStep::CheckMemory,
+            self.client.routine_control(0x01, self.config.check_routine, &record),
+        )?;
+        /* Optional routine status record, 0x00 meaning correct */
+        match response.get(4) {
+            Some(0x00) | None => Ok(()),
+            Some(_) => Err(FlashError::CheckFailed(response)),
+        }
+    }
+}
+
+/* addressAndLengthFormatIdentifier with the lengths of its address and size fields */
+#[derive(Copy, Clone)]
+struct MemoryFormat {
+    identifier: u8,
+    address_length: usize,
+    size_length: usize,
+}
+impl MemoryFormat {
+    fn new(identifier: u8) -> Result<Self, FlashError> {
+        let size_length = (identifier >> 4) as usize;
+        let address_length = (identifier & 0x0F) as usize;
+        if !(1..=4).contains(&size_length) || !(1..=4).contains(&address_length) {
+            return Err(FlashError::InvalidAddressAndLengthFormat(identifier));
+        }
+        Ok(MemoryFormat { identifier, address_length, size_length })
+    }
+    fn record(&self, block: &FlashBlock) -> Result<Vec<u8>, FlashError> {
+        let fits = |value: u32, length: usize| length == 4 || value >> (8 * length) == 0;
+        let size = u32::try_from(block.data.len()).ok().filter(|size| fits(*size, self.size_length));
+        let Some(size) = size.filter(|_| fits(block.address, self.address_length)) else {
+            return Err(FlashError::BlockOutOfFormat {
+                address: block.address,
+                length: block.data.len(),
+                format: self.identifier,
+            });
+        };
+        let mut record = vec![self.identifier];
+        record.extend_from_slice(&block.address.to_be_bytes()[4 - self.address_length..]);
+        record.extend_from_slice(&size.to_be_bytes()[4 - self.size_length..]);
+        Ok(record)
+    }
+}
+
+fn step<T>(step: FlashStep, result: Result<T, UdsError>) -> Result<T, FlashError> {
+    result.map_err(|error| FlashError::Uds { step, error })
+}
+
+fn is_transient(error: &UdsError) -> bool {
+    matches!(error, UdsError::Timeout | UdsError::Session(SessionError::DiagnosticNack(_)))
+}
+
+const CRC32_INIT: u32 = 0xFFFF_FFFF;
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 }
+        })
+    })
+}
+
+/* CRC-32 (IEEE 802.3) as used by the memory check routine */
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC32_INIT, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doip_client::DoIPClientSession;
+    use crate::message::diag_message::DiagMessage;
+    use crate::test_util::FakeEntity;
+    use crate::uds::{client::UdsTiming, NegativeResponseCode};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    const SEED: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+    const MASK: u32 = 0xA5A5_A5A5;
+
+    fn xor_key(_: u16, _: u8, seed: &[u8]) -> io::Result<Vec<u8>> {
+        let seed = u32::from_be_bytes(seed.try_into().unwrap());
+        Ok((seed ^ MASK).to_be_bytes().to_vec())
+    }
+
+    /* In-process bootloader accepting a single download at a time */
+    #[derive(Default)]
+    struct SimulatedBootloader {
+        session: u8,
+        unlocked: bool,
+        erased: Vec<u32>,
+        download: Option<(u32, Vec<u8>)>,
+        expected_counter: u8,
+        drop_response_of_counter: Option<u8>,
+        memory: Vec<FlashBlock>,
+        reset: bool,
+    }
+    impl SimulatedBootloader {
+        fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+            let negative = |nrc: NegativeResponseCode| vec![0x7F, request[0], nrc as u8];
+            match request {
+                [0x10, session] => {
+                    self.session = *session;
+                    vec![0x50, *session, 0x00, 0x32, 0x01, 0xF4]
+                }
+                [0x27, 0x11] if self.session == PROGRAMMING_SESSION => {
+                    [&[0x67, 0x11][..], &SEED].concat()
+                }
+                [0x27, 0x12, key @ ..] => {
+                    if key == (u32::from_be_bytes(SEED) ^ MASK).to_be_bytes() {
+                        self.unlocked = true;
+                        vec![0x67, 0x12]
+                    } else {
+                        negative(NegativeResponseCode::InvalidKey)
+                    }
+                }
+                _ if !self.unlocked && [0x31, 0x34, 0x36].contains(&request[0]) => {
+                    negative(NegativeResponseCode::SecurityAccessDenied)
+                }
+                [0x31, 0x01, 0xFF, 0x00, format, memory @ ..] => {
+                    self.erased.push(memory_address(*format, memory));
+                    vec![0x71, 0x01, 0xFF, 0x00]
+                }
+                [0x34, 0x00, format, memory @ ..] => {
+                    self.download = Some((memory_address(*format, memory), Vec::new()));
+                    self.expected_counter = 1;
+                    vec![0x74, 0x20, 0x00, 0x12]
+                }
+                [0x36, counter, data @ ..] => {
+                    let Some((_, received)) = self.download.as_mut() else {
+                        return negative(NegativeResponseCode::RequestSequenceError);
+                    };
+                    if *counter == self.expected_counter {
+                        received.extend_from_slice(data);
+                        self.expected_counter = self.expected_counter.wrapping_add(1);
+                        if self.drop_response_of_counter.take_if(|dropped| dropped == counter).is_some() {
+                            return Vec::new();
+                        }
+                    } else if counter.wrapping_add(1) != self.expected_counter {
+                        return negative(NegativeResponseCode::WrongBlockSequenceCounter);
+                    }
+                    vec![0x76, *counter]
+                }
+                [0x37] => match self.download.take() {
+                    Some((address, data)) => {
+                        self.memory.push(FlashBlock { address, data });
+                        vec![0x77]
+                    }
+                    None => negative(NegativeResponseCode::RequestSequenceError),
+                },
+                [0x31, 0x01, 0x02, 0x02, crc @ ..] => {
+                    let data: Vec<u8> = self.memory.iter().flat_map(|block| block.data.clone()).collect();
+                    let status = if crc == crc32(&data).to_be_bytes() { 0x00 } else { 0x01 };
+                    vec![0x71, 0x01, 0x02, 0x02, status]
+                }
+                [0x11, reset_type] => {
+                    self.reset = true;
+                    vec![0x51, *reset_type]
+                }
+                _ => negative(NegativeResponseCode::ServiceNotSupported),
+            }
+        }
+    }
+
+    fn memory_address(format: u8, memory: &[u8]) -> u32 {
+        memory[..(format & 0x0F) as usize]
+            .iter()
+            .fold(0, |address, byte| (address << 8) | *byte as u32)
+    }
+
+    #[derive(Default)]
+    struct RecordedProgress {
+        steps: Vec<FlashStep>,
+        transferred: Vec<(usize, usize, usize)>,
+        retries: Vec<(usize, u8)>,
+    }
+    impl FlashProgress for RecordedProgress {
+        fn on_step(&mut self, step: FlashStep) {
+            self.steps.push(step);
+        }
+        fn on_transfer(&mut self, block: usize, transferred: usize, total: usize) {
+            self.transferred.push((block, transferred, total));
+        }
+        fn on_retry(&mut self, block: usize, sequence_counter: u8, _error: &UdsError) {
+            self.retries.push((block, sequence_counter));
+        }
+    }
+
+    fn ecu(bootloader: SimulatedBootloader) -> (FakeEntity, Arc<Mutex<SimulatedBootloader>>) {
+        let bootloader = Arc::new(Mutex::new(bootloader));
+        let state = bootloader.clone();
+        let entity = FakeEntity::spawn(0x1000, move |request: &DiagMessage| {
+            match state.lock().unwrap().handle(&request.user_data) {
+                response if response.is_empty() => Vec::new(),
+                response => vec![(Duration::ZERO, response)],
+            }
+        });
+        (entity, bootloader)
+    }
+    fn client(entity: &FakeEntity) -> UdsClient {
+        let session = DoIPClientSession::connect(entity.address(), 0x0E80).unwrap();
+        let mut client = UdsClient::new(session, 0x1000);
+        client.set_timing(UdsTiming {
+            p2: Duration::from_millis(100),
+            p2_star: Duration::from_millis(500),
+        });
+        client
+    }
+    fn blocks() -> Vec<FlashBlock> {
+        vec![
+            FlashBlock::new(0x0800_0000, &(0..40).collect::<Vec<u8>>()),
+            FlashBlock::new(0x0801_0000, &[0xAB; 20]),
+        ]
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+    #[test]
+    fn flash_blocks() {
+        let (entity, bootloader) = ecu(SimulatedBootloader::default());
+        let mut client = client(&entity);
+        let mut progress = RecordedProgress::default();
+        Flasher::new(&mut client, &xor_key).flash(&blocks(), &mut progress).unwrap();
+
+        let bootloader = bootloader.lock().unwrap();
+        assert_eq!(bootloader.memory, blocks());
+        assert_eq!(bootloader.erased, vec![0x0800_0000, 0x0801_0000]);
+        assert!(bootloader.reset);
+        assert_eq!(progress.steps.first(), Some(&FlashStep::ExtendedSession));
+        assert_eq!(progress.steps.last(), Some(&FlashStep::EcuReset));
+        assert!(progress.steps.contains(&FlashStep::RequestTransferExit { block: 1 }));
+        /* 16 data bytes per block: 0x12 minus service id and sequence counter */
+        assert_eq!(
+            progress.transferred,
+            vec![(0, 16, 40), (0, 32, 40), (0, 40, 40), (1, 16, 20), (1, 20, 20)]
+        );
+        assert!(progress.retries.is_empty());
+    }
+    #[test]
+    fn flash_srecord_image() {
+        let image = FirmwareImage::from_srecord(
+            "S1130000000102030405060708090A0B0C0D0E0F74\nS10B0010101112131415161748\n",
+        )
+        .unwrap();
+        let (entity, bootloader) = ecu(SimulatedBootloader::default());
+        let mut client = client(&entity);
+        let mut flasher = Flasher::new(&mut client, &xor_key);
+        assert_eq!(image.address_and_length_format(), 0x11);
+        flasher.flash_image(&image, &mut ()).unwrap();
+        assert_eq!(bootloader.lock().unwrap().memory, image.segments());
+        /* The format of the image is used for this download only */
+        assert_eq!(flasher.config.address_and_length_format, 0x44);
+    }
+    #[test]
+    fn reject_block_beyond_address_and_length_format() {
+        let (entity, bootloader) = ecu(SimulatedBootloader::default());
+        let mut client = client(&entity);
+        let mut flasher = Flasher::new(&mut client, &xor_key);
+        flasher.set_config(FlashConfig { address_and_length_format: 0x22, ..Default::default() });
+        let mut progress = RecordedProgress::default();
+        let blocks = [FlashBlock::new(0xFF00, &[0; 16]), FlashBlock::new(0x0001_0000, &[0; 16])];
+        assert!(matches!(
+            flasher.flash(&blocks, &mut progress),
+            Err(FlashError::BlockOutOfFormat { address: 0x0001_0000, length: 16, format: 0x22 })
+        ));
+        let blocks = [FlashBlock::new(0xFF00, &[0; 0x1_0000])];
+        assert!(matches!(
+            flasher.flash(&blocks, &mut progress),
+            Err(FlashError::BlockOutOfFormat { address: 0xFF00, length: 0x1_0000, format: 0x22 })
+        ));
+        assert!(progress.steps.is_empty());
+        assert!(bootloader.lock().unwrap().erased.is_empty());
+    }
+    #[test]
+    fn flash_retries_lost_transfer_data_response() {
+        let (entity, bootloader) = ecu(SimulatedBootloader {
+            drop_response_of_counter: Some(2),
+            ..Default::default()
+        });
+        let mut client = client(&entity);
+        let mut progress = RecordedProgress::default();
+        Flasher::new(&mut client, &xor_key).flash(&blocks(), &mut progress).unwrap();
+        assert_eq!(progress.retries, vec![(0, 2)]);
+        assert_eq!(bootloader.lock().unwrap().memory, blocks());
+    }
+    #[test]
+    fn flash_fails_with_invalid_key() {
+        let (entity, bootloader) = ecu(SimulatedBootloader::default());
+        let mut client = client(&entity);
+        let wrong_key = |_: u16, _: u8, _: &[u8]| -> io::Result<Vec<u8>> { Ok(vec![0; 4]) };
+        let error = Flasher::new(&mut client, &wrong_key).flash(&blocks(), &mut ()).unwrap_err();
+        match error {
+            FlashError::Uds { step, error } => {
+                assert_eq!(step, FlashStep::SecurityAccess);
+                assert_eq!(error.negative_response_code(), Some(NegativeResponseCode::InvalidKey));
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+        assert!(bootloader.lock().unwrap().erased.is_empty());
+    }
+}
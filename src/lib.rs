@@ -1,15 +1,25 @@
 extern crate num;
 #[macro_use]
 extern crate num_derive;
+mod logging;
 pub mod message;
+pub mod clock;
 pub mod doip_server;
+pub mod server_event;
+pub mod timing;
 pub mod doip_client;
-
+pub mod uds;
+pub mod flash;
+pub mod firmware_image;
+pub mod recorder;
+pub mod pcap;
+pub mod proxy;
+pub mod shell;
+mod network;
+pub mod transport;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "simulator")]
+pub mod simulator;
 #[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-    }
-}
+mod test_util;
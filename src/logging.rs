@@ -0,0 +1,72 @@
+/* Instrumentation through the tracing facade with the tracing feature, compiled out otherwise.
+ * Call sites use the tracing macro syntax, restricted to `name = value`, `name`, `%name` and `?name`. */
+use crate::message::header::{DoIPHeader, PayloadType};
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, debug_span, info, info_span, trace, warn, Span};
+
+/* Payload type field of frame level events */
+pub(crate) fn payload_type(frame: &[u8]) -> Option<PayloadType> {
+    DoIPHeader::from_buffer(frame).ok().map(|header| header.payload_type)
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    /* Borrows every field and argument in dead code, so they do not turn up as unused */
+    macro_rules! fields {
+        () => {};
+        ($message:literal $(, $arg:expr)* $(,)?) => {
+            let _ = ($(&$arg,)*);
+        };
+        ($field:ident = %$value:expr $(, $($rest:tt)*)?) => {
+            let _ = &$value;
+            $crate::logging::fields!($($($rest)*)?);
+        };
+        ($field:ident = ?$value:expr $(, $($rest:tt)*)?) => {
+            let _ = &$value;
+            $crate::logging::fields!($($($rest)*)?);
+        };
+        ($field:ident = $value:expr $(, $($rest:tt)*)?) => {
+            let _ = &$value;
+            $crate::logging::fields!($($($rest)*)?);
+        };
+        (%$field:ident $(, $($rest:tt)*)?) => {
+            let _ = &$field;
+            $crate::logging::fields!($($($rest)*)?);
+        };
+        (?$field:ident $(, $($rest:tt)*)?) => {
+            let _ = &$field;
+            $crate::logging::fields!($($($rest)*)?);
+        };
+        ($field:ident $(, $($rest:tt)*)?) => {
+            let _ = &$field;
+            $crate::logging::fields!($($($rest)*)?);
+        };
+    }
+    macro_rules! event {
+        ($($fields:tt)*) => {
+            if false {
+                $crate::logging::fields!($($fields)*);
+            }
+        };
+    }
+    macro_rules! span {
+        ($name:literal $(, $($fields:tt)*)?) => {{
+            if false {
+                $crate::logging::fields!($($($fields)*)?);
+            }
+            $crate::logging::Span
+        }};
+    }
+    pub(crate) use {event as debug, event as info, event as trace, event as warn};
+    pub(crate) use {fields, span as debug_span, span as info_span};
+
+    #[derive(Clone)]
+    pub(crate) struct Span;
+    impl Span {
+        pub(crate) fn entered(self) -> Self {
+            self
+        }
+    }
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) use disabled::*;
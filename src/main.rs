@@ -1,10 +1,491 @@
+use clap::{ArgAction, Args, Parser, Subcommand};
+use doip_lib::doip_client::{DoIPClient, DoIPClientSession, IdentificationRequest};
+use doip_lib::message::diag_power_mode::DiagnosticPowerMode;
+use doip_lib::message::dump::{EntityId, HexBytes, Vin};
+use doip_lib::message::entity_status::EntityStatusResponse;
+use doip_lib::pcap::PcapCapture;
+use doip_lib::proxy::DoIPProxy;
+use doip_lib::recorder::Recording;
+use doip_lib::shell::Shell;
+use doip_lib::simulator::{config::VehicleConfig, replay::ReplayHandler, VehicleSimulator};
+use doip_lib::uds::client::{UdsClient, UdsError};
+use doip_lib::uds::security::{ExternalProcessProvider, XorMaskProvider};
+use doip_lib::uds::{NegativeResponseCode, ServiceId, NEGATIVE_RESPONSE_SID};
+use serde::Serialize;
+use std::{
+    error::Error,
+    fs,
+    io::{self, IsTerminal},
+    net::{SocketAddr, ToSocketAddrs},
+    process,
+    time::Duration,
+};
+use tracing::Level;
 
+/* Numbers on the command line (addresses, DIDs, service parameters) are hex, with or without 0x */
+#[derive(Parser)]
+#[command(name = "doip", version, about = "DoIP (ISO 13400) tester and vehicle simulator")]
+struct Cli {
+    #[arg(long, global = true, help = "Print results as JSON")]
+    json: bool,
+    #[arg(long, short, global = true, action = ArgAction::Count,
+          help = "Log protocol activity to stderr, repeat for more detail down to frame hex dumps")]
+    verbose: u8,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Send a vehicle identification request and list the responding entities")]
+    Discover {
+        #[arg(
+            long,
+            default_value = "255.255.255.255",
+            help = "Destination, broadcast by default; ff02::1%<interface> for IPv6"
+        )]
+        address: String,
+        #[arg(long, value_parser = parse_eid, conflicts_with = "vin", help = "Only entities with this EID")]
+        eid: Option<[u8; 6]>,
+        #[arg(long, value_parser = parse_vin, help = "Only entities with this VIN")]
+        vin: Option<[u8; 17]>,
+        #[arg(long, default_value_t = 2000, help = "Time to wait for responses in milliseconds")]
+        timeout_ms: u64,
+    },
+    #[command(about = "Query the entity status and the diagnostic power mode")]
+    Status(Connection),
+    #[command(about = "Connect and activate routing")]
+    Connect(Connection),
+    #[command(about = "Send a raw UDS request and print the response")]
+    Send {
+        #[command(flatten)]
+        connection: Connection,
+        #[arg(long, short, value_parser = parse_u16, help = "Logical address of the ECU")]
+        target: u16,
+        #[arg(required = true, help = "Request bytes, e.g. 22 F1 90 or 22F190")]
+        data: Vec<String>,
+    },
+    #[command(about = "Shortcuts for common UDS services")]
+    Uds {
+        #[command(flatten)]
+        connection: Connection,
+        #[arg(long, short, value_parser = parse_u16, help = "Logical address of the ECU")]
+        target: u16,
+        #[command(subcommand)]
+        service: UdsCommand,
+    },
+    #[command(about = "Interactive UDS shell on a routed session")]
+    Shell(ShellArgs),
+    #[command(about = "Forward and log the traffic between testers and a DoIP entity")]
+    Proxy {
+        #[arg(help = "DoIP entity as host or host:port")]
+        entity: String,
+        #[arg(long, help = "Write the forwarded traffic to a pcap file")]
+        pcap: Option<String>,
+    },
+    #[command(about = "Run the vehicle simulator")]
+    Serve {
+        #[arg(help = "Vehicle configuration (.toml, .yaml), a single default ECU if omitted")]
+        config: Option<String>,
+        #[arg(long, help = "Answer diagnostic requests from a recorded session")]
+        replay: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct Connection {
+    #[arg(help = "DoIP entity as host or host:port")]
+    entity: String,
+    #[arg(long, short, default_value = "0E80", value_parser = parse_u16,
+          help = "Logical address of the tester")]
+    source: u16,
+}
+
+#[derive(Args)]
+struct ShellArgs {
+    #[command(flatten)]
+    connection: Connection,
+    #[arg(long, short, value_parser = parse_u16, help = "Logical address of the ECU")]
+    target: u16,
+    #[arg(long, help = "Run the commands of the file instead of reading standard input")]
+    script: Option<String>,
+    #[arg(long, help = "File the command history is loaded from and saved to")]
+    history: Option<String>,
+    #[arg(long, value_parser = parse_hex_arg, conflicts_with = "key_command",
+          help = "SecurityAccess key is the seed XORed with this mask")]
+    xor_mask: Option<Vec<u8>>,
+    #[arg(long, help = "Program computing the SecurityAccess key, see ExternalProcessProvider")]
+    key_command: Option<String>,
+    #[cfg(feature = "key-library")]
+    #[arg(long, help = "Library exporting GenerateKeyEx, see SharedLibraryProvider")]
+    key_library: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum UdsCommand {
+    #[command(about = "DiagnosticSessionControl (0x10)")]
+    Session {
+        #[arg(value_parser = parse_u8)]
+        session_type: u8,
+    },
+    #[command(about = "ECUReset (0x11)")]
+    Reset {
+        #[arg(value_parser = parse_u8, default_value = "01")]
+        reset_type: u8,
+    },
+    #[command(about = "TesterPresent (0x3E)")]
+    TesterPresent,
+    #[command(about = "ReadDataByIdentifier (0x22)")]
+    ReadDid {
+        #[arg(value_parser = parse_u16)]
+        did: u16,
+    },
+    #[command(about = "WriteDataByIdentifier (0x2E)")]
+    WriteDid {
+        #[arg(value_parser = parse_u16)]
+        did: u16,
+        #[arg(required = true)]
+        data: Vec<String>,
+    },
+    #[command(about = "ReadDTCInformation reportDTCByStatusMask (0x19 0x02)")]
+    ReadDtc {
+        #[arg(value_parser = parse_u8, default_value = "FF")]
+        status_mask: u8,
+    },
+    #[command(about = "ClearDiagnosticInformation (0x14)")]
+    ClearDtc {
+        #[arg(value_parser = parse_u32, default_value = "FFFFFF")]
+        group: u32,
+    },
+    #[command(about = "RoutineControl (0x31)")]
+    Routine {
+        #[arg(value_parser = parse_u8, help = "01 start, 02 stop, 03 request results")]
+        control_type: u8,
+        #[arg(value_parser = parse_u16)]
+        routine: u16,
+        record: Vec<String>,
+    },
+}
+impl UdsCommand {
+    fn request(&self) -> Result<Vec<u8>, String> {
+        let mut request = Vec::new();
+        match self {
+            UdsCommand::Session { session_type } => {
+                request.extend([ServiceId::DiagnosticSessionControl as u8, *session_type])
+            }
+            UdsCommand::Reset { reset_type } => request.extend([ServiceId::EcuReset as u8, *reset_type]),
+            UdsCommand::TesterPresent => request.extend([ServiceId::TesterPresent as u8, 0x00]),
+            UdsCommand::ReadDid { did } => {
+                request.push(ServiceId::ReadDataByIdentifier as u8);
+                request.extend(did.to_be_bytes());
+            }
+            UdsCommand::WriteDid { did, data } => {
+                request.push(ServiceId::WriteDataByIdentifier as u8);
+                request.extend(did.to_be_bytes());
+                request.extend(parse_data(data)?);
+            }
+            UdsCommand::ReadDtc { status_mask } => {
+                request.extend([ServiceId::ReadDtcInformation as u8, 0x02, *status_mask])
+            }
+            UdsCommand::ClearDtc { group } => {
+                request.push(ServiceId::ClearDiagnosticInformation as u8);
+                request.extend(&group.to_be_bytes()[1..]);
+            }
+            UdsCommand::Routine { control_type, routine, record } => {
+                request.extend([ServiceId::RoutineControl as u8, *control_type]);
+                request.extend(routine.to_be_bytes());
+                if !record.is_empty() {
+                    request.extend(parse_data(record)?);
+                }
+            }
+        }
+        Ok(request)
+    }
+}
 
-use doip_lib::doip_server::{DoIPServerBuilder, DoIPServer};
+#[derive(Serialize)]
+struct Status {
+    entity_status: EntityStatusResponse,
+    power_mode: DiagnosticPowerMode,
+}
+
+#[derive(Serialize)]
+struct RoutingActivation {
+    source_address: u16,
+    entity_address: u16,
+}
+
+/* Request and response of one UDS exchange, the bytes as contiguous hex */
+#[derive(Serialize)]
+struct Exchange {
+    target_address: u16,
+    request: String,
+    response: String,
+    negative_response_code: Option<String>,
+}
 
 fn main() {
-    let builder = DoIPServerBuilder::new();
-    let server = builder.get_server();
-    server.start();
-    println!("dupa");
+    let cli = Cli::parse();
+    let level = match cli.verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    tracing_subscriber::fmt().with_max_level(level).with_writer(io::stderr).init();
+    let result = match cli.command {
+        Command::Discover { address, eid, vin, timeout_ms } => {
+            let request = match (eid, vin) {
+                (Some(eid), _) => IdentificationRequest::ByEid(eid),
+                (_, Some(vin)) => IdentificationRequest::ByVin(vin),
+                _ => IdentificationRequest::All,
+            };
+            discover(&address, &request, Duration::from_millis(timeout_ms), cli.json)
+        }
+        Command::Status(connection) => status(&connection, cli.json),
+        Command::Connect(connection) => connect(&connection, cli.json),
+        Command::Send { connection, target, data } => {
+            let request = parse_data(&data).map_err(Into::into);
+            request.and_then(|request| send(&connection, target, &request, cli.json))
+        }
+        Command::Uds { connection, target, service } => {
+            let request = service.request().map_err(Into::into);
+            request.and_then(|request| send(&connection, target, &request, cli.json))
+        }
+        Command::Shell(args) => shell(&args),
+        Command::Proxy { entity, pcap } => proxy(&entity, pcap),
+        Command::Serve { config, replay } => serve(config, replay),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn discover(
+    address: &str,
+    request: &IdentificationRequest,
+    timeout: Duration,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let entities = DoIPClient::discover(entity_address(address)?, request, timeout)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entities)?);
+        return Ok(());
+    }
+    println!("{:<22} {:<17} {:<6} {:<17} {:<17} FURTHER ACTION", "ADDRESS", "VIN", "LA", "EID", "GID");
+    for entity in &entities {
+        let response = &entity.response;
+        println!(
+            "{:<22} {:<17} {:04X}   {:<17} {:<17} {:?}",
+            entity.address.to_string(),
+            Vin(&response.vin).to_string(),
+            response.logical_address,
+            EntityId(&response.eid).to_string(),
+            EntityId(&response.gid).to_string(),
+            response.further_action_required
+        );
+    }
+    Ok(())
+}
+
+fn status(connection: &Connection, json: bool) -> Result<(), Box<dyn Error>> {
+    let mut session = connection.open()?;
+    let status = Status { entity_status: session.entity_status()?, power_mode: session.power_mode()? };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+    let entity_status = &status.entity_status;
+    println!("node type      {:?}", entity_status.node_type);
+    println!("sockets        {}/{} open", entity_status.open_sockets, entity_status.max_sockets);
+    println!("max data size  {}", entity_status.max_data_size);
+    println!("power mode     {:?}", status.power_mode);
+    Ok(())
+}
+
+fn connect(connection: &Connection, json: bool) -> Result<(), Box<dyn Error>> {
+    let session = connection.open()?;
+    let activation = RoutingActivation {
+        source_address: session.source_address(),
+        entity_address: session.entity_address(),
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&activation)?);
+    } else {
+        println!(
+            "routing activated: tester 0x{:04X}, entity 0x{:04X}",
+            activation.source_address, activation.entity_address
+        );
+    }
+    Ok(())
+}
+
+/* Prints the response, a negative response is printed too but ends the process with status 2 */
+fn send(connection: &Connection, target: u16, request: &[u8], json: bool) -> Result<(), Box<dyn Error>> {
+    let mut client = UdsClient::new(connection.open()?, target);
+    let (response, nrc_name) = match client.request(request) {
+        Ok(response) => (response, None),
+        Err(UdsError::NegativeResponse { service, nrc }) => {
+            let code: Option<NegativeResponseCode> = num::FromPrimitive::from_u8(nrc);
+            let name = code.map_or_else(|| format!("0x{:02X}", nrc), |code| format!("{:?}", code));
+            (vec![NEGATIVE_RESPONSE_SID, service, nrc], Some(name))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let negative_response = nrc_name.is_some();
+    if json {
+        let exchange = Exchange {
+            target_address: target,
+            request: HexBytes(request).to_string().replace(' ', ""),
+            response: HexBytes(&response).to_string().replace(' ', ""),
+            negative_response_code: nrc_name,
+        };
+        println!("{}", serde_json::to_string_pretty(&exchange)?);
+    } else {
+        match &nrc_name {
+            Some(name) => println!("0x{:04X}: {} ({})", target, HexBytes(&response), name),
+            None => println!("0x{:04X}: {}", target, HexBytes(&response)),
+        }
+    }
+    if negative_response {
+        process::exit(2);
+    }
+    Ok(())
+}
+
+fn shell(args: &ShellArgs) -> Result<(), Box<dyn Error>> {
+    let mut shell = Shell::new(UdsClient::new(args.connection.open()?, args.target));
+    if let Some(mask) = &args.xor_mask {
+        shell.set_key_provider(XorMaskProvider::new(mask));
+    }
+    if let Some(program) = &args.key_command {
+        shell.set_key_provider(ExternalProcessProvider::new(program));
+    }
+    #[cfg(feature = "key-library")]
+    if let Some(path) = &args.key_library {
+        shell.set_key_provider(doip_lib::uds::security::SharedLibraryProvider::load(path)?);
+    }
+    if let Some(path) = &args.history {
+        /* A missing history file is created on exit */
+        let _ = shell.load_history(path);
+    }
+    let mut stdout = io::stdout();
+    match &args.script {
+        Some(path) => {
+            let file = fs::File::open(path).map_err(|err| format!("{}: {}", path, err))?;
+            shell.run(io::BufReader::new(file), &mut stdout, None)?;
+        }
+        None => {
+            let stdin = io::stdin();
+            let prompt = stdin.is_terminal().then_some("doip> ");
+            shell.run(stdin.lock(), &mut stdout, prompt)?;
+        }
+    }
+    if let Some(path) = &args.history {
+        shell.save_history(path).map_err(|err| format!("{}: {}", path, err))?;
+    }
+    Ok(())
+}
+
+fn proxy(entity: &str, pcap: Option<String>) -> Result<(), Box<dyn Error>> {
+    let mut proxy = DoIPProxy::new(entity_address(entity)?);
+    proxy.set_log(io::stdout());
+    if let Some(path) = pcap {
+        proxy.set_capture(PcapCapture::create(&path).map_err(|err| format!("{}: {}", path, err))?);
+    }
+    proxy.start()?;
+    Ok(())
+}
+
+fn serve(config: Option<String>, replay: Option<String>) -> Result<(), Box<dyn Error>> {
+    let config = match config {
+        Some(path) => VehicleConfig::load(&path).map_err(|err| format!("{}: {}", path, err))?,
+        None => VehicleConfig::default(),
+    };
+    let server = match replay {
+        Some(path) => {
+            let recording = Recording::load(&path).map_err(|err| format!("{}: {}", path, err))?;
+            VehicleSimulator::build_replay_server(&config, ReplayHandler::new(&recording))?
+        }
+        None => VehicleSimulator::build_server(&config)?,
+    };
+    server.start()?.join();
+    Ok(())
+}
+
+impl Connection {
+    fn open(&self) -> Result<DoIPClientSession, Box<dyn Error>> {
+        Ok(DoIPClientSession::connect(entity_address(&self.entity)?, self.source)?)
+    }
+}
+
+/* host:port, or a host alone for the DoIP port */
+fn entity_address(host: &str) -> io::Result<SocketAddr> {
+    let mut addresses = match host.to_socket_addrs() {
+        Ok(addresses) => addresses,
+        Err(_) => (host, DoIPClientSession::DOIP_PORT).to_socket_addrs()?,
+    };
+    addresses
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}: no address found", host)))
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u64::from_str_radix(digits, 16).map_err(|err| format!("{}: {}", text, err))
+}
+fn parse_u8(text: &str) -> Result<u8, String> {
+    parse_number(text)?.try_into().map_err(|_| format!("{} does not fit into 8 bits", text))
+}
+fn parse_u16(text: &str) -> Result<u16, String> {
+    parse_number(text)?.try_into().map_err(|_| format!("{} does not fit into 16 bits", text))
+}
+fn parse_u32(text: &str) -> Result<u32, String> {
+    parse_number(text)?.try_into().map_err(|_| format!("{} does not fit into 32 bits", text))
+}
+
+/* Hex bytes, optionally split into several arguments and separated by spaces or colons */
+fn parse_data<S: AsRef<str>>(parts: &[S]) -> Result<Vec<u8>, String> {
+    let text = parts.iter().map(AsRef::as_ref).collect::<Vec<_>>().join(" ");
+    match HexBytes::parse(&text) {
+        Ok(data) if data.is_empty() => Err(format!("{:?}: expected hex bytes", text)),
+        Ok(data) => Ok(data),
+        Err(err) => Err(format!("{:?}: {}", text, err)),
+    }
+}
+fn parse_hex_arg(text: &str) -> Result<Vec<u8>, String> {
+    parse_data(&[text])
+}
+fn parse_eid(text: &str) -> Result<[u8; 6], String> {
+    parse_data(&[text])?.try_into().map_err(|_| format!("{}: an EID has 6 bytes", text))
+}
+fn parse_vin(text: &str) -> Result<[u8; 17], String> {
+    text.as_bytes().try_into().map_err(|_| format!("{}: a VIN has 17 characters", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+    #[test]
+    fn parse_arguments() {
+        assert_eq!(parse_u16("0x0E80"), Ok(0x0E80));
+        assert_eq!(parse_u16("1001"), Ok(0x1001));
+        assert!(parse_u8("100").is_err());
+        assert_eq!(parse_data(&["22 F1", "90"]), Ok(vec![0x22, 0xF1, 0x90]));
+        assert!(parse_data(&["2"]).is_err());
+        assert_eq!(parse_eid("00:1A:2B:3C:4D:5E"), Ok([0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5E]));
+        let args = ["doip", "uds", "localhost", "-t", "1001", "clear-dtc", "--json"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        let Command::Uds { target, service, .. } = cli.command else { panic!("expected uds command") };
+        assert_eq!(target, 0x1001);
+        assert_eq!(service.request(), Ok(vec![0x14, 0xFF, 0xFF, 0xFF]));
+        assert!(cli.json);
+    }
 }
@@ -6,6 +6,11 @@ pub mod alive_check;
 pub mod entity_status;
 pub mod header_nack;
 pub mod diag_power_mode;
+pub mod decoder;
+pub mod dump;
+pub(crate) mod hex;
+#[cfg(feature = "serde")]
+mod serde_hex;
 
 use crate::message::diag_message::{DiagMessage, DiagMessageAck, DiagMessageNAck};
 use crate::message::diag_power_mode::{DiagnosticPowerModeRequest, DiagnosticPowerModeResponse};
@@ -19,33 +24,92 @@ use crate::message::vehicle_identification::{
 };
 use crate::message::alive_check::{AliveCheckRequest, AliveCheckResponse};
 use crate::message::entity_status::{EntityStatusRequest, EntityStatusResponse};
+use std::fmt;
 
 
 pub trait Message {
     fn deserialize(&mut self, payload: &[u8]) -> Result<(), NackCode>;
     fn serialize(&self) -> Vec<u8>;
 }
+/* With the serde feature messages are tagged by their payload type, e.g.
+ * {"payload_type": "AliveCheckRes", "source_address": 3712} */
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "payload_type"))]
 pub enum MessageVariant {
+    #[cfg_attr(feature = "serde", serde(rename = "HeaderNack"))]
     HeaderNackMessageVariant(HeaderNackMessage),
+    #[cfg_attr(feature = "serde", serde(rename = "VehicleIDRes"))]
     VehicleIDResVariant(VehicleIdentificationResponse),
+    #[cfg_attr(feature = "serde", serde(rename = "VehicleIDReq"))]
     VehicleIDReqVariant(VehicleIdentificationRequest),
+    #[cfg_attr(feature = "serde", serde(rename = "VehicleIDReqByEID"))]
     VehicleIDReqByEIDVariant(VehicleIdentificationRequestEID),
+    #[cfg_attr(feature = "serde", serde(rename = "VehicleIDReqByVIN"))]
     VehicleIDReqByVINVariant(VehicleIdentificationRequestVIN),
+    #[cfg_attr(feature = "serde", serde(rename = "RoutingActivationReq"))]
     RoutingActivationRequestVariant(RoutingActivationRequest),
+    #[cfg_attr(feature = "serde", serde(rename = "RoutingActivationRes"))]
     RoutingActivationResponseVariant(RoutingActivationResponse),
+    #[cfg_attr(feature = "serde", serde(rename = "AliveCheckReq"))]
     AliveCheckRequestVariant(AliveCheckRequest),
+    #[cfg_attr(feature = "serde", serde(rename = "AliveCheckRes"))]
     AliveCheckRespnseVariant(AliveCheckResponse),
+    #[cfg_attr(feature = "serde", serde(rename = "EntityStatusReq"))]
     EntityStatusRequestVariant(EntityStatusRequest),
+    #[cfg_attr(feature = "serde", serde(rename = "EntityStatusRes"))]
     EntityStatusResponseVariant(EntityStatusResponse),
+    #[cfg_attr(feature = "serde", serde(rename = "DiagMessage"))]
     DiagnoticMessageVariant(DiagMessage),
+    #[cfg_attr(feature = "serde", serde(rename = "DiagPowerModeReq"))]
     DiagnosticPowerModeRequestVariant(DiagnosticPowerModeRequest),
+    #[cfg_attr(feature = "serde", serde(rename = "DiagPowerModeRes"))]
     DiagnosticPowerModeResponseVariant(DiagnosticPowerModeResponse),
+    #[cfg_attr(feature = "serde", serde(rename = "DiagMessageAck"))]
     DiagnosticMessageAckVariant(DiagMessageAck),
+    #[cfg_attr(feature = "serde", serde(rename = "DiagMessageNAck"))]
     DiagnosticMessageNAckVariant(DiagMessageNAck),
 }
 
+impl MessageVariant {
+    fn inner(&self) -> &dyn RenderedMessage {
+        match self {
+            MessageVariant::HeaderNackMessageVariant(message) => message,
+            MessageVariant::VehicleIDResVariant(message) => message,
+            MessageVariant::VehicleIDReqVariant(message) => message,
+            MessageVariant::VehicleIDReqByEIDVariant(message) => message,
+            MessageVariant::VehicleIDReqByVINVariant(message) => message,
+            MessageVariant::RoutingActivationRequestVariant(message) => message,
+            MessageVariant::RoutingActivationResponseVariant(message) => message,
+            MessageVariant::AliveCheckRequestVariant(message) => message,
+            MessageVariant::AliveCheckRespnseVariant(message) => message,
+            MessageVariant::EntityStatusRequestVariant(message) => message,
+            MessageVariant::EntityStatusResponseVariant(message) => message,
+            MessageVariant::DiagnoticMessageVariant(message) => message,
+            MessageVariant::DiagnosticPowerModeRequestVariant(message) => message,
+            MessageVariant::DiagnosticPowerModeResponseVariant(message) => message,
+            MessageVariant::DiagnosticMessageAckVariant(message) => message,
+            MessageVariant::DiagnosticMessageNAckVariant(message) => message,
+        }
+    }
+    pub fn serialize(&self) -> Vec<u8> {
+        self.inner().serialize()
+    }
+    /* Annotated byte level breakdown of the serialized message */
+    pub fn dump(&self) -> String {
+        dump::dump(&self.serialize())
+    }
+}
+impl fmt::Display for MessageVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.inner(), f)
+    }
+}
+trait RenderedMessage: Message + fmt::Display {}
+impl<T: Message + fmt::Display> RenderedMessage for T {}
+
 pub fn message_factory(payload: &[u8]) -> Result<MessageVariant, NackCode> {
-    let header = DoIPHeader::from_buffer(&payload)?;
+    let header = DoIPHeader::from_buffer(payload)?;
     let message = match header.payload_type {
         PayloadType::HeaderNack => MessageVariant::HeaderNackMessageVariant(
             HeaderNackMessage::from_payload(payload)?
@@ -103,7 +167,55 @@ pub fn message_factory(payload: &[u8]) -> Result<MessageVariant, NackCode> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::message::alive_check::AliveCheckResponse;
 
     #[test]
-    fn check_factory() {}
+    fn check_factory() {
+        let serialized = AliveCheckResponse::new(0x0E80).serialize();
+        assert!(matches!(
+            message_factory(&serialized),
+            Ok(MessageVariant::AliveCheckRespnseVariant(AliveCheckResponse { source_address: 0x0E80 }))
+        ));
+    }
+    #[test]
+    fn display_message_variant() {
+        let serialized = DiagMessage::new(0x0E80, 0x1001, &[0x22, 0xF1, 0x90]).serialize();
+        let message = message_factory(&serialized).unwrap();
+        assert_eq!(message.to_string(), "DiagMessage 0x0E80 -> 0x1001 [3] 22 F1 90");
+        assert_eq!(message.serialize(), serialized);
+        let message = message_factory(&HeaderNackMessage::new(NackCode::OutOfMemory).serialize()).unwrap();
+        assert_eq!(message.to_string(), "HeaderNack OutOfMemory");
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let serialized = DiagMessage::new(0x0E80, 0x1001, &[0x22, 0xF1, 0x90]).serialize();
+        let json = serde_json::to_value(message_factory(&serialized).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "payload_type": "DiagMessage", "source_address": 0x0E80, "target_address": 0x1001,
+                "user_data": "22F190"
+            })
+        );
+        let message: MessageVariant = serde_json::from_value(json).unwrap();
+        assert_eq!(message.serialize(), serialized);
+
+        let eid = [0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5E];
+        let vin = b"WVWZZZ1JZXW000001";
+        let mut response = VehicleIdentificationResponse::new(vin, 0x1000, &eid, &eid, Default::default());
+        let serialized = response.serialize();
+        let json = serde_json::to_string(&message_factory(&serialized).unwrap()).unwrap();
+        assert!(json.contains(r#""payload_type":"VehicleIDRes","vin":"WVWZZZ1JZXW000001""#));
+        assert!(json.contains(r#""eid":"001A2B3C4D5E""#));
+        let message: MessageVariant = serde_json::from_str(&json).unwrap();
+        assert_eq!(message.serialize(), serialized);
+
+        response.vin = [0xFF; 17];
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains(&format!(r#""vin":"{}""#, "FF".repeat(17))));
+        let parsed: VehicleIdentificationResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.vin, [0xFF; 17]);
+        assert!(serde_json::from_str::<MessageVariant>(r#"{"payload_type":"DiagMessage"}"#).is_err());
+    }
 }
@@ -1,10 +1,12 @@
 use crate::message::header::NackCode;
 use crate::message::Message;
+use std::fmt;
 use byteorder::{BigEndian, ByteOrder};
 
-use super::header::DoIPHeader;
+use super::header::{DoIPHeader, PayloadType};
 
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AliveCheckRequest {}
 impl AliveCheckRequest {
     pub fn from_payload(payload: &[u8]) ->Result<Self,NackCode> {
@@ -23,14 +25,18 @@ impl Message for AliveCheckRequest {
     }
 
     fn serialize(&self) -> Vec<u8> {
-        Vec::<u8>::default()
+        DoIPHeader::new(PayloadType::AliveCheckReq, 0).serialize()
     }
 }
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AliveCheckResponse {
     pub source_address: u16,
 }
 impl AliveCheckResponse {
+    pub fn new(source_address: u16) -> Self {
+        AliveCheckResponse { source_address }
+    }
     pub fn from_payload(payload: &[u8]) ->Result<Self,NackCode> {
         let mut s = Self::default();
         s.deserialize(payload)?;
@@ -40,18 +46,29 @@ impl AliveCheckResponse {
 impl Message for AliveCheckResponse {
     fn deserialize(&mut self, payload: &[u8]) -> Result<(), NackCode> {
         let header = DoIPHeader::from_buffer(payload)?;
-        if payload.len() < 2 || header.payload_length != 2  {
+        if header.payload_length != 2 || payload.len() < DoIPHeader::length() + 2 {
             return Err(NackCode::InvalidPayloadLength);
         }
+        let payload = &payload[DoIPHeader::length()..];
         self.source_address = BigEndian::read_u16(&payload[0..2]);
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
-        let mut result = Vec::<u8>::new();
-        result.resize(2, 0);
-        BigEndian::write_u16(&mut result, self.source_address);
-        result
+        let mut buf = DoIPHeader::new(PayloadType::AliveCheckRes, 2).serialize();
+        buf.extend_from_slice(&self.source_address.to_be_bytes());
+        buf
+    }
+}
+
+impl fmt::Display for AliveCheckRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AliveCheckRequest")
+    }
+}
+impl fmt::Display for AliveCheckResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AliveCheckResponse SA 0x{:04X}", self.source_address)
     }
 }
 
@@ -61,12 +78,32 @@ mod tests {
 
     #[test]
     fn test_alive_check_request() {
-        let mut message = AliveCheckRequest::default();
+        let message = AliveCheckRequest::default();
+        let serialized = message.serialize();
+        assert_eq!(serialized.len(), DoIPHeader::length());
+        assert!(AliveCheckRequest::from_payload(&serialized).is_ok());
     }
     #[test]
     fn test_alive_check_request_fail() {
+        let mut serialized = AliveCheckRequest::default().serialize();
+        serialized[7] = 1;
+        serialized.push(0);
+        assert_eq!(
+            AliveCheckRequest::from_payload(&serialized).err(),
+            Some(NackCode::InvalidPayloadLength)
+        );
     }
     #[test]
     fn test_alive_check_response_ok() {
+        let serialized = AliveCheckResponse::new(0x0E80).serialize();
+        let deserialized = AliveCheckResponse::from_payload(&serialized).unwrap();
+        assert_eq!(deserialized.source_address, 0x0E80);
+    }
+    #[test]
+    fn encode_alive_check_on_the_wire() {
+        assert_eq!(AliveCheckRequest::default().serialize(), [0x03, 0xFC, 0x00, 0x07, 0, 0, 0, 0]);
+        let frame = [0x03, 0xFC, 0x00, 0x08, 0, 0, 0, 2, 0x0E, 0x80];
+        assert_eq!(AliveCheckResponse::new(0x0E80).serialize(), frame);
+        assert_eq!(AliveCheckResponse::from_payload(&frame).unwrap().source_address, 0x0E80);
     }
 }
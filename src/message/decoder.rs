@@ -0,0 +1,64 @@
+use crate::message::header::{DoIPHeader, NackCode};
+
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder::default()
+    }
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+    /* Returns the next complete frame (header and payload) once enough bytes were pushed.
+     * A broken sync pattern cannot be recovered from on a stream, so it is reported
+     * and the buffer is left untouched. */
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, NackCode> {
+        if self.buffer.len() < DoIPHeader::length() {
+            return Ok(None);
+        }
+        if self.buffer[0] ^ self.buffer[1] != 0xFF {
+            return Err(NackCode::IncorrectPattern);
+        }
+        let frame_len = DoIPHeader::length() + DoIPHeader::get_payload_len(&self.buffer) as usize;
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+        let rest = self.buffer.split_off(frame_len);
+        Ok(Some(std::mem::replace(&mut self.buffer, rest)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::alive_check::AliveCheckResponse;
+    use crate::message::Message;
+
+    #[test]
+    fn decode_frames_split_at_arbitrary_boundaries() {
+        let mut stream = AliveCheckResponse::new(0x0E80).serialize();
+        stream.extend(AliveCheckResponse::new(0x0E81).serialize());
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(3) {
+            decoder.push(chunk);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!(AliveCheckResponse::from_payload(&frames[1]).unwrap().source_address, 0x0E81);
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+    #[test]
+    fn decode_incorrect_pattern() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[0x02, 0x02, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(decoder.next_frame(), Err(NackCode::IncorrectPattern));
+    }
+}
@@ -1,16 +1,23 @@
 use crate::message::header::NackCode;
 use crate::message::Message;
+use crate::message::dump::HexBytes;
+use std::fmt;
 use byteorder::{BigEndian, ByteOrder};
 
-use super::header::DoIPHeader;
+use super::header::{DoIPHeader, PayloadType};
 
-#[derive(Default)]
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DiagMessage {
-    source_address: u16,
-    target_address: u16,
-    user_data: Vec<u8>
+    pub source_address: u16,
+    pub target_address: u16,
+    #[cfg_attr(feature = "serde", serde(with = "crate::message::serde_hex::bytes"))]
+    pub user_data: Vec<u8>
 }
 impl DiagMessage {
+    pub fn new(source_address: u16, target_address: u16, user_data: &[u8]) -> Self {
+        DiagMessage { source_address, target_address, user_data: user_data.to_vec() }
+    }
     pub fn from_payload(payload: &[u8]) ->Result<Self,NackCode> {
         let mut s = Self::default();
         s.deserialize(payload)?;
@@ -20,36 +27,52 @@ impl DiagMessage {
 impl Message for DiagMessage {
     fn deserialize(&mut self, payload: &[u8]) -> Result<(), NackCode> {
         let header = DoIPHeader::from_buffer(payload)?;
-        if !(4..=8).contains(&header.payload_length) ||
-           payload.len() < header.payload_length as usize {
+        if header.payload_length < 5 ||
+           payload.len() < DoIPHeader::length() + header.payload_length as usize {
             return Err(NackCode::InvalidPayloadLength);
         }
+        let payload = &payload[DoIPHeader::length()..];
         self.source_address = BigEndian::read_u16(&payload[0..2]);
         self.target_address = BigEndian::read_u16(&payload[2..4]);
-        if header.payload_length > 4  {
-            self.user_data.extend_from_slice(&payload[4.. header.payload_length as usize])
-        }
+        self.user_data.clear();
+        self.user_data.extend_from_slice(&payload[4.. header.payload_length as usize]);
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8>{
-        todo!()
+        let header = DoIPHeader::new(PayloadType::DiagMessage, 4 + self.user_data.len() as u32);
+        let mut buf = header.serialize();
+        buf.extend_from_slice(&self.source_address.to_be_bytes());
+        buf.extend_from_slice(&self.target_address.to_be_bytes());
+        buf.extend_from_slice(&self.user_data);
+        buf
     }
 }
 #[repr(u8)]
-#[derive(Default, FromPrimitive)]
-enum AckCode {
+#[derive(Copy, Clone, Debug, Default, PartialEq, FromPrimitive, ToPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AckCode {
     #[default]
-    Ack = 0x16,
+    Ack = 0x00,
 }
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DiagMessageAck {
-    source_address: u16,
-    target_address: u16,
-    ack_code: AckCode,
-    prev_diag_data: Vec<u8>
+    pub source_address: u16,
+    pub target_address: u16,
+    pub ack_code: AckCode,
+    #[cfg_attr(feature = "serde", serde(with = "crate::message::serde_hex::bytes"))]
+    pub prev_diag_data: Vec<u8>
 }
 impl DiagMessageAck {
+    pub fn new(source_address: u16, target_address: u16, prev_diag_data: &[u8]) -> Self {
+        DiagMessageAck {
+            source_address,
+            target_address,
+            ack_code: AckCode::Ack,
+            prev_diag_data: prev_diag_data.to_vec(),
+        }
+    }
     pub fn from_payload(payload: &[u8]) ->Result<Self,NackCode> {
         let mut s = Self::default();
         s.deserialize(payload )?;
@@ -59,32 +82,60 @@ impl DiagMessageAck {
 impl Message for DiagMessageAck {
     fn deserialize(&mut self, payload: &[u8]) -> Result<(), NackCode> {
         let header = DoIPHeader::from_buffer(payload)?;
-        if !(5..=10).contains(&header.payload_length) ||
-           payload.len() < header.payload_length as usize {
+        if header.payload_length < 5 ||
+           payload.len() < DoIPHeader::length() + header.payload_length as usize {
             return Err(NackCode::InvalidPayloadLength);
         }
+        let payload = &payload[DoIPHeader::length()..];
         self.source_address = BigEndian::read_u16(&payload[0..2]);
         self.target_address = BigEndian::read_u16(&payload[2..4]);
-        self.ack_code = num::FromPrimitive::from_u8(payload[4]).unwrap();
-        if header.payload_length > 5 {
-            self.prev_diag_data.extend_from_slice(&payload[5..header.payload_length as usize]);
-        }
+        self.ack_code = num::FromPrimitive::from_u8(payload[4]).ok_or(NackCode::IncorrectPattern)?;
+        self.prev_diag_data.clear();
+        self.prev_diag_data.extend_from_slice(&payload[5..header.payload_length as usize]);
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        let header = DoIPHeader::new(PayloadType::DiagMessageAck, 5 + self.prev_diag_data.len() as u32);
+        let mut buf = header.serialize();
+        buf.extend_from_slice(&self.source_address.to_be_bytes());
+        buf.extend_from_slice(&self.target_address.to_be_bytes());
+        buf.push(num::ToPrimitive::to_u8(&self.ack_code).unwrap());
+        buf.extend_from_slice(&self.prev_diag_data);
+        buf
     }
 }
 
-#[derive(Default)]
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, FromPrimitive, ToPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DiagNackCode {
+    /*0x0 - 0x1 Reserved by 13400*/
+    #[default]
+    InvalidSourceAddress = 0x2,
+    UnknownTargetAddress = 0x3,
+    DiagnosticMessageTooLarge = 0x4,
+    OutOfMemory = 0x5,
+    TargetUnreachable = 0x6,
+    UnknownNetwork = 0x7,
+    TransportProtocolError = 0x8,
+    /*0x9 - 0xFF Reserved by 13400*/
+}
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DiagMessageNAck {
-    source_address: u16,
-    target_address: u16,
-    nack_code: NackCode,
-    prev_diag_data: Vec<u8>
+    pub source_address: u16,
+    pub target_address: u16,
+    pub nack_code: DiagNackCode,
+    #[cfg_attr(feature = "serde", serde(with = "crate::message::serde_hex::bytes"))]
+    pub prev_diag_data: Vec<u8>
 }
 impl DiagMessageNAck {
+    pub fn new(source_address: u16, target_address: u16, nack_code: DiagNackCode,
+               prev_diag_data: &[u8]) -> Self {
+        DiagMessageNAck { source_address, target_address, nack_code,
+            prev_diag_data: prev_diag_data.to_vec() }
+    }
     pub fn from_payload(payload: &[u8]) ->Result<Self,NackCode> {
         let mut s = Self::default();
         s.deserialize(payload)?;
@@ -94,20 +145,107 @@ impl DiagMessageNAck {
 impl Message for DiagMessageNAck {
     fn deserialize(&mut self,payload: &[u8]) -> Result<(), NackCode> {
         let header = DoIPHeader::from_buffer(payload)?;
-        if !(5..=10).contains(&header.payload_length) ||
-           payload.len() < header.payload_length as usize {
+        if header.payload_length < 5 ||
+           payload.len() < DoIPHeader::length() + header.payload_length as usize {
             return Err(NackCode::InvalidPayloadLength);
         }
+        let payload = &payload[DoIPHeader::length()..];
         self.source_address = BigEndian::read_u16(&payload[0..2]);
         self.target_address = BigEndian::read_u16(&payload[2..4]);
-        self.nack_code = num::FromPrimitive::from_u8(payload[4]).unwrap();
-        if header.payload_length > 5 {
-            self.prev_diag_data.extend_from_slice(&payload[5..header.payload_length as usize]);
-        }
+        self.nack_code = num::FromPrimitive::from_u8(payload[4]).ok_or(NackCode::IncorrectPattern)?;
+        self.prev_diag_data.clear();
+        self.prev_diag_data.extend_from_slice(&payload[5..header.payload_length as usize]);
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        let header = DoIPHeader::new(PayloadType::DiagMessageNAck, 5 + self.prev_diag_data.len() as u32);
+        let mut buf = header.serialize();
+        buf.extend_from_slice(&self.source_address.to_be_bytes());
+        buf.extend_from_slice(&self.target_address.to_be_bytes());
+        buf.push(num::ToPrimitive::to_u8(&self.nack_code).unwrap());
+        buf.extend_from_slice(&self.prev_diag_data);
+        buf
+    }
+}
+
+impl fmt::Display for DiagMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DiagMessage 0x{:04X} -> 0x{:04X} [{}] {}",
+            self.source_address,
+            self.target_address,
+            self.user_data.len(),
+            HexBytes(&self.user_data)
+        )
+    }
+}
+impl fmt::Display for DiagMessageAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (source, target) = (self.source_address, self.target_address);
+        write!(f, "DiagMessageAck 0x{:04X} -> 0x{:04X} {:?}", source, target, self.ack_code)
+    }
+}
+impl fmt::Display for DiagMessageNAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (source, target) = (self.source_address, self.target_address);
+        write!(f, "DiagMessageNAck 0x{:04X} -> 0x{:04X} {:?}", source, target, self.nack_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_deserialize_diag_message() {
+        let message = DiagMessage::new(0x0E80, 0x1001, &[0x22, 0xF1, 0x90]);
+        let serialized = message.serialize();
+        let deserialized = DiagMessage::from_payload(&serialized).unwrap();
+        assert_eq!(deserialized, message);
+    }
+    #[test]
+    fn deserialize_diag_message_without_user_data() {
+        let mut serialized = DiagMessage::new(0x0E80, 0x1001, &[0x3E]).serialize();
+        serialized.pop();
+        serialized[7] = 4;
+        assert_eq!(
+            DiagMessage::from_payload(&serialized),
+            Err(NackCode::InvalidPayloadLength)
+        );
+    }
+    #[test]
+    fn serialize_deserialize_diag_message_ack() {
+        let serialized = DiagMessageAck::new(0x1001, 0x0E80, &[0x22]).serialize();
+        let deserialized = DiagMessageAck::from_payload(&serialized).unwrap();
+        assert_eq!(deserialized.source_address, 0x1001);
+        assert_eq!(deserialized.target_address, 0x0E80);
+        assert_eq!(deserialized.prev_diag_data, vec![0x22]);
+    }
+    #[test]
+    fn serialize_deserialize_diag_message_nack() {
+        let serialized = DiagMessageNAck::new(
+            0x1001, 0x0E80, DiagNackCode::UnknownTargetAddress, &[]).serialize();
+        let deserialized = DiagMessageNAck::from_payload(&serialized).unwrap();
+        assert_eq!(deserialized.nack_code, DiagNackCode::UnknownTargetAddress);
+    }
+    #[test]
+    fn encode_diag_messages_on_the_wire() {
+        let message = DiagMessage::new(0x0E80, 0x1001, &[0x22, 0xF1, 0x90]);
+        let frame = [0x03, 0xFC, 0x80, 0x01, 0, 0, 0, 7, 0x0E, 0x80, 0x10, 0x01, 0x22, 0xF1, 0x90];
+        assert_eq!(message.serialize(), frame);
+        assert_eq!(DiagMessage::from_payload(&frame).unwrap(), message);
+        /* Positive acknowledge code 0x00 */
+        let frame = [0x03, 0xFC, 0x80, 0x02, 0, 0, 0, 6, 0x10, 0x01, 0x0E, 0x80, 0x00, 0x22];
+        assert_eq!(DiagMessageAck::new(0x1001, 0x0E80, &[0x22]).serialize(), frame);
+        assert_eq!(DiagMessageAck::from_payload(&frame).unwrap().ack_code, AckCode::Ack);
+        let nack = DiagMessageNAck::new(0x1001, 0x0E80, DiagNackCode::UnknownTargetAddress, &[]);
+        assert_eq!(nack.serialize(), [0x03, 0xFC, 0x80, 0x03, 0, 0, 0, 5, 0x10, 0x01, 0x0E, 0x80, 0x03]);
+    }
+    #[test]
+    fn reject_unknown_acknowledge_code() {
+        let frame = [0x03, 0xFC, 0x80, 0x02, 0, 0, 0, 5, 0x10, 0x01, 0x0E, 0x80, 0x16];
+        assert_eq!(DiagMessageAck::from_payload(&frame).err(), Some(NackCode::IncorrectPattern));
     }
 }
@@ -1,9 +1,11 @@
 use crate::message::header::NackCode;
 use crate::message::Message;
+use std::fmt;
 
-use super::header::DoIPHeader;
+use super::header::{DoIPHeader, PayloadType};
 
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DiagnosticPowerModeRequest {
 }
 impl DiagnosticPowerModeRequest {
@@ -23,28 +25,29 @@ impl Message for DiagnosticPowerModeRequest {
     }
 
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        DoIPHeader::new(PayloadType::DiagPowerModeReq, 0).serialize()
     }
 }
 #[repr(u8)]
-#[derive(FromPrimitive)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, FromPrimitive, ToPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DiagnosticPowerMode {
+    #[default]
     NotReady = 0x0,
     Ready = 0x1,
     NotSupported = 0x2,
     /*Reserved 0x3-0xf*/
 }
 
-impl Default for DiagnosticPowerMode {
-    fn default() -> Self {
-        Self::NotReady
-    }
-}
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DiagnosticPowerModeResponse {
-    power_mode: DiagnosticPowerMode,
+    pub power_mode: DiagnosticPowerMode,
 }
 impl DiagnosticPowerModeResponse {
+    pub fn new(power_mode: DiagnosticPowerMode) -> Self {
+        DiagnosticPowerModeResponse { power_mode }
+    }
     pub fn from_payload(payload: &[u8]) ->Result<Self,NackCode> {
         let mut s = Self::default();
         s.deserialize(payload)?;
@@ -54,14 +57,41 @@ impl DiagnosticPowerModeResponse {
 impl Message for DiagnosticPowerModeResponse {
     fn deserialize(&mut self, payload: &[u8]) -> Result<(), NackCode> {
         let header = DoIPHeader::from_buffer(payload)?;
-        if header.payload_length != 1 || payload.is_empty() {
+        if header.payload_length != 1 || payload.len() < DoIPHeader::length() + 1 {
             return Err(NackCode::InvalidPayloadLength);
         }
-        self.power_mode = num::FromPrimitive::from_u8(payload[0]).unwrap();
+        self.power_mode = num::FromPrimitive::from_u8(payload[DoIPHeader::length()])
+            .ok_or(NackCode::IncorrectPattern)?;
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        let mut buf = DoIPHeader::new(PayloadType::DiagPowerModeRes, 1).serialize();
+        buf.push(num::ToPrimitive::to_u8(&self.power_mode).unwrap());
+        buf
+    }
+}
+impl fmt::Display for DiagnosticPowerModeRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DiagnosticPowerModeRequest")
+    }
+}
+impl fmt::Display for DiagnosticPowerModeResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DiagnosticPowerModeResponse {:?}", self.power_mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_power_mode_on_the_wire() {
+        assert_eq!(DiagnosticPowerModeRequest::default().serialize(), [0x03, 0xFC, 0x40, 0x03, 0, 0, 0, 0]);
+        let frame = [0x03, 0xFC, 0x40, 0x04, 0, 0, 0, 1, 0x01];
+        assert_eq!(DiagnosticPowerModeResponse::new(DiagnosticPowerMode::Ready).serialize(), frame);
+        let deserialized = DiagnosticPowerModeResponse::from_payload(&frame).unwrap();
+        assert_eq!(deserialized.power_mode, DiagnosticPowerMode::Ready);
     }
 }
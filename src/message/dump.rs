@@ -0,0 +1,226 @@
+use crate::message::header::{DoIPHeader, PayloadType, ProtocolVersion};
+use crate::message::{hex, message_factory, MessageVariant};
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt::{self, Write};
+
+/* Bytes as space separated hex, e.g. "22 F1 90" */
+pub struct HexBytes<'a>(pub &'a [u8]);
+impl fmt::Display for HexBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_char(' ')?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+pub use crate::message::hex::HexError;
+impl HexBytes<'_> {
+    /* Reads bytes back from hex, with or without the spaces of the display form */
+    pub fn parse(text: &str) -> Result<Vec<u8>, HexError> {
+        hex::decode(text)
+    }
+}
+
+/* VIN as ASCII, non printable characters are shown as '.' */
+pub struct Vin<'a>(pub &'a [u8]);
+impl fmt::Display for Vin<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            f.write_char(if byte.is_ascii_graphic() { *byte as char } else { '.' })?;
+        }
+        Ok(())
+    }
+}
+
+/* EID/GID as colon separated hex, e.g. "00:1A:2B:3C:4D:5E" */
+pub struct EntityId<'a>(pub &'a [u8]);
+impl fmt::Display for EntityId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_char(':')?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/* Field of a frame with its position and interpretation */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub offset: usize,
+    pub length: usize,
+    pub name: &'static str,
+    pub value: String,
+}
+
+/* Splits a frame (header + payload) into its fields. Bytes which cannot be attributed to a
+ * field, e.g. of an undecodable payload, end up in a single trailing field. */
+pub fn fields(frame: &[u8]) -> Vec<Field> {
+    let mut layout: Vec<(usize, &'static str, String)> = Vec::new();
+    if frame.len() >= DoIPHeader::length() {
+        let version: Option<ProtocolVersion> = num::FromPrimitive::from_u8(frame[0]);
+        let payload_type: Option<PayloadType> =
+            num::FromPrimitive::from_u16(BigEndian::read_u16(&frame[2..4]));
+        layout.push((1, "protocol version", name_or_unknown(version)));
+        layout.push((1, "inverse protocol version", String::new()));
+        layout.push((2, "payload type", name_or_unknown(payload_type)));
+        layout.push((4, "payload length", BigEndian::read_u32(&frame[4..8]).to_string()));
+        match message_factory(frame) {
+            Ok(message) => layout.extend(payload_layout(&message)),
+            Err(code) => layout.push((usize::MAX, "payload", format!("not decodable: {:?}", code))),
+        }
+    }
+    let mut fields = Vec::new();
+    let mut offset = 0;
+    for (length, name, value) in layout {
+        let length = length.min(frame.len() - offset);
+        if length == 0 {
+            continue;
+        }
+        fields.push(Field { offset, length, name, value });
+        offset += length;
+    }
+    if offset < frame.len() {
+        let name = if fields.is_empty() { "truncated header" } else { "trailing bytes" };
+        fields.push(Field { offset, length: frame.len() - offset, name, value: String::new() });
+    }
+    fields
+}
+
+/* Annotated byte level breakdown of a frame, one field per line */
+pub fn dump(frame: &[u8]) -> String {
+    const BYTES_PER_LINE: usize = 16;
+    let mut text = String::new();
+    for field in fields(frame) {
+        let bytes = &frame[field.offset..field.offset + field.length];
+        let mut chunks = bytes.chunks(BYTES_PER_LINE);
+        let first = chunks.next().unwrap_or_default();
+        let description = match field.value.as_str() {
+            "" => field.name.to_string(),
+            value => format!("{}: {}", field.name, value),
+        };
+        let _ = writeln!(text, "{:04X}  {:<48} {}", field.offset, HexBytes(first).to_string(), description);
+        for (index, chunk) in chunks.enumerate() {
+            let offset = field.offset + (index + 1) * BYTES_PER_LINE;
+            let _ = writeln!(text, "{:04X}  {}", offset, HexBytes(chunk));
+        }
+    }
+    text
+}
+
+fn name_or_unknown<T: fmt::Debug>(value: Option<T>) -> String {
+    value.map_or_else(|| "unknown".to_string(), |value| format!("{:?}", value))
+}
+
+fn address(address: u16) -> String {
+    format!("0x{:04X}", address)
+}
+
+/* (length, name, value) of the payload fields, usize::MAX for the rest of the frame */
+fn payload_layout(message: &MessageVariant) -> Vec<(usize, &'static str, String)> {
+    match message {
+        MessageVariant::HeaderNackMessageVariant(nack) => {
+            vec![(1, "NACK code", format!("{:?}", nack.nack_code))]
+        }
+        MessageVariant::VehicleIDReqByEIDVariant(request) => {
+            vec![(6, "EID", EntityId(&request.eid).to_string())]
+        }
+        MessageVariant::VehicleIDReqByVINVariant(request) => vec![(17, "VIN", Vin(&request.vin).to_string())],
+        MessageVariant::VehicleIDResVariant(response) => vec![
+            (17, "VIN", Vin(&response.vin).to_string()),
+            (2, "logical address", address(response.logical_address)),
+            (6, "EID", EntityId(&response.eid).to_string()),
+            (6, "GID", EntityId(&response.gid).to_string()),
+            (1, "further action required", format!("{:?}", response.further_action_required)),
+            (1, "VIN/GID sync status", name_or_unknown(response.sync_status)),
+        ],
+        MessageVariant::RoutingActivationRequestVariant(request) => vec![
+            (2, "source address", address(request.source_address)),
+            (1, "activation type", format!("0x{:02X}", request.activation_type)),
+            (4, "reserved by ISO 13400", String::new()),
+            (4, "reserved for OEM", String::new()),
+        ],
+        MessageVariant::RoutingActivationResponseVariant(response) => vec![
+            (2, "tester logical address", address(response.client_logical_address)),
+            (2, "entity logical address", address(response.entity_logical_address)),
+            (1, "response code", format!("{:?}", response.routing_activation_response_code)),
+            (4, "reserved by ISO 13400", String::new()),
+            (4, "reserved for OEM", String::new()),
+        ],
+        MessageVariant::AliveCheckRespnseVariant(response) => {
+            vec![(2, "source address", address(response.source_address))]
+        }
+        MessageVariant::EntityStatusResponseVariant(response) => vec![
+            (1, "node type", format!("{:?}", response.node_type)),
+            (1, "max concurrent sockets", response.max_sockets.to_string()),
+            (1, "currently open sockets", response.open_sockets.to_string()),
+            (4, "max data size", response.max_data_size.to_string()),
+        ],
+        MessageVariant::DiagnosticPowerModeResponseVariant(response) => {
+            vec![(1, "diagnostic power mode", format!("{:?}", response.power_mode))]
+        }
+        MessageVariant::DiagnoticMessageVariant(message) => vec![
+            (2, "source address", address(message.source_address)),
+            (2, "target address", address(message.target_address)),
+            (usize::MAX, "user data", format!("{} bytes", message.user_data.len())),
+        ],
+        MessageVariant::DiagnosticMessageAckVariant(ack) => vec![
+            (2, "source address", address(ack.source_address)),
+            (2, "target address", address(ack.target_address)),
+            (1, "ACK code", format!("{:?}", ack.ack_code)),
+            (usize::MAX, "previous diagnostic data", String::new()),
+        ],
+        MessageVariant::DiagnosticMessageNAckVariant(nack) => vec![
+            (2, "source address", address(nack.source_address)),
+            (2, "target address", address(nack.target_address)),
+            (1, "NACK code", format!("{:?}", nack.nack_code)),
+            (usize::MAX, "previous diagnostic data", String::new()),
+        ],
+        MessageVariant::VehicleIDReqVariant(_)
+        | MessageVariant::AliveCheckRequestVariant(_)
+        | MessageVariant::EntityStatusRequestVariant(_)
+        | MessageVariant::DiagnosticPowerModeRequestVariant(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::diag_message::DiagMessage;
+    use crate::message::Message;
+
+    #[test]
+    fn dump_diagnostic_message() {
+        let user_data: Vec<u8> = (0..20).collect();
+        let frame = DiagMessage::new(0x0E80, 0x1001, &user_data).serialize();
+        let layout = fields(&frame);
+        let names: Vec<_> = layout.iter().map(|field| field.name).collect();
+        assert_eq!(
+            names,
+            ["protocol version", "inverse protocol version", "payload type", "payload length",
+             "source address", "target address", "user data"]
+        );
+        assert_eq!(layout[2].value, "DiagMessage");
+        let target = Field { offset: 10, length: 2, name: "target address", value: "0x1001".into() };
+        assert_eq!(layout[5], target);
+        let text = dump(&frame);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert!(lines[4].starts_with("0008  0E 80") && lines[4].ends_with("source address: 0x0E80"));
+        assert_eq!(lines[7], "001C  10 11 12 13");
+    }
+    #[test]
+    fn dump_undecodable_frames() {
+        let payload = fields(&[0x02, 0xFD, 0x80, 0x01, 0, 0, 0, 0x02, 0x0E, 0x80]).pop().unwrap();
+        assert_eq!(payload.name, "payload");
+        assert_eq!(payload.value, "not decodable: InvalidPayloadLength");
+        assert_eq!(fields(&[0x02, 0xFD])[0].name, "truncated header");
+        assert_eq!(Vin(b"WVW\x00").to_string(), "WVW.");
+        assert_eq!(EntityId(&[0x00, 0x1A, 0x2B]).to_string(), "00:1A:2B");
+    }
+}
@@ -1,22 +1,25 @@
 use crate::message::header::NackCode;
 use crate::message::Message;
+use std::fmt;
 use byteorder::{BigEndian, ByteOrder};
 
-use super::header::DoIPHeader;
+use super::header::{DoIPHeader, PayloadType};
 #[repr(u8)]
-#[derive(FromPrimitive, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, FromPrimitive, ToPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NodeType {
     #[default]
     Gateway = 0x0,
     Node = 0x1,
     /*0x2-0xff - reserved*/
 }
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EntityStatusResponse {
-    node_type: NodeType,
-    max_sockets: u8,
-    open_sockets: u8,
-    max_data_size: u32
+    pub node_type: NodeType,
+    pub max_sockets: u8,
+    pub open_sockets: u8,
+    pub max_data_size: u32
 }
 impl EntityStatusResponse {
     pub fn new(node_type: NodeType, max_sockets:u8, open_sockets: u8,
@@ -32,10 +35,11 @@ impl EntityStatusResponse {
 impl Message for EntityStatusResponse {
     fn deserialize(&mut self, payload: &[u8]) -> Result<(), NackCode> {
         let header = DoIPHeader::from_buffer(payload)?;
-        if header.payload_length != 7 || payload.len() < 7 {
+        if header.payload_length != 7 || payload.len() < DoIPHeader::length() + 7 {
             return Err(NackCode::InvalidPayloadLength);
         }
-        self.node_type = num::FromPrimitive::from_u8(payload[0]).unwrap();
+        let payload = &payload[DoIPHeader::length()..];
+        self.node_type = num::FromPrimitive::from_u8(payload[0]).ok_or(NackCode::IncorrectPattern)?;
         self.max_sockets = payload[1];
         self.open_sockets = payload[2];
         self.max_data_size = BigEndian::read_u32(&payload[3..7]);
@@ -43,10 +47,16 @@ impl Message for EntityStatusResponse {
     }
 
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        let mut buf = DoIPHeader::new(PayloadType::EntityStatusRes, 7).serialize();
+        buf.push(num::ToPrimitive::to_u8(&self.node_type).unwrap());
+        buf.push(self.max_sockets);
+        buf.push(self.open_sockets);
+        buf.extend_from_slice(&self.max_data_size.to_be_bytes());
+        buf
     }
 }
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EntityStatusRequest {
 }
 impl EntityStatusRequest {
@@ -66,6 +76,42 @@ impl Message for EntityStatusRequest {
     }
 
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        DoIPHeader::new(PayloadType::EntityStatusReq, 0).serialize()
+    }
+}
+
+impl fmt::Display for EntityStatusRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EntityStatusRequest")
+    }
+}
+impl fmt::Display for EntityStatusResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "EntityStatusResponse {:?} sockets {}/{} max data size {}",
+            self.node_type, self.open_sockets, self.max_sockets, self.max_data_size
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_deserialize_entity_status_response() {
+        let serialized = EntityStatusResponse::new(NodeType::Node, 10, 1, 4096).serialize();
+        let deserialized = EntityStatusResponse::from_payload(&serialized).unwrap();
+        assert_eq!(deserialized.node_type, NodeType::Node);
+        assert_eq!(deserialized.max_sockets, 10);
+        assert_eq!(deserialized.open_sockets, 1);
+        assert_eq!(deserialized.max_data_size, 4096);
+    }
+    #[test]
+    fn encode_entity_status_on_the_wire() {
+        let frame = [0x03, 0xFC, 0x40, 0x02, 0, 0, 0, 7, 0x01, 10, 1, 0, 0, 0x10, 0];
+        assert_eq!(EntityStatusResponse::new(NodeType::Node, 10, 1, 4096).serialize(), frame);
+        assert_eq!(EntityStatusRequest::default().serialize(), [0x03, 0xFC, 0x40, 0x01, 0, 0, 0, 0]);
     }
 }
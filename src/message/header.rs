@@ -2,6 +2,7 @@ use byteorder::{BigEndian, ByteOrder};
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Default, PartialEq, FromPrimitive, ToPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NackCode {
     #[default]
     IncorrectPattern = 0x0,
@@ -13,7 +14,8 @@ pub enum NackCode {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive, Default)]
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive, ToPrimitive, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ProtocolVersion {
     /*0x0 - Reserved */
     ISO13400_2010 = 0x1,
@@ -25,6 +27,7 @@ pub enum ProtocolVersion {
 }
 #[repr(u16)]
 #[derive(Copy, Clone, PartialEq, Debug, FromPrimitive, ToPrimitive, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PayloadType {
     #[default]
     HeaderNack = 0x0,
@@ -49,13 +52,21 @@ pub enum PayloadType {
     /*0xF000 - 0xFFFF Reserved for manufacturer*/
 }
 
-#[derive(Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DoIPHeader {
     pub protocol_version: ProtocolVersion,
     pub payload_type: PayloadType,
     pub payload_length: u32,
 }
 impl DoIPHeader {
+    pub fn new(payload_type: PayloadType, payload_length: u32) -> Self {
+        DoIPHeader {
+            protocol_version: ProtocolVersion::ISO13400_2019,
+            payload_type,
+            payload_length,
+        }
+    }
     pub fn serialize(&self) -> Vec<u8> {
         let mut buf = Vec::<u8>::new();
         let mut convert_buf: [u8; 4] = [0; 4];
@@ -77,7 +88,7 @@ impl DoIPHeader {
         if buffer.len() < DoIPHeader::length() {
             return 0;
         }
-        return BigEndian::read_u32(&buffer[4..8]);
+        BigEndian::read_u32(&buffer[4..8])
     }
     pub fn from_buffer(buffer: &[u8]) -> Result<DoIPHeader, NackCode> {
         if buffer.len() < DoIPHeader::length() {
@@ -1,11 +1,13 @@
 use crate::message::header::NackCode;
 use crate::message::Message;
+use std::fmt;
 
-use super::header::{DoIPHeader, PayloadType, ProtocolVersion};
+use super::header::{DoIPHeader, PayloadType};
 
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HeaderNackMessage {
-    nack_code: NackCode,
+    pub nack_code: NackCode,
 }
 impl HeaderNackMessage {
     pub fn from_payload(payload: &[u8]) -> Result<Self, NackCode> {
@@ -25,17 +27,39 @@ impl Message for HeaderNackMessage {
         {
             return Err(NackCode::InvalidPayloadLength);
         }
-        self.nack_code = num::FromPrimitive::from_u8(payload[0]).unwrap();
+        self.nack_code = num::FromPrimitive::from_u8(payload[DoIPHeader::length()])
+            .ok_or(NackCode::IncorrectPattern)?;
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
-        let mut header: DoIPHeader = Default::default();
-        header.payload_length = 1;
-        header.payload_type = PayloadType::HeaderNack;
-        header.protocol_version = ProtocolVersion::ISO13400_2019;
+        let header = DoIPHeader::new(PayloadType::HeaderNack, 1);
         let mut buf: Vec<u8> = header.serialize();
         buf.push(num::ToPrimitive::to_u8(&self.nack_code).unwrap());
         buf
     }
 }
+
+impl fmt::Display for HeaderNackMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HeaderNack {:?}", self.nack_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_deserialize_header_nack() {
+        let message = HeaderNackMessage::new(NackCode::MessageTooLong);
+        let serialized = message.serialize();
+        let deserialized = HeaderNackMessage::from_payload(&serialized).unwrap();
+        assert_eq!(deserialized.nack_code, NackCode::MessageTooLong);
+    }
+    #[test]
+    fn encode_header_nack_on_the_wire() {
+        let frame = [0x03, 0xFC, 0x00, 0x00, 0, 0, 0, 1, 0x02];
+        assert_eq!(HeaderNackMessage::new(NackCode::MessageTooLong).serialize(), frame);
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HexError {
+    OddLength,
+    InvalidDigit(char),
+}
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexError::OddLength => write!(f, "odd number of hex digits"),
+            HexError::InvalidDigit(digit) => write!(f, "invalid hex digit {:?}", digit),
+        }
+    }
+}
+impl std::error::Error for HexError {}
+
+/* Bytes as contiguous uppercase hex, e.g. "22F190" */
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/* Hex digits in pairs, whitespace, colons and dashes in between are skipped */
+pub(crate) fn decode(text: &str) -> Result<Vec<u8>, HexError> {
+    let mut bytes = Vec::with_capacity(text.len() / 2);
+    let mut high = None;
+    for digit in text.chars().filter(|c| !c.is_whitespace() && !matches!(c, ':' | '-')) {
+        let nibble = digit.to_digit(16).ok_or(HexError::InvalidDigit(digit))? as u8;
+        match high.take() {
+            Some(high) => bytes.push(high << 4 | nibble),
+            None => high = Some(nibble),
+        }
+    }
+    match high {
+        Some(_) => Err(HexError::OddLength),
+        None => Ok(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        assert_eq!(encode(&[0x22, 0xF1, 0x90]), "22F190");
+        assert_eq!(decode("22f190"), Ok(vec![0x22, 0xF1, 0x90]));
+        assert_eq!(decode(""), Ok(Vec::new()));
+    }
+    #[test]
+    fn decode_skips_separators() {
+        assert_eq!(decode("00:1A-2B 3C\t4D"), Ok(vec![0x00, 0x1A, 0x2B, 0x3C, 0x4D]));
+    }
+    #[test]
+    fn decode_rejects_malformed_input() {
+        assert_eq!(decode("22F"), Err(HexError::OddLength));
+        assert_eq!(decode("2G"), Err(HexError::InvalidDigit('G')));
+        assert_eq!(decode("2ä"), Err(HexError::InvalidDigit('ä')));
+    }
+}
@@ -1,17 +1,27 @@
 use crate::message::header::NackCode;
 use crate::message::Message;
+use std::fmt;
 use byteorder::{BigEndian, ByteOrder};
 
-use super::header::DoIPHeader;
+use super::header::{DoIPHeader, PayloadType};
 
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RoutingActivationRequest {
     pub source_address: u16,
     pub activation_type: u8,
     reserved_doc: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
     reserved_vm: Option<u32>
 }
 impl RoutingActivationRequest {
+    pub const ACTIVATION_TYPE_DEFAULT: u8 = 0x00;
+    pub const ACTIVATION_TYPE_WWH_OBD: u8 = 0x01;
+    pub const ACTIVATION_TYPE_CENTRAL_SECURITY: u8 = 0xE0;
+    pub fn new(source_address: u16, activation_type: u8) -> Self {
+        RoutingActivationRequest { source_address, activation_type, reserved_doc: 0,
+            reserved_vm: None }
+    }
     pub fn from_payload(payload: &[u8]) ->Result<Self,NackCode> {
         let mut s = Self::default();
         s.deserialize(payload)?;
@@ -22,40 +32,56 @@ impl Message for RoutingActivationRequest  {
     fn deserialize(&mut self, payload: &[u8]) -> Result<(), NackCode> {
         let header = DoIPHeader::from_buffer(payload)?;
         if ![7,11].contains(&header.payload_length) 
-        || payload.len() < header.payload_length as usize {
+        || payload.len() < DoIPHeader::length() + header.payload_length as usize {
             return Err(NackCode::InvalidPayloadLength);
         }
+        let payload = &payload[DoIPHeader::length()..];
         self.source_address = BigEndian::read_u16(&payload[0..2]);
         self.activation_type = payload[2];
         self.reserved_doc = BigEndian::read_u32(&payload[3..7]);
-        if payload.len() == 11 {
+        if header.payload_length == 11 {
             self.reserved_vm = Some(BigEndian::read_u32(&payload[7..11]));
         }
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        let payload_length = if self.reserved_vm.is_some() { 11 } else { 7 };
+        let mut buf = DoIPHeader::new(PayloadType::RoutingActivationReq, payload_length).serialize();
+        buf.extend_from_slice(&self.source_address.to_be_bytes());
+        buf.push(self.activation_type);
+        buf.extend_from_slice(&self.reserved_doc.to_be_bytes());
+        if let Some(reserved_vm) = self.reserved_vm {
+            buf.extend_from_slice(&reserved_vm.to_be_bytes());
+        }
+        buf
     }
 }
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Default, PartialEq, FromPrimitive, ToPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RoutingActivationCode {
     #[default]
     DeniedUnknownSourceAddress=0x0,
     DeniedNoSocketAvailable=0x1,
     DeniedDifferentSA = 0x2,
     DeniedSAInUse = 0x3,
+    DeniedMissingAuthentication = 0x4,
+    DeniedRejectedConfirmation = 0x5,
     DeniedActivationTypeUnsupported = 0x6,
-    RoutingActivated = 0x10
-
+    DeniedRequiresTls = 0x7,
+    /*0x8 - 0xF Reserved by 13400*/
+    RoutingActivated = 0x10,
+    RoutingWillBeActivatedConfirmationRequired = 0x11,
 }
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RoutingActivationResponse {
-    client_logical_address: u16,
-    entity_logical_address: u16,
-    routing_activation_response_code: RoutingActivationCode,
+    pub client_logical_address: u16,
+    pub entity_logical_address: u16,
+    pub routing_activation_response_code: RoutingActivationCode,
     reserved_doc: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
     reserved_vm: Option<u32>
 }
 impl RoutingActivationResponse {
@@ -73,20 +99,87 @@ impl RoutingActivationResponse {
 impl Message for RoutingActivationResponse  {
     fn deserialize(&mut self,payload: &[u8]) -> Result<(), NackCode> {
         let header = DoIPHeader::from_buffer(payload)?;
-        if ![9,13].contains(&header.payload_length ) {
+        if ![9,13].contains(&header.payload_length )
+        || payload.len() < DoIPHeader::length() + header.payload_length as usize {
             return Err(NackCode::InvalidPayloadLength);
         }
+        let payload = &payload[DoIPHeader::length()..];
         self.client_logical_address = BigEndian::read_u16(&payload[0..2]);
         self.entity_logical_address = BigEndian::read_u16(&payload[2..4]);
-        self.routing_activation_response_code = payload[4];
+        self.routing_activation_response_code =
+            num::FromPrimitive::from_u8(payload[4]).ok_or(NackCode::IncorrectPattern)?;
         self.reserved_doc = BigEndian::read_u32(&payload[5..9]);
-        if payload.len() == 13 {
-            self.reserved_vm = Some(BigEndian::read_u32(&payload[7..13]));
+        if header.payload_length == 13 {
+            self.reserved_vm = Some(BigEndian::read_u32(&payload[9..13]));
         }
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        let payload_length = if self.reserved_vm.is_some() { 13 } else { 9 };
+        let mut buf = DoIPHeader::new(PayloadType::RoutingActivationRes, payload_length).serialize();
+        buf.extend_from_slice(&self.client_logical_address.to_be_bytes());
+        buf.extend_from_slice(&self.entity_logical_address.to_be_bytes());
+        buf.push(num::ToPrimitive::to_u8(&self.routing_activation_response_code).unwrap());
+        buf.extend_from_slice(&self.reserved_doc.to_be_bytes());
+        if let Some(reserved_vm) = self.reserved_vm {
+            buf.extend_from_slice(&reserved_vm.to_be_bytes());
+        }
+        buf
+    }
+}
+
+impl fmt::Display for RoutingActivationRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (source, activation_type) = (self.source_address, self.activation_type);
+        write!(f, "RoutingActivationRequest SA 0x{:04X} type 0x{:02X}", source, activation_type)
+    }
+}
+impl fmt::Display for RoutingActivationResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RoutingActivationResponse tester 0x{:04X} entity 0x{:04X} {:?}",
+            self.client_logical_address, self.entity_logical_address, self.routing_activation_response_code
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_deserialize_routing_activation_request() {
+        let serialized = RoutingActivationRequest::new(0x0E80, 0).serialize();
+        let deserialized = RoutingActivationRequest::from_payload(&serialized).unwrap();
+        assert_eq!(deserialized.source_address, 0x0E80);
+        assert_eq!(deserialized.serialize(), serialized);
+    }
+    #[test]
+    fn serialize_deserialize_routing_activation_response() {
+        let serialized = RoutingActivationResponse::new(
+            0x0E80, 0x1000, RoutingActivationCode::RoutingActivated).serialize();
+        let deserialized = RoutingActivationResponse::from_payload(&serialized).unwrap();
+        assert_eq!(deserialized.routing_activation_response_code,
+                   RoutingActivationCode::RoutingActivated);
+        assert_eq!(deserialized.serialize(), serialized);
+    }
+    #[test]
+    fn encode_routing_activation_on_the_wire() {
+        let frame = [0x03, 0xFC, 0x00, 0x05, 0, 0, 0, 7, 0x0E, 0x80, 0x00, 0, 0, 0, 0];
+        assert_eq!(RoutingActivationRequest::new(0x0E80, 0).serialize(), frame);
+        let activated = RoutingActivationCode::RoutingActivated;
+        let response = RoutingActivationResponse::new(0x0E80, 0x1000, activated);
+        let frame = [0x03, 0xFC, 0x00, 0x06, 0, 0, 0, 9, 0x0E, 0x80, 0x10, 0x00, 0x10, 0, 0, 0, 0];
+        assert_eq!(response.serialize(), frame);
+        /* OEM specific field after the reserved one */
+        let frame = [
+            0x03, 0xFC, 0x00, 0x06, 0, 0, 0, 13, 0x0E, 0x80, 0x10, 0x00, 0x10, 0, 0, 0, 0,
+            1, 2, 3, 4,
+        ];
+        let deserialized = RoutingActivationResponse::from_payload(&frame).unwrap();
+        assert_eq!(deserialized.reserved_vm, Some(0x01020304));
+        assert_eq!(deserialized.serialize(), frame);
     }
 }
@@ -0,0 +1,66 @@
+use crate::message::hex::{decode, encode};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::Serializer;
+
+/* Byte buffers as contiguous uppercase hex strings, e.g. "22F190" */
+fn decode_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    decode(&text).map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&text), &"a hex string"))
+}
+
+/* Vec<u8> fields */
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(bytes))
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        decode_string(deserializer)
+    }
+}
+
+/* Fixed size arrays like EID and GID */
+pub mod array {
+    use super::*;
+
+    pub fn serialize<S, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&encode(bytes))
+    }
+    pub fn deserialize<'de, D, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bytes = decode_string(deserializer)?;
+        let length = bytes.len();
+        bytes.try_into().map_err(|_| de::Error::invalid_length(length, &format!("{} bytes", N).as_str()))
+    }
+}
+
+/* VINs are plain strings if they are printable ASCII, otherwise hex. Both are told apart by their
+ * length: 17 characters for the string, 34 for the hex form. */
+pub mod vin {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(vin: &[u8; 17], serializer: S) -> Result<S::Ok, S::Error> {
+        if vin.iter().all(|byte| byte.is_ascii_graphic()) {
+            serializer.serialize_str(std::str::from_utf8(vin).unwrap_or_default())
+        } else {
+            serializer.serialize_str(&encode(vin))
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 17], D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let bytes = match text.len() {
+            17 => Some(text.as_bytes().to_vec()),
+            34 => decode(&text).ok(),
+            _ => None,
+        };
+        bytes
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(&text), &"a 17 character VIN"))
+    }
+}
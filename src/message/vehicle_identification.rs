@@ -1,10 +1,13 @@
 use crate::message::header::NackCode;
 use crate::message::Message;
+use crate::message::dump::{EntityId, Vin};
+use std::fmt;
 use byteorder::{BigEndian, ByteOrder};
 
-use super::header::{DoIPHeader, PayloadType, ProtocolVersion};
+use super::header::{DoIPHeader, PayloadType};
 
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VehicleIdentificationRequest {}
 impl VehicleIdentificationRequest {
     pub fn new() -> Self{
@@ -31,19 +34,18 @@ impl Message for VehicleIdentificationRequest {
     }
 
     fn serialize(&self) -> Vec<u8> {
-        let mut header: DoIPHeader = Default::default();
-        header.payload_length = 0;
-        header.payload_type = PayloadType::VehicleIDReq;
-        header.protocol_version = ProtocolVersion::ISO13400_2019;
+        let header = DoIPHeader::new(PayloadType::VehicleIDReq, 0);
         header.serialize()
     }
 }
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VehicleIdentificationRequestEID {
+    #[cfg_attr(feature = "serde", serde(with = "crate::message::serde_hex::array"))]
     pub eid: [u8; 6],
 }
 impl VehicleIdentificationRequestEID {
-    fn new(eid: &[u8]) -> Self {
+    pub fn new(eid: &[u8]) -> Self {
         VehicleIdentificationRequestEID { eid: eid.try_into().unwrap() }
     }
 }
@@ -70,21 +72,20 @@ impl Message for VehicleIdentificationRequestEID {
     }
 
     fn serialize(&self) -> Vec<u8> {
-        let mut header: DoIPHeader = Default::default();
-        header.payload_length = 6;
-        header.payload_type = PayloadType::VehicleIDReqByEID;
-        header.protocol_version = ProtocolVersion::ISO13400_2019;
+        let header = DoIPHeader::new(PayloadType::VehicleIDReqByEID, 6);
         let mut head_buff = header.serialize();
         head_buff.extend_from_slice(&self.eid);
         head_buff
     }
 }
-#[derive(Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VehicleIdentificationRequestVIN {
+    #[cfg_attr(feature = "serde", serde(with = "crate::message::serde_hex::vin"))]
     pub vin: [u8; 17],
 }
 impl VehicleIdentificationRequestVIN {
-    fn new(vin: &[u8]) -> Self {
+    pub fn new(vin: &[u8]) -> Self {
         let mut result = VehicleIdentificationRequestVIN::default();
         result.vin.copy_from_slice(vin);
         result
@@ -110,10 +111,7 @@ impl Message for VehicleIdentificationRequestVIN {
     }
 
     fn serialize(&self) -> Vec<u8> {
-        let mut header: DoIPHeader = Default::default();
-        header.payload_length = 17;
-        header.payload_type = PayloadType::VehicleIDReqByVIN;
-        header.protocol_version = ProtocolVersion::ISO13400_2019;
+        let header = DoIPHeader::new(PayloadType::VehicleIDReqByVIN, 17);
         let mut head_buff = header.serialize();
         head_buff.extend_from_slice(&self.vin);
         head_buff
@@ -123,7 +121,8 @@ impl Message for VehicleIdentificationRequestVIN {
 * So any reserved value would crash in the deserialize
 * Maybe change the type to something like c_enum*/
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, ToPrimitive, FromPrimitive, Default)]
+#[derive(Copy, Clone, Debug, PartialEq, ToPrimitive, FromPrimitive, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FurtherAction {
     #[default]
     NoFurtherAction = 0x0,
@@ -131,7 +130,8 @@ pub enum FurtherAction {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive, Default)]
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive, ToPrimitive, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SyncStatus {
     #[default]
     Synchronized = 0x0,
@@ -139,13 +139,17 @@ pub enum SyncStatus {
 }
 
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VehicleIdentificationResponse {
+    #[cfg_attr(feature = "serde", serde(with = "crate::message::serde_hex::vin"))]
     pub vin: [u8; 17],
     pub logical_address: u16,
+    #[cfg_attr(feature = "serde", serde(with = "crate::message::serde_hex::array"))]
     pub eid: [u8; 6],
+    #[cfg_attr(feature = "serde", serde(with = "crate::message::serde_hex::array"))]
     pub gid: [u8; 6],
-    further_action_required: FurtherAction,
-    sync_status: Option<SyncStatus>,
+    pub further_action_required: FurtherAction,
+    pub sync_status: Option<SyncStatus>,
 }
 impl VehicleIdentificationResponse {
     pub fn from_payload(payload: &[u8]) -> Result<Self, NackCode> {
@@ -171,7 +175,7 @@ impl VehicleIdentificationResponse {
 }
 impl Message for VehicleIdentificationResponse {
     fn deserialize(&mut self, payload: &[u8]) -> Result<(), NackCode> {
-        let header = DoIPHeader::from_buffer(&payload)?;
+        let header = DoIPHeader::from_buffer(payload)?;
 
         if header.payload_type != PayloadType::VehicleIDRes {
             return Err(NackCode::UnknownPayloadType);
@@ -186,25 +190,25 @@ impl Message for VehicleIdentificationResponse {
         let payload = &payload[DoIPHeader::length()..];
         self.vin.copy_from_slice(&payload[0..17]);
         self.logical_address = BigEndian::read_u16(&payload[17..19]);
-        self.gid.copy_from_slice(&payload[19..25]);
-        self.eid.copy_from_slice(&payload[25..31]);
+        self.eid.copy_from_slice(&payload[19..25]);
+        self.gid.copy_from_slice(&payload[25..31]);
         self.further_action_required =
-            num::FromPrimitive::from_u8(payload[31]).unwrap();
-        if payload.len() > 32 {
-            self.sync_status = Some(num::FromPrimitive::from_u8(payload[32]).unwrap());
+            num::FromPrimitive::from_u8(payload[31]).ok_or(NackCode::IncorrectPattern)?;
+        if header.payload_length > 32 {
+            self.sync_status = Some(
+                num::FromPrimitive::from_u8(payload[32]).ok_or(NackCode::IncorrectPattern)?,
+            );
         }
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
         let mut conversion_buffer: [u8; 2] = [0; 2];
-        let mut header: DoIPHeader = Default::default();
-        header.payload_length = match self.sync_status {
+        let payload_length = match self.sync_status {
             Some(_) => 33,
             None => 32,
         };
-        header.payload_type = PayloadType::VehicleIDRes;
-        header.protocol_version = ProtocolVersion::ISO13400_2019;
+        let header = DoIPHeader::new(PayloadType::VehicleIDRes, payload_length);
         let mut buf = header.serialize();
         buf.extend_from_slice(&self.vin);
         BigEndian::write_u16(&mut conversion_buffer, self.logical_address);
@@ -219,10 +223,41 @@ impl Message for VehicleIdentificationResponse {
     }
 }
 
+impl fmt::Display for VehicleIdentificationRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VehicleIdentificationRequest")
+    }
+}
+impl fmt::Display for VehicleIdentificationRequestEID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VehicleIdentificationRequest EID {}", EntityId(&self.eid))
+    }
+}
+impl fmt::Display for VehicleIdentificationRequestVIN {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VehicleIdentificationRequest VIN {}", Vin(&self.vin))
+    }
+}
+impl fmt::Display for VehicleIdentificationResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "VehicleIdentificationResponse VIN {} LA 0x{:04X} EID {} GID {} {:?}",
+            Vin(&self.vin),
+            self.logical_address,
+            EntityId(&self.eid),
+            EntityId(&self.gid),
+            self.further_action_required
+        )?;
+        if let Some(sync_status) = self.sync_status {
+            write!(f, " {:?}", sync_status)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::result;
-
     use super::*;
     #[test]
     fn deserialize_vehicle_id_request_vin_invalid_payload_type() {
@@ -374,4 +409,23 @@ mod tests {
         assert!(deserialized.deserialize(&serialized).is_ok());
         assert!(deserialized.serialize() == serialized);
     }
+    #[test]
+    fn encode_eid_before_gid_on_the_wire() {
+        let response = VehicleIdentificationResponse::new(
+            b"WVWZZZ1JZXW000001",
+            0x1000,
+            &[0xE1; 6],
+            &[0x61; 6],
+            FurtherAction::NoFurtherAction,
+        );
+        let mut frame = vec![0x03, 0xFC, 0x00, 0x04, 0, 0, 0, 32];
+        frame.extend_from_slice(b"WVWZZZ1JZXW000001");
+        frame.extend_from_slice(&[0x10, 0x00]);
+        frame.extend_from_slice(&[0xE1; 6]);
+        frame.extend_from_slice(&[0x61; 6]);
+        frame.push(0x00);
+        assert_eq!(response.serialize(), frame);
+        let deserialized = VehicleIdentificationResponse::from_payload(&frame).unwrap();
+        assert_eq!((deserialized.eid, deserialized.gid), ([0xE1; 6], [0x61; 6]));
+    }
 }
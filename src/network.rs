@@ -0,0 +1,90 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+};
+
+/* Index of the named network interface, e.g. as scope of IPv6 link-local addresses */
+#[cfg(unix)]
+pub(crate) fn interface_index(name: &str) -> io::Result<u32> {
+    let c_name = std::ffi::CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "interface name contains NUL"))?;
+    /* if_nametoindex only reads the NUL terminated name */
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown interface {}", name))),
+        index => Ok(index),
+    }
+}
+#[cfg(not(unix))]
+pub(crate) fn interface_index(name: &str) -> io::Result<u32> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("cannot look up interface {}", name)))
+}
+
+/* Restricts the socket to traffic over the named interface */
+fn bind_to_interface(socket: &Socket, name: &str) -> io::Result<()> {
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    return socket.bind_device(Some(name.as_bytes()));
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    {
+        let _ = socket;
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("cannot bind to interface {}", name)))
+    }
+}
+
+/* Listens on the given address, or on :: for IPv6 and IPv4 peers alike (0.0.0.0 on hosts without IPv6) */
+pub(crate) fn tcp_listener(
+    address: Option<IpAddr>,
+    port: u16,
+    interface: Option<&str>,
+) -> io::Result<TcpListener> {
+    let listen = |address: IpAddr, dual_stack: bool| -> io::Result<TcpListener> {
+        let address = SocketAddr::new(address, port);
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+        if dual_stack {
+            socket.set_only_v6(false)?;
+        }
+        socket.set_reuse_address(true)?;
+        if let Some(name) = interface {
+            bind_to_interface(&socket, name)?;
+        }
+        socket.bind(&address.into())?;
+        socket.listen(128)?;
+        Ok(socket.into())
+    };
+    match address {
+        Some(address) => listen(address, false),
+        None => listen(Ipv6Addr::UNSPECIFIED.into(), true)
+            .or_else(|_| listen(Ipv4Addr::UNSPECIFIED.into(), false)),
+    }
+}
+
+/* UDP socket for a single IP version, so IPv4 and IPv6 sockets can share a port */
+pub(crate) fn udp_socket(address: SocketAddr, interface: Option<&str>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if let Some(name) = interface {
+        bind_to_interface(&socket, name)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+/* TCP connection from the given local address, if any, through the named interface, if any */
+pub(crate) fn tcp_connect(
+    address: SocketAddr,
+    local_address: Option<SocketAddr>,
+    interface: Option<&str>,
+) -> io::Result<std::net::TcpStream> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(name) = interface {
+        bind_to_interface(&socket, name)?;
+    }
+    if let Some(local_address) = local_address {
+        socket.set_reuse_address(true)?;
+        socket.bind(&local_address.into())?;
+    }
+    socket.connect(&address.into())?;
+    Ok(socket.into())
+}
@@ -0,0 +1,862 @@
+use crate::message::{decoder::FrameDecoder, header::NackCode, message_factory, MessageVariant};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt, fs,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/* DoIP over TCP/UDP and DoIP over TLS */
+pub const DOIP_PORTS: [u16; 2] = [13200, 3496];
+
+const PCAP_MAGIC_MICROS: u32 = 0xA1B2C3D4;
+const PCAP_MAGIC_NANOS: u32 = 0xA1B23C4D;
+const PCAPNG_SECTION_HEADER: [u8; 4] = [0x0A, 0x0D, 0x0D, 0x0A];
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x00000003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x00000006;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+const MAX_RECORD_LENGTH: usize = 16 * 1024 * 1024;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: [u16; 2] = [0x8100, 0x88A8];
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/* DoIP message found in a capture, the timestamp is relative to the Unix epoch */
+pub struct CapturedMessage {
+    pub timestamp: Duration,
+    pub transport: Transport,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub message: MessageVariant,
+}
+
+#[derive(Debug)]
+pub enum PcapError {
+    Io(io::Error),
+    Format(String),
+    /* DoIP traffic which could not be decoded; reading goes on with the next packet */
+    Decode {
+        timestamp: Duration,
+        source: SocketAddr,
+        destination: SocketAddr,
+        code: NackCode,
+    },
+}
+impl fmt::Display for PcapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PcapError::Io(err) => write!(f, "I/O error: {}", err),
+            PcapError::Format(reason) => write!(f, "invalid capture: {}", reason),
+            PcapError::Decode { source, destination, code, .. } => {
+                write!(f, "undecodable DoIP traffic {} -> {}: {:?}", source, destination, code)
+            }
+        }
+    }
+}
+impl std::error::Error for PcapError {}
+impl From<io::Error> for PcapError {
+    fn from(err: io::Error) -> Self {
+        PcapError::Io(err)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Interface {
+    link_type: u32,
+    /* Timestamp units per second */
+    resolution: u64,
+}
+
+enum Format {
+    Pcap { big_endian: bool, interface: Interface },
+    PcapNg { big_endian: bool, interfaces: Vec<Interface> },
+}
+
+struct Packet {
+    timestamp: Duration,
+    link_type: u32,
+    data: Vec<u8>,
+}
+
+/* Reassembly state of one direction of a TCP connection */
+#[derive(Default)]
+struct TcpStreamState {
+    next_seq: Option<u32>,
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    decoder: FrameDecoder,
+    broken: bool,
+}
+impl TcpStreamState {
+    /* Places the segment at its sequence number and returns the bytes which became contiguous */
+    fn push_segment(&mut self, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut contiguous = Vec::new();
+        if payload.is_empty() {
+            return contiguous;
+        }
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        if (seq.wrapping_sub(next_seq) as i32) > 0 {
+            self.out_of_order.insert(seq, payload.to_vec());
+            return contiguous;
+        }
+        self.append(seq, payload, &mut contiguous);
+        while let Some(seq) = self.out_of_order.keys().copied().find(|seq| {
+            self.next_seq.is_some_and(|next_seq| (seq.wrapping_sub(next_seq) as i32) <= 0)
+        }) {
+            let payload = self.out_of_order.remove(&seq).unwrap_or_default();
+            self.append(seq, &payload, &mut contiguous);
+        }
+        contiguous
+    }
+    fn append(&mut self, seq: u32, payload: &[u8], contiguous: &mut Vec<u8>) {
+        let next_seq = self.next_seq.unwrap_or(seq);
+        /* Retransmitted bytes were delivered already */
+        let overlap = next_seq.wrapping_sub(seq) as usize;
+        if overlap < payload.len() {
+            contiguous.extend_from_slice(&payload[overlap..]);
+            self.next_seq = Some(seq.wrapping_add(payload.len() as u32));
+        }
+    }
+}
+
+/* Reads the DoIP messages of a pcap or pcapng capture with Ethernet, Linux cooked, loopback or
+ * raw IP link layer. TCP streams are reassembled, traffic on other ports than DOIP_PORTS is
+ * skipped. Encrypted DoIP over TLS cannot be decoded and is reported once per connection. */
+pub struct PcapReader<R: Read> {
+    reader: R,
+    format: Format,
+    ports: Vec<u16>,
+    streams: HashMap<(SocketAddr, SocketAddr), TcpStreamState>,
+    ready: VecDeque<Result<CapturedMessage, PcapError>>,
+    finished: bool,
+}
+impl PcapReader<BufReader<fs::File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PcapError> {
+        PcapReader::new(BufReader::new(fs::File::open(path)?))
+    }
+}
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, PcapError> {
+        let magic: [u8; 4] = read_array(&mut reader)?;
+        let format = if magic == PCAPNG_SECTION_HEADER {
+            read_section_header(&mut reader)?
+        } else {
+            let (big_endian, magic) = match u32::from_le_bytes(magic) {
+                PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS => (false, u32::from_le_bytes(magic)),
+                _ => (true, u32::from_be_bytes(magic)),
+            };
+            let resolution = match magic {
+                PCAP_MAGIC_MICROS => 1_000_000,
+                PCAP_MAGIC_NANOS => 1_000_000_000,
+                _ => return Err(PcapError::Format("neither a pcap nor a pcapng file".to_string())),
+            };
+            let header: [u8; 20] = read_array(&mut reader)?;
+            /* The upper bits of the link type carry FCS information */
+            let link_type = read_u32(&header, 16, big_endian) & 0x0FFF_FFFF;
+            Format::Pcap { big_endian, interface: Interface { link_type, resolution } }
+        };
+        Ok(PcapReader {
+            reader,
+            format,
+            ports: DOIP_PORTS.to_vec(),
+            streams: HashMap::new(),
+            ready: VecDeque::new(),
+            finished: false,
+        })
+    }
+    /* TCP/UDP ports carrying DoIP, DOIP_PORTS by default */
+    pub fn set_ports(&mut self, ports: &[u16]) -> &mut Self {
+        self.ports = ports.to_vec();
+        self
+    }
+    /* Returns the next DoIP message, None at the end of the capture */
+    pub fn next_message(&mut self) -> Result<Option<CapturedMessage>, PcapError> {
+        loop {
+            if let Some(result) = self.ready.pop_front() {
+                return result.map(Some);
+            }
+            if self.finished {
+                return Ok(None);
+            }
+            match self.next_packet() {
+                Ok(Some(packet)) => self.process_packet(&packet),
+                Ok(None) => self.finished = true,
+                Err(err) => {
+                    self.finished = true;
+                    return Err(err);
+                }
+            }
+        }
+    }
+    fn next_packet(&mut self) -> Result<Option<Packet>, PcapError> {
+        loop {
+            let Some(start) = read_array_or_eof::<_, 4>(&mut self.reader)? else {
+                return Ok(None);
+            };
+            match &mut self.format {
+                Format::Pcap { big_endian, interface } => {
+                    let rest: [u8; 12] = read_array(&mut self.reader)?;
+                    let seconds = read_u32(&start, 0, *big_endian);
+                    let fraction = read_u32(&rest, 0, *big_endian);
+                    let length = read_u32(&rest, 4, *big_endian) as usize;
+                    if length > MAX_RECORD_LENGTH {
+                        return Err(PcapError::Format(format!("packet of {} bytes", length)));
+                    }
+                    let mut data = vec![0; length];
+                    self.reader.read_exact(&mut data)?;
+                    let timestamp = Duration::from_secs(seconds.into())
+                        + units_to_duration(fraction.into(), interface.resolution);
+                    return Ok(Some(Packet { timestamp, link_type: interface.link_type, data }));
+                }
+                Format::PcapNg { big_endian, interfaces } => {
+                    if start == PCAPNG_SECTION_HEADER {
+                        self.format = read_section_header(&mut self.reader)?;
+                        continue;
+                    }
+                    let block_type = read_u32(&start, 0, *big_endian);
+                    let body = read_block_body(&mut self.reader, *big_endian)?;
+                    match block_type {
+                        PCAPNG_INTERFACE_DESCRIPTION => interfaces.push(parse_interface(&body, *big_endian)?),
+                        PCAPNG_ENHANCED_PACKET if body.len() >= 20 => {
+                            let interface = interface_at(interfaces, read_u32(&body, 0, *big_endian))?;
+                            let units = (u64::from(read_u32(&body, 4, *big_endian)) << 32)
+                                | u64::from(read_u32(&body, 8, *big_endian));
+                            let length = read_u32(&body, 12, *big_endian) as usize;
+                            let data = body.get(20..20 + length).ok_or_else(|| {
+                                PcapError::Format("truncated enhanced packet block".to_string())
+                            })?;
+                            return Ok(Some(Packet {
+                                timestamp: units_to_duration(units, interface.resolution),
+                                link_type: interface.link_type,
+                                data: data.to_vec(),
+                            }));
+                        }
+                        PCAPNG_SIMPLE_PACKET if body.len() >= 4 => {
+                            let interface = interface_at(interfaces, 0)?;
+                            let length = (read_u32(&body, 0, *big_endian) as usize).min(body.len() - 4);
+                            return Ok(Some(Packet {
+                                timestamp: Duration::ZERO,
+                                link_type: interface.link_type,
+                                data: body[4..4 + length].to_vec(),
+                            }));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+    fn process_packet(&mut self, packet: &Packet) {
+        let Some((source, destination, protocol, segment)) =
+            network_layer(packet.link_type, &packet.data).and_then(transport_layer)
+        else {
+            return;
+        };
+        match protocol {
+            IP_PROTOCOL_TCP if segment.len() >= 20 => {
+                let source = SocketAddr::new(source, u16::from_be_bytes([segment[0], segment[1]]));
+                let destination = SocketAddr::new(destination, u16::from_be_bytes([segment[2], segment[3]]));
+                if !self.ports.contains(&source.port()) && !self.ports.contains(&destination.port()) {
+                    return;
+                }
+                let mut seq = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+                let header_length = usize::from(segment[12] >> 4) * 4;
+                let flags = segment[13];
+                let payload = segment.get(header_length..).unwrap_or_default();
+                let key = (source, destination);
+                let stream = self.streams.entry(key).or_default();
+                if flags & TCP_SYN != 0 {
+                    *stream = TcpStreamState::default();
+                    seq = seq.wrapping_add(1);
+                    stream.next_seq = Some(seq);
+                }
+                let bytes = stream.push_segment(seq, payload);
+                if !stream.broken && !bytes.is_empty() {
+                    stream.decoder.push(&bytes);
+                    let meta = (packet.timestamp, Transport::Tcp, source, destination);
+                    stream.broken = !decode_frames(&mut stream.decoder, meta, &mut self.ready);
+                }
+                if flags & (TCP_FIN | TCP_RST) != 0 {
+                    self.streams.remove(&key);
+                }
+            }
+            IP_PROTOCOL_UDP if segment.len() >= 8 => {
+                let source = SocketAddr::new(source, u16::from_be_bytes([segment[0], segment[1]]));
+                let destination = SocketAddr::new(destination, u16::from_be_bytes([segment[2], segment[3]]));
+                if !self.ports.contains(&source.port()) && !self.ports.contains(&destination.port()) {
+                    return;
+                }
+                let length = usize::from(u16::from_be_bytes([segment[4], segment[5]]));
+                let length = length.clamp(8, segment.len());
+                let mut decoder = FrameDecoder::new();
+                decoder.push(&segment[8..length]);
+                let meta = (packet.timestamp, Transport::Udp, source, destination);
+                decode_frames(&mut decoder, meta, &mut self.ready);
+            }
+            _ => (),
+        }
+    }
+}
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = Result<CapturedMessage, PcapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_message().transpose()
+    }
+}
+
+/* Decodes all complete frames, returns false once the stream cannot be decoded any more */
+fn decode_frames(
+    decoder: &mut FrameDecoder,
+    (timestamp, transport, source, destination): (Duration, Transport, SocketAddr, SocketAddr),
+    ready: &mut VecDeque<Result<CapturedMessage, PcapError>>,
+) -> bool {
+    let decode_error = |code| PcapError::Decode { timestamp, source, destination, code };
+    loop {
+        match decoder.next_frame() {
+            Ok(Some(frame)) => ready.push_back(
+                message_factory(&frame)
+                    .map(|message| CapturedMessage { timestamp, transport, source, destination, message })
+                    .map_err(decode_error),
+            ),
+            Ok(None) => return true,
+            Err(code) => {
+                ready.push_back(Err(decode_error(code)));
+                return false;
+            }
+        }
+    }
+}
+
+fn read_section_header<R: Read>(reader: &mut R) -> Result<Format, PcapError> {
+    let header: [u8; 8] = read_array(reader)?;
+    let big_endian = match u32::from_le_bytes([header[4], header[5], header[6], header[7]]) {
+        PCAPNG_BYTE_ORDER_MAGIC => false,
+        _ if read_u32(&header, 4, true) == PCAPNG_BYTE_ORDER_MAGIC => true,
+        _ => return Err(PcapError::Format("invalid pcapng byte order magic".to_string())),
+    };
+    let length = read_u32(&header, 0, big_endian) as usize;
+    if !(28..=MAX_RECORD_LENGTH).contains(&length) {
+        return Err(PcapError::Format(format!("section header block of {} bytes", length)));
+    }
+    /* Version, section length and options are not needed */
+    io::copy(&mut reader.take((length - 12) as u64), &mut io::sink())?;
+    Ok(Format::PcapNg { big_endian, interfaces: Vec::new() })
+}
+
+fn read_block_body<R: Read>(reader: &mut R, big_endian: bool) -> Result<Vec<u8>, PcapError> {
+    let length: [u8; 4] = read_array(reader)?;
+    let length = read_u32(&length, 0, big_endian) as usize;
+    if !(12..=MAX_RECORD_LENGTH).contains(&length) || length % 4 != 0 {
+        return Err(PcapError::Format(format!("pcapng block of {} bytes", length)));
+    }
+    let mut body = vec![0; length - 8];
+    reader.read_exact(&mut body)?;
+    /* Trailing copy of the block length */
+    body.truncate(length - 12);
+    Ok(body)
+}
+
+fn parse_interface(body: &[u8], big_endian: bool) -> Result<Interface, PcapError> {
+    if body.len() < 8 {
+        return Err(PcapError::Format("truncated interface description block".to_string()));
+    }
+    let mut interface = Interface { link_type: read_u16(body, 0, big_endian).into(), resolution: 1_000_000 };
+    let mut offset = 8;
+    while offset + 4 <= body.len() {
+        let code = read_u16(body, offset, big_endian);
+        let length = usize::from(read_u16(body, offset + 2, big_endian));
+        if code == 0 {
+            break;
+        }
+        if let (PCAPNG_OPTION_TSRESOL, Some(value)) = (code, body.get(offset + 4)) {
+            interface.resolution = match value & 0x80 {
+                0 => 10u64.checked_pow((value & 0x7F).into()),
+                _ => 2u64.checked_pow((value & 0x7F).into()),
+            }
+            .filter(|resolution| *resolution > 0)
+            .ok_or_else(|| PcapError::Format(format!("timestamp resolution 0x{:02X}", value)))?;
+        }
+        offset += 4 + length.next_multiple_of(4);
+    }
+    Ok(interface)
+}
+
+fn interface_at(interfaces: &[Interface], index: u32) -> Result<Interface, PcapError> {
+    interfaces
+        .get(index as usize)
+        .copied()
+        .ok_or_else(|| PcapError::Format(format!("packet on undescribed interface {}", index)))
+}
+
+/* Strips the link layer header, returns the IP packet */
+fn network_layer(link_type: u32, data: &[u8]) -> Option<&[u8]> {
+    let is_ip = |ethertype| ethertype == ETHERTYPE_IPV4 || ethertype == ETHERTYPE_IPV6;
+    match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = read_be16(data, offset)?;
+            while ETHERTYPE_VLAN.contains(&ethertype) {
+                offset += 4;
+                ethertype = read_be16(data, offset)?;
+            }
+            data.get(offset + 2..).filter(|_| is_ip(ethertype))
+        }
+        LINKTYPE_LINUX_SLL => data.get(16..).filter(|_| read_be16(data, 14).is_some_and(is_ip)),
+        LINKTYPE_NULL => data.get(4..),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(data),
+        _ => None,
+    }
+}
+
+/* Returns source, destination, protocol and the TCP/UDP segment of an unfragmented IP packet */
+fn transport_layer(packet: &[u8]) -> Option<(IpAddr, IpAddr, u8, &[u8])> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header_length = usize::from(packet[0] & 0x0F) * 4;
+            let total_length = usize::from(read_be16(packet, 2)?).min(packet.len());
+            /* More fragments flag or fragment offset */
+            if read_be16(packet, 6)? & 0x3FFF != 0 {
+                return None;
+            }
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            let segment = packet.get(header_length..total_length)?;
+            Some((Ipv4Addr::from(source).into(), Ipv4Addr::from(destination).into(), packet[9], segment))
+        }
+        6 => {
+            let end = (40 + usize::from(read_be16(packet, 4)?)).min(packet.len());
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            let mut next_header = packet[6];
+            let mut offset = 40;
+            loop {
+                let length = match next_header {
+                    /* Hop-by-hop, routing and destination options */
+                    0 | 43 | 60 => (usize::from(*packet.get(offset + 1)?) + 1) * 8,
+                    /* Authentication header */
+                    51 => (usize::from(*packet.get(offset + 1)?) + 2) * 4,
+                    _ => break,
+                };
+                next_header = *packet.get(offset)?;
+                offset += length;
+            }
+            let segment = packet.get(offset..end)?;
+            Some((Ipv6Addr::from(source).into(), Ipv6Addr::from(destination).into(), next_header, segment))
+        }
+        _ => None,
+    }
+}
+
+/* Writes classic pcap captures (Ethernet, microsecond timestamps) of synthetic TCP/UDP packets */
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    /* Next sequence number of every TCP flow */
+    flows: HashMap<(SocketAddr, SocketAddr), u32>,
+}
+impl<W: Write> PcapWriter<W> {
+    const SNAP_LENGTH: u32 = 65535;
+    const SEGMENT_SIZE: usize = 1460;
+    const INITIAL_SEQUENCE: u32 = 1;
+
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&PCAP_MAGIC_MICROS.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        writer.write_all(&[0; 8])?;
+        writer.write_all(&PcapWriter::<W>::SNAP_LENGTH.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        Ok(PcapWriter { writer, flows: HashMap::new() })
+    }
+    /* Writes the DoIP frame as it was sent from source to destination */
+    pub fn write_frame(
+        &mut self,
+        timestamp: Duration,
+        transport: Transport,
+        source: SocketAddr,
+        destination: SocketAddr,
+        frame: &[u8],
+    ) -> io::Result<()> {
+        match transport {
+            Transport::Tcp => self.write_tcp(timestamp, source, destination, frame),
+            Transport::Udp => self.write_udp(timestamp, source, destination, frame),
+        }
+    }
+    /* Writes the stream data as segments of the flow, the sequence numbers continue per flow */
+    pub fn write_tcp(
+        &mut self,
+        timestamp: Duration,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<()> {
+        for chunk in data.chunks(PcapWriter::<W>::SEGMENT_SIZE) {
+            let seq = *self.flows.entry((source, destination)).or_insert(PcapWriter::<W>::INITIAL_SEQUENCE);
+            let ack = self.flows.get(&(destination, source)).copied().unwrap_or_default();
+            let mut segment = Vec::with_capacity(20 + chunk.len());
+            segment.extend_from_slice(&source.port().to_be_bytes());
+            segment.extend_from_slice(&destination.port().to_be_bytes());
+            segment.extend_from_slice(&seq.to_be_bytes());
+            segment.extend_from_slice(&ack.to_be_bytes());
+            segment.extend_from_slice(&[0x50, TCP_PSH | TCP_ACK, 0xFF, 0xFF, 0, 0, 0, 0]);
+            segment.extend_from_slice(chunk);
+            let checksum = transport_checksum(source.ip(), destination.ip(), IP_PROTOCOL_TCP, &segment);
+            segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+            self.write_packet(timestamp, source.ip(), destination.ip(), IP_PROTOCOL_TCP, &segment)?;
+            self.flows.insert((source, destination), seq.wrapping_add(chunk.len() as u32));
+        }
+        Ok(())
+    }
+    pub fn write_udp(
+        &mut self,
+        timestamp: Duration,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let length = u16::try_from(8 + data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "UDP datagram too long"))?;
+        let mut datagram = Vec::with_capacity(usize::from(length));
+        datagram.extend_from_slice(&source.port().to_be_bytes());
+        datagram.extend_from_slice(&destination.port().to_be_bytes());
+        datagram.extend_from_slice(&length.to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        let checksum = match transport_checksum(source.ip(), destination.ip(), IP_PROTOCOL_UDP, &datagram) {
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+        self.write_packet(timestamp, source.ip(), destination.ip(), IP_PROTOCOL_UDP, &datagram)
+    }
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+    fn write_packet(
+        &mut self,
+        timestamp: Duration,
+        source: IpAddr,
+        destination: IpAddr,
+        protocol: u8,
+        segment: &[u8],
+    ) -> io::Result<()> {
+        let mut packet = vec![0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01];
+        match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                let total_length = u16::try_from(20 + segment.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "IPv4 packet too long"))?;
+                packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+                let mut header = vec![0x45, 0];
+                header.extend_from_slice(&total_length.to_be_bytes());
+                /* Identification, don't fragment, TTL 64 */
+                header.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+                header.extend_from_slice(&source.octets());
+                header.extend_from_slice(&destination.octets());
+                let checksum = internet_checksum(&header);
+                header[10..12].copy_from_slice(&checksum.to_be_bytes());
+                packet.extend_from_slice(&header);
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                let payload_length = u16::try_from(segment.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "IPv6 packet too long"))?;
+                packet.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+                packet.extend_from_slice(&[0x60, 0, 0, 0]);
+                packet.extend_from_slice(&payload_length.to_be_bytes());
+                packet.extend_from_slice(&[protocol, 64]);
+                packet.extend_from_slice(&source.octets());
+                packet.extend_from_slice(&destination.octets());
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "source and destination use different IP versions",
+                ))
+            }
+        }
+        packet.extend_from_slice(segment);
+        let length = packet.len() as u32;
+        self.writer.write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.writer.write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&length.to_le_bytes())?;
+        self.writer.write_all(&length.to_le_bytes())?;
+        self.writer.write_all(&packet)
+    }
+}
+
+/* Shared pcap file the client or server write their traffic to while it happens */
+#[derive(Clone)]
+pub struct PcapCapture {
+    writer: Arc<Mutex<PcapWriter<Box<dyn Write + Send>>>>,
+}
+impl PcapCapture {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        Ok(PcapCapture { writer: Arc::new(Mutex::new(PcapWriter::new(writer)?)) })
+    }
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        PcapCapture::new(BufWriter::new(fs::File::create(path)?))
+    }
+    /* Capture errors are ignored, a full disk must not break the diagnostic communication */
+    pub fn record(&self, transport: Transport, source: SocketAddr, destination: SocketAddr, frame: &[u8]) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writer
+            .write_frame(timestamp, transport, source, destination, frame)
+            .and_then(|_| writer.flush());
+    }
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buff = [0; N];
+    reader.read_exact(&mut buff)?;
+    Ok(buff)
+}
+
+/* Like read_array, but None when the reader is at its end */
+fn read_array_or_eof<R: Read, const N: usize>(reader: &mut R) -> io::Result<Option<[u8; N]>> {
+    let mut buff = [0; N];
+    let mut filled = 0;
+    while filled < N {
+        match reader.read(&mut buff[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => filled += len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Some(buff))
+}
+
+fn read_u32(data: &[u8], offset: usize, big_endian: bool) -> u32 {
+    let bytes = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize, big_endian: bool) -> u16 {
+    let bytes = [data[offset], data[offset + 1]];
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_be16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn units_to_duration(units: u64, resolution: u64) -> Duration {
+    let nanos = u128::from(units % resolution) * 1_000_000_000 / u128::from(resolution);
+    Duration::new(units / resolution, nanos as u32)
+}
+
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or_default()])))
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn transport_checksum(source: IpAddr, destination: IpAddr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut pseudo_header = Vec::with_capacity(40 + segment.len());
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&destination.octets());
+            pseudo_header.extend_from_slice(&[0, protocol]);
+            pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        }
+        _ => {
+            pseudo_header.extend_from_slice(&ip_octets(source));
+            pseudo_header.extend_from_slice(&ip_octets(destination));
+            pseudo_header.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, protocol]);
+        }
+    }
+    pseudo_header.extend_from_slice(segment);
+    internet_checksum(&pseudo_header)
+}
+
+fn ip_octets(address: IpAddr) -> [u8; 16] {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped().octets(),
+        IpAddr::V6(address) => address.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        diag_message::DiagMessage, routing_activation::RoutingActivationRequest,
+        vehicle_identification::VehicleIdentificationRequest, Message,
+    };
+
+    fn tester() -> SocketAddr {
+        "192.168.0.2:50000".parse().unwrap()
+    }
+    fn entity() -> SocketAddr {
+        "192.168.0.10:13200".parse().unwrap()
+    }
+    fn read_all(capture: &[u8]) -> Vec<Result<CapturedMessage, PcapError>> {
+        PcapReader::new(capture).unwrap().collect()
+    }
+    /* Splits a classic pcap capture into its global header and packet records */
+    fn records(capture: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut records = Vec::new();
+        let mut offset = 24;
+        while offset < capture.len() {
+            let length = read_u32(capture, offset + 8, false) as usize;
+            records.push(capture[offset..offset + 16 + length].to_vec());
+            offset += 16 + length;
+        }
+        (capture[..24].to_vec(), records)
+    }
+
+    #[test]
+    fn write_and_read_tcp_and_udp() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let broadcast = "255.255.255.255:13200".parse().unwrap();
+        let second = Duration::from_secs(1_700_000_000);
+        let long_request = DiagMessage::new(0x0E80, 0x1001, &[0x36; 5000]);
+        writer
+            .write_udp(second, tester(), broadcast, &VehicleIdentificationRequest::default().serialize())
+            .unwrap();
+        writer
+            .write_tcp(second, tester(), entity(), &RoutingActivationRequest::new(0x0E80, 0).serialize())
+            .unwrap();
+        writer
+            .write_tcp(second + Duration::from_micros(1500), tester(), entity(), &long_request.serialize())
+            .unwrap();
+        let web_server = "192.168.0.10:80".parse().unwrap();
+        writer.write_tcp(second, "192.168.0.2:50001".parse().unwrap(), web_server, b"GET /").unwrap();
+        let capture = writer.into_inner();
+        assert_eq!(records(&capture).1.len(), 1 + 1 + 4 + 1);
+        let messages: Vec<CapturedMessage> = read_all(&capture).into_iter().map(Result::unwrap).collect();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].transport, Transport::Udp);
+        assert!(matches!(messages[0].message, MessageVariant::VehicleIDReqVariant(_)));
+        assert_eq!((messages[1].source, messages[1].destination), (tester(), entity()));
+        assert_eq!(messages[2].timestamp, second + Duration::from_micros(1500));
+        assert!(matches!(&messages[2].message, MessageVariant::DiagnoticMessageVariant(message)
+            if *message == long_request));
+    }
+    #[test]
+    fn reassemble_out_of_order_and_retransmitted_segments() {
+        let request = DiagMessage::new(0x0E80, 0x1001, &(0..4000).map(|i| i as u8).collect::<Vec<u8>>());
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_tcp(Duration::ZERO, tester(), entity(), &request.serialize()).unwrap();
+        let (header, mut segments) = records(&writer.into_inner());
+        segments.swap(1, 2);
+        segments.insert(3, segments[2].clone());
+        let capture = [header, segments.concat()].concat();
+        let messages = read_all(&capture);
+        assert_eq!(messages.len(), 1);
+        let message = &messages[0].as_ref().unwrap().message;
+        assert!(matches!(message, MessageVariant::DiagnoticMessageVariant(message) if *message == request));
+    }
+    #[test]
+    fn report_undecodable_stream_once() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let tls_entity = "192.168.0.10:3496".parse().unwrap();
+        let client_hello = [0x16, 0x03, 0x01, 0x02, 0, 0x01, 0, 0, 0];
+        let application_data = [0x17, 0x03, 0x03, 0, 0x20, 0, 0, 0, 0];
+        writer.write_tcp(Duration::ZERO, tester(), tls_entity, &client_hello).unwrap();
+        writer.write_tcp(Duration::ZERO, tester(), tls_entity, &application_data).unwrap();
+        let messages = read_all(&writer.into_inner());
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            messages[0],
+            Err(PcapError::Decode { code: NackCode::IncorrectPattern, destination, .. })
+                if destination == tls_entity
+        ));
+    }
+    #[test]
+    fn read_pcapng_with_ipv6() {
+        let source: SocketAddr = "[fd00::2]:50000".parse().unwrap();
+        let destination: SocketAddr = "[fd00::10]:13200".parse().unwrap();
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let request = RoutingActivationRequest::new(0x0E80, 0).serialize();
+        writer.write_tcp(Duration::ZERO, source, destination, &request).unwrap();
+        let packet = records(&writer.into_inner()).1[0][16..].to_vec();
+
+        let block = |block_type: u32, body: &[u8]| {
+            let length = (12 + body.len().next_multiple_of(4)) as u32;
+            let mut block = block_type.to_le_bytes().to_vec();
+            block.extend_from_slice(&length.to_le_bytes());
+            block.extend_from_slice(body);
+            block.resize(length as usize - 4, 0);
+            block.extend_from_slice(&length.to_le_bytes());
+            block
+        };
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        section.extend_from_slice(&[1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        /* Ethernet, nanosecond timestamps */
+        let interface = [1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0];
+        let nanos: u64 = 1_700_000_000_123_456_789;
+        let mut packet_body = 0u32.to_le_bytes().to_vec();
+        packet_body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+        packet_body.extend_from_slice(&(nanos as u32).to_le_bytes());
+        packet_body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        packet_body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        packet_body.extend_from_slice(&packet);
+        let capture = [
+            block(u32::from_le_bytes(PCAPNG_SECTION_HEADER), &section),
+            block(PCAPNG_INTERFACE_DESCRIPTION, &interface),
+            block(0x00000005, &[0; 8]),
+            block(PCAPNG_ENHANCED_PACKET, &packet_body),
+        ]
+        .concat();
+
+        let messages: Vec<CapturedMessage> = read_all(&capture).into_iter().map(Result::unwrap).collect();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].timestamp, Duration::new(1_700_000_000, 123_456_789));
+        assert_eq!((messages[0].source, messages[0].destination), (source, destination));
+        assert!(matches!(messages[0].message, MessageVariant::RoutingActivationRequestVariant(_)));
+    }
+    #[test]
+    fn reject_unknown_file() {
+        assert!(matches!(PcapReader::new(&b"GIF89a"[..]), Err(PcapError::Format(_))));
+        let (header, records) = records(&PcapWriter::new(Vec::new()).unwrap().into_inner());
+        assert!(records.is_empty());
+        let truncated = [header, vec![0; 10]].concat();
+        assert!(matches!(read_all(&truncated)[..], [Err(PcapError::Io(_))]));
+    }
+}
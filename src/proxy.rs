@@ -0,0 +1,430 @@
+use crate::message::dump::HexBytes;
+use crate::message::{decoder::FrameDecoder, message_factory, MessageVariant};
+use crate::network;
+use crate::pcap::{PcapCapture, Transport};
+use crate::recorder::{Direction, FrameRecorder};
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/* What the proxy does with a decoded message */
+#[derive(Debug)]
+pub enum ProxyAction {
+    Forward,
+    /* Forward another message instead, e.g. with rewritten addresses */
+    Replace(MessageVariant),
+    /* Forward after the delay, later frames of the same direction wait as well */
+    Delay(Duration),
+    Drop,
+}
+
+/* Called for every decoded message before it is forwarded, over TCP and UDP alike */
+pub trait ProxyHook: Send {
+    fn on_message(&mut self, direction: Direction, message: &MessageVariant) -> ProxyAction;
+}
+impl<F> ProxyHook for F
+where
+    F: FnMut(Direction, &MessageVariant) -> ProxyAction + Send,
+{
+    fn on_message(&mut self, direction: Direction, message: &MessageVariant) -> ProxyAction {
+        self(direction, message)
+    }
+}
+
+/* Man in the middle between testers and a DoIP entity. Every tester connection gets its own
+ * connection to the entity, frames are decoded, logged and passed through the hook. UDP
+ * vehicle identification requests are relayed to the entity and its responses back. */
+pub struct DoIPProxy {
+    entity: SocketAddr,
+    bind_address: Option<IpAddr>,
+    interface: Option<String>,
+    port: u16,
+    hook: Option<Arc<Mutex<dyn ProxyHook>>>,
+    log: Option<Arc<Mutex<dyn Write + Send>>>,
+    recorder: Option<FrameRecorder>,
+    capture: Option<PcapCapture>,
+}
+impl DoIPProxy {
+    pub const DOIP_PORT: u16 = 13200;
+    /* A_DoIP_Ctrl, how long responses to a relayed UDP request are passed back to its tester */
+    const UDP_RESPONSE_TIME: Duration = Duration::from_secs(2);
+
+    /* The entity is reached on the given address over TCP and on the same address over UDP */
+    pub fn new(entity: SocketAddr) -> Self {
+        DoIPProxy {
+            entity,
+            bind_address: None,
+            interface: None,
+            port: DoIPProxy::DOIP_PORT,
+            hook: None,
+            log: None,
+            recorder: None,
+            capture: None,
+        }
+    }
+    /* Listens for testers on this address only, on IPv4 and IPv6 by default */
+    pub fn set_bind_address(&mut self, address: IpAddr) -> &mut Self {
+        self.bind_address = Some(address);
+        self
+    }
+    /* Keeps the tester side on the named network interface */
+    pub fn set_interface(&mut self, name: &str) -> &mut Self {
+        self.interface = Some(name.to_string());
+        self
+    }
+    /* TCP and UDP port the testers connect to */
+    pub fn set_port(&mut self, port: u16) -> &mut Self {
+        self.port = port;
+        self
+    }
+    pub fn set_hook<H: ProxyHook + 'static>(&mut self, hook: H) -> &mut Self {
+        self.hook = Some(Arc::new(Mutex::new(hook)));
+        self
+    }
+    /* Writes one line per message, e.g. to stdout */
+    pub fn set_log<W: Write + Send + 'static>(&mut self, log: W) -> &mut Self {
+        self.log = Some(Arc::new(Mutex::new(log)));
+        self
+    }
+    pub fn set_recorder(&mut self, recorder: FrameRecorder) -> &mut Self {
+        self.recorder = Some(recorder);
+        self
+    }
+    pub fn set_capture(&mut self, capture: PcapCapture) -> &mut Self {
+        self.capture = Some(capture);
+        self
+    }
+    /* Listens on the configured address, interface and port for TCP and UDP, blocks while serving */
+    pub fn start(self) -> io::Result<()> {
+        let interface = self.interface.as_deref();
+        let listener = network::tcp_listener(self.bind_address, self.port, interface)?;
+        let mut sockets = Vec::new();
+        match self.bind_address {
+            Some(address) => sockets.push(self.udp_socket(address)?),
+            None => {
+                sockets.push(self.udp_socket(Ipv4Addr::UNSPECIFIED.into())?);
+                match self.udp_socket(Ipv6Addr::UNSPECIFIED.into()) {
+                    Ok(socket) => sockets.push(socket),
+                    Err(err) => self.log_line(format_args!("IPv6 identification unavailable: {}", err)),
+                }
+            }
+        }
+        self.run(listener, sockets)
+    }
+    pub fn serve(self, listener: TcpListener, socket: Option<UdpSocket>) -> io::Result<()> {
+        self.run(listener, socket.into_iter().collect())
+    }
+    fn udp_socket(&self, address: IpAddr) -> io::Result<UdpSocket> {
+        let socket = network::udp_socket(SocketAddr::new(address, self.port), self.interface.as_deref())?;
+        if address.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
+        Ok(socket)
+    }
+    fn run(self, listener: TcpListener, sockets: Vec<UdpSocket>) -> io::Result<()> {
+        let proxy = Arc::new(self);
+        for socket in sockets {
+            let relay = proxy.clone();
+            thread::spawn(move || relay.relay_udp(socket));
+        }
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let proxy = proxy.clone();
+            thread::spawn(move || proxy.handle_connection(stream));
+        }
+        Ok(())
+    }
+    fn handle_connection(self: Arc<Self>, tester: TcpStream) {
+        let entity = match TcpStream::connect(self.entity) {
+            Ok(entity) => entity,
+            Err(err) => {
+                self.log_line(format_args!("connection to {} failed: {}", self.entity, err));
+                return;
+            }
+        };
+        let _ = tester.set_nodelay(true);
+        let _ = entity.set_nodelay(true);
+        let (Ok(tester_reader), Ok(entity_reader)) = (tester.try_clone(), entity.try_clone()) else {
+            return;
+        };
+        let proxy = self.clone();
+        let upstream = thread::spawn(move || proxy.pump(Direction::TesterToEntity, tester_reader, entity));
+        self.pump(Direction::EntityToTester, entity_reader, tester);
+        let _ = upstream.join();
+    }
+    /* Forwards the frames read from one side to the other until either side closes */
+    fn pump(&self, direction: Direction, mut from: TcpStream, mut to: TcpStream) {
+        let (Ok(source), Ok(destination)) = (from.peer_addr(), to.peer_addr()) else {
+            return;
+        };
+        let mut decoder = FrameDecoder::new();
+        let mut buff: [u8; 4096] = [0; 4096];
+        'connection: loop {
+            match from.read(&mut buff) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => decoder.push(&buff[..len]),
+            }
+            loop {
+                let frame = match decoder.next_frame() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(code) => {
+                        let (from, to) = (source, destination);
+                        self.log_line(format_args!("{} -> {}: invalid frame {:?}", from, to, code));
+                        break 'connection;
+                    }
+                };
+                let Some(frame) = self.process(direction, Transport::Tcp, source, destination, frame) else {
+                    continue;
+                };
+                if to.write_all(&frame).is_err() {
+                    break 'connection;
+                }
+            }
+        }
+        /* Ends the opposite direction as well */
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    }
+    /* Every request is relayed from a socket of its own, so whatever arrives on that socket belongs
+     * to the tester of the request, also while several testers identify vehicles at a time */
+    fn relay_udp(self: Arc<Self>, socket: UdpSocket) {
+        let mut buff: [u8; 1024] = [0; 1024];
+        while let Ok((len, tester)) = socket.recv_from(&mut buff) {
+            let (frame, entity) = (buff[..len].to_vec(), self.entity);
+            let frame = self.process(Direction::TesterToEntity, Transport::Udp, tester, entity, frame);
+            let Some(frame) = frame else { continue };
+            let relayed = self.upstream_socket().and_then(|upstream| {
+                upstream.send_to(&frame, entity)?;
+                Ok((upstream, socket.try_clone()?))
+            });
+            match relayed {
+                Ok((upstream, downstream)) => {
+                    let proxy = self.clone();
+                    thread::spawn(move || proxy.relay_udp_responses(upstream, downstream, tester));
+                }
+                Err(err) => self.log_line(format_args!("{} -> {}: relay failed: {}", tester, entity, err)),
+            }
+        }
+    }
+    fn upstream_socket(&self) -> io::Result<UdpSocket> {
+        let address: IpAddr = match self.entity {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = network::udp_socket(SocketAddr::new(address, 0), None)?;
+        /* The entity address may be a broadcast address, to discover every entity behind the proxy */
+        if address.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
+        Ok(socket)
+    }
+    fn relay_udp_responses(&self, upstream: UdpSocket, downstream: UdpSocket, tester: SocketAddr) {
+        let deadline = Instant::now() + DoIPProxy::UDP_RESPONSE_TIME;
+        let mut buff: [u8; 1024] = [0; 1024];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || upstream.set_read_timeout(Some(remaining)).is_err() {
+                return;
+            }
+            let Ok((len, source)) = upstream.recv_from(&mut buff) else {
+                return;
+            };
+            let frame = buff[..len].to_vec();
+            let frame = self.process(Direction::EntityToTester, Transport::Udp, source, tester, frame);
+            let Some(frame) = frame else { continue };
+            let _ = downstream.send_to(&frame, tester);
+        }
+    }
+    /* Logs the frame and applies the hook, returns the frame to forward */
+    fn process(
+        &self,
+        direction: Direction,
+        transport: Transport,
+        source: SocketAddr,
+        destination: SocketAddr,
+        frame: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let frame = match message_factory(&frame) {
+            Ok(message) => {
+                self.log_line(format_args!("{} -> {}: {}", source, destination, message));
+                let action = match &self.hook {
+                    Some(hook) => hook.lock().unwrap().on_message(direction, &message),
+                    None => ProxyAction::Forward,
+                };
+                match action {
+                    ProxyAction::Forward => frame,
+                    ProxyAction::Replace(message) => {
+                        self.log_line(format_args!("{} -> {}: replaced by {}", source, destination, message));
+                        message.serialize()
+                    }
+                    ProxyAction::Delay(delay) => {
+                        thread::sleep(delay);
+                        frame
+                    }
+                    ProxyAction::Drop => {
+                        self.log_line(format_args!("{} -> {}: dropped", source, destination));
+                        return None;
+                    }
+                }
+            }
+            Err(code) => {
+                self.log_line(format_args!("{} -> {}: {:?} {}", source, destination, code, HexBytes(&frame)));
+                frame
+            }
+        };
+        if let Some(recorder) = &self.recorder {
+            recorder.record(direction, &frame);
+        }
+        if let Some(capture) = &self.capture {
+            capture.record(transport, source, destination, &frame);
+        }
+        Some(frame)
+    }
+    fn log_line(&self, line: std::fmt::Arguments) {
+        if let Some(log) = &self.log {
+            let _ = writeln!(log.lock().unwrap(), "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doip_client::{DoIPClient, IdentificationRequest};
+    use crate::message::diag_message::DiagMessage;
+    use crate::message::vehicle_identification::VehicleIdentificationResponse;
+    use crate::message::Message;
+    use crate::test_util::{FakeEntity, SharedBuffer};
+
+    fn spawn(proxy: DoIPProxy, udp: bool) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let socket = udp.then(|| UdpSocket::bind(address).unwrap());
+        thread::spawn(move || proxy.serve(listener, socket));
+        address
+    }
+
+    #[test]
+    fn forward_and_rewrite_diagnostic_messages() {
+        let entity = FakeEntity::spawn(0x1001, |request: &DiagMessage| {
+            vec![(Duration::ZERO, [&[request.user_data[0] + 0x40], &request.user_data[1..]].concat())]
+        });
+        let log = SharedBuffer::default();
+        let recorder = FrameRecorder::new();
+        let mut proxy = DoIPProxy::new(entity.address());
+        proxy.set_log(log.clone()).set_recorder(recorder.clone());
+        proxy.set_hook(|direction: Direction, message: &MessageVariant| match message {
+            MessageVariant::DiagnoticMessageVariant(request) if direction == Direction::TesterToEntity => {
+                match request.target_address {
+                    0x2001 => ProxyAction::Replace(MessageVariant::DiagnoticMessageVariant(DiagMessage::new(
+                        request.source_address,
+                        0x1001,
+                        &request.user_data,
+                    ))),
+                    _ if request.user_data == [0x3E, 0x80] => ProxyAction::Drop,
+                    _ => ProxyAction::Forward,
+                }
+            }
+            _ => ProxyAction::Forward,
+        });
+        let address = spawn(proxy, false);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(&DiagMessage::new(0x0E80, 0x1001, &[0x3E, 0x80]).serialize()).unwrap();
+        stream.write_all(&DiagMessage::new(0x0E80, 0x2001, &[0x22, 0xF1, 0x90]).serialize()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        let mut buff = [0; 256];
+        while frames.len() < 2 {
+            let len = stream.read(&mut buff).unwrap();
+            decoder.push(&buff[..len]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(message_factory(&frame).unwrap());
+            }
+        }
+        assert!(matches!(frames[0], MessageVariant::DiagnosticMessageAckVariant(_)));
+        let MessageVariant::DiagnoticMessageVariant(response) = &frames[1] else {
+            panic!("expected a diagnostic response")
+        };
+        assert_eq!(response.user_data, vec![0x62, 0xF1, 0x90]);
+        assert_eq!(entity.received(), vec![DiagMessage::new(0x0E80, 0x1001, &[0x22, 0xF1, 0x90])]);
+
+        let log = String::from_utf8(log.contents()).unwrap();
+        assert!(log.contains("DiagMessage 0x0E80 -> 0x2001 [3] 22 F1 90"));
+        assert!(log.contains("replaced by DiagMessage 0x0E80 -> 0x1001 [3] 22 F1 90"));
+        assert!(log.contains("dropped"));
+        /* Request, ACK and response */
+        assert_eq!(recorder.recording().frames.len(), 3);
+    }
+    #[test]
+    fn relay_vehicle_identification() {
+        let entity = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut proxy = DoIPProxy::new(entity.local_addr().unwrap());
+        proxy.set_hook(|_: Direction, message: &MessageVariant| match message {
+            MessageVariant::VehicleIDResVariant(response) => {
+                let frame = response.serialize();
+                let mut rewritten = VehicleIdentificationResponse::from_payload(&frame).unwrap();
+                rewritten.logical_address = 0x2000;
+                ProxyAction::Replace(MessageVariant::VehicleIDResVariant(rewritten))
+            }
+            _ => ProxyAction::Forward,
+        });
+        let address = spawn(proxy, true);
+        thread::spawn(move || {
+            let mut buff = [0; 64];
+            let (_, proxy) = entity.recv_from(&mut buff).unwrap();
+            let response =
+                VehicleIdentificationResponse::new(&[b'W'; 17], 0x1000, &[1; 6], &[1; 6], Default::default());
+            entity.send_to(&response.serialize(), proxy).unwrap();
+        });
+        let timeout = Duration::from_millis(300);
+        let entities = DoIPClient::discover(address, &IdentificationRequest::All, timeout).unwrap();
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].address, address);
+        assert_eq!(entities[0].response.logical_address, 0x2000);
+    }
+    #[test]
+    fn route_identification_responses_to_their_tester() {
+        let entity = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = spawn(DoIPProxy::new(entity.local_addr().unwrap()), true);
+        /* Both requests are outstanding before the entity answers them, in reverse order */
+        thread::spawn(move || {
+            let mut buff = [0; 64];
+            let mut requests = Vec::new();
+            while requests.len() < 2 {
+                let (len, proxy) = entity.recv_from(&mut buff).unwrap();
+                let request = message_factory(&buff[..len]).unwrap();
+                let MessageVariant::VehicleIDReqByEIDVariant(request) = request else {
+                    panic!("expected an identification request by EID")
+                };
+                requests.push((request.eid, proxy));
+            }
+            for (eid, proxy) in requests.into_iter().rev() {
+                let (vin, gid) = ([b'W'; 17], [1; 6]);
+                let status = Default::default();
+                let response = VehicleIdentificationResponse::new(&vin, 0x1000, &eid, &gid, status);
+                entity.send_to(&response.serialize(), proxy).unwrap();
+            }
+        });
+        let testers: Vec<_> = [[1; 6], [2; 6]]
+            .into_iter()
+            .map(|eid| {
+                thread::spawn(move || {
+                    let request = IdentificationRequest::ByEid(eid);
+                    (eid, DoIPClient::discover(address, &request, Duration::from_millis(500)).unwrap())
+                })
+            })
+            .collect();
+        for tester in testers {
+            let (eid, entities) = tester.join().unwrap();
+            assert_eq!(entities.len(), 1);
+            assert_eq!(entities[0].response.eid, eid);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::message::hex;
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    TesterToEntity,
+    EntityToTester,
+}
+impl Direction {
+    fn marker(self) -> char {
+        match self {
+            Direction::TesterToEntity => '>',
+            Direction::EntityToTester => '<',
+        }
+    }
+}
+
+/* Complete DoIP frame (header + payload) with its time since the start of the recording */
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedFrame {
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub frame: Vec<u8>,
+}
+
+/* Captured DoIP session. The text format has one frame per line:
+ * "<seconds> <'>' tester to entity | '<' entity to tester> <frame hex>", '#' starts a comment */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recording {
+    pub frames: Vec<RecordedFrame>,
+}
+impl Recording {
+    pub fn new() -> Self {
+        Recording::default()
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Recording::read_from(BufReader::new(fs::File::open(path)?))
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()
+    }
+    pub fn read_from<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut recording = Recording::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", index + 1, reason))
+            };
+            let mut fields = line.split_whitespace();
+            let (Some(timestamp), Some(direction), Some(frame), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid("expected timestamp, direction and frame"));
+            };
+            let timestamp = timestamp
+                .parse::<f64>()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .ok_or_else(|| invalid("invalid timestamp"))?;
+            let direction = match direction {
+                ">" => Direction::TesterToEntity,
+                "<" => Direction::EntityToTester,
+                _ => return Err(invalid("direction is neither '>' nor '<'")),
+            };
+            let frame = hex::decode(frame).map_err(|err| invalid(&format!("invalid frame: {}", err)))?;
+            recording.frames.push(RecordedFrame { timestamp, direction, frame });
+        }
+        Ok(recording)
+    }
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "{}", self)
+    }
+}
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for frame in &self.frames {
+            write!(f, "{:.6} {} ", frame.timestamp.as_secs_f64(), frame.direction.marker())?;
+            for byte in &frame.frame {
+                write!(f, "{:02X}", byte)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/* Shared handle collecting the frames of one or more connections into a recording */
+#[derive(Clone)]
+pub struct FrameRecorder {
+    start: Instant,
+    recording: Arc<Mutex<Recording>>,
+}
+impl Default for FrameRecorder {
+    fn default() -> Self {
+        FrameRecorder { start: Instant::now(), recording: Arc::new(Mutex::new(Recording::new())) }
+    }
+}
+impl FrameRecorder {
+    pub fn new() -> Self {
+        FrameRecorder::default()
+    }
+    pub fn record(&self, direction: Direction, frame: &[u8]) {
+        let timestamp = self.start.elapsed();
+        let mut recording = self.recording.lock().unwrap();
+        recording.frames.push(RecordedFrame { timestamp, direction, frame: frame.to_vec() });
+    }
+    /* Snapshot of the frames recorded so far */
+    pub fn recording(&self) -> Recording {
+        self.recording.lock().unwrap().clone()
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.recording().save(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recording_text_round_trip() {
+        let recorder = FrameRecorder::new();
+        let request = [0x02, 0xFD, 0x80, 0x01, 0, 0, 0, 0x06, 0x0E, 0x80, 0x10, 0x00, 0x3E, 0x00];
+        let ack = [0x02, 0xFD, 0x80, 0x02, 0, 0, 0, 0x05, 0x10, 0x00, 0x0E, 0x80, 0x00];
+        recorder.record(Direction::TesterToEntity, &request);
+        recorder.record(Direction::EntityToTester, &ack);
+        let recording = recorder.recording();
+        let mut text = Vec::new();
+        recording.write_to(&mut text).unwrap();
+        let parsed = Recording::read_from(&text[..]).unwrap();
+        assert_eq!(parsed.frames.len(), 2);
+        assert_eq!(parsed.frames[1].direction, Direction::EntityToTester);
+        assert_eq!(parsed.frames[1].frame, recording.frames[1].frame);
+        let drift = parsed.frames[1].timestamp.abs_diff(recording.frames[1].timestamp);
+        assert!(drift < Duration::from_micros(1));
+    }
+    #[test]
+    fn reject_invalid_recording() {
+        let text = "# captured on the bench\n0.5 > 02FD\n\n1.0 ? 02FD\n";
+        let err = Recording::read_from(text.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 4"));
+        assert!(Recording::read_from("0.5 > 02F\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use crate::doip_client::IdentificationRequest;
+use crate::message::{diag_message::DiagNackCode, header::NackCode, routing_activation::RoutingActivationCode};
+use std::{net::SocketAddr, sync::mpsc};
+
+/* Protocol activity of a DoIP server, peer being the tester's address */
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerEvent {
+    ConnectionOpened { peer: SocketAddr, tls: bool },
+    /* The source address is the one routing was activated for, if any */
+    ConnectionClosed { peer: SocketAddr, source_address: Option<u16> },
+    RoutingActivation {
+        peer: SocketAddr,
+        source_address: u16,
+        activation_type: u8,
+        code: RoutingActivationCode,
+    },
+    /* Alive check response received from the tester */
+    AliveCheck { peer: SocketAddr, source_address: u16 },
+    HeaderNack { peer: SocketAddr, code: NackCode },
+    /* Diagnostic message from the tester, passed to the diagnostic handler unless negatively acknowledged */
+    DiagnosticMessage {
+        peer: SocketAddr,
+        source_address: u16,
+        target_address: u16,
+        user_data: Vec<u8>,
+        nack: Option<DiagNackCode>,
+    },
+    /* Vehicle identification request, answered when it addresses this entity */
+    IdentificationRequest { peer: SocketAddr, request: IdentificationRequest, answered: bool },
+    AnnouncementSent { destination: SocketAddr },
+}
+
+/* Receives the events of a running server, called from the thread the activity happened on */
+pub trait ServerObserver: Send {
+    fn on_event(&mut self, event: &ServerEvent);
+}
+impl<F> ServerObserver for F
+where
+    F: FnMut(&ServerEvent) + Send,
+{
+    fn on_event(&mut self, event: &ServerEvent) {
+        self(event)
+    }
+}
+/* Forwards events to a channel, dropping them once the receiver is gone */
+impl ServerObserver for mpsc::Sender<ServerEvent> {
+    fn on_event(&mut self, event: &ServerEvent) {
+        let _ = self.send(event.clone());
+    }
+}
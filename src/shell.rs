@@ -0,0 +1,369 @@
+use crate::doip_client::SessionError;
+use crate::message::{dump::HexBytes, hex};
+use crate::uds::client::{UdsClient, UdsError};
+use crate::uds::keep_alive::KeepAliveConfig;
+use crate::uds::security::SeedKeyProvider;
+use crate::uds::{NegativeResponseCode, ServiceId, NEGATIVE_RESPONSE_SID, SUPPRESS_POSITIVE_RESPONSE};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+/* Service mnemonics, the arguments following them are appended as hex bytes: "rdbi F190" */
+const MNEMONICS: [(&str, ServiceId); 14] = [
+    ("dsc", ServiceId::DiagnosticSessionControl),
+    ("er", ServiceId::EcuReset),
+    ("cdtci", ServiceId::ClearDiagnosticInformation),
+    ("rdtci", ServiceId::ReadDtcInformation),
+    ("rdbi", ServiceId::ReadDataByIdentifier),
+    ("sa", ServiceId::SecurityAccess),
+    ("cc", ServiceId::CommunicationControl),
+    ("wdbi", ServiceId::WriteDataByIdentifier),
+    ("rc", ServiceId::RoutineControl),
+    ("rd", ServiceId::RequestDownload),
+    ("td", ServiceId::TransferData),
+    ("rte", ServiceId::RequestTransferExit),
+    ("tp", ServiceId::TesterPresent),
+    ("cdtcs", ServiceId::ControlDtcSetting),
+];
+
+/* Services with a sub-function whose positive response can be suppressed */
+const SUPPRESSIBLE: [ServiceId; 7] = [
+    ServiceId::DiagnosticSessionControl,
+    ServiceId::EcuReset,
+    ServiceId::SecurityAccess,
+    ServiceId::CommunicationControl,
+    ServiceId::RoutineControl,
+    ServiceId::TesterPresent,
+    ServiceId::ControlDtcSetting,
+];
+
+const HELP: &str = "\
+<hex bytes>             send a raw request, e.g. 22 F1 90
+<mnemonic> <hex>...     send a request, e.g. rdbi F190, dsc 03, sa 01
+                        mnemonics: dsc er cdtci rdtci rdbi sa cc wdbi rc rd td rte tp cdtcs
+keepalive on|off        TesterPresent in the background
+sleep <ms>              wait, e.g. in scripts
+source <file>           run the commands of a file
+history                 list previous commands, !<n> runs one again
+quit                    end the shell";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShellCommand {
+    Request(Vec<u8>),
+    /* Complete seed/key exchange for an odd level, if a key provider is set */
+    SecurityAccess(u8),
+    KeepAlive(bool),
+    Sleep(Duration),
+    Source(String),
+    History,
+    Recall(usize),
+    Help,
+    Quit,
+}
+impl ShellCommand {
+    /* None for empty lines and comments starting with '#' */
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut words = line.split_whitespace();
+        let Some(first) = words.next() else {
+            return Ok(None);
+        };
+        let args: Vec<&str> = words.collect();
+        let command = match (first.to_ascii_lowercase().as_str(), args.as_slice()) {
+            ("quit" | "exit", []) => ShellCommand::Quit,
+            ("help" | "?", []) => ShellCommand::Help,
+            ("history", []) => ShellCommand::History,
+            ("keepalive", ["on"]) => ShellCommand::KeepAlive(true),
+            ("keepalive", ["off"]) => ShellCommand::KeepAlive(false),
+            ("sleep", [millis]) => {
+                let millis = millis.parse().map_err(|_| format!("invalid duration: {}", millis))?;
+                ShellCommand::Sleep(Duration::from_millis(millis))
+            }
+            ("source", [path]) => ShellCommand::Source(path.to_string()),
+            (recall, []) if recall.starts_with('!') => {
+                let index = recall[1..].parse();
+                ShellCommand::Recall(index.map_err(|_| format!("invalid history entry: {}", recall))?)
+            }
+            (mnemonic, args) => match MNEMONICS.iter().find(|(name, _)| *name == mnemonic) {
+                Some((_, service)) => {
+                    let mut request = vec![*service as u8];
+                    request.extend(parse_hex(args)?);
+                    match request[..] {
+                        [0x27, level] if level % 2 == 1 => ShellCommand::SecurityAccess(level),
+                        _ => ShellCommand::Request(request),
+                    }
+                }
+                None => ShellCommand::Request(parse_hex(&[&[first], args].concat())?),
+            },
+        };
+        Ok(Some(command))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+/* Interactive UDS shell on top of a routed session to one ECU */
+pub struct Shell {
+    client: UdsClient,
+    key_provider: Option<Box<dyn SeedKeyProvider>>,
+    history: Vec<String>,
+    script_depth: usize,
+}
+impl Shell {
+    const MAX_SCRIPT_DEPTH: usize = 8;
+
+    pub fn new(client: UdsClient) -> Self {
+        Shell { client, key_provider: None, history: Vec::new(), script_depth: 0 }
+    }
+    /* Used by "sa <odd level>" to answer the seed, without one only the seed is requested */
+    pub fn set_key_provider<P: SeedKeyProvider + 'static>(&mut self, provider: P) -> &mut Self {
+        self.key_provider = Some(Box::new(provider));
+        self
+    }
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+    pub fn load_history<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        self.history.extend(text.lines().filter(|line| !line.trim().is_empty()).map(String::from));
+        Ok(())
+    }
+    pub fn save_history<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for line in &self.history {
+            writeln!(file, "{}", line)?;
+        }
+        file.flush()
+    }
+    /* Executes commands line by line until the input ends or "quit" */
+    pub fn run<R, W>(&mut self, input: R, output: &mut W, prompt: Option<&str>) -> io::Result<Flow>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        let mut lines = input.lines();
+        loop {
+            if let Some(prompt) = prompt {
+                write!(output, "{}", prompt)?;
+                output.flush()?;
+            }
+            let Some(line) = lines.next() else {
+                return Ok(Flow::Continue);
+            };
+            if self.execute(&line?, output)? == Flow::Quit {
+                return Ok(Flow::Quit);
+            }
+        }
+    }
+    pub fn execute<W: Write>(&mut self, line: &str, output: &mut W) -> io::Result<Flow> {
+        let command = match ShellCommand::parse(line) {
+            Ok(Some(ShellCommand::Recall(index))) => {
+                let recalled = index.checked_sub(1).and_then(|index| self.history.get(index)).cloned();
+                let Some(recalled) = recalled.filter(|recalled| !recalled.starts_with('!')) else {
+                    writeln!(output, "error: no history entry {}", index)?;
+                    return Ok(Flow::Continue);
+                };
+                writeln!(output, "{}", recalled)?;
+                return self.execute(&recalled, output);
+            }
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(Flow::Continue),
+            Err(err) => {
+                writeln!(output, "error: {}", err)?;
+                return Ok(Flow::Continue);
+            }
+        };
+        self.history.push(line.trim().to_string());
+        let result = match command {
+            ShellCommand::Request(request) => self.request(&request, output),
+            ShellCommand::SecurityAccess(level) => self.security_access(level, output),
+            ShellCommand::KeepAlive(enabled) => {
+                if enabled {
+                    self.client.set_keep_alive(Some(KeepAliveConfig::default())).start_keep_alive();
+                } else {
+                    self.client.set_keep_alive(None);
+                }
+                Ok(())
+            }
+            ShellCommand::Sleep(duration) => {
+                thread::sleep(duration);
+                Ok(())
+            }
+            ShellCommand::Source(path) => return self.source(&path, output),
+            ShellCommand::History => {
+                for (index, line) in self.history.iter().enumerate() {
+                    writeln!(output, "{:>4}  {}", index + 1, line)?;
+                }
+                Ok(())
+            }
+            ShellCommand::Help => {
+                writeln!(output, "{}", HELP)?;
+                Ok(())
+            }
+            ShellCommand::Recall(_) => Ok(()),
+            ShellCommand::Quit => return Ok(Flow::Quit),
+        };
+        match result {
+            Ok(()) => Ok(Flow::Continue),
+            /* Nothing more can be sent once the connection is gone */
+            Err(UdsError::Session(err @ (SessionError::ConnectionClosed | SessionError::Io(_)))) => {
+                writeln!(output, "error: {}", err)?;
+                Ok(Flow::Quit)
+            }
+            Err(err) => {
+                writeln!(output, "error: {}", err)?;
+                Ok(Flow::Continue)
+            }
+        }
+    }
+    fn request<W: Write>(&mut self, request: &[u8], output: &mut W) -> Result<(), UdsError> {
+        if is_suppressed(request) {
+            self.client.send(request)?;
+            let _ = writeln!(output, "> {} (positive response suppressed)", HexBytes(request));
+            return Ok(());
+        }
+        let response = match self.client.request(request) {
+            Ok(response) => response,
+            Err(UdsError::NegativeResponse { service, nrc }) => {
+                vec![NEGATIVE_RESPONSE_SID, service, nrc]
+            }
+            Err(err) => return Err(err),
+        };
+        let _ = writeln!(output, "< {}", describe_response(&response));
+        Ok(())
+    }
+    fn security_access<W: Write>(&mut self, level: u8, output: &mut W) -> Result<(), UdsError> {
+        match &self.key_provider {
+            Some(provider) => {
+                self.client.security_access(level, provider.as_ref())?;
+                let _ = writeln!(output, "security level 0x{:02X} unlocked", level);
+                Ok(())
+            }
+            None => self.request(&[ServiceId::SecurityAccess as u8, level], output),
+        }
+    }
+    fn source<W: Write>(&mut self, path: &str, output: &mut W) -> io::Result<Flow> {
+        if self.script_depth >= Shell::MAX_SCRIPT_DEPTH {
+            writeln!(output, "error: scripts nested too deeply")?;
+            return Ok(Flow::Continue);
+        }
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                writeln!(output, "error: {}: {}", path, err)?;
+                return Ok(Flow::Continue);
+            }
+        };
+        self.script_depth += 1;
+        let flow = self.run(BufReader::new(file), output, None);
+        self.script_depth -= 1;
+        flow
+    }
+}
+
+/* Hex dump of a response, with the NRC name of negative responses and ASCII of printable data */
+pub fn describe_response(response: &[u8]) -> String {
+    match response {
+        [NEGATIVE_RESPONSE_SID, _, nrc, ..] => {
+            let code: Option<NegativeResponseCode> = num::FromPrimitive::from_u8(*nrc);
+            match code {
+                Some(code) => format!("{} {:?}", HexBytes(response), code),
+                None => format!("{} (NRC 0x{:02X})", HexBytes(response), nrc),
+            }
+        }
+        /* Data record of ReadDataByIdentifier */
+        [0x62, _, _, data @ ..]
+            if !data.is_empty() && data.iter().all(|byte| byte.is_ascii_graphic() || *byte == b' ') =>
+        {
+            format!("{} \"{}\"", HexBytes(response), String::from_utf8_lossy(data))
+        }
+        _ => HexBytes(response).to_string(),
+    }
+}
+
+fn is_suppressed(request: &[u8]) -> bool {
+    match request {
+        [service, sub_function, ..] => {
+            sub_function & SUPPRESS_POSITIVE_RESPONSE != 0
+                && SUPPRESSIBLE.iter().any(|suppressible| *suppressible as u8 == *service)
+        }
+        _ => false,
+    }
+}
+
+/* Hex bytes given as words of any even length, e.g. ["F190", "01"] */
+fn parse_hex(words: &[&str]) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for word in words {
+        bytes.extend(hex::decode(word).map_err(|_| format!("invalid hex: {}", word))?);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doip_client::DoIPClientSession;
+    use crate::message::diag_message::DiagMessage;
+    use crate::test_util::FakeEntity;
+    use crate::uds::security::XorMaskProvider;
+
+    #[test]
+    fn parse_commands() {
+        let request = |bytes: &[u8]| Ok(Some(ShellCommand::Request(bytes.to_vec())));
+        assert_eq!(ShellCommand::parse("rdbi F190"), request(&[0x22, 0xF1, 0x90]));
+        assert_eq!(ShellCommand::parse("DSC 03  # extended"), request(&[0x10, 0x03]));
+        assert_eq!(ShellCommand::parse("22 F1 90"), request(&[0x22, 0xF1, 0x90]));
+        assert_eq!(ShellCommand::parse("sa 01"), Ok(Some(ShellCommand::SecurityAccess(0x01))));
+        assert_eq!(ShellCommand::parse("sa 02 1234"), request(&[0x27, 0x02, 0x12, 0x34]));
+        assert_eq!(ShellCommand::parse("keepalive on"), Ok(Some(ShellCommand::KeepAlive(true))));
+        assert_eq!(ShellCommand::parse("!2"), Ok(Some(ShellCommand::Recall(2))));
+        assert_eq!(ShellCommand::parse("  # comment"), Ok(None));
+        assert!(ShellCommand::parse("rdbi F19").is_err());
+        assert!(ShellCommand::parse("hello").is_err());
+        assert!(is_suppressed(&[0x3E, 0x80]));
+        assert!(!is_suppressed(&[0x22, 0x80, 0x01]));
+    }
+    #[test]
+    fn run_script_against_entity() {
+        let entity = FakeEntity::spawn(0x1001, |request: &DiagMessage| {
+            let response = match request.user_data.as_slice() {
+                [0x22, 0xF1, 0x90] => b"\x62\xF1\x90WDOIP".to_vec(),
+                [0x27, 0x01] => vec![0x67, 0x01, 0x12, 0x34],
+                [0x27, 0x02, 0xB8, 0x9E] => vec![0x67, 0x02],
+                [service, ..] => vec![0x7F, *service, 0x31],
+                [] => Vec::new(),
+            };
+            vec![(Duration::ZERO, response)]
+        });
+        let session = DoIPClientSession::connect(entity.address(), 0x0E80).unwrap();
+        let mut shell = Shell::new(UdsClient::new(session, 0x1001));
+        shell.set_key_provider(XorMaskProvider::new(&[0xAA]));
+        let script = "rdbi F190\n22 12 34\nsa 01\nbogus\n!1\nquit\nrdbi F190\n";
+        let mut output = Vec::new();
+        assert_eq!(shell.run(script.as_bytes(), &mut output, None).unwrap(), Flow::Quit);
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "< 62 F1 90 57 44 4F 49 50 \"WDOIP\"",
+                "< 7F 22 31 RequestOutOfRange",
+                "security level 0x01 unlocked",
+                "error: invalid hex: bogus",
+                "rdbi F190",
+                "< 62 F1 90 57 44 4F 49 50 \"WDOIP\"",
+            ]
+        );
+        assert_eq!(shell.history(), ["rdbi F190", "22 12 34", "sa 01", "rdbi F190", "quit"]);
+        assert_eq!(entity.received().len(), 5);
+    }
+}
@@ -0,0 +1,238 @@
+pub mod config;
+pub mod ecu;
+pub mod replay;
+
+use crate::clock::{Clock, SystemClock};
+use crate::doip_server::{DiagnosticHandler, DiagnosticResponse, DoIPServer, DoIPServerBuilder};
+use crate::message::entity_status::NodeType;
+use crate::simulator::{
+    config::{ConfigError, VehicleConfig},
+    ecu::SimulatedEcu,
+    replay::ReplayHandler,
+};
+use crate::uds::{parse_negative_response, NegativeResponseCode};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/* All ECUs of a simulated vehicle, reachable physically or through the functional address */
+pub struct VehicleSimulator {
+    functional_address: u16,
+    ecus: BTreeMap<u16, SimulatedEcu>,
+}
+impl VehicleSimulator {
+    pub fn new(config: &VehicleConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let mut ecus = BTreeMap::new();
+        for ecu in &config.ecus {
+            ecus.insert(ecu.logical_address, SimulatedEcu::from_config(ecu)?);
+        }
+        Ok(VehicleSimulator { functional_address: config.functional_address, ecus })
+    }
+    pub fn ecu(&self, logical_address: u16) -> Option<&SimulatedEcu> {
+        self.ecus.get(&logical_address)
+    }
+    /* Time source of the S3 and security access timers of every ECU */
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        for ecu in self.ecus.values_mut() {
+            ecu.set_clock(clock.clone());
+        }
+        self
+    }
+    /* Seeds the security access seed source of every ECU from the RNG */
+    pub fn set_rng<R: RngCore>(&mut self, mut rng: R) -> &mut Self {
+        for ecu in self.ecus.values_mut() {
+            ecu.set_rng(StdRng::seed_from_u64(rng.next_u64()));
+        }
+        self
+    }
+    /* Builds the DoIP entity serving every ECU of the vehicle */
+    pub fn build_server(config: &VehicleConfig) -> Result<DoIPServer, ConfigError> {
+        VehicleSimulator::build_server_with(config, Arc::new(SystemClock), StdRng::from_entropy())
+    }
+    /* Builds the DoIP entity of the vehicle with the entity and its ECUs on the clock, seeded from the RNG */
+    pub fn build_server_with<R: RngCore>(
+        config: &VehicleConfig,
+        clock: Arc<dyn Clock>,
+        mut rng: R,
+    ) -> Result<DoIPServer, ConfigError> {
+        let mut simulator = VehicleSimulator::new(config)?;
+        simulator.set_clock(clock.clone()).set_rng(&mut rng);
+        let mut builder = VehicleSimulator::entity_builder(config)?;
+        builder
+            .set_clock(clock)
+            .set_rng(StdRng::seed_from_u64(rng.next_u64()))
+            .set_diagnostic_handler(simulator);
+        Ok(builder.get_server())
+    }
+    /* Builds the DoIP entity of the vehicle answering with a recorded session instead of the ECU models */
+    pub fn build_replay_server(
+        config: &VehicleConfig,
+        replay: ReplayHandler,
+    ) -> Result<DoIPServer, ConfigError> {
+        config.validate()?;
+        let mut builder = VehicleSimulator::entity_builder(config)?;
+        builder.set_diagnostic_handler(replay);
+        Ok(builder.get_server())
+    }
+    fn entity_builder(config: &VehicleConfig) -> Result<DoIPServerBuilder, ConfigError> {
+        let node_type = if config.ecus.iter().all(|ecu| ecu.logical_address == config.logical_address) {
+            NodeType::Node
+        } else {
+            NodeType::Gateway
+        };
+        let mut builder = DoIPServerBuilder::new();
+        builder
+            .set_vin(&config.vin()?)
+            .set_eid(&config.eid()?)
+            .set_gid(&config.gid()?)
+            .set_logical_address(config.logical_address)
+            .set_node_type(node_type);
+        let network = &config.network;
+        if let Some(address) = network.bind_address {
+            builder.set_bind_address(address);
+        }
+        if let Some(interface) = &network.interface {
+            builder.set_interface(interface);
+        }
+        if let Some(port) = network.tcp_port {
+            builder.set_tcp_port(port);
+        }
+        if let Some(port) = network.udp_port {
+            builder.set_udp_port(port);
+        }
+        if let Some(destinations) = &network.announce {
+            builder.set_announcement_destinations(destinations);
+        }
+        Ok(builder)
+    }
+}
+impl DiagnosticHandler for VehicleSimulator {
+    fn is_target_known(&self, target_address: u16) -> bool {
+        target_address == self.functional_address || self.ecus.contains_key(&target_address)
+    }
+    fn handle_request(
+        &mut self,
+        _source_address: u16,
+        target_address: u16,
+        request: &[u8],
+    ) -> Vec<DiagnosticResponse> {
+        if target_address != self.functional_address {
+            return self
+                .ecus
+                .get_mut(&target_address)
+                .and_then(|ecu| ecu.handle_request(request))
+                .map(|response| DiagnosticResponse::new(target_address, response))
+                .into_iter()
+                .collect();
+        }
+        /* Negative responses meaning "not supported" are not sent to functional requests */
+        self.ecus
+            .iter_mut()
+            .filter_map(|(address, ecu)| Some((*address, ecu.handle_request(request)?)))
+            .filter(|(_, response)| {
+                !parse_negative_response(response).is_some_and(|(_, nrc)| {
+                    [
+                        NegativeResponseCode::ServiceNotSupported,
+                        NegativeResponseCode::SubFunctionNotSupported,
+                        NegativeResponseCode::RequestOutOfRange,
+                        NegativeResponseCode::SubFunctionNotSupportedInActiveSession,
+                        NegativeResponseCode::ServiceNotSupportedInActiveSession,
+                    ]
+                    .iter()
+                    .any(|code| *code as u8 == nrc)
+                })
+            })
+            .map(|(address, response)| DiagnosticResponse::new(address, response))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::doip_client::DoIPClientSession;
+    use crate::uds::client::{UdsClient, UdsError};
+    use rand::rngs::mock::StepRng;
+    use std::time::Duration;
+
+    const EXAMPLE: &str = include_str!("../examples/vehicle.toml");
+
+    fn connect(target_address: u16) -> UdsClient {
+        let config = VehicleConfig::from_toml_str(EXAMPLE).unwrap();
+        let address = VehicleSimulator::build_server(&config).unwrap().serve_loopback();
+        UdsClient::new(DoIPClientSession::connect(address, 0x0E80).unwrap(), target_address)
+    }
+
+    #[test]
+    fn read_vin_from_every_ecu() {
+        let config = VehicleConfig::from_toml_str(EXAMPLE).unwrap();
+        let address = VehicleSimulator::build_server(&config).unwrap().serve_loopback();
+        for ecu in [0x1000, 0x1001] {
+            let session = DoIPClientSession::connect(address, 0x0E80 + ecu - 0x1000).unwrap();
+            let mut client = UdsClient::new(session, ecu);
+            assert_eq!(client.read_did(0xF190).unwrap(), b"WDOIPSIM000000001".to_vec());
+        }
+    }
+    #[test]
+    fn unsupported_did_and_service() {
+        let mut client = connect(0x1001);
+        assert_eq!(client.read_did(0xF187).unwrap(), vec![0x12, 0x34, 0x56, 0x78]);
+        assert!(matches!(
+            client.read_did(0x1234),
+            Err(UdsError::NegativeResponse { service: 0x22, nrc: 0x31 })
+        ));
+        assert!(matches!(
+            client.request(&[0x85, 0x02]),
+            Err(UdsError::NegativeResponse { service: 0x85, nrc: 0x11 })
+        ));
+    }
+    #[test]
+    fn read_dtc_by_status_mask() {
+        let mut client = connect(0x1001);
+        let response = client.request(&[0x19, 0x02, 0x08]).unwrap();
+        assert_eq!(response, vec![0x59, 0x02, 0xFF, 0x01, 0x23, 0x00, 0x2F]);
+        let response = client.request(&[0x19, 0x02, 0x40]).unwrap();
+        assert_eq!(response, vec![0x59, 0x02, 0xFF]);
+    }
+    #[test]
+    fn ecus_run_on_server_clock_and_rng() {
+        let config = VehicleConfig::from_toml_str(EXAMPLE).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let extended_session = || {
+            let rng = StepRng::new(7, 1);
+            let server = VehicleSimulator::build_server_with(&config, clock.clone(), rng).unwrap();
+            let session = DoIPClientSession::connect(server.serve_loopback(), 0x0E80).unwrap();
+            let mut client = UdsClient::new(session, 0x1001);
+            client.diagnostic_session_control(0x03).unwrap();
+            client
+        };
+        /* Equally seeded entities hand out the same seeds */
+        let (mut first, mut second) = (extended_session(), extended_session());
+        assert_eq!(first.request(&[0x27, 0x01]).unwrap(), second.request(&[0x27, 0x01]).unwrap());
+        /* The S3 timer of the engine ECU expires on the server clock */
+        clock.advance(Duration::from_millis(5001));
+        assert!(matches!(
+            first.request(&[0x27, 0x01]),
+            Err(UdsError::NegativeResponse { service: 0x27, nrc: 0x7F })
+        ));
+    }
+    #[test]
+    fn functional_request_reaches_every_ecu() {
+        let config = VehicleConfig::from_toml_str(EXAMPLE).unwrap();
+        let mut simulator = VehicleSimulator::new(&config).unwrap();
+        let responses = simulator.handle_request(0x0E80, 0xE400, &[0x10, 0x03]);
+        let sources: Vec<u16> = responses.iter().map(|response| response.source_address).collect();
+        assert_eq!(sources, vec![0x1000, 0x1001]);
+        assert!(simulator.handle_request(0x0E80, 0xE400, &[0x3E, 0x80]).is_empty());
+        assert!(simulator.handle_request(0x0E80, 0xE400, &[0x85, 0x02]).is_empty());
+        assert_eq!(simulator.ecu(0x1001).unwrap().active_session(), 0x03);
+        let mut client = connect(0x1000);
+        client.set_timing(crate::uds::client::UdsTiming {
+            p2: Duration::from_millis(200),
+            p2_star: Duration::from_millis(200),
+        });
+        assert!(client.diagnostic_session_control(0x03).is_ok());
+    }
+}
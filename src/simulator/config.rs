@@ -0,0 +1,302 @@
+use crate::message::hex;
+use crate::uds::security;
+use serde::Deserialize;
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    Yaml(serde_yaml::Error),
+    Invalid(String),
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "I/O error: {}", err),
+            ConfigError::Toml(err) => write!(f, "invalid TOML: {}", err),
+            ConfigError::Yaml(err) => write!(f, "invalid YAML: {}", err),
+            ConfigError::Invalid(reason) => write!(f, "invalid vehicle description: {}", reason),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+/* Description of a simulated vehicle: the DoIP entity and the ECUs behind it */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VehicleConfig {
+    pub vin: String,
+    #[serde(default = "default_id")]
+    pub eid: String,
+    #[serde(default = "default_id")]
+    pub gid: String,
+    pub logical_address: u16,
+    #[serde(default = "default_functional_address")]
+    pub functional_address: u16,
+    #[serde(default)]
+    pub ecus: Vec<EcuConfig>,
+    #[serde(default)]
+    pub network: NetworkConfig,
+}
+impl Default for VehicleConfig {
+    fn default() -> Self {
+        VehicleConfig {
+            vin: "0".repeat(17),
+            eid: default_id(),
+            gid: default_id(),
+            logical_address: 0,
+            functional_address: default_functional_address(),
+            ecus: Vec::new(),
+            network: NetworkConfig::default(),
+        }
+    }
+}
+
+/* Sockets of the simulated entity, the DoIP defaults for anything omitted */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub bind_address: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub tcp_port: Option<u16>,
+    pub udp_port: Option<u16>,
+    /* Announcement destinations replacing broadcast and all-nodes multicast */
+    pub announce: Option<Vec<SocketAddr>>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EcuConfig {
+    #[serde(default)]
+    pub name: String,
+    pub logical_address: u16,
+    #[serde(default = "default_sessions")]
+    pub sessions: Vec<u8>,
+    #[serde(default)]
+    pub security_levels: Vec<SecurityLevelConfig>,
+    #[serde(default)]
+    pub dids: Vec<DidConfig>,
+    #[serde(default)]
+    pub dtcs: Vec<DtcConfig>,
+    /* Time without requests after which a non-default session falls back to the default one */
+    #[serde(default = "default_s3_timeout_ms")]
+    pub s3_timeout_ms: u64,
+    /* Failed SecurityAccess attempts before the time delay applies */
+    #[serde(default = "default_security_attempts")]
+    pub security_attempts: u8,
+    #[serde(default = "default_security_delay_ms")]
+    pub security_delay_ms: u64,
+}
+
+/* Data identifier, the value is given either as hex bytes or as ASCII text */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DidConfig {
+    pub id: u16,
+    pub hex: Option<String>,
+    pub ascii: Option<String>,
+    /* Sessions the DID can be read in, all sessions when not given */
+    pub read_sessions: Option<Vec<u8>>,
+    /* Sessions the DID can be written in, read-only when not given */
+    pub write_sessions: Option<Vec<u8>>,
+    /* Security level which has to be unlocked before writing */
+    pub security_level: Option<u8>,
+}
+impl DidConfig {
+    pub fn data(&self) -> Result<Vec<u8>, ConfigError> {
+        match (&self.hex, &self.ascii) {
+            (Some(hex), None) => parse_hex(hex),
+            (None, Some(ascii)) => Ok(ascii.as_bytes().to_vec()),
+            _ => Err(ConfigError::Invalid(format!(
+                "DID 0x{:04X} needs exactly one of hex or ascii",
+                self.id
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DtcConfig {
+    /* 3 byte DTC number */
+    pub code: u32,
+    #[serde(default = "default_dtc_status")]
+    pub status: u8,
+}
+
+/* Security level unlocked with a key computed as seed XOR mask */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityLevelConfig {
+    pub level: u8,
+    pub xor_mask: String,
+}
+
+impl VehicleConfig {
+    /* Loads a YAML (.yaml/.yml) or TOML (anything else) vehicle description */
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path.as_ref())?;
+        match path.as_ref().extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => VehicleConfig::from_yaml_str(&text),
+            _ => VehicleConfig::from_toml_str(&text),
+        }
+    }
+    pub fn from_toml_str(text: &str) -> Result<Self, ConfigError> {
+        let config: VehicleConfig = toml::from_str(text).map_err(ConfigError::Toml)?;
+        config.validate()?;
+        Ok(config)
+    }
+    pub fn from_yaml_str(text: &str) -> Result<Self, ConfigError> {
+        let config: VehicleConfig = serde_yaml::from_str(text).map_err(ConfigError::Yaml)?;
+        config.validate()?;
+        Ok(config)
+    }
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.vin()?;
+        self.eid()?;
+        self.gid()?;
+        for (index, ecu) in self.ecus.iter().enumerate() {
+            if self.ecus[..index].iter().any(|other| other.logical_address == ecu.logical_address) {
+                return Err(ConfigError::Invalid(format!(
+                    "duplicate ECU logical address 0x{:04X}",
+                    ecu.logical_address
+                )));
+            }
+            for did in &ecu.dids {
+                did.data()?;
+                if let Some(level) = did.security_level {
+                    if !ecu.security_levels.iter().any(|config| config.level == level) {
+                        return Err(ConfigError::Invalid(format!(
+                            "DID 0x{:04X} is protected by unknown security level 0x{:02X}",
+                            did.id, level
+                        )));
+                    }
+                }
+            }
+            for level in &ecu.security_levels {
+                if !security::is_request_seed_level(level.level) {
+                    return Err(ConfigError::Invalid(format!(
+                        "security level 0x{:02X} is not an odd requestSeed level of 0x01 to 0x7D",
+                        level.level
+                    )));
+                }
+                level.mask()?;
+            }
+        }
+        Ok(())
+    }
+    pub fn vin(&self) -> Result<[u8; 17], ConfigError> {
+        self.vin
+            .as_bytes()
+            .try_into()
+            .map_err(|_| ConfigError::Invalid(format!("VIN {:?} is not 17 characters long", self.vin)))
+    }
+    pub fn eid(&self) -> Result<[u8; 6], ConfigError> {
+        parse_id(&self.eid)
+    }
+    pub fn gid(&self) -> Result<[u8; 6], ConfigError> {
+        parse_id(&self.gid)
+    }
+}
+impl SecurityLevelConfig {
+    pub fn mask(&self) -> Result<Vec<u8>, ConfigError> {
+        match parse_hex(&self.xor_mask)? {
+            mask if mask.is_empty() => Err(ConfigError::Invalid("empty XOR mask".to_string())),
+            mask => Ok(mask),
+        }
+    }
+}
+
+fn default_id() -> String {
+    "000000000000".to_string()
+}
+fn default_functional_address() -> u16 {
+    0xE400
+}
+fn default_sessions() -> Vec<u8> {
+    vec![0x01, 0x02, 0x03]
+}
+fn default_dtc_status() -> u8 {
+    0x09
+}
+fn default_s3_timeout_ms() -> u64 {
+    5000
+}
+fn default_security_attempts() -> u8 {
+    3
+}
+fn default_security_delay_ms() -> u64 {
+    10000
+}
+
+/* Hex bytes, optionally separated by spaces, colons or dashes */
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, ConfigError> {
+    hex::decode(text).map_err(|err| ConfigError::Invalid(format!("invalid hex bytes {:?}: {}", text, err)))
+}
+
+fn parse_id(text: &str) -> Result<[u8; 6], ConfigError> {
+    parse_hex(text)?
+        .try_into()
+        .map_err(|_| ConfigError::Invalid(format!("{:?} is not a 6 byte identifier", text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../../examples/vehicle.toml");
+
+    #[test]
+    fn load_example_vehicle() {
+        let config = VehicleConfig::from_toml_str(EXAMPLE).unwrap();
+        assert_eq!(&config.vin().unwrap(), b"WDOIPSIM000000001");
+        assert_eq!(config.eid().unwrap(), [0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5E]);
+        assert_eq!(config.logical_address, 0x1000);
+        assert_eq!(config.ecus.len(), 2);
+        assert_eq!(config.ecus[0].dids[0].data().unwrap(), b"WDOIPSIM000000001".to_vec());
+    }
+    #[test]
+    fn load_yaml_vehicle() {
+        let config = VehicleConfig::from_yaml_str(
+            "vin: WDOIPSIM000000001\nlogical_address: 0x1000\necus:\n  - logical_address: 0x1001\n    dids:\n      - id: 0xF18C\n        hex: \"01 02\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.ecus[0].logical_address, 0x1001);
+        assert_eq!(config.ecus[0].sessions, vec![1, 2, 3]);
+        assert_eq!(config.ecus[0].dids[0].data().unwrap(), vec![1, 2]);
+    }
+    #[test]
+    fn reject_invalid_vehicle() {
+        let short_vin = "vin = \"WDOIP\"\nlogical_address = 0x1000\n";
+        assert!(matches!(VehicleConfig::from_toml_str(short_vin), Err(ConfigError::Invalid(_))));
+        let duplicate = "vin = \"WDOIPSIM000000001\"\nlogical_address = 0x1000\n\
+                         [[ecus]]\nlogical_address = 0x1001\n[[ecus]]\nlogical_address = 0x1001\n";
+        assert!(matches!(VehicleConfig::from_toml_str(duplicate), Err(ConfigError::Invalid(_))));
+        let reserved_level = "vin = \"WDOIPSIM000000001\"\nlogical_address = 0x1000\n\
+                              [[ecus]]\nlogical_address = 0x1001\n\
+                              [[ecus.security_levels]]\nlevel = 0x7F\nxor_mask = \"5A\"\n";
+        assert!(matches!(VehicleConfig::from_toml_str(reserved_level), Err(ConfigError::Invalid(_))));
+    }
+    #[test]
+    fn load_network_settings() {
+        let config = VehicleConfig::from_toml_str(
+            "vin = \"WDOIPSIM000000001\"\nlogical_address = 0x1000\n\
+             [network]\ninterface = \"eth1\"\ntcp_port = 0\nannounce = [\"192.168.10.255:13200\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.network.interface.as_deref(), Some("eth1"));
+        assert_eq!(config.network.tcp_port, Some(0));
+        assert_eq!(config.network.announce, Some(vec!["192.168.10.255:13200".parse().unwrap()]));
+        assert_eq!(VehicleConfig::from_toml_str(EXAMPLE).unwrap().network, NetworkConfig::default());
+    }
+}
@@ -0,0 +1,465 @@
+use crate::clock::{Clock, SystemClock};
+use crate::simulator::config::{ConfigError, EcuConfig};
+use crate::uds::{
+    parse_negative_response, positive_response_sid,
+    security::{ProviderRegistry, SecurityAccessServer, XorMaskProvider},
+    NegativeResponseCode, ServiceId, DEFAULT_SESSION, SUPPRESS_POSITIVE_RESPONSE,
+};
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct DataIdentifier {
+    data: Vec<u8>,
+    read_sessions: Option<Vec<u8>>,
+    write_sessions: Option<Vec<u8>>,
+    security_level: Option<u8>,
+}
+
+/* ECU model with session, security access, DID and DTC state */
+pub struct SimulatedEcu {
+    name: String,
+    logical_address: u16,
+    sessions: Vec<u8>,
+    active_session: u8,
+    s3_timeout: Duration,
+    last_request: Instant,
+    /* Time source of the S3 timer, shared with the security access lockout */
+    clock: Arc<dyn Clock>,
+    security: Option<SecurityAccessServer>,
+    dids: BTreeMap<u16, DataIdentifier>,
+    dtcs: Vec<(u32, u8)>,
+    reset_count: u32,
+}
+impl SimulatedEcu {
+    const DTC_STATUS_AVAILABILITY_MASK: u8 = 0xFF;
+    const DTC_FORMAT_ISO14229_1: u8 = 0x01;
+    const ALL_DTC_GROUPS: u32 = 0xFFFFFF;
+    const TEST_FAILED_THIS_OPERATION_CYCLE: u8 = 0x02;
+
+    pub fn from_config(config: &EcuConfig) -> Result<Self, ConfigError> {
+        let mut dids = BTreeMap::new();
+        for did in &config.dids {
+            dids.insert(
+                did.id,
+                DataIdentifier {
+                    data: did.data()?,
+                    read_sessions: did.read_sessions.clone(),
+                    write_sessions: did.write_sessions.clone(),
+                    security_level: did.security_level,
+                },
+            );
+        }
+        let security = if config.security_levels.is_empty() {
+            None
+        } else {
+            let mut registry = ProviderRegistry::new();
+            for level in &config.security_levels {
+                registry.register(config.logical_address, level.level, XorMaskProvider::new(&level.mask()?));
+            }
+            let levels: Vec<u8> = config.security_levels.iter().map(|level| level.level).collect();
+            let mut security = SecurityAccessServer::new(config.logical_address, registry);
+            security
+                .set_levels(&levels)
+                .map_err(|err| ConfigError::Invalid(err.to_string()))?
+                .set_attempt_limit(config.security_attempts, Duration::from_millis(config.security_delay_ms));
+            Some(security)
+        };
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Ok(SimulatedEcu {
+            name: config.name.clone(),
+            logical_address: config.logical_address,
+            sessions: config.sessions.clone(),
+            active_session: DEFAULT_SESSION,
+            s3_timeout: Duration::from_millis(config.s3_timeout_ms),
+            last_request: clock.now(),
+            clock,
+            security,
+            dids,
+            dtcs: config.dtcs.iter().map(|dtc| (dtc.code, dtc.status)).collect(),
+            reset_count: 0,
+        })
+    }
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        if let Some(security) = self.security.as_mut() {
+            security.set_clock(clock.clone());
+        }
+        self.last_request = clock.now();
+        self.clock = clock;
+        self
+    }
+    /* Source of the security access seeds */
+    pub fn set_rng<R: RngCore + Send + 'static>(&mut self, rng: R) -> &mut Self {
+        if let Some(security) = self.security.as_mut() {
+            security.set_rng(rng);
+        }
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn logical_address(&self) -> u16 {
+        self.logical_address
+    }
+    pub fn active_session(&self) -> u8 {
+        if self.s3_expired() {
+            DEFAULT_SESSION
+        } else {
+            self.active_session
+        }
+    }
+    pub fn unlocked_level(&self) -> Option<u8> {
+        self.security.as_ref().and_then(|security| security.unlocked_level())
+    }
+    pub fn did(&self, did: u16) -> Option<&[u8]> {
+        self.dids.get(&did).map(|did| did.data.as_slice())
+    }
+    /* Stored DTCs as (3 byte DTC number, status byte) */
+    pub fn dtcs(&self) -> &[(u32, u8)] {
+        &self.dtcs
+    }
+    pub fn reset_count(&self) -> u32 {
+        self.reset_count
+    }
+    /* Returns the response to a UDS request, None when the response is suppressed */
+    pub fn handle_request(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let service = *request.first()?;
+        if self.s3_expired() {
+            self.enter_session(DEFAULT_SESSION);
+        }
+        self.last_request = self.clock.now();
+        let result = match num::FromPrimitive::from_u8(service) {
+            Some(ServiceId::DiagnosticSessionControl) => self.session_control(request),
+            Some(ServiceId::EcuReset) => self.ecu_reset(request),
+            Some(ServiceId::ClearDiagnosticInformation) => self.clear_diagnostic_information(request),
+            Some(ServiceId::ReadDtcInformation) => self.read_dtc_information(request),
+            Some(ServiceId::ReadDataByIdentifier) => self.read_data_by_identifier(request),
+            Some(ServiceId::SecurityAccess) => self.security_access(request),
+            Some(ServiceId::WriteDataByIdentifier) => self.write_data_by_identifier(request),
+            Some(ServiceId::TesterPresent) => self.tester_present(request),
+            _ => Err(NegativeResponseCode::ServiceNotSupported),
+        };
+        match result {
+            Ok(response) => {
+                let suppressed = request.get(1).is_some_and(|sub_function| {
+                    sub_function & SUPPRESS_POSITIVE_RESPONSE != 0 && has_sub_function(service)
+                });
+                (!suppressed).then_some(response)
+            }
+            Err(nrc) => Some(vec![0x7F, service, nrc as u8]),
+        }
+    }
+    fn s3_expired(&self) -> bool {
+        let idle = self.clock.now().saturating_duration_since(self.last_request);
+        self.active_session != DEFAULT_SESSION && idle > self.s3_timeout
+    }
+    /* Every session transition locks the ECU again */
+    fn enter_session(&mut self, session: u8) {
+        self.active_session = session;
+        if let Some(security) = self.security.as_mut() {
+            security.lock();
+        }
+    }
+    fn session_control(&mut self, request: &[u8]) -> Result<Vec<u8>, NegativeResponseCode> {
+        let [service, sub_function] = request else {
+            return Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat);
+        };
+        let session = sub_function & !SUPPRESS_POSITIVE_RESPONSE;
+        if !self.sessions.contains(&session) {
+            return Err(NegativeResponseCode::SubFunctionNotSupported);
+        }
+        self.enter_session(session);
+        /* P2 server 50 ms, P2* server 5000 ms in 10 ms resolution */
+        Ok(vec![positive_response_sid(*service), session, 0x00, 0x32, 0x01, 0xF4])
+    }
+    fn ecu_reset(&mut self, request: &[u8]) -> Result<Vec<u8>, NegativeResponseCode> {
+        let [service, sub_function] = request else {
+            return Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat);
+        };
+        /* hardReset, keyOffOnReset and softReset */
+        let reset_type = sub_function & !SUPPRESS_POSITIVE_RESPONSE;
+        if !matches!(reset_type, 0x01..=0x03) {
+            return Err(NegativeResponseCode::SubFunctionNotSupported);
+        }
+        self.enter_session(DEFAULT_SESSION);
+        for (_, status) in self.dtcs.iter_mut() {
+            *status &= !SimulatedEcu::TEST_FAILED_THIS_OPERATION_CYCLE;
+        }
+        self.reset_count += 1;
+        Ok(vec![positive_response_sid(*service), reset_type])
+    }
+    fn tester_present(&mut self, request: &[u8]) -> Result<Vec<u8>, NegativeResponseCode> {
+        match request {
+            [service, sub_function] if sub_function & !SUPPRESS_POSITIVE_RESPONSE == 0 => {
+                Ok(vec![positive_response_sid(*service), 0x00])
+            }
+            [_, _] => Err(NegativeResponseCode::SubFunctionNotSupported),
+            _ => Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat),
+        }
+    }
+    fn security_access(&mut self, request: &[u8]) -> Result<Vec<u8>, NegativeResponseCode> {
+        let Some(security) = self.security.as_mut() else {
+            return Err(NegativeResponseCode::ServiceNotSupported);
+        };
+        if self.active_session == DEFAULT_SESSION {
+            return Err(NegativeResponseCode::ServiceNotSupportedInActiveSession);
+        }
+        /* A suppressed positive response is dropped by handle_request, as for the other services */
+        let Some(response) = security.handle(request) else {
+            return Ok(Vec::new());
+        };
+        match parse_negative_response(&response) {
+            Some((_, nrc)) => {
+                Err(num::FromPrimitive::from_u8(nrc).unwrap_or(NegativeResponseCode::GeneralReject))
+            }
+            None => Ok(response),
+        }
+    }
+    fn read_data_by_identifier(&mut self, request: &[u8]) -> Result<Vec<u8>, NegativeResponseCode> {
+        let identifiers = &request[1..];
+        if identifiers.is_empty() || identifiers.len() % 2 != 0 {
+            return Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat);
+        }
+        let mut response = vec![positive_response_sid(request[0])];
+        for identifier in identifiers.chunks(2) {
+            let did = self
+                .dids
+                .get(&u16::from_be_bytes([identifier[0], identifier[1]]))
+                .filter(|did| {
+                    did.read_sessions.as_ref().is_none_or(|sessions| sessions.contains(&self.active_session))
+                })
+                .ok_or(NegativeResponseCode::RequestOutOfRange)?;
+            response.extend_from_slice(identifier);
+            response.extend_from_slice(&did.data);
+        }
+        Ok(response)
+    }
+    fn write_data_by_identifier(&mut self, request: &[u8]) -> Result<Vec<u8>, NegativeResponseCode> {
+        let [service, high, low, data @ ..] = request else {
+            return Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat);
+        };
+        if data.is_empty() {
+            return Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat);
+        }
+        let unlocked_level = self.unlocked_level();
+        let did = self
+            .dids
+            .get_mut(&u16::from_be_bytes([*high, *low]))
+            .filter(|did| {
+                did.write_sessions.as_ref().is_some_and(|sessions| sessions.contains(&self.active_session))
+            })
+            .ok_or(NegativeResponseCode::RequestOutOfRange)?;
+        if did.data.len() != data.len() {
+            return Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat);
+        }
+        if did.security_level.is_some_and(|level| unlocked_level != Some(level)) {
+            return Err(NegativeResponseCode::SecurityAccessDenied);
+        }
+        did.data = data.to_vec();
+        Ok(vec![positive_response_sid(*service), *high, *low])
+    }
+    fn clear_diagnostic_information(&mut self, request: &[u8]) -> Result<Vec<u8>, NegativeResponseCode> {
+        let [service, group @ ..] = request else {
+            return Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat);
+        };
+        let group: [u8; 3] = group
+            .try_into()
+            .map_err(|_| NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat)?;
+        let group = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+        if group == SimulatedEcu::ALL_DTC_GROUPS {
+            self.dtcs.clear();
+        } else if self.dtcs.iter().any(|(code, _)| *code == group) {
+            self.dtcs.retain(|(code, _)| *code != group);
+        } else {
+            return Err(NegativeResponseCode::RequestOutOfRange);
+        }
+        Ok(vec![positive_response_sid(*service)])
+    }
+    fn read_dtc_information(&mut self, request: &[u8]) -> Result<Vec<u8>, NegativeResponseCode> {
+        let matching = |mask: u8| self.dtcs.iter().filter(move |(_, status)| status & mask != 0);
+        match request {
+            /* reportNumberOfDTCByStatusMask */
+            [service, 0x01, mask] => {
+                let count = matching(*mask).count() as u16;
+                let mut response = vec![
+                    positive_response_sid(*service),
+                    0x01,
+                    SimulatedEcu::DTC_STATUS_AVAILABILITY_MASK,
+                    SimulatedEcu::DTC_FORMAT_ISO14229_1,
+                ];
+                response.extend_from_slice(&count.to_be_bytes());
+                Ok(response)
+            }
+            /* reportDTCByStatusMask */
+            [service, 0x02, mask] => {
+                let mut response = vec![
+                    positive_response_sid(*service),
+                    0x02,
+                    SimulatedEcu::DTC_STATUS_AVAILABILITY_MASK,
+                ];
+                for (code, status) in matching(*mask) {
+                    response.extend_from_slice(&code.to_be_bytes()[1..]);
+                    response.push(*status);
+                }
+                Ok(response)
+            }
+            /* reportSupportedDTC */
+            [service, 0x0A] => {
+                let mut response = vec![
+                    positive_response_sid(*service),
+                    0x0A,
+                    SimulatedEcu::DTC_STATUS_AVAILABILITY_MASK,
+                ];
+                for (code, status) in &self.dtcs {
+                    response.extend_from_slice(&code.to_be_bytes()[1..]);
+                    response.push(*status);
+                }
+                Ok(response)
+            }
+            [_, 0x01 | 0x02 | 0x0A, ..] => Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat),
+            [_, _, ..] => Err(NegativeResponseCode::SubFunctionNotSupported),
+            _ => Err(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat),
+        }
+    }
+}
+
+fn has_sub_function(service: u8) -> bool {
+    matches!(
+        num::FromPrimitive::from_u8(service),
+        Some(ServiceId::DiagnosticSessionControl | ServiceId::TesterPresent | ServiceId::EcuReset
+            | ServiceId::SecurityAccess | ServiceId::ReadDtcInformation | ServiceId::RoutineControl
+            | ServiceId::CommunicationControl | ServiceId::ControlDtcSetting)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const ECU: &str = r#"
+        name = "body"
+        logical_address = 0x1002
+        s3_timeout_ms = 100
+        security_attempts = 2
+        security_delay_ms = 60000
+
+        [[dids]]
+        id = 0xF190
+        ascii = "WDOIPSIM000000001"
+
+        [[dids]]
+        id = 0x0100
+        hex = "00 00"
+        write_sessions = [0x03]
+        security_level = 0x01
+
+        [[dids]]
+        id = 0x0200
+        hex = "AA"
+        read_sessions = [0x03]
+
+        [[dtcs]]
+        code = 0x012300
+        status = 0x2F
+
+        [[dtcs]]
+        code = 0xC07300
+        status = 0x08
+
+        [[security_levels]]
+        level = 0x01
+        xor_mask = "11223344"
+    "#;
+
+    fn ecu() -> SimulatedEcu {
+        SimulatedEcu::from_config(&toml::from_str(ECU).unwrap()).unwrap()
+    }
+    fn unlock(ecu: &mut SimulatedEcu) {
+        let response = ecu.handle_request(&[0x27, 0x01]).unwrap();
+        let key: Vec<u8> = response[2..].iter().zip([0x11, 0x22, 0x33, 0x44]).map(|(a, b)| a ^ b).collect();
+        let mut request = vec![0x27, 0x02];
+        request.extend_from_slice(&key);
+        assert_eq!(ecu.handle_request(&request), Some(vec![0x67, 0x02]));
+    }
+
+    #[test]
+    fn session_falls_back_after_s3_timeout() {
+        let clock = Arc::new(ManualClock::new());
+        let mut ecu = ecu();
+        ecu.set_clock(clock.clone());
+        assert_eq!(ecu.handle_request(&[0x10, 0x03]).unwrap()[..2], [0x50, 0x03]);
+        assert_eq!(ecu.handle_request(&[0x3E, 0x80]), None);
+        assert_eq!(ecu.active_session(), 0x03);
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(ecu.active_session(), 0x03);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(ecu.active_session(), DEFAULT_SESSION);
+        assert_eq!(ecu.handle_request(&[0x22, 0x02, 0x00]), Some(vec![0x7F, 0x22, 0x31]));
+    }
+    #[test]
+    fn protected_did_needs_session_and_security() {
+        let mut ecu = ecu();
+        assert_eq!(ecu.handle_request(&[0x2E, 0x01, 0x00, 0x12, 0x34]), Some(vec![0x7F, 0x2E, 0x31]));
+        assert_eq!(ecu.handle_request(&[0x27, 0x01]), Some(vec![0x7F, 0x27, 0x7F]));
+        ecu.handle_request(&[0x10, 0x03]);
+        assert_eq!(ecu.handle_request(&[0x2E, 0x01, 0x00, 0x12, 0x34]), Some(vec![0x7F, 0x2E, 0x33]));
+        unlock(&mut ecu);
+        assert_eq!(ecu.handle_request(&[0x2E, 0x01, 0x00, 0x12]), Some(vec![0x7F, 0x2E, 0x13]));
+        assert_eq!(ecu.handle_request(&[0x2E, 0x01, 0x00, 0x12, 0x34]), Some(vec![0x6E, 0x01, 0x00]));
+        assert_eq!(ecu.handle_request(&[0x22, 0x01, 0x00]), Some(vec![0x62, 0x01, 0x00, 0x12, 0x34]));
+        assert_eq!(ecu.handle_request(&[0x2E, 0xF1, 0x90, 0x00]), Some(vec![0x7F, 0x2E, 0x31]));
+        ecu.handle_request(&[0x10, 0x03]);
+        assert_eq!(ecu.unlocked_level(), None);
+    }
+    #[test]
+    fn security_lockout_after_failed_attempts() {
+        let clock = Arc::new(ManualClock::new());
+        let mut ecu = ecu();
+        ecu.set_clock(clock.clone());
+        ecu.handle_request(&[0x10, 0x03]);
+        ecu.handle_request(&[0x27, 0x01]);
+        assert_eq!(ecu.handle_request(&[0x27, 0x02, 0, 0, 0, 0]), Some(vec![0x7F, 0x27, 0x35]));
+        ecu.handle_request(&[0x27, 0x01]);
+        assert_eq!(ecu.handle_request(&[0x27, 0x02, 0, 0, 0, 0]), Some(vec![0x7F, 0x27, 0x36]));
+        assert_eq!(ecu.handle_request(&[0x27, 0x01]), Some(vec![0x7F, 0x27, 0x37]));
+        assert_eq!(ecu.handle_request(&[0x27, 0x03]), Some(vec![0x7F, 0x27, 0x12]));
+        /* The delay runs on the ECU clock, across the S3 fall back to the default session */
+        clock.advance(Duration::from_millis(59_999));
+        ecu.handle_request(&[0x10, 0x03]);
+        assert_eq!(ecu.handle_request(&[0x27, 0x01]), Some(vec![0x7F, 0x27, 0x37]));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(ecu.handle_request(&[0x27, 0x01]).unwrap().len(), 6);
+    }
+    #[test]
+    fn dtc_memory() {
+        let mut ecu = ecu();
+        assert_eq!(ecu.handle_request(&[0x19, 0x01, 0x08]), Some(vec![0x59, 0x01, 0xFF, 0x01, 0x00, 0x02]));
+        assert_eq!(
+            ecu.handle_request(&[0x19, 0x02, 0x01]),
+            Some(vec![0x59, 0x02, 0xFF, 0x01, 0x23, 0x00, 0x2F])
+        );
+        assert_eq!(ecu.handle_request(&[0x19, 0x42]), Some(vec![0x7F, 0x19, 0x12]));
+        assert_eq!(ecu.handle_request(&[0x14, 0x00, 0x00, 0x01]), Some(vec![0x7F, 0x14, 0x31]));
+        assert_eq!(ecu.handle_request(&[0x14, 0x01, 0x23, 0x00]), Some(vec![0x54]));
+        assert_eq!(ecu.dtcs(), &[(0xC07300, 0x08)]);
+        assert_eq!(ecu.handle_request(&[0x14, 0xFF, 0xFF, 0xFF]), Some(vec![0x54]));
+        assert_eq!(ecu.handle_request(&[0x19, 0x0A]), Some(vec![0x59, 0x0A, 0xFF]));
+    }
+    #[test]
+    fn reset_returns_to_default_session() {
+        let mut ecu = ecu();
+        ecu.handle_request(&[0x10, 0x03]);
+        unlock(&mut ecu);
+        assert_eq!(ecu.handle_request(&[0x11, 0x01]), Some(vec![0x51, 0x01]));
+        assert_eq!(ecu.active_session(), DEFAULT_SESSION);
+        assert_eq!(ecu.unlocked_level(), None);
+        assert_eq!(ecu.dtcs()[0], (0x012300, 0x2D));
+        assert_eq!(ecu.handle_request(&[0x11, 0x83]), None);
+        assert_eq!(ecu.reset_count(), 2);
+        assert_eq!(ecu.handle_request(&[0x11, 0x04]), Some(vec![0x7F, 0x11, 0x12]));
+        assert_eq!(ecu.handle_request(&[0x31, 0x01, 0xFF, 0x00]), Some(vec![0x7F, 0x31, 0x11]));
+        assert_eq!(ecu.handle_request(&[0x10]), Some(vec![0x7F, 0x10, 0x13]));
+    }
+}
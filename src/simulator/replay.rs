@@ -0,0 +1,268 @@
+use crate::doip_server::{DiagnosticHandler, DiagnosticResponse};
+use crate::message::{message_factory, MessageVariant};
+use crate::recorder::{Direction, Recording};
+use crate::uds::{NegativeResponseCode, NEGATIVE_RESPONSE_SID};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/* How a request received by the simulator is matched against the recorded requests */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MatchMode {
+    /* Requests have to arrive in the recorded order with identical bytes */
+    Sequential,
+    /* Any recorded request to the same target with identical bytes, unused ones first */
+    Exact,
+    /* Like Exact but only the first n request bytes are compared, e.g. 1 for the service */
+    Prefix(usize),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimingMode {
+    /* Responses are sent right away */
+    Immediate,
+    /* Responses keep the recorded delays */
+    Recorded,
+    /* Recorded delays multiplied by the factor */
+    Scaled(f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Exchange {
+    target_address: u16,
+    request: Vec<u8>,
+    /* Responses with their delay to the previous frame of the exchange */
+    responses: Vec<(u16, Vec<u8>, Duration)>,
+    used: bool,
+}
+
+/* Diagnostic handler answering requests with the responses of a recorded session */
+pub struct ReplayHandler {
+    exchanges: Vec<Exchange>,
+    /* Logical addresses of the ECUs answering in the recording */
+    ecus: BTreeSet<u16>,
+    match_mode: MatchMode,
+    timing_mode: TimingMode,
+    next_exchange: usize,
+    unmatched_response: Option<NegativeResponseCode>,
+    unmatched: Vec<(u16, Vec<u8>)>,
+}
+impl ReplayHandler {
+    /* Pairs every recorded tester request with the diagnostic responses following it */
+    pub fn new(recording: &Recording) -> Self {
+        let mut exchanges: Vec<Exchange> = Vec::new();
+        let mut last_frame = Duration::ZERO;
+        for frame in &recording.frames {
+            let Ok(MessageVariant::DiagnoticMessageVariant(message)) = message_factory(&frame.frame) else {
+                continue;
+            };
+            match frame.direction {
+                Direction::TesterToEntity => exchanges.push(Exchange {
+                    target_address: message.target_address,
+                    request: message.user_data,
+                    responses: Vec::new(),
+                    used: false,
+                }),
+                Direction::EntityToTester => {
+                    let Some(exchange) = exchanges.last_mut() else { continue };
+                    let delay = frame.timestamp.saturating_sub(last_frame);
+                    exchange.responses.push((message.source_address, message.user_data, delay));
+                }
+            }
+            last_frame = frame.timestamp;
+        }
+        let ecus = exchanges
+            .iter()
+            .flat_map(|exchange| exchange.responses.iter().map(|(source_address, _, _)| *source_address))
+            .collect();
+        ReplayHandler {
+            exchanges,
+            ecus,
+            match_mode: MatchMode::Exact,
+            timing_mode: TimingMode::Recorded,
+            next_exchange: 0,
+            unmatched_response: Some(NegativeResponseCode::GeneralReject),
+            unmatched: Vec::new(),
+        }
+    }
+    pub fn set_match_mode(&mut self, match_mode: MatchMode) -> &mut Self {
+        self.match_mode = match_mode;
+        self
+    }
+    pub fn set_timing_mode(&mut self, timing_mode: TimingMode) -> &mut Self {
+        self.timing_mode = timing_mode;
+        self
+    }
+    /* Negative response sent for physical requests without a recorded match, None to stay silent */
+    pub fn set_unmatched_response(&mut self, nrc: Option<NegativeResponseCode>) -> &mut Self {
+        self.unmatched_response = nrc;
+        self
+    }
+    /* Requests (target address, UDS bytes) which had no recorded match */
+    pub fn unmatched(&self) -> &[(u16, Vec<u8>)] {
+        &self.unmatched
+    }
+    /* Number of recorded requests which have not been replayed yet */
+    pub fn remaining(&self) -> usize {
+        self.exchanges.iter().filter(|exchange| !exchange.used).count()
+    }
+    fn find_exchange(&self, target_address: u16, request: &[u8]) -> Option<usize> {
+        let matches = |exchange: &Exchange| {
+            exchange.target_address == target_address
+                && match self.match_mode {
+                    MatchMode::Sequential | MatchMode::Exact => exchange.request == request,
+                    MatchMode::Prefix(length) => {
+                        exchange.request.iter().take(length).eq(request.iter().take(length))
+                    }
+                }
+        };
+        match self.match_mode {
+            MatchMode::Sequential => {
+                self.exchanges.get(self.next_exchange).filter(|exchange| matches(exchange))?;
+                Some(self.next_exchange)
+            }
+            MatchMode::Exact | MatchMode::Prefix(_) => {
+                let mut candidates =
+                    self.exchanges.iter().enumerate().filter(|(_, exchange)| matches(exchange));
+                let first_unused = candidates.clone().find(|(_, exchange)| !exchange.used);
+                first_unused.or_else(|| candidates.next_back()).map(|(index, _)| index)
+            }
+        }
+    }
+    fn delay(&self, recorded: Duration) -> Duration {
+        match self.timing_mode {
+            TimingMode::Immediate => Duration::ZERO,
+            TimingMode::Recorded => recorded,
+            TimingMode::Scaled(factor) => recorded.mul_f64(factor.max(0.0)),
+        }
+    }
+}
+impl DiagnosticHandler for ReplayHandler {
+    fn is_target_known(&self, target_address: u16) -> bool {
+        self.ecus.contains(&target_address)
+            || self.exchanges.iter().any(|exchange| exchange.target_address == target_address)
+    }
+    fn handle_request(
+        &mut self,
+        _source_address: u16,
+        target_address: u16,
+        request: &[u8],
+    ) -> Vec<DiagnosticResponse> {
+        let Some(index) = self.find_exchange(target_address, request) else {
+            self.unmatched.push((target_address, request.to_vec()));
+            /* Only ECUs answer, nothing is sent for unmatched functional requests */
+            return match (self.unmatched_response, request.first()) {
+                (Some(nrc), Some(service)) if self.ecus.contains(&target_address) => {
+                    let response = vec![NEGATIVE_RESPONSE_SID, *service, nrc as u8];
+                    vec![DiagnosticResponse::new(target_address, response)]
+                }
+                _ => Vec::new(),
+            };
+        };
+        self.exchanges[index].used = true;
+        self.next_exchange = index + 1;
+        self.exchanges[index]
+            .responses
+            .iter()
+            .map(|(source_address, user_data, delay)| DiagnosticResponse {
+                source_address: *source_address,
+                user_data: user_data.clone(),
+                delay: self.delay(*delay),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doip_client::DoIPClientSession;
+    use crate::message::{diag_message::DiagMessage, Message};
+    use crate::recorder::{FrameRecorder, RecordedFrame};
+    use crate::simulator::{config::VehicleConfig, VehicleSimulator};
+    use crate::uds::client::{UdsClient, UdsError};
+
+    fn frame(millis: u64, direction: Direction, sa: u16, ta: u16, data: &[u8]) -> RecordedFrame {
+        RecordedFrame {
+            timestamp: Duration::from_millis(millis),
+            direction,
+            frame: DiagMessage::new(sa, ta, data).serialize(),
+        }
+    }
+    fn recording() -> Recording {
+        Recording {
+            frames: vec![
+                frame(0, Direction::TesterToEntity, 0x0E80, 0x1001, &[0x22, 0xF1, 0x90]),
+                frame(20, Direction::EntityToTester, 0x1001, 0x0E80, &[0x7F, 0x22, 0x78]),
+                frame(70, Direction::EntityToTester, 0x1001, 0x0E80, &[0x62, 0xF1, 0x90, 0x01]),
+                frame(100, Direction::TesterToEntity, 0x0E80, 0x1001, &[0x22, 0xF1, 0x87]),
+                frame(110, Direction::EntityToTester, 0x1001, 0x0E80, &[0x62, 0xF1, 0x87, 0x02]),
+                frame(200, Direction::TesterToEntity, 0x0E80, 0x1001, &[0x22, 0xF1, 0x90]),
+                frame(210, Direction::EntityToTester, 0x1001, 0x0E80, &[0x62, 0xF1, 0x90, 0x03]),
+            ],
+        }
+    }
+    fn data(responses: &[DiagnosticResponse]) -> Vec<Vec<u8>> {
+        responses.iter().map(|response| response.user_data.clone()).collect()
+    }
+
+    #[test]
+    fn exact_match_replays_recorded_timing() {
+        let mut handler = ReplayHandler::new(&recording());
+        let responses = handler.handle_request(0x0E80, 0x1001, &[0x22, 0xF1, 0x90]);
+        assert_eq!(data(&responses), vec![vec![0x7F, 0x22, 0x78], vec![0x62, 0xF1, 0x90, 0x01]]);
+        assert_eq!(responses[0].delay, Duration::from_millis(20));
+        assert_eq!(responses[1].delay, Duration::from_millis(50));
+        let responses = handler.handle_request(0x0E80, 0x1001, &[0x22, 0xF1, 0x90]);
+        assert_eq!(data(&responses), vec![vec![0x62, 0xF1, 0x90, 0x03]]);
+        let responses = handler.handle_request(0x0E80, 0x1001, &[0x22, 0xF1, 0x90]);
+        assert_eq!(data(&responses), vec![vec![0x62, 0xF1, 0x90, 0x03]]);
+        assert_eq!(handler.remaining(), 1);
+        handler.set_timing_mode(TimingMode::Scaled(0.5));
+        let responses = handler.handle_request(0x0E80, 0x1001, &[0x22, 0xF1, 0x87]);
+        assert_eq!(responses[0].delay, Duration::from_millis(5));
+        assert_eq!(handler.remaining(), 0);
+    }
+    #[test]
+    fn sequential_match_requires_recorded_order() {
+        let mut handler = ReplayHandler::new(&recording());
+        handler.set_match_mode(MatchMode::Sequential).set_timing_mode(TimingMode::Immediate);
+        let responses = handler.handle_request(0x0E80, 0x1001, &[0x22, 0xF1, 0x87]);
+        assert_eq!(data(&responses), vec![vec![0x7F, 0x22, 0x10]]);
+        let responses = handler.handle_request(0x0E80, 0x1001, &[0x22, 0xF1, 0x90]);
+        assert_eq!(responses.len(), 2);
+        assert!(responses.iter().all(|response| response.delay.is_zero()));
+        assert_eq!(handler.handle_request(0x0E80, 0x1001, &[0x22, 0xF1, 0x87]).len(), 1);
+        assert_eq!(handler.unmatched(), &[(0x1001, vec![0x22, 0xF1, 0x87])]);
+    }
+    #[test]
+    fn prefix_match_and_unmatched_requests() {
+        let mut handler = ReplayHandler::new(&recording());
+        handler.set_match_mode(MatchMode::Prefix(1)).set_unmatched_response(None);
+        let responses = handler.handle_request(0x0E80, 0x1001, &[0x22, 0x01, 0x00]);
+        assert_eq!(data(&responses)[0], vec![0x7F, 0x22, 0x78]);
+        assert!(handler.handle_request(0x0E80, 0x1001, &[0x2E, 0x01, 0x00, 0x00]).is_empty());
+        assert!(handler.is_target_known(0x1001));
+        assert!(!handler.is_target_known(0x1002));
+    }
+    #[test]
+    fn replay_recorded_simulator_session() {
+        let config = VehicleConfig::from_toml_str(include_str!("../../examples/vehicle.toml")).unwrap();
+        let address = VehicleSimulator::build_server(&config).unwrap().serve_loopback();
+        let recorder = FrameRecorder::new();
+        let session = DoIPClientSession::connect_recorded(address, 0x0E80, recorder.clone()).unwrap();
+        let mut client = UdsClient::new(session, 0x1001);
+        let vin = client.read_did(0xF190).unwrap();
+        client.diagnostic_session_control(0x03).unwrap();
+        drop(client);
+
+        let mut replay = ReplayHandler::new(&recorder.recording());
+        replay.set_match_mode(MatchMode::Sequential);
+        let address = VehicleSimulator::build_replay_server(&config, replay).unwrap().serve_loopback();
+        let mut client = UdsClient::new(DoIPClientSession::connect(address, 0x0E80).unwrap(), 0x1001);
+        assert_eq!(client.read_did(0xF190).unwrap(), vin);
+        assert!(matches!(
+            client.read_did(0xF190),
+            Err(UdsError::NegativeResponse { service: 0x22, nrc: 0x10 })
+        ));
+    }
+}
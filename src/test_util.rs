@@ -0,0 +1,256 @@
+use crate::message::{
+    decoder::FrameDecoder,
+    diag_message::{DiagMessage, DiagMessageAck, DiagMessageNAck, DiagNackCode},
+    message_factory,
+    routing_activation::{RoutingActivationCode, RoutingActivationResponse},
+    Message, MessageVariant,
+};
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/* Minimal DoIP entity answering diagnostic requests of a single tester connection */
+pub struct FakeEntity {
+    address: SocketAddr,
+    received: Arc<Mutex<Vec<DiagMessage>>>,
+}
+impl FakeEntity {
+    pub const FUNCTIONAL_ADDRESS: u16 = 0xE400;
+
+    pub fn spawn<F>(logical_address: u16, mut handler: F) -> Self
+    where
+        F: FnMut(&DiagMessage) -> Vec<(Duration, Vec<u8>)> + Send + 'static,
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                FakeEntity::serve(&mut stream, logical_address, &mut handler, &log);
+            }
+        });
+        FakeEntity { address, received }
+    }
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+    pub fn received(&self) -> Vec<DiagMessage> {
+        self.received.lock().unwrap().clone()
+    }
+    fn serve<F>(
+        stream: &mut TcpStream,
+        logical_address: u16,
+        handler: &mut F,
+        log: &Mutex<Vec<DiagMessage>>,
+    ) where
+        F: FnMut(&DiagMessage) -> Vec<(Duration, Vec<u8>)>,
+    {
+        let mut decoder = FrameDecoder::new();
+        let mut buff: [u8; 4096] = [0; 4096];
+        loop {
+            let frame = match decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => match stream.read(&mut buff) {
+                    Ok(0) | Err(_) => return,
+                    Ok(len) => {
+                        decoder.push(&buff[..len]);
+                        continue;
+                    }
+                },
+                Err(_) => return,
+            };
+            let reply = match message_factory(&frame) {
+                Ok(MessageVariant::RoutingActivationRequestVariant(request)) => {
+                    vec![(
+                        Duration::ZERO,
+                        RoutingActivationResponse::new(
+                            request.source_address,
+                            logical_address,
+                            RoutingActivationCode::RoutingActivated,
+                        )
+                        .serialize(),
+                    )]
+                }
+                Ok(MessageVariant::DiagnoticMessageVariant(request)) => {
+                    if request.target_address != logical_address
+                        && request.target_address != FakeEntity::FUNCTIONAL_ADDRESS
+                    {
+                        vec![(
+                            Duration::ZERO,
+                            DiagMessageNAck::new(
+                                request.target_address,
+                                request.source_address,
+                                DiagNackCode::UnknownTargetAddress,
+                                &[],
+                            )
+                            .serialize(),
+                        )]
+                    } else {
+                        log.lock().unwrap().push(request.clone());
+                        let ack = DiagMessageAck::new(
+                            request.target_address,
+                            request.source_address,
+                            &[],
+                        );
+                        if stream.write_all(&ack.serialize()).is_err() {
+                            return;
+                        }
+                        handler(&request)
+                            .into_iter()
+                            .map(|(delay, data)| {
+                                let response =
+                                    DiagMessage::new(logical_address, request.source_address, &data);
+                                (delay, response.serialize())
+                            })
+                            .collect()
+                    }
+                }
+                _ => Vec::new(),
+            };
+            for (delay, frame) in reply {
+                thread::sleep(delay);
+                if stream.write_all(&frame).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/* In-memory writer whose content stays readable after it was handed over, e.g. to a capture */
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/* Locally generated CA issuing certificates for TLS tests */
+#[cfg(feature = "tls")]
+pub struct TestPki {
+    issuer: rcgen::Issuer<'static, rcgen::KeyPair>,
+    ca: String,
+}
+#[cfg(feature = "tls")]
+impl TestPki {
+    pub fn new() -> Self {
+        use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "DoIP test CA");
+        let key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&key).unwrap().pem();
+        TestPki { issuer: rcgen::Issuer::new(params, key), ca }
+    }
+    pub fn ca(&self) -> &[u8] {
+        self.ca.as_bytes()
+    }
+    /* Certificate with the given common and DNS name */
+    pub fn issue(&self, name: &str) -> crate::tls::TlsIdentity {
+        let mut params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.issuer).unwrap();
+        crate::tls::TlsIdentity::from_pem(cert.pem().as_bytes(), key.serialize_pem().as_bytes()).unwrap()
+    }
+}
+
+/* Event captured by TraceRecorder, with the fields of the spans it happened in */
+#[cfg(feature = "tracing")]
+#[derive(Clone, Debug)]
+pub struct TracedEvent {
+    pub spans: Vec<&'static str>,
+    pub fields: std::collections::HashMap<&'static str, String>,
+}
+#[cfg(feature = "tracing")]
+impl TracedEvent {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+    pub fn message(&self) -> Option<&str> {
+        self.field("message")
+    }
+}
+/* Subscriber recording the events of the threads it is the default of, fields in Debug format */
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+pub struct TraceRecorder(Arc<Mutex<TraceState>>);
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+struct TraceState {
+    spans: std::collections::HashMap<u64, TracedEvent>,
+    entered: Vec<u64>,
+    events: Vec<TracedEvent>,
+}
+#[cfg(feature = "tracing")]
+struct FieldRecorder<'a>(&'a mut std::collections::HashMap<&'static str, String>);
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for FieldRecorder<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+#[cfg(feature = "tracing")]
+impl TraceRecorder {
+    pub fn capture<R>(&self, f: impl FnOnce() -> R) -> R {
+        tracing::subscriber::with_default(self.clone(), f)
+    }
+    pub fn events(&self) -> Vec<TracedEvent> {
+        self.0.lock().unwrap().events.clone()
+    }
+}
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for TraceRecorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut state = self.0.lock().unwrap();
+        let mut traced = TracedEvent { spans: vec![span.metadata().name()], fields: Default::default() };
+        span.record(&mut FieldRecorder(&mut traced.fields));
+        let id = state.spans.len() as u64 + 1;
+        state.spans.insert(id, traced);
+        tracing::span::Id::from_u64(id)
+    }
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        if let Some(traced) = self.0.lock().unwrap().spans.get_mut(&span.into_u64()) {
+            values.record(&mut FieldRecorder(&mut traced.fields));
+        }
+    }
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+    fn event(&self, event: &tracing::Event<'_>) {
+        let mut state = self.0.lock().unwrap();
+        let mut traced = TracedEvent { spans: Vec::new(), fields: Default::default() };
+        for span in state.entered.iter().filter_map(|id| state.spans.get(id)) {
+            traced.spans.extend(&span.spans);
+            traced.fields.extend(span.fields.iter().map(|(name, value)| (*name, value.clone())));
+        }
+        event.record(&mut FieldRecorder(&mut traced.fields));
+        state.events.push(traced);
+    }
+    fn enter(&self, span: &tracing::span::Id) {
+        self.0.lock().unwrap().entered.push(span.into_u64());
+    }
+    fn exit(&self, span: &tracing::span::Id) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(position) = state.entered.iter().rposition(|id| *id == span.into_u64()) {
+            state.entered.remove(position);
+        }
+    }
+}
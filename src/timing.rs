@@ -0,0 +1,143 @@
+use std::{fmt, ops::RangeInclusive, time::Duration};
+
+/* ISO 13400-2 timing parameters, defaulting to the values of the standard. Entities and testers may
+ * use shorter values, e.g. for tests, but none beyond the limits of the standard unless non-ISO
+ * values are allowed explicitly. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimingParameters {
+    /* Tester timeout for responses to control messages, e.g. routing activation */
+    pub a_doip_ctrl: Duration,
+    /* Upper bound of the random wait before the first vehicle announcement */
+    pub a_doip_announce_wait: Duration,
+    pub a_doip_announce_interval: Duration,
+    pub a_doip_announce_num: u8,
+    /* Tester timeout for the acknowledgement of a diagnostic message */
+    pub a_doip_diagnostic_message: Duration,
+    /* Inactivity before the entity closes a connection with routing activated */
+    pub t_tcp_general_inactivity: Duration,
+    /* Inactivity before the entity closes a connection without routing activation */
+    pub t_tcp_initial_inactivity: Duration,
+    /* Tester response time to the alive checks of an entity out of sockets */
+    pub t_tcp_alive_check: Duration,
+    /* Time the entity may take to acknowledge a diagnostic message */
+    pub a_processing_time: Duration,
+    /* Lifts the upper limits of the standard, e.g. for the values of an OEM specification */
+    pub allow_non_iso: bool,
+}
+impl TimingParameters {
+    const MIN_TIMEOUT: Duration = Duration::from_millis(1);
+    /* Bound of non-ISO values, keeping the deadlines computed from them representable */
+    const MAX_NON_ISO: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn validate(&self) -> Result<(), TimingError> {
+        let max = |iso| if self.allow_non_iso { TimingParameters::MAX_NON_ISO } else { iso };
+        let timeout = |iso| TimingParameters::MIN_TIMEOUT..=max(iso);
+        let announce_wait = Duration::ZERO..=max(Duration::from_millis(500));
+        let checks = [
+            ("A_DoIP_Ctrl", self.a_doip_ctrl, timeout(Duration::from_secs(2))),
+            ("A_DoIP_Announce_Wait", self.a_doip_announce_wait, announce_wait),
+            ("A_DoIP_Announce_Interval", self.a_doip_announce_interval, timeout(Duration::from_millis(500))),
+            ("A_DoIP_Diagnostic_Message", self.a_doip_diagnostic_message, timeout(Duration::from_secs(2))),
+            ("T_TCP_General_Inactivity", self.t_tcp_general_inactivity, timeout(Duration::from_secs(5 * 60))),
+            ("T_TCP_Initial_Inactivity", self.t_tcp_initial_inactivity, timeout(Duration::from_secs(2))),
+            ("T_TCP_Alive_Check", self.t_tcp_alive_check, timeout(Duration::from_millis(500))),
+            ("A_Processing_Time", self.a_processing_time, timeout(Duration::from_secs(2))),
+        ];
+        for (parameter, value, range) in checks {
+            if !range.contains(&value) {
+                return Err(TimingError::OutOfRange { parameter, value, range });
+            }
+        }
+        let max_announce_num = if self.allow_non_iso { u8::MAX } else { 3 };
+        if !(1..=max_announce_num).contains(&self.a_doip_announce_num) {
+            return Err(TimingError::AnnounceNum { value: self.a_doip_announce_num, max: max_announce_num });
+        }
+        Ok(())
+    }
+}
+impl Default for TimingParameters {
+    fn default() -> Self {
+        TimingParameters {
+            a_doip_ctrl: Duration::from_secs(2),
+            a_doip_announce_wait: Duration::from_millis(500),
+            a_doip_announce_interval: Duration::from_millis(500),
+            a_doip_announce_num: 3,
+            a_doip_diagnostic_message: Duration::from_secs(2),
+            t_tcp_general_inactivity: Duration::from_secs(5 * 60),
+            t_tcp_initial_inactivity: Duration::from_secs(2),
+            t_tcp_alive_check: Duration::from_millis(500),
+            a_processing_time: Duration::from_secs(2),
+            allow_non_iso: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TimingError {
+    OutOfRange { parameter: &'static str, value: Duration, range: RangeInclusive<Duration> },
+    AnnounceNum { value: u8, max: u8 },
+}
+impl fmt::Display for TimingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimingError::OutOfRange { parameter, value, range } => {
+                write!(f, "{} of {:?} outside of {:?} to {:?}", parameter, value, range.start(), range.end())
+            }
+            TimingError::AnnounceNum { value, max } => {
+                write!(f, "A_DoIP_Announce_Num of {} outside of 1 to {}", value, max)
+            }
+        }
+    }
+}
+impl std::error::Error for TimingError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_iso_defaults_and_shorter_values() {
+        assert_eq!(TimingParameters::default().validate(), Ok(()));
+        let timing = TimingParameters {
+            a_doip_announce_wait: Duration::ZERO,
+            a_doip_announce_interval: Duration::from_millis(10),
+            a_doip_announce_num: 1,
+            t_tcp_initial_inactivity: Duration::from_millis(100),
+            ..Default::default()
+        };
+        assert_eq!(timing.validate(), Ok(()));
+    }
+    #[test]
+    fn reject_values_beyond_the_standard() {
+        let initial_inactivity = Duration::from_secs(3);
+        let timing = TimingParameters { t_tcp_initial_inactivity: initial_inactivity, ..Default::default() };
+        assert_eq!(
+            timing.validate(),
+            Err(TimingError::OutOfRange {
+                parameter: "T_TCP_Initial_Inactivity",
+                value: Duration::from_secs(3),
+                range: Duration::from_millis(1)..=Duration::from_secs(2),
+            })
+        );
+        let timing = TimingParameters { a_doip_ctrl: Duration::ZERO, ..Default::default() };
+        assert!(timing.validate().is_err());
+        let timing = TimingParameters { a_doip_announce_num: 0, ..Default::default() };
+        assert_eq!(timing.validate(), Err(TimingError::AnnounceNum { value: 0, max: 3 }));
+    }
+    #[test]
+    fn accept_values_beyond_the_standard_when_allowed() {
+        let timing = TimingParameters {
+            a_doip_ctrl: Duration::from_secs(5),
+            a_doip_announce_wait: Duration::from_secs(1),
+            a_doip_announce_num: 5,
+            t_tcp_initial_inactivity: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert!(timing.validate().is_err());
+        assert_eq!(TimingParameters { allow_non_iso: true, ..timing }.validate(), Ok(()));
+        let timing = TimingParameters { allow_non_iso: true, a_doip_announce_num: 0, ..timing };
+        assert_eq!(timing.validate(), Err(TimingError::AnnounceNum { value: 0, max: u8::MAX }));
+        let timing = TimingParameters { allow_non_iso: true, a_doip_ctrl: Duration::ZERO, ..timing };
+        assert!(timing.validate().is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::transport::DoIPStream;
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{VerifierBuilderError, WebPkiClientVerifier},
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::{fmt, fs, io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(io::Error),
+    Pem(rustls::pki_types::pem::Error),
+    Rustls(rustls::Error),
+    Verifier(VerifierBuilderError),
+    /* PEM input without any certificate */
+    NoCertificate,
+}
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(err) => write!(f, "I/O error: {}", err),
+            TlsError::Pem(err) => write!(f, "invalid PEM input: {}", err),
+            TlsError::Rustls(err) => write!(f, "TLS error: {}", err),
+            TlsError::Verifier(err) => write!(f, "invalid client verification: {}", err),
+            TlsError::NoCertificate => write!(f, "no certificate found"),
+        }
+    }
+}
+impl std::error::Error for TlsError {}
+impl From<io::Error> for TlsError {
+    fn from(err: io::Error) -> Self {
+        TlsError::Io(err)
+    }
+}
+impl From<rustls::pki_types::pem::Error> for TlsError {
+    fn from(err: rustls::pki_types::pem::Error) -> Self {
+        TlsError::Pem(err)
+    }
+}
+impl From<rustls::Error> for TlsError {
+    fn from(err: rustls::Error) -> Self {
+        TlsError::Rustls(err)
+    }
+}
+impl From<VerifierBuilderError> for TlsError {
+    fn from(err: VerifierBuilderError) -> Self {
+        TlsError::Verifier(err)
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+fn certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certificates = CertificateDer::pem_slice_iter(pem).collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate);
+    }
+    Ok(certificates)
+}
+fn root_store(roots: &[CertificateDer<'static>]) -> Result<RootCertStore, TlsError> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root.clone())?;
+    }
+    Ok(store)
+}
+
+/* Certificate chain and private key presented during the handshake */
+pub struct TlsIdentity {
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+impl TlsIdentity {
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<Self, TlsError> {
+        Ok(TlsIdentity { cert_chain: certificates(cert_chain)?, key: PrivateKeyDer::from_pem_slice(key)? })
+    }
+    pub fn from_pem_files<P: AsRef<Path>>(cert_chain: P, key: P) -> Result<Self, TlsError> {
+        TlsIdentity::from_pem(&fs::read(cert_chain)?, &fs::read(key)?)
+    }
+}
+
+/* Tester identity taken from its client certificate */
+#[derive(Clone, Debug, PartialEq)]
+pub enum CertificateIdentity {
+    /* Subject distinguished name, e.g. "CN=Workshop Tester, O=Garage" */
+    Subject(String),
+    /* SHA-256 digest of the DER encoded certificate */
+    Fingerprint([u8; 32]),
+}
+impl CertificateIdentity {
+    pub fn matches(&self, certificate: &[u8]) -> bool {
+        match self {
+            CertificateIdentity::Subject(subject) => {
+                certificate_subject(certificate).is_some_and(|name| &name == subject)
+            }
+            CertificateIdentity::Fingerprint(fingerprint) => {
+                certificate_fingerprint(certificate) == *fingerprint
+            }
+        }
+    }
+}
+pub fn certificate_subject(certificate: &[u8]) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    Some(certificate.subject().to_string())
+}
+pub fn certificate_fingerprint(certificate: &[u8]) -> [u8; 32] {
+    let mut fingerprint = [0; 32];
+    fingerprint.copy_from_slice(::ring::digest::digest(&::ring::digest::SHA256, certificate).as_ref());
+    fingerprint
+}
+
+/* Source addresses and activation types a certificate holder may activate routing with */
+#[derive(Clone, Debug)]
+pub struct TesterAuthorization {
+    pub identity: CertificateIdentity,
+    pub source_addresses: Vec<u16>,
+    pub activation_types: Vec<u8>,
+}
+
+/* TLS settings of a DoIP entity, passed to the server builder once built */
+pub struct TlsServerConfig {
+    identity: TlsIdentity,
+    client_roots: Option<Vec<CertificateDer<'static>>>,
+    client_auth_optional: bool,
+}
+impl TlsServerConfig {
+    pub fn new(identity: TlsIdentity) -> Self {
+        TlsServerConfig { identity, client_roots: None, client_auth_optional: false }
+    }
+    /* Verifies tester certificates against the given CA certificates */
+    pub fn set_client_roots(&mut self, roots: &[u8]) -> Result<&mut Self, TlsError> {
+        self.client_roots = Some(certificates(roots)?);
+        Ok(self)
+    }
+    /* Also accepts testers presenting no certificate at all */
+    pub fn set_client_auth_optional(&mut self, optional: bool) -> &mut Self {
+        self.client_auth_optional = optional;
+        self
+    }
+    pub fn build(&self) -> Result<Arc<ServerConfig>, TlsError> {
+        let builder = ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+        let builder = match &self.client_roots {
+            None => builder.with_no_client_auth(),
+            Some(roots) => {
+                let roots = Arc::new(root_store(roots)?);
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider());
+                let verifier = match self.client_auth_optional {
+                    true => verifier.allow_unauthenticated(),
+                    false => verifier,
+                };
+                builder.with_client_cert_verifier(verifier.build()?)
+            }
+        };
+        let identity = &self.identity;
+        let config = builder.with_single_cert(identity.cert_chain.clone(), identity.key.clone_key())?;
+        Ok(Arc::new(config))
+    }
+}
+
+/* TLS settings of a tester: trusted entity CAs and an optional client certificate */
+pub struct TlsClientConfig {
+    roots: Vec<CertificateDer<'static>>,
+    identity: Option<TlsIdentity>,
+}
+impl TlsClientConfig {
+    pub fn new(roots: &[u8]) -> Result<Self, TlsError> {
+        Ok(TlsClientConfig { roots: certificates(roots)?, identity: None })
+    }
+    pub fn set_identity(&mut self, identity: TlsIdentity) -> &mut Self {
+        self.identity = Some(identity);
+        self
+    }
+    pub fn build(&self) -> Result<Arc<ClientConfig>, TlsError> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store(&self.roots)?);
+        let config = match &self.identity {
+            None => builder.with_no_client_auth(),
+            Some(identity) => {
+                builder.with_client_auth_cert(identity.cert_chain.clone(), identity.key.clone_key())?
+            }
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/* Socket operations of TLS streams, which pass through to the underlying stream */
+macro_rules! delegate_to_sock {
+    () => {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.sock.peer_addr()
+        }
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.sock.local_addr()
+        }
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.sock.set_read_timeout(timeout)
+        }
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.sock.set_write_timeout(timeout)
+        }
+        fn shutdown(&self) -> io::Result<()> {
+            self.sock.shutdown()
+        }
+        fn is_tls(&self) -> bool {
+            true
+        }
+    };
+}
+impl<S: DoIPStream> DoIPStream for StreamOwned<ServerConnection, S> {
+    delegate_to_sock!();
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        self.conn.peer_certificates()?.first().map(|certificate| certificate.as_ref())
+    }
+}
+impl<S: DoIPStream> DoIPStream for StreamOwned<ClientConnection, S> {
+    delegate_to_sock!();
+}
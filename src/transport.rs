@@ -0,0 +1,111 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    time::Duration,
+};
+
+pub mod impairment;
+pub mod memory;
+
+/* Byte stream carrying DoIP frames between tester and entity, e.g. plain TCP, TLS protected or in memory.
+ * Read timeouts surface as WouldBlock or TimedOut errors, like those of TcpStream. */
+pub trait DoIPStream: Read + Write + Send {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /* Closes both directions, the peer reads the end of the stream */
+    fn shutdown(&self) -> io::Result<()>;
+    fn is_tls(&self) -> bool {
+        false
+    }
+    /* End-entity certificate presented by the peer */
+    #[cfg(feature = "tls")]
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        None
+    }
+}
+impl DoIPStream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+impl DoIPStream for Box<dyn DoIPStream> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+    fn is_tls(&self) -> bool {
+        (**self).is_tls()
+    }
+    #[cfg(feature = "tls")]
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        (**self).peer_certificate()
+    }
+}
+
+/* Source of tester connections, polled by the server: accept returns WouldBlock while none is waiting
+ * once set to non-blocking */
+pub trait StreamListener: Send {
+    fn accept(&self) -> io::Result<Box<dyn DoIPStream>>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+impl StreamListener for TcpListener {
+    fn accept(&self) -> io::Result<Box<dyn DoIPStream>> {
+        let (stream, _) = TcpListener::accept(self)?;
+        stream.set_nonblocking(false)?;
+        Ok(Box::new(stream))
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+/* Socket for vehicle identification and announcements */
+pub trait DatagramSocket: Send {
+    fn send_to(&self, datagram: &[u8], destination: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buff: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+impl DatagramSocket for UdpSocket {
+    fn send_to(&self, datagram: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, datagram, destination)
+    }
+    fn recv_from(&self, buff: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buff)
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
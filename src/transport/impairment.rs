@@ -0,0 +1,507 @@
+use super::{DatagramSocket, DoIPStream, StreamListener};
+use crate::clock::{self, Clock, SystemClock};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/* Network conditions imposed on what arrives at an impaired stream or socket, e.g. a slow gateway or
+ * lossy workshop Wi-Fi. Wrapping both ends of a connection impairs both directions. Delays pass on the
+ * clock of the impaired end, the system clock unless set, so a manual clock runs scenarios without
+ * waiting. */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Impairment {
+    /* Delay of every segment and datagram */
+    pub latency: Duration,
+    /* Upper bound of the uniformly random delay added to the latency */
+    pub jitter: Duration,
+    /* Bytes per second, unlimited if unset */
+    pub bandwidth: Option<u64>,
+    /* Upper bound of the random segment sizes a stream delivers in, 1 for single bytes */
+    pub max_segment_size: Option<usize>,
+    /* Probability of a datagram getting lost */
+    pub loss: f64,
+    /* Probability of a datagram arriving twice */
+    pub duplication: f64,
+    /* Bytes a stream delivers before its connection is reset */
+    pub reset_after: Option<usize>,
+    /* Seed of the random choices, for reproducible scenarios; chosen by the system if unset */
+    pub seed: Option<u64>,
+}
+
+/* Schedules arriving data according to an impairment, on the clock of the link */
+struct Link {
+    impairment: Impairment,
+    rng: StdRng,
+    clock: Arc<dyn Clock>,
+    /* End of the transmission of the data scheduled last, bounding the bandwidth */
+    busy_until: Instant,
+}
+impl Link {
+    fn new(impairment: Impairment, clock: Arc<dyn Clock>) -> Self {
+        let rng = match impairment.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Link { impairment, rng, busy_until: clock.now(), clock }
+    }
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.busy_until = clock.now();
+        self.clock = clock;
+    }
+    /* Time at which len bytes arriving now get delivered */
+    fn schedule(&mut self, len: usize) -> Instant {
+        let transmission = match self.impairment.bandwidth {
+            Some(bandwidth) if bandwidth > 0 => Duration::from_secs_f64(len as f64 / bandwidth as f64),
+            _ => Duration::ZERO,
+        };
+        self.busy_until = self.busy_until.max(self.clock.now()) + transmission;
+        let jitter = match self.impairment.jitter {
+            Duration::ZERO => Duration::ZERO,
+            jitter => self.rng.gen_range(Duration::ZERO..=jitter),
+        };
+        self.busy_until + self.impairment.latency + jitter
+    }
+    fn segment_size(&mut self, remaining: usize) -> usize {
+        match self.impairment.max_segment_size {
+            Some(max) => self.rng.gen_range(1..=max.max(1)).min(remaining),
+            None => remaining,
+        }
+    }
+    fn occurs(&mut self, probability: f64) -> bool {
+        self.rng.gen::<f64>() < probability
+    }
+}
+/* Timeout of a read of the underlying transport waking up at the given time on the clock, if any */
+fn timeout_until(clock: &dyn Clock, wake: Option<Instant>) -> Option<Duration> {
+    /* Zero timeouts are rejected by sockets */
+    wake.map(|wake| clock::wait_slice(clock, wake).unwrap_or_default().max(Duration::from_millis(1)))
+}
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/* Stream delivering what it receives delayed, throttled and in segments of random size, in order, and
+ * resetting the connection once the configured number of bytes was delivered */
+pub struct ImpairedStream<S> {
+    inner: S,
+    link: Link,
+    segments: VecDeque<(Instant, Vec<u8>)>,
+    read_timeout: Mutex<Option<Duration>>,
+    delivered: usize,
+    end_of_stream: bool,
+    reset: bool,
+}
+impl<S: DoIPStream> ImpairedStream<S> {
+    pub fn new(inner: S, impairment: Impairment) -> Self {
+        ImpairedStream {
+            inner,
+            link: Link::new(impairment, Arc::new(SystemClock)),
+            segments: VecDeque::new(),
+            read_timeout: Mutex::new(None),
+            delivered: 0,
+            end_of_stream: false,
+            reset: false,
+        }
+    }
+    /* Time source of the delays and the read timeout */
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.link.set_clock(clock);
+        self
+    }
+    /* Resets the connection once the limit is reached; the peer reads the end of the stream */
+    fn check_reset(&mut self) -> io::Result<()> {
+        if !self.reset && self.link.impairment.reset_after == Some(self.delivered) {
+            self.reset = true;
+            let _ = self.inner.shutdown();
+        }
+        match self.reset {
+            true => Err(io::ErrorKind::ConnectionReset.into()),
+            false => Ok(()),
+        }
+    }
+    fn receive(&mut self, data: &[u8]) {
+        let mut data = data;
+        while !data.is_empty() {
+            let (segment, rest) = data.split_at(self.link.segment_size(data.len()));
+            /* Jitter must not reorder the stream */
+            let mut due = self.link.schedule(segment.len());
+            if let Some((last, _)) = self.segments.back() {
+                due = due.max(*last);
+            }
+            self.segments.push_back((due, segment.to_vec()));
+            data = rest;
+        }
+    }
+    fn deliver(&mut self, buff: &mut [u8]) -> usize {
+        let Some((due, mut segment)) = self.segments.pop_front() else {
+            return 0;
+        };
+        let mut len = segment.len().min(buff.len());
+        if let Some(limit) = self.link.impairment.reset_after {
+            len = len.min(limit - self.delivered);
+        }
+        buff[..len].copy_from_slice(&segment[..len]);
+        segment.drain(..len);
+        if !segment.is_empty() {
+            self.segments.push_front((due, segment));
+        }
+        self.delivered += len;
+        len
+    }
+}
+impl<S: DoIPStream> Read for ImpairedStream<S> {
+    fn read(&mut self, buff: &mut [u8]) -> io::Result<usize> {
+        let clock = self.link.clock.clone();
+        let deadline = self.read_timeout.lock().unwrap().map(|timeout| clock.now() + timeout);
+        let mut received = [0; 4096];
+        loop {
+            self.check_reset()?;
+            let now = clock.now();
+            let due = self.segments.front().map(|(due, _)| *due);
+            if buff.is_empty() || due.is_some_and(|due| due <= now) {
+                return Ok(self.deliver(buff));
+            }
+            if self.end_of_stream && due.is_none() {
+                return Ok(0);
+            }
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let wake = [due, deadline].into_iter().flatten().min();
+            if self.end_of_stream {
+                thread::sleep(timeout_until(&*clock, wake).unwrap_or_default());
+                continue;
+            }
+            self.inner.set_read_timeout(timeout_until(&*clock, wake))?;
+            match self.inner.read(&mut received) {
+                Ok(0) => self.end_of_stream = true,
+                Ok(len) => self.receive(&received[..len]),
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+impl<S: DoIPStream> Write for ImpairedStream<S> {
+    fn write(&mut self, buff: &[u8]) -> io::Result<usize> {
+        self.check_reset()?;
+        self.inner.write(buff)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<S: DoIPStream> DoIPStream for ImpairedStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+    fn is_tls(&self) -> bool {
+        self.inner.is_tls()
+    }
+    #[cfg(feature = "tls")]
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        self.inner.peer_certificate()
+    }
+}
+
+/* Listener impairing every connection it accepts, each with its own seed derived from the configured one */
+pub struct ImpairedListener<L> {
+    inner: L,
+    impairment: Impairment,
+    clock: Arc<dyn Clock>,
+    accepted: AtomicU64,
+}
+impl<L: StreamListener> ImpairedListener<L> {
+    pub fn new(inner: L, impairment: Impairment) -> Self {
+        ImpairedListener { inner, impairment, clock: Arc::new(SystemClock), accepted: AtomicU64::new(0) }
+    }
+    /* Time source of the streams accepted from now on */
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+}
+impl<L: StreamListener> StreamListener for ImpairedListener<L> {
+    fn accept(&self) -> io::Result<Box<dyn DoIPStream>> {
+        let stream = self.inner.accept()?;
+        let accepted = self.accepted.fetch_add(1, Ordering::SeqCst);
+        let seed = self.impairment.seed.map(|seed| seed.wrapping_add(accepted));
+        let mut stream = ImpairedStream::new(stream, Impairment { seed, ..self.impairment.clone() });
+        stream.set_clock(self.clock.clone());
+        Ok(Box::new(stream))
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+}
+
+/* Socket losing, duplicating, delaying and throttling the datagrams it receives; jitter reorders them */
+pub struct ImpairedSocket<D> {
+    inner: D,
+    link: Mutex<Link>,
+    pending: Mutex<Vec<(Instant, Vec<u8>, SocketAddr)>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+impl<D: DatagramSocket> ImpairedSocket<D> {
+    pub fn new(inner: D, impairment: Impairment) -> Self {
+        ImpairedSocket {
+            inner,
+            link: Mutex::new(Link::new(impairment, Arc::new(SystemClock))),
+            pending: Mutex::new(Vec::new()),
+            read_timeout: Mutex::new(None),
+        }
+    }
+    /* Time source of the delays and the read timeout */
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.link.get_mut().unwrap().set_clock(clock);
+        self
+    }
+    fn receive(&self, datagram: &[u8], source: SocketAddr) {
+        let mut link = self.link.lock().unwrap();
+        let loss = link.impairment.loss;
+        let duplication = link.impairment.duplication;
+        if link.occurs(loss) {
+            return;
+        }
+        let copies = if link.occurs(duplication) { 2 } else { 1 };
+        for _ in 0..copies {
+            let due = link.schedule(datagram.len());
+            self.pending.lock().unwrap().push((due, datagram.to_vec(), source));
+        }
+    }
+}
+impl<D: DatagramSocket> DatagramSocket for ImpairedSocket<D> {
+    fn send_to(&self, datagram: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(datagram, destination)
+    }
+    fn recv_from(&self, buff: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let clock = self.link.lock().unwrap().clock.clone();
+        let deadline = self.read_timeout.lock().unwrap().map(|timeout| clock.now() + timeout);
+        let mut received = vec![0; 65536];
+        loop {
+            let now = clock.now();
+            {
+                let mut pending = self.pending.lock().unwrap();
+                let next = (0..pending.len()).min_by_key(|index| pending[*index].0);
+                if let Some(index) = next.filter(|index| pending[*index].0 <= now) {
+                    let (_, datagram, source) = pending.remove(index);
+                    /* Excess bytes are discarded like those of a UDP datagram larger than the buffer */
+                    let len = datagram.len().min(buff.len());
+                    buff[..len].copy_from_slice(&datagram[..len]);
+                    return Ok((len, source));
+                }
+                if deadline.is_some_and(|deadline| deadline <= now) {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                let wake = [next.map(|index| pending[index].0), deadline].into_iter().flatten().min();
+                self.inner.set_read_timeout(timeout_until(&*clock, wake))?;
+            }
+            match self.inner.recv_from(&mut received) {
+                Ok((len, source)) => self.receive(&received[..len], source),
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::doip_client::{DoIPClient, SessionError};
+    use crate::doip_server::{DiagnosticHandler, DiagnosticResponse, DoIPServerBuilder};
+    use crate::message::{alive_check::AliveCheckResponse, decoder::FrameDecoder, Message};
+    use crate::timing::TimingParameters;
+    use crate::transport::memory::{MemoryNetwork, MemoryStream};
+    use std::net::Ipv4Addr;
+
+    const ENTITY: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 0, 10), 13400);
+
+    /* Tester end impaired, entity end plain */
+    fn stream_pair(
+        network: &MemoryNetwork,
+        impairment: Impairment,
+    ) -> (ImpairedStream<MemoryStream>, Box<dyn DoIPStream>) {
+        let listener = network.listen(ENTITY.into()).unwrap();
+        let stream = network.connect(ENTITY.into()).unwrap();
+        (ImpairedStream::new(stream, impairment), listener.accept().unwrap())
+    }
+    struct EchoHandler;
+    impl DiagnosticHandler for EchoHandler {
+        fn is_target_known(&self, _: u16) -> bool {
+            true
+        }
+        fn handle_request(&mut self, _: u16, target_address: u16, request: &[u8]) -> Vec<DiagnosticResponse> {
+            vec![DiagnosticResponse::new(target_address, request.to_vec())]
+        }
+    }
+
+    #[test]
+    fn segment_stream_at_arbitrary_boundaries() {
+        let network = MemoryNetwork::new();
+        let impairment = Impairment { max_segment_size: Some(3), seed: Some(7), ..Default::default() };
+        let (mut stream, mut entity) = stream_pair(&network, impairment);
+        let mut frames = AliveCheckResponse::new(0x0E80).serialize();
+        frames.extend(AliveCheckResponse::new(0x0E81).serialize());
+        entity.write_all(&frames).unwrap();
+        let mut decoder = FrameDecoder::new();
+        let mut buff = [0; 64];
+        let mut decoded = Vec::new();
+        while decoded.len() < 2 {
+            let len = stream.read(&mut buff).unwrap();
+            assert!((1..=3).contains(&len));
+            decoder.push(&buff[..len]);
+            decoded.extend(decoder.next_frame().unwrap());
+        }
+        assert_eq!(decoded.concat(), frames);
+    }
+    #[test]
+    fn delay_by_latency_and_bandwidth() {
+        let network = MemoryNetwork::new();
+        let impairment =
+            Impairment { latency: Duration::from_millis(50), bandwidth: Some(1000), ..Default::default() };
+        let (mut stream, mut entity) = stream_pair(&network, impairment);
+        let start = Instant::now();
+        entity.write_all(&[0; 50]).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let mut buff = [0; 64];
+        assert_eq!(stream.read(&mut buff).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        stream.read_exact(&mut buff[..50]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+    #[test]
+    fn delay_on_manual_clock() {
+        let network = MemoryNetwork::new();
+        let clock = Arc::new(ManualClock::new());
+        let impairment = Impairment { latency: Duration::from_secs(10), ..Default::default() };
+        let (mut stream, mut entity) = stream_pair(&network, impairment.clone());
+        stream.set_clock(clock.clone());
+        let mut socket = ImpairedSocket::new(network.bind(ENTITY.into()).unwrap(), impairment);
+        socket.set_clock(clock.clone());
+        let sender = network.bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        entity.write_all(&[1, 2, 3]).unwrap();
+        sender.send_to(&[4], ENTITY.into()).unwrap();
+        let stream = thread::spawn(move || {
+            let mut buff = [0; 8];
+            let len = stream.read(&mut buff).unwrap();
+            buff[..len].to_vec()
+        });
+        let socket = thread::spawn(move || {
+            let mut buff = [0; 8];
+            let (len, _) = socket.recv_from(&mut buff).unwrap();
+            buff[..len].to_vec()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!stream.is_finished() && !socket.is_finished());
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(stream.join().unwrap(), [1, 2, 3]);
+        assert_eq!(socket.join().unwrap(), [4]);
+    }
+    #[test]
+    fn reset_connection_after_bytes() {
+        let network = MemoryNetwork::new();
+        let impairment = Impairment { reset_after: Some(10), ..Default::default() };
+        let (mut stream, mut entity) = stream_pair(&network, impairment);
+        entity.write_all(&[0; 20]).unwrap();
+        let mut buff = [0; 64];
+        stream.read_exact(&mut buff[..10]).unwrap();
+        assert_eq!(stream.read(&mut buff).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(stream.write(&[0]).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+        assert!(entity.write_all(&[0]).is_err());
+    }
+    #[test]
+    fn lose_and_duplicate_datagrams() {
+        let network = MemoryNetwork::new();
+        let sender = network.bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let receive = |impairment: Impairment| {
+            let socket = ImpairedSocket::new(network.bind(ENTITY.into()).unwrap(), impairment);
+            socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+            for datagram in 0..100 {
+                sender.send_to(&[datagram], ENTITY.into()).unwrap();
+            }
+            let mut buff = [0; 8];
+            std::iter::from_fn(|| socket.recv_from(&mut buff).ok().map(|_| buff[0])).collect::<Vec<u8>>()
+        };
+        assert!(receive(Impairment { loss: 1.0, ..Default::default() }).is_empty());
+        let received = receive(Impairment { duplication: 1.0, ..Default::default() });
+        assert_eq!(received, (0..100).flat_map(|datagram| [datagram, datagram]).collect::<Vec<u8>>());
+        let received = receive(Impairment { loss: 0.5, seed: Some(7), ..Default::default() });
+        assert!((20..80).contains(&received.len()));
+        let jitter = Impairment { jitter: Duration::from_millis(20), seed: Some(7), ..Default::default() };
+        let mut received = receive(jitter);
+        assert_ne!(received, (0..100).collect::<Vec<u8>>());
+        received.sort();
+        assert_eq!(received, (0..100).collect::<Vec<u8>>());
+    }
+    #[test]
+    fn route_diagnostic_messages_over_impaired_network() {
+        let network = MemoryNetwork::new();
+        let impairment = Impairment {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            max_segment_size: Some(2),
+            seed: Some(7),
+            ..Default::default()
+        };
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000).set_diagnostic_handler(EchoHandler);
+        let listener = ImpairedListener::new(network.listen(ENTITY.into()).unwrap(), impairment.clone());
+        let handle = builder.get_server().serve(Box::new(listener), None).unwrap();
+        let stream = ImpairedStream::new(network.connect(ENTITY.into()).unwrap(), impairment);
+        let mut session = DoIPClient::default().connect_over(stream, 0x0E80, 0).unwrap();
+        for request in [[0x22, 0xF1, 0x90], [0x22, 0xF1, 0x8C]] {
+            session.send_diagnostic(0x1001, &request).unwrap();
+            assert_eq!(session.receive_diagnostic(Duration::from_secs(1)).unwrap().user_data, request);
+        }
+        handle.shutdown();
+    }
+    #[test]
+    fn time_out_on_slow_gateway() {
+        let network = MemoryNetwork::new();
+        let mut builder = DoIPServerBuilder::new();
+        builder.set_logical_address(0x1000);
+        let listener = Box::new(network.listen(ENTITY.into()).unwrap());
+        let handle = builder.get_server().serve(listener, None).unwrap();
+        let impairment = Impairment { latency: Duration::from_millis(300), ..Default::default() };
+        let stream = ImpairedStream::new(network.connect(ENTITY.into()).unwrap(), impairment);
+        let mut client = DoIPClient::default();
+        let timing = TimingParameters { a_doip_ctrl: Duration::from_millis(100), ..Default::default() };
+        client.set_timing(timing).unwrap();
+        assert!(matches!(client.connect_over(stream, 0x0E80, 0), Err(SessionError::Timeout)));
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,369 @@
+use super::{DatagramSocket, DoIPStream, StreamListener};
+use crate::clock::{self, Clock, SystemClock};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+/* In-process network of listeners, streams and datagram sockets addressed like their IP counterparts,
+ * wiring testers and entities together without binding real ports. Addresses with an unspecified IP
+ * receive for all addresses of their family, datagrams to the broadcast or a multicast address reach
+ * every socket on the port. Read timeouts run on the clock of the network, the system clock if unset. */
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+type Datagram = (Vec<u8>, SocketAddr);
+#[derive(Default)]
+struct NetworkState {
+    listeners: HashMap<SocketAddr, mpsc::Sender<MemoryStream>>,
+    sockets: HashMap<SocketAddr, mpsc::Sender<Datagram>>,
+    next_port: u16,
+}
+impl NetworkState {
+    const EPHEMERAL_PORTS: u16 = 49152;
+
+    fn ephemeral_port(&mut self) -> u16 {
+        self.next_port = self.next_port.max(NetworkState::EPHEMERAL_PORTS).wrapping_add(1);
+        self.next_port
+    }
+    /* Picks an ephemeral port unless given one, failing for addresses in use */
+    fn allocate<T>(
+        &mut self,
+        address: SocketAddr,
+        bound: fn(&NetworkState) -> &HashMap<SocketAddr, T>,
+    ) -> io::Result<SocketAddr> {
+        let mut address = address;
+        if address.port() == 0 {
+            loop {
+                address.set_port(self.ephemeral_port());
+                if !bound(self).contains_key(&address) {
+                    return Ok(address);
+                }
+            }
+        }
+        match bound(self).contains_key(&address) {
+            true => Err(io::ErrorKind::AddrInUse.into()),
+            false => Ok(address),
+        }
+    }
+}
+/* Entry for the destination itself or, failing that, for the unspecified address of its family */
+fn lookup<T>(entries: &HashMap<SocketAddr, T>, destination: SocketAddr) -> Option<&T> {
+    let unspecified: IpAddr = match destination {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    entries.get(&destination).or_else(|| entries.get(&SocketAddr::new(unspecified, destination.port())))
+}
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        MemoryNetwork::default()
+    }
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        MemoryNetwork { clock: Some(clock), ..MemoryNetwork::default() }
+    }
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+    pub fn listen(&self, address: SocketAddr) -> io::Result<MemoryListener> {
+        let mut state = self.state.lock().unwrap();
+        let address = state.allocate(address, |state| &state.listeners)?;
+        let (sender, receiver) = mpsc::channel();
+        state.listeners.insert(address, sender);
+        Ok(MemoryListener {
+            network: self.clone(),
+            address,
+            incoming: Mutex::new(receiver),
+            nonblocking: AtomicBool::new(false),
+        })
+    }
+    pub fn connect(&self, address: SocketAddr) -> io::Result<MemoryStream> {
+        let mut state = self.state.lock().unwrap();
+        let local_ip = match address.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+            ip => ip,
+        };
+        let local = SocketAddr::new(local_ip, state.ephemeral_port());
+        let listener = lookup(&state.listeners, address).ok_or(io::ErrorKind::ConnectionRefused)?;
+        let (to_server, to_client) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let server = MemoryStream::new(to_server.clone(), to_client.clone(), address, local, self.clock());
+        listener.send(server).map_err(|_| io::ErrorKind::ConnectionRefused)?;
+        Ok(MemoryStream::new(to_client, to_server, local, address, self.clock()))
+    }
+    pub fn bind(&self, address: SocketAddr) -> io::Result<MemorySocket> {
+        let mut state = self.state.lock().unwrap();
+        let address = state.allocate(address, |state| &state.sockets)?;
+        let (sender, receiver) = mpsc::channel();
+        state.sockets.insert(address, sender);
+        Ok(MemorySocket {
+            network: self.clone(),
+            address,
+            incoming: Mutex::new(receiver),
+            read_timeout: Mutex::new(None),
+        })
+    }
+}
+
+pub struct MemoryListener {
+    network: MemoryNetwork,
+    address: SocketAddr,
+    incoming: Mutex<mpsc::Receiver<MemoryStream>>,
+    nonblocking: AtomicBool,
+}
+impl StreamListener for MemoryListener {
+    fn accept(&self) -> io::Result<Box<dyn DoIPStream>> {
+        let incoming = self.incoming.lock().unwrap();
+        let stream = match self.nonblocking.load(Ordering::SeqCst) {
+            true => incoming.try_recv().map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?,
+            false => incoming.recv().map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?,
+        };
+        Ok(Box::new(stream))
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::SeqCst);
+        Ok(())
+    }
+}
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.network.state.lock().unwrap().listeners.remove(&self.address);
+    }
+}
+
+/* One direction of a stream */
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar,
+}
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+/* Connected end of an in-memory stream, closed when dropped */
+pub struct MemoryStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    local: SocketAddr,
+    peer: SocketAddr,
+    read_timeout: Mutex<Option<Duration>>,
+    clock: Arc<dyn Clock>,
+}
+impl MemoryStream {
+    fn new(
+        incoming: Arc<Pipe>,
+        outgoing: Arc<Pipe>,
+        local: SocketAddr,
+        peer: SocketAddr,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        MemoryStream { incoming, outgoing, local, peer, read_timeout: Mutex::new(None), clock }
+    }
+}
+impl Read for MemoryStream {
+    fn read(&mut self, buff: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.lock().unwrap().map(|timeout| self.clock.now() + timeout);
+        let mut state = self.incoming.state.lock().unwrap();
+        while state.data.is_empty() && !state.closed && !buff.is_empty() {
+            state = match deadline {
+                None => self.incoming.changed.wait(state).unwrap(),
+                Some(deadline) => {
+                    let Some(timeout) = clock::wait_slice(&*self.clock, deadline) else {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    };
+                    self.incoming.changed.wait_timeout(state, timeout).unwrap().0
+                }
+            };
+        }
+        let len = buff.len().min(state.data.len());
+        for (byte, data) in buff.iter_mut().zip(state.data.drain(..len)) {
+            *byte = data;
+        }
+        Ok(len)
+    }
+}
+impl Write for MemoryStream {
+    fn write(&mut self, buff: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.data.extend(buff);
+        self.outgoing.changed.notify_all();
+        Ok(buff.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl DoIPStream for MemoryStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+    /* Writes never block */
+    fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        self.incoming.close();
+        self.outgoing.close();
+        Ok(())
+    }
+}
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+pub struct MemorySocket {
+    network: MemoryNetwork,
+    address: SocketAddr,
+    incoming: Mutex<mpsc::Receiver<Datagram>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+impl DatagramSocket for MemorySocket {
+    /* Datagrams without receiver get lost like those of UDP */
+    fn send_to(&self, datagram: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        let state = self.network.state.lock().unwrap();
+        let to_all = match destination.ip() {
+            IpAddr::V4(ip) => ip.is_broadcast() || ip.is_multicast(),
+            IpAddr::V6(ip) => ip.is_multicast(),
+        };
+        let message = || (datagram.to_vec(), self.address);
+        if to_all {
+            let receivers = state.sockets.iter().filter(|(address, _)| {
+                **address != self.address
+                    && address.port() == destination.port()
+                    && address.is_ipv4() == destination.is_ipv4()
+            });
+            for (_, receiver) in receivers {
+                let _ = receiver.send(message());
+            }
+        } else if let Some(receiver) = lookup(&state.sockets, destination) {
+            let _ = receiver.send(message());
+        }
+        Ok(datagram.len())
+    }
+    fn recv_from(&self, buff: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let clock = self.network.clock();
+        let deadline = self.read_timeout.lock().unwrap().map(|timeout| clock.now() + timeout);
+        let incoming = self.incoming.lock().unwrap();
+        let (datagram, source) = match deadline {
+            None => incoming.recv().map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?,
+            Some(deadline) => loop {
+                let timeout = clock::wait_slice(&*clock, deadline).ok_or(io::ErrorKind::WouldBlock)?;
+                match incoming.recv_timeout(timeout) {
+                    Ok(received) => break received,
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return Err(io::ErrorKind::NotConnected.into()),
+                }
+            },
+        };
+        /* Excess bytes are discarded like those of a UDP datagram larger than the buffer */
+        let len = datagram.len().min(buff.len());
+        buff[..len].copy_from_slice(&datagram[..len]);
+        Ok((len, source))
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        self.network.state.lock().unwrap().sockets.remove(&self.address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::thread;
+
+    #[test]
+    fn connect_stream_pair() {
+        let network = MemoryNetwork::new();
+        let listener = network.listen((Ipv4Addr::UNSPECIFIED, 13400).into()).unwrap();
+        assert!(network.connect((Ipv4Addr::LOCALHOST, 13401).into()).is_err());
+        let mut client = network.connect((Ipv4Addr::LOCALHOST, 13400).into()).unwrap();
+        let mut server = listener.accept().unwrap();
+        assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+        client.write_all(&[1, 2, 3]).unwrap();
+        let mut buff = [0; 8];
+        assert_eq!(server.read(&mut buff).unwrap(), 3);
+        server.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(server.read(&mut buff).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        drop(client);
+        assert_eq!(server.read(&mut buff).unwrap(), 0);
+        assert!(server.write_all(&[4]).is_err());
+    }
+    #[test]
+    fn broadcast_datagrams() {
+        let network = MemoryNetwork::new();
+        let sender = network.bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let bind = |host| network.bind((Ipv4Addr::new(127, 0, 0, host), 13400).into()).unwrap();
+        let receivers: Vec<MemorySocket> = (1..=2).map(bind).collect();
+        assert!(network.bind((Ipv4Addr::LOCALHOST, 13400).into()).is_err());
+        sender.send_to(&[1], (Ipv4Addr::BROADCAST, 13400).into()).unwrap();
+        sender.send_to(&[2], receivers[1].local_addr().unwrap()).unwrap();
+        let mut buff = [0; 8];
+        for receiver in &receivers {
+            receiver.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            assert_eq!(receiver.recv_from(&mut buff).unwrap(), (1, sender.local_addr().unwrap()));
+        }
+        assert_eq!(receivers[1].recv_from(&mut buff).unwrap().0, 1);
+        assert_eq!(buff[0], 2);
+        assert!(receivers[0].recv_from(&mut buff).is_err());
+    }
+    #[test]
+    fn read_timeouts_on_network_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let network = MemoryNetwork::with_clock(clock.clone());
+        let listener = network.listen((Ipv4Addr::LOCALHOST, 13400).into()).unwrap();
+        let mut client = network.connect((Ipv4Addr::LOCALHOST, 13400).into()).unwrap();
+        let _server = listener.accept().unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let socket = network.bind((Ipv4Addr::LOCALHOST, 13400).into()).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let stream_read = thread::spawn(move || client.read(&mut [0; 8]).unwrap_err().kind());
+        let socket_read = thread::spawn(move || socket.recv_from(&mut [0; 8]).unwrap_err().kind());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!stream_read.is_finished() && !socket_read.is_finished());
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(stream_read.join().unwrap(), io::ErrorKind::WouldBlock);
+        assert_eq!(socket_read.join().unwrap(), io::ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,80 @@
+pub mod client;
+pub mod keep_alive;
+pub mod security;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive, ToPrimitive)]
+pub enum ServiceId {
+    DiagnosticSessionControl = 0x10,
+    EcuReset = 0x11,
+    ClearDiagnosticInformation = 0x14,
+    ReadDtcInformation = 0x19,
+    ReadDataByIdentifier = 0x22,
+    SecurityAccess = 0x27,
+    CommunicationControl = 0x28,
+    WriteDataByIdentifier = 0x2E,
+    RoutineControl = 0x31,
+    RequestDownload = 0x34,
+    TransferData = 0x36,
+    RequestTransferExit = 0x37,
+    TesterPresent = 0x3E,
+    ControlDtcSetting = 0x85,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive, ToPrimitive)]
+pub enum NegativeResponseCode {
+    GeneralReject = 0x10,
+    ServiceNotSupported = 0x11,
+    SubFunctionNotSupported = 0x12,
+    IncorrectMessageLengthOrInvalidFormat = 0x13,
+    ResponseTooLong = 0x14,
+    BusyRepeatRequest = 0x21,
+    ConditionsNotCorrect = 0x22,
+    RequestSequenceError = 0x24,
+    NoResponseFromSubnetComponent = 0x25,
+    FailurePreventsExecutionOfRequestedAction = 0x26,
+    RequestOutOfRange = 0x31,
+    SecurityAccessDenied = 0x33,
+    InvalidKey = 0x35,
+    ExceededNumberOfAttempts = 0x36,
+    RequiredTimeDelayNotExpired = 0x37,
+    UploadDownloadNotAccepted = 0x70,
+    TransferDataSuspended = 0x71,
+    GeneralProgrammingFailure = 0x72,
+    WrongBlockSequenceCounter = 0x73,
+    RequestCorrectlyReceivedResponsePending = 0x78,
+    SubFunctionNotSupportedInActiveSession = 0x7E,
+    ServiceNotSupportedInActiveSession = 0x7F,
+}
+
+pub const NEGATIVE_RESPONSE_SID: u8 = 0x7F;
+pub const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+pub const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+pub const DEFAULT_SESSION: u8 = 0x01;
+pub const PROGRAMMING_SESSION: u8 = 0x02;
+pub const EXTENDED_SESSION: u8 = 0x03;
+
+pub fn positive_response_sid(service: u8) -> u8 {
+    service.wrapping_add(POSITIVE_RESPONSE_OFFSET)
+}
+
+/* Returns the rejected service and the negative response code of a 0x7F response */
+pub fn parse_negative_response(response: &[u8]) -> Option<(u8, u8)> {
+    match response {
+        [NEGATIVE_RESPONSE_SID, service, nrc, ..] => Some((*service, *nrc)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_negative_response_ok() {
+        assert_eq!(parse_negative_response(&[0x7F, 0x22, 0x31]), Some((0x22, 0x31)));
+        assert_eq!(parse_negative_response(&[0x62, 0xF1, 0x90]), None);
+        assert_eq!(parse_negative_response(&[0x7F, 0x22]), None);
+    }
+}
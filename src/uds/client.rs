@@ -0,0 +1,501 @@
+use crate::clock;
+use crate::doip_client::{DoIPClientSession, SessionError};
+use crate::uds::{
+    keep_alive::{KeepAliveConfig, TesterPresentKeepAlive},
+    security::{self, SecurityAccessPolicy, SeedKeyProvider},
+    parse_negative_response, positive_response_sid, NegativeResponseCode, ServiceId,
+    DEFAULT_SESSION,
+};
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UdsTiming {
+    /* Time to wait for the first response to a request */
+    pub p2: Duration,
+    /* Time to wait after a response pending (NRC 0x78) */
+    pub p2_star: Duration,
+}
+impl Default for UdsTiming {
+    fn default() -> Self {
+        UdsTiming {
+            p2: Duration::from_millis(1000),
+            p2_star: Duration::from_millis(5000),
+        }
+    }
+}
+
+/* How often and how fast a request is repeated after busyRepeatRequest (NRC 0x21) */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BusyRepeatPolicy {
+    pub max_retries: u32,
+    pub delay: Duration,
+}
+impl Default for BusyRepeatPolicy {
+    fn default() -> Self {
+        BusyRepeatPolicy {
+            max_retries: 3,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UdsError {
+    Session(SessionError),
+    Timeout,
+    NegativeResponse { service: u8, nrc: u8 },
+    InvalidResponse(Vec<u8>),
+    /* Request without service identifier, not sent */
+    EmptyRequest,
+    /* requestSeed level outside the odd values 0x01..=0x7D, not sent */
+    InvalidSecurityLevel(u8),
+    KeyComputation(io::Error),
+}
+impl UdsError {
+    pub fn negative_response_code(&self) -> Option<NegativeResponseCode> {
+        match self {
+            UdsError::NegativeResponse { nrc, .. } => num::FromPrimitive::from_u8(*nrc),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for UdsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdsError::Session(err) => write!(f, "{}", err),
+            UdsError::Timeout => write!(f, "no response within P2/P2*"),
+            UdsError::NegativeResponse { service, nrc } => {
+                write!(f, "negative response to service 0x{:02X}: NRC 0x{:02X}", service, nrc)?;
+                match self.negative_response_code() {
+                    Some(code) => write!(f, " ({:?})", code),
+                    None => Ok(()),
+                }
+            }
+            UdsError::InvalidResponse(data) => write!(f, "invalid response: {:02X?}", data),
+            UdsError::EmptyRequest => write!(f, "empty request"),
+            UdsError::InvalidSecurityLevel(level) => {
+                write!(f, "invalid security access level 0x{:02X}", level)
+            }
+            UdsError::KeyComputation(err) => write!(f, "key computation failed: {}", err),
+        }
+    }
+}
+impl std::error::Error for UdsError {}
+impl From<SessionError> for UdsError {
+    fn from(err: SessionError) -> Self {
+        match err {
+            SessionError::Timeout => UdsError::Timeout,
+            err => UdsError::Session(err),
+        }
+    }
+}
+
+/* UDS client talking to one target ECU over a routed DoIP session */
+pub struct UdsClient {
+    session: Arc<Mutex<DoIPClientSession>>,
+    target_address: u16,
+    timing: UdsTiming,
+    busy_repeat_policy: BusyRepeatPolicy,
+    security_access_policy: SecurityAccessPolicy,
+    keep_alive_config: Option<KeepAliveConfig>,
+    keep_alive: Option<TesterPresentKeepAlive>,
+}
+impl UdsClient {
+    pub fn new(session: DoIPClientSession, target_address: u16) -> Self {
+        UdsClient {
+            session: Arc::new(Mutex::new(session)),
+            target_address,
+            timing: UdsTiming::default(),
+            busy_repeat_policy: BusyRepeatPolicy::default(),
+            security_access_policy: SecurityAccessPolicy::default(),
+            keep_alive_config: None,
+            keep_alive: None,
+        }
+    }
+    pub fn set_timing(&mut self, timing: UdsTiming) -> &mut Self {
+        self.timing = timing;
+        self
+    }
+    pub fn set_busy_repeat_policy(&mut self, policy: BusyRepeatPolicy) -> &mut Self {
+        self.busy_repeat_policy = policy;
+        self
+    }
+    pub fn set_security_access_policy(&mut self, policy: SecurityAccessPolicy) -> &mut Self {
+        self.security_access_policy = policy;
+        self
+    }
+    /* Enables TesterPresent keep-alive, started on entering a non-default session */
+    pub fn set_keep_alive(&mut self, config: Option<KeepAliveConfig>) -> &mut Self {
+        self.keep_alive_config = config;
+        if config.is_none() {
+            self.stop_keep_alive();
+        }
+        self
+    }
+    pub fn is_keep_alive_running(&self) -> bool {
+        self.keep_alive.as_ref().is_some_and(|keep_alive| keep_alive.is_running())
+    }
+    pub fn start_keep_alive(&mut self) {
+        if let Some(config) = self.keep_alive_config {
+            if !self.is_keep_alive_running() {
+                self.keep_alive = Some(TesterPresentKeepAlive::start(
+                    self.session.clone(),
+                    self.target_address,
+                    config,
+                ));
+            }
+        }
+    }
+    pub fn stop_keep_alive(&mut self) {
+        if let Some(mut keep_alive) = self.keep_alive.take() {
+            keep_alive.stop();
+        }
+    }
+    pub fn target_address(&self) -> u16 {
+        self.target_address
+    }
+    /* Shared with the keep-alive, which waits while the guard is held */
+    pub fn session(&self) -> MutexGuard<'_, DoIPClientSession> {
+        self.session.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /* Stops the keep-alive and hands the session back */
+    pub fn into_session(mut self) -> DoIPClientSession {
+        self.stop_keep_alive();
+        let session = self.session.clone();
+        drop(self);
+        match Arc::try_unwrap(session) {
+            Ok(session) => session.into_inner().unwrap_or_else(PoisonError::into_inner),
+            Err(_) => unreachable!("keep-alive thread joined, no other owner of the session"),
+        }
+    }
+    /* Sends a request and returns the positive response belonging to its service.
+     * Response pending is waited through, busy repeat request is retried per policy. */
+    pub fn request(&mut self, request: &[u8]) -> Result<Vec<u8>, UdsError> {
+        let response = self.exchange(request)?;
+        match num::FromPrimitive::from_u8(request[0]) {
+            Some(ServiceId::DiagnosticSessionControl) if request.get(1) == Some(&DEFAULT_SESSION) => {
+                self.stop_keep_alive()
+            }
+            Some(ServiceId::DiagnosticSessionControl) => self.start_keep_alive(),
+            Some(ServiceId::EcuReset) => self.stop_keep_alive(),
+            _ => (),
+        }
+        Ok(response)
+    }
+    fn exchange(&mut self, request: &[u8]) -> Result<Vec<u8>, UdsError> {
+        let service = *request.first().ok_or(UdsError::EmptyRequest)?;
+        let mut session = self.session();
+        let clock = session.clock();
+        let mut retries = 0;
+        'send: loop {
+            session.send_diagnostic(self.target_address, request)?;
+            let mut deadline = clock.now() + self.timing.p2;
+            loop {
+                let remaining = deadline.saturating_duration_since(clock.now());
+                let response = session.receive_diagnostic(remaining)?;
+                if response.source_address != self.target_address {
+                    continue;
+                }
+                let data = response.user_data;
+                if let Some((rejected, nrc)) = parse_negative_response(&data) {
+                    if rejected != service {
+                        continue;
+                    }
+                    match num::FromPrimitive::from_u8(nrc) {
+                        Some(NegativeResponseCode::RequestCorrectlyReceivedResponsePending) => {
+                            deadline = clock.now() + self.timing.p2_star;
+                            continue;
+                        }
+                        Some(NegativeResponseCode::BusyRepeatRequest)
+                            if retries < self.busy_repeat_policy.max_retries =>
+                        {
+                            retries += 1;
+                            clock::sleep(&*clock, self.busy_repeat_policy.delay);
+                            continue 'send;
+                        }
+                        _ => return Err(UdsError::NegativeResponse { service, nrc }),
+                    }
+                }
+                if data.first() == Some(&positive_response_sid(service)) {
+                    return Ok(data);
+                }
+            }
+        }
+    }
+    /* Sends a request without waiting for any response, e.g. with suppressPosRspMsgIndicationBit */
+    pub fn send(&mut self, request: &[u8]) -> Result<(), UdsError> {
+        self.session().send_diagnostic(self.target_address, request)?;
+        Ok(())
+    }
+    pub fn diagnostic_session_control(&mut self, session_type: u8) -> Result<Vec<u8>, UdsError> {
+        self.request(&[ServiceId::DiagnosticSessionControl as u8, session_type])
+    }
+    pub fn ecu_reset(&mut self, reset_type: u8) -> Result<Vec<u8>, UdsError> {
+        self.request(&[ServiceId::EcuReset as u8, reset_type])
+    }
+    pub fn tester_present(&mut self) -> Result<(), UdsError> {
+        self.request(&[ServiceId::TesterPresent as u8, 0x00])?;
+        Ok(())
+    }
+    /* Returns the data record of the identifier, without the echoed service and DID */
+    pub fn read_did(&mut self, did: u16) -> Result<Vec<u8>, UdsError> {
+        let [did_high, did_low] = did.to_be_bytes();
+        let response = self.request(&[ServiceId::ReadDataByIdentifier as u8, did_high, did_low])?;
+        if response.len() < 3 || response[1..3] != did.to_be_bytes() {
+            return Err(UdsError::InvalidResponse(response));
+        }
+        Ok(response[3..].to_vec())
+    }
+    /* Unlocks the given (odd) security level, a zero seed means it is already unlocked.
+     * A running time delay (NRC 0x37) is waited out according to the security access policy. */
+    pub fn security_access(&mut self, level: u8, provider: &dyn SeedKeyProvider) -> Result<(), UdsError> {
+        if !security::is_request_seed_level(level) {
+            return Err(UdsError::InvalidSecurityLevel(level));
+        }
+        let mut retries = 0;
+        let response = loop {
+            match self.request(&[ServiceId::SecurityAccess as u8, level]) {
+                Err(err)
+                    if err.negative_response_code()
+                        == Some(NegativeResponseCode::RequiredTimeDelayNotExpired)
+                        && retries < self.security_access_policy.time_delay_retries =>
+                {
+                    retries += 1;
+                    let clock = self.session().clock();
+                    clock::sleep(&*clock, self.security_access_policy.time_delay);
+                }
+                result => break result?,
+            }
+        };
+        if response.len() < 2 || response[1] != level {
+            return Err(UdsError::InvalidResponse(response));
+        }
+        let seed = &response[2..];
+        if seed.iter().all(|byte| *byte == 0) {
+            return Ok(());
+        }
+        let key = provider
+            .compute_key(self.target_address, level, seed)
+            .map_err(UdsError::KeyComputation)?;
+        let send_key = level.checked_add(1).ok_or(UdsError::InvalidSecurityLevel(level))?;
+        let mut request = vec![ServiceId::SecurityAccess as u8, send_key];
+        request.extend_from_slice(&key);
+        self.request(&request)?;
+        Ok(())
+    }
+    pub fn routine_control(&mut self, control_type: u8, routine: u16, record: &[u8]) -> Result<Vec<u8>, UdsError> {
+        let mut request = vec![ServiceId::RoutineControl as u8, control_type];
+        request.extend_from_slice(&routine.to_be_bytes());
+        request.extend_from_slice(record);
+        self.request(&request)
+    }
+    pub fn write_did(&mut self, did: u16, data: &[u8]) -> Result<(), UdsError> {
+        let mut request = vec![ServiceId::WriteDataByIdentifier as u8];
+        request.extend_from_slice(&did.to_be_bytes());
+        request.extend_from_slice(data);
+        self.request(&request)?;
+        Ok(())
+    }
+}
+impl Drop for UdsClient {
+    fn drop(&mut self) {
+        self.stop_keep_alive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::doip_client::DoIPClient;
+    use crate::message::diag_message::DiagMessage;
+    use crate::test_util::FakeEntity;
+    use crate::uds::security::{SecurityAccessServer, XorMaskProvider};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::{thread, time::Instant};
+
+    const VIN: &[u8; 17] = b"WDD1234567890ABCD";
+
+    fn short_timing() -> UdsTiming {
+        UdsTiming {
+            p2: Duration::from_millis(100),
+            p2_star: Duration::from_millis(500),
+        }
+    }
+    fn client(entity: &FakeEntity) -> UdsClient {
+        let session = DoIPClientSession::connect(entity.address(), 0x0E80).unwrap();
+        let mut client = UdsClient::new(session, 0x1000);
+        client.set_timing(short_timing());
+        client
+    }
+    fn manual_client(entity: &FakeEntity, clock: &Arc<ManualClock>) -> UdsClient {
+        let mut doip_client = DoIPClient::default();
+        doip_client.set_clock(clock.clone());
+        let session = doip_client.connect(entity.address(), 0x0E80, 0x00).unwrap();
+        let mut client = UdsClient::new(session, 0x1000);
+        client.set_timing(short_timing());
+        client
+    }
+
+    #[test]
+    fn read_did_waits_through_response_pending() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| {
+            vec![
+                (Duration::ZERO, vec![0x7F, 0x22, 0x78]),
+                (Duration::from_millis(200), vec![0x7F, 0x22, 0x78]),
+                (Duration::from_millis(200), [&[0x62, 0xF1, 0x90][..], VIN].concat()),
+            ]
+        });
+        let mut client = client(&entity);
+        assert_eq!(client.read_did(0xF190).unwrap(), VIN.to_vec());
+    }
+    #[test]
+    fn hand_back_session_with_keep_alive_stopped() {
+        let entity = FakeEntity::spawn(0x1000, |request: &DiagMessage| match request.user_data[..] {
+            [0x10, session] => vec![(Duration::ZERO, vec![0x50, session, 0x00, 0x32, 0x01, 0xF4])],
+            _ => Vec::new(),
+        });
+        let mut client = client(&entity);
+        client.set_keep_alive(Some(KeepAliveConfig::default()));
+        client.diagnostic_session_control(0x03).unwrap();
+        assert!(client.is_keep_alive_running());
+        assert_eq!(client.session().entity_address(), 0x1000);
+        let session = client.into_session();
+        assert_eq!(session.source_address(), 0x0E80);
+    }
+    #[test]
+    fn reject_empty_request() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| Vec::new());
+        let mut client = client(&entity);
+        assert!(matches!(client.request(&[]), Err(UdsError::EmptyRequest)));
+    }
+    #[test]
+    fn request_times_out_after_p2() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| Vec::new());
+        let mut client = client(&entity);
+        let start = Instant::now();
+        assert!(matches!(client.read_did(0xF190), Err(UdsError::Timeout)));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+    #[test]
+    fn request_times_out_on_session_clock() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| Vec::new());
+        let clock = Arc::new(ManualClock::new());
+        let mut client = manual_client(&entity, &clock);
+        let request = thread::spawn(move || client.read_did(0xF190));
+        thread::sleep(Duration::from_millis(150));
+        assert!(!request.is_finished());
+        clock.advance(Duration::from_millis(100));
+        assert!(matches!(request.join().unwrap(), Err(UdsError::Timeout)));
+    }
+    #[test]
+    fn busy_repeat_request_waits_on_session_clock() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| {
+            vec![(Duration::ZERO, vec![0x7F, 0x10, 0x21])]
+        });
+        let clock = Arc::new(ManualClock::new());
+        let mut client = manual_client(&entity, &clock);
+        client.set_busy_repeat_policy(BusyRepeatPolicy {
+            max_retries: 1,
+            delay: Duration::from_secs(10),
+        });
+        let request = thread::spawn(move || client.diagnostic_session_control(0x03));
+        thread::sleep(Duration::from_millis(100));
+        assert!(!request.is_finished());
+        assert_eq!(entity.received().len(), 1);
+        clock.advance(Duration::from_secs(10));
+        let err = request.join().unwrap().unwrap_err();
+        assert_eq!(err.negative_response_code(), Some(NegativeResponseCode::BusyRepeatRequest));
+        assert_eq!(entity.received().len(), 2);
+    }
+    #[test]
+    fn busy_repeat_request_is_retried() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counter = attempts.clone();
+        let entity = FakeEntity::spawn(0x1000, move |_: &DiagMessage| {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                vec![(Duration::ZERO, vec![0x7F, 0x10, 0x21])]
+            } else {
+                vec![(Duration::ZERO, vec![0x50, 0x03, 0x00, 0x32, 0x01, 0xF4])]
+            }
+        });
+        let mut client = client(&entity);
+        client.set_busy_repeat_policy(BusyRepeatPolicy {
+            max_retries: 2,
+            delay: Duration::from_millis(10),
+        });
+        assert!(client.diagnostic_session_control(0x03).is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+    #[test]
+    fn busy_repeat_request_exhausted() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| {
+            vec![(Duration::ZERO, vec![0x7F, 0x10, 0x21])]
+        });
+        let mut client = client(&entity);
+        client.set_busy_repeat_policy(BusyRepeatPolicy {
+            max_retries: 1,
+            delay: Duration::from_millis(10),
+        });
+        let err = client.diagnostic_session_control(0x03).unwrap_err();
+        assert_eq!(err.negative_response_code(), Some(NegativeResponseCode::BusyRepeatRequest));
+    }
+    #[test]
+    fn security_access_waits_for_time_delay() {
+        let server = Arc::new(Mutex::new(SecurityAccessServer::new(0x1000, XorMaskProvider::new(&[0x5A]))));
+        server.lock().unwrap().set_attempt_limit(3, Duration::from_millis(150)).start_delay();
+        let ecu = server.clone();
+        let entity = FakeEntity::spawn(0x1000, move |request: &DiagMessage| {
+            let response = ecu.lock().unwrap().handle(&request.user_data);
+            response.map(|response| (Duration::ZERO, response)).into_iter().collect()
+        });
+        let mut client = client(&entity);
+        let provider = XorMaskProvider::new(&[0x5A]);
+        let err = client.security_access(0x01, &provider).unwrap_err();
+        assert_eq!(err.negative_response_code(), Some(NegativeResponseCode::RequiredTimeDelayNotExpired));
+        client.set_security_access_policy(SecurityAccessPolicy {
+            time_delay_retries: 1,
+            time_delay: Duration::from_millis(200),
+        });
+        client.security_access(0x01, &provider).unwrap();
+        assert_eq!(server.lock().unwrap().unlocked_level(), Some(0x01));
+    }
+    #[test]
+    fn security_access_invalid_key() {
+        let server = Arc::new(Mutex::new(SecurityAccessServer::new(0x1000, XorMaskProvider::new(&[0x5A]))));
+        let entity = FakeEntity::spawn(0x1000, move |request: &DiagMessage| {
+            let response = server.lock().unwrap().handle(&request.user_data);
+            response.map(|response| (Duration::ZERO, response)).into_iter().collect()
+        });
+        let mut client = client(&entity);
+        let err = client.security_access(0x01, &XorMaskProvider::new(&[0xA5])).unwrap_err();
+        assert_eq!(err.negative_response_code(), Some(NegativeResponseCode::InvalidKey));
+    }
+    #[test]
+    fn security_access_rejects_invalid_level() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| Vec::new());
+        let mut client = client(&entity);
+        for level in [0x00, 0x02, 0x7F, 0xFF] {
+            let err = client.security_access(level, &XorMaskProvider::new(&[0x5A])).unwrap_err();
+            assert!(matches!(err, UdsError::InvalidSecurityLevel(rejected) if rejected == level));
+        }
+        assert!(entity.received().is_empty());
+    }
+    #[test]
+    fn responses_of_other_services_are_ignored() {
+        let entity = FakeEntity::spawn(0x1000, |_: &DiagMessage| {
+            vec![
+                (Duration::ZERO, vec![0x7E, 0x00]),
+                (Duration::ZERO, vec![0x7F, 0x3E, 0x11]),
+                (Duration::ZERO, vec![0x7F, 0x22, 0x31]),
+            ]
+        });
+        let mut client = client(&entity);
+        let err = client.read_did(0x1234).unwrap_err();
+        assert_eq!(err.negative_response_code(), Some(NegativeResponseCode::RequestOutOfRange));
+    }
+}
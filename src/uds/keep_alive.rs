@@ -0,0 +1,207 @@
+use crate::doip_client::DoIPClientSession;
+use crate::uds::{ServiceId, SUPPRESS_POSITIVE_RESPONSE};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeepAliveAddressing {
+    /* TesterPresent goes to the ECU the client talks to */
+    Physical,
+    /* TesterPresent goes to the given functional logical address */
+    Functional(u16),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct KeepAliveConfig {
+    pub interval: Duration,
+    pub addressing: KeepAliveAddressing,
+}
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        KeepAliveConfig {
+            interval: Duration::from_secs(2),
+            addressing: KeepAliveAddressing::Physical,
+        }
+    }
+}
+
+/* Background thread sending TesterPresent with suppressed positive response whenever
+ * the session was idle for the configured interval. The session lock is held for the
+ * whole duration of a request, so the keep-alive pauses while a request is ongoing. */
+pub struct TesterPresentKeepAlive {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+impl TesterPresentKeepAlive {
+    const REQUEST: [u8; 2] = [ServiceId::TesterPresent as u8, SUPPRESS_POSITIVE_RESPONSE];
+
+    pub fn start(
+        session: Arc<Mutex<DoIPClientSession>>,
+        physical_address: u16,
+        config: KeepAliveConfig,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let target_address = match config.addressing {
+            KeepAliveAddressing::Physical => physical_address,
+            KeepAliveAddressing::Functional(address) => address,
+        };
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                let (wait, poll_interval) = {
+                    let mut session = session.lock().unwrap();
+                    if stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let idle = session.idle_time();
+                    let poll_interval = session.clock().poll_interval();
+                    let wait = if idle >= config.interval {
+                        if session
+                            .send_diagnostic(target_address, &TesterPresentKeepAlive::REQUEST)
+                            .is_err()
+                        {
+                            return;
+                        }
+                        config.interval
+                    } else {
+                        config.interval - idle
+                    };
+                    (wait, poll_interval)
+                };
+                /* Looks at the session clock again after its poll interval, as the other timers do */
+                thread::park_timeout(wait.min(poll_interval));
+            }
+        });
+        TesterPresentKeepAlive {
+            stop,
+            handle: Some(handle),
+        }
+    }
+    pub fn is_running(&self) -> bool {
+        self.handle.as_ref().is_some_and(|handle| !handle.is_finished())
+    }
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+impl Drop for TesterPresentKeepAlive {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::doip_client::DoIPClient;
+    use crate::message::diag_message::DiagMessage;
+    use crate::test_util::FakeEntity;
+    use crate::uds::client::{UdsClient, UdsTiming};
+    use std::time::Instant;
+
+    fn positive_response(request: &DiagMessage) -> Vec<(Duration, Vec<u8>)> {
+        match request.user_data.as_slice() {
+            [0x10, session] => vec![(Duration::ZERO, vec![0x50, *session, 0x00, 0x32, 0x01, 0xF4])],
+            [0x22, 0xF1, 0x90] => vec![
+                (Duration::ZERO, vec![0x7F, 0x22, 0x78]),
+                (Duration::from_millis(200), vec![0x62, 0xF1, 0x90, 0x01]),
+            ],
+            _ => Vec::new(),
+        }
+    }
+    fn tester_present_count(entity: &FakeEntity, target_address: u16) -> usize {
+        entity
+            .received()
+            .iter()
+            .filter(|message| {
+                message.target_address == target_address && message.user_data == vec![0x3E, 0x80]
+            })
+            .count()
+    }
+    /* Waits in real time for the entity to have received the number of TesterPresent requests */
+    fn wait_for_count(entity: &FakeEntity, target_address: u16, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while tester_present_count(entity, target_address) < count {
+            assert!(Instant::now() < deadline, "no TesterPresent #{count} to {target_address:#06X}");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+    fn client(entity: &FakeEntity, clock: &Arc<ManualClock>, addressing: KeepAliveAddressing) -> UdsClient {
+        let mut doip_client = DoIPClient::default();
+        doip_client.set_clock(clock.clone());
+        let session = doip_client.connect(entity.address(), 0x0E80, 0x00).unwrap();
+        let mut client = UdsClient::new(session, 0x1000);
+        client.set_timing(UdsTiming {
+            p2: Duration::from_millis(200),
+            p2_star: Duration::from_millis(1000),
+        });
+        client.set_keep_alive(Some(KeepAliveConfig {
+            interval: Duration::from_millis(100),
+            addressing,
+        }));
+        client
+    }
+
+    #[test]
+    fn keep_alive_runs_in_non_default_session_only() {
+        let entity = FakeEntity::spawn(0x1000, positive_response);
+        let clock = Arc::new(ManualClock::new());
+        let mut client = client(&entity, &clock, KeepAliveAddressing::Physical);
+        assert!(!client.is_keep_alive_running());
+        client.diagnostic_session_control(0x03).unwrap();
+        assert!(client.is_keep_alive_running());
+        clock.advance(Duration::from_millis(100));
+        wait_for_count(&entity, 0x1000, 1);
+        clock.advance(Duration::from_millis(100));
+        wait_for_count(&entity, 0x1000, 2);
+        client.diagnostic_session_control(0x01).unwrap();
+        assert!(!client.is_keep_alive_running());
+        let count = tester_present_count(&entity, 0x1000);
+        clock.advance(Duration::from_millis(250));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(tester_present_count(&entity, 0x1000), count);
+    }
+    #[test]
+    fn keep_alive_functional_addressing() {
+        let entity = FakeEntity::spawn(0x1000, positive_response);
+        let clock = Arc::new(ManualClock::new());
+        let functional = KeepAliveAddressing::Functional(FakeEntity::FUNCTIONAL_ADDRESS);
+        let mut client = client(&entity, &clock, functional);
+        client.diagnostic_session_control(0x02).unwrap();
+        clock.advance(Duration::from_millis(100));
+        wait_for_count(&entity, FakeEntity::FUNCTIONAL_ADDRESS, 1);
+        assert_eq!(tester_present_count(&entity, 0x1000), 0);
+    }
+    #[test]
+    fn keep_alive_pauses_during_request() {
+        let entity = FakeEntity::spawn(0x1000, positive_response);
+        let clock = Arc::new(ManualClock::new());
+        let mut client = client(&entity, &clock, KeepAliveAddressing::Physical);
+        client.diagnostic_session_control(0x03).unwrap();
+        let count = tester_present_count(&entity, 0x1000);
+        let request = thread::spawn(move || {
+            let response = client.read_did(0xF190);
+            (client, response)
+        });
+        /* Idle beyond the interval while the response is pending, below P2* */
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(30));
+            clock.advance(Duration::from_millis(150));
+        }
+        let (client, response) = request.join().unwrap();
+        assert_eq!(response.unwrap(), vec![0x01]);
+        assert_eq!(tester_present_count(&entity, 0x1000), count);
+        assert!(client.is_keep_alive_running());
+    }
+}
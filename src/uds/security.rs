@@ -0,0 +1,525 @@
+use crate::clock::{Clock, SystemClock};
+use crate::message::hex;
+use crate::uds::client::UdsError;
+use crate::uds::{
+    NegativeResponseCode, ServiceId, NEGATIVE_RESPONSE_SID, POSITIVE_RESPONSE_OFFSET,
+    SUPPRESS_POSITIVE_RESPONSE,
+};
+use rand::RngCore;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "key-library")]
+use std::ffi::{c_char, CString, OsStr};
+
+/* Odd requestSeed sub-function outside of the ranges ISO 14229-1 reserves */
+pub(crate) fn is_request_seed_level(level: u8) -> bool {
+    level % 2 == 1 && (0x01..=0x7D).contains(&level)
+}
+
+/* Computes the SecurityAccess key for a seed sent by the ECU at the given logical address */
+pub trait SeedKeyProvider {
+    fn compute_key(&self, ecu_address: u16, level: u8, seed: &[u8]) -> io::Result<Vec<u8>>;
+}
+impl<F> SeedKeyProvider for F
+where
+    F: Fn(u16, u8, &[u8]) -> io::Result<Vec<u8>>,
+{
+    fn compute_key(&self, ecu_address: u16, level: u8, seed: &[u8]) -> io::Result<Vec<u8>> {
+        self(ecu_address, level, seed)
+    }
+}
+
+/* Key is the seed XORed with a mask, repeated when the seed is longer than the mask */
+#[derive(Clone, Debug, PartialEq)]
+pub struct XorMaskProvider {
+    mask: Vec<u8>,
+}
+impl XorMaskProvider {
+    pub fn new(mask: &[u8]) -> Self {
+        XorMaskProvider { mask: mask.to_vec() }
+    }
+}
+impl SeedKeyProvider for XorMaskProvider {
+    fn compute_key(&self, _ecu_address: u16, _level: u8, seed: &[u8]) -> io::Result<Vec<u8>> {
+        if self.mask.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty XOR mask"));
+        }
+        Ok(seed.iter().zip(self.mask.iter().cycle()).map(|(seed, mask)| seed ^ mask).collect())
+    }
+}
+
+/* Keys looked up from a table of known seed/key pairs per ECU and level */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TableProvider {
+    keys: HashMap<(u16, u8, Vec<u8>), Vec<u8>>,
+}
+impl TableProvider {
+    pub fn new() -> Self {
+        TableProvider::default()
+    }
+    pub fn insert(&mut self, ecu_address: u16, level: u8, seed: &[u8], key: &[u8]) -> &mut Self {
+        self.keys.insert((ecu_address, level, seed.to_vec()), key.to_vec());
+        self
+    }
+}
+impl SeedKeyProvider for TableProvider {
+    fn compute_key(&self, ecu_address: u16, level: u8, seed: &[u8]) -> io::Result<Vec<u8>> {
+        self.keys
+            .get(&(ecu_address, level, seed.to_vec()))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "seed not in key table"))
+    }
+}
+
+/* Dispatches to the provider registered for the ECU and level, or to a fallback */
+#[derive(Default)]
+pub struct ProviderRegistry {
+    providers: HashMap<(u16, u8), Box<dyn SeedKeyProvider + Send + Sync>>,
+    fallback: Option<Box<dyn SeedKeyProvider + Send + Sync>>,
+}
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        ProviderRegistry::default()
+    }
+    pub fn register<P>(&mut self, ecu_address: u16, level: u8, provider: P) -> &mut Self
+    where
+        P: SeedKeyProvider + Send + Sync + 'static,
+    {
+        self.providers.insert((ecu_address, level), Box::new(provider));
+        self
+    }
+    pub fn set_fallback<P>(&mut self, provider: P) -> &mut Self
+    where
+        P: SeedKeyProvider + Send + Sync + 'static,
+    {
+        self.fallback = Some(Box::new(provider));
+        self
+    }
+}
+impl SeedKeyProvider for ProviderRegistry {
+    fn compute_key(&self, ecu_address: u16, level: u8, seed: &[u8]) -> io::Result<Vec<u8>> {
+        match self.providers.get(&(ecu_address, level)).or(self.fallback.as_ref()) {
+            Some(provider) => provider.compute_key(ecu_address, level, seed),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no seed/key provider for ECU 0x{:04X} level 0x{:02X}", ecu_address, level),
+            )),
+        }
+    }
+}
+
+/* Bridge to an external seed/key tool, e.g. a wrapper around an OEM seed/key library.
+ * The program is called with the ECU address, level and seed as hex arguments
+ * and prints the key as hex on its standard output. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternalProcessProvider {
+    program: String,
+    args: Vec<String>,
+}
+impl ExternalProcessProvider {
+    pub fn new(program: &str) -> Self {
+        ExternalProcessProvider { program: program.to_string(), args: Vec::new() }
+    }
+    pub fn arg(&mut self, arg: &str) -> &mut Self {
+        self.args.push(arg.to_string());
+        self
+    }
+}
+impl SeedKeyProvider for ExternalProcessProvider {
+    fn compute_key(&self, ecu_address: u16, level: u8, seed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg(format!("{:04X}", ecu_address))
+            .arg(format!("{:02X}", level))
+            .arg(hex::encode(seed))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        /* Tools reading the seed from stdin get it there as well */
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = writeln!(stdin, "{}", hex::encode(seed));
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("{} failed: {}", self.program, output.status)));
+        }
+        let text = String::from_utf8_lossy(&output.stdout);
+        hex::decode(&text).map_err(|err| {
+            let reason = format!("invalid key output {:?}: {}", text.trim(), err);
+            io::Error::new(io::ErrorKind::InvalidData, reason)
+        })
+    }
+}
+
+/* Bridge to an OEM seed/key library following the Vector GenerateKeyEx convention:
+ *   int GenerateKeyEx(const unsigned char* seed, unsigned int seedSize, unsigned int level,
+ *                     const char* variant, unsigned char* key, unsigned int maxKeySize,
+ *                     unsigned int* keySize)
+ * returning 0 on success. One library serves one ECU family, a registry maps ECUs to libraries. */
+#[cfg(feature = "key-library")]
+pub struct SharedLibraryProvider {
+    library: libloading::Library,
+    variant: CString,
+    max_key_length: usize,
+}
+#[cfg(feature = "key-library")]
+type GenerateKeyEx =
+    unsafe extern "C" fn(*const u8, u32, u32, *const c_char, *mut u8, u32, *mut u32) -> i32;
+#[cfg(feature = "key-library")]
+impl SharedLibraryProvider {
+    const SYMBOL: &'static [u8] = b"GenerateKeyEx\0";
+
+    /* Loading runs the initialisation code of the library, which therefore has to be trusted */
+    pub fn load<P: AsRef<OsStr>>(path: P) -> io::Result<Self> {
+        let library = unsafe { libloading::Library::new(path) }.map_err(io::Error::other)?;
+        let provider = SharedLibraryProvider { library, variant: CString::default(), max_key_length: 256 };
+        provider.generate_key()?;
+        Ok(provider)
+    }
+    fn generate_key(&self) -> io::Result<libloading::Symbol<'_, GenerateKeyEx>> {
+        unsafe { self.library.get(SharedLibraryProvider::SYMBOL) }.map_err(io::Error::other)
+    }
+    /* Variant string passed to the library, empty by default */
+    pub fn set_variant(&mut self, variant: &str) -> io::Result<&mut Self> {
+        let variant = CString::new(variant).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.variant = variant;
+        Ok(self)
+    }
+    pub fn set_max_key_length(&mut self, max_key_length: usize) -> &mut Self {
+        self.max_key_length = max_key_length;
+        self
+    }
+}
+#[cfg(feature = "key-library")]
+impl SeedKeyProvider for SharedLibraryProvider {
+    fn compute_key(&self, _ecu_address: u16, level: u8, seed: &[u8]) -> io::Result<Vec<u8>> {
+        let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "seed or key buffer too long");
+        let seed_length = u32::try_from(seed.len()).map_err(|_| too_long())?;
+        let max_key_length = u32::try_from(self.max_key_length).map_err(|_| too_long())?;
+        let mut key = vec![0; self.max_key_length];
+        let mut key_length = 0;
+        let generate_key = self.generate_key()?;
+        let result = unsafe {
+            generate_key(
+                seed.as_ptr(),
+                seed_length,
+                level as u32,
+                self.variant.as_ptr(),
+                key.as_mut_ptr(),
+                max_key_length,
+                &mut key_length,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::other(format!("GenerateKeyEx failed with {}", result)));
+        }
+        if key_length > max_key_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "key longer than the key buffer"));
+        }
+        key.truncate(key_length as usize);
+        Ok(key)
+    }
+}
+
+/* Client side reaction to requiredTimeDelayNotExpired (NRC 0x37) */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SecurityAccessPolicy {
+    pub time_delay_retries: u32,
+    pub time_delay: Duration,
+}
+impl Default for SecurityAccessPolicy {
+    fn default() -> Self {
+        SecurityAccessPolicy {
+            time_delay_retries: 0,
+            time_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+/* ECU side SecurityAccess state: seed generation, key verification with the same
+ * provider the tester uses, failed attempt counter and lockout delay timer */
+pub struct SecurityAccessServer {
+    ecu_address: u16,
+    provider: Box<dyn SeedKeyProvider + Send + Sync>,
+    levels: Vec<u8>,
+    seed_length: usize,
+    max_attempts: u8,
+    delay: Duration,
+    unlocked_level: Option<u8>,
+    pending_seed: Option<(u8, Vec<u8>)>,
+    failed_attempts: u8,
+    locked_until: Option<Instant>,
+    /* Time source of the lockout delay and seed source, thread RNG if unset */
+    clock: Arc<dyn Clock>,
+    rng: Option<Box<dyn RngCore + Send>>,
+}
+impl SecurityAccessServer {
+    pub fn new<P>(ecu_address: u16, provider: P) -> Self
+    where
+        P: SeedKeyProvider + Send + Sync + 'static,
+    {
+        SecurityAccessServer {
+            ecu_address,
+            provider: Box::new(provider),
+            levels: vec![0x01],
+            seed_length: 4,
+            max_attempts: 3,
+            delay: Duration::from_secs(10),
+            unlocked_level: None,
+            pending_seed: None,
+            failed_attempts: 0,
+            locked_until: None,
+            clock: Arc::new(SystemClock),
+            rng: None,
+        }
+    }
+    /* Odd requestSeed sub-functions accepted by the ECU, 0x01 to 0x7D */
+    pub fn set_levels(&mut self, levels: &[u8]) -> Result<&mut Self, UdsError> {
+        if let Some(level) = levels.iter().find(|level| !is_request_seed_level(**level)) {
+            return Err(UdsError::InvalidSecurityLevel(*level));
+        }
+        self.levels = levels.to_vec();
+        Ok(self)
+    }
+    pub fn set_seed_length(&mut self, seed_length: usize) -> &mut Self {
+        self.seed_length = seed_length;
+        self
+    }
+    pub fn set_attempt_limit(&mut self, max_attempts: u8, delay: Duration) -> &mut Self {
+        self.max_attempts = max_attempts;
+        self.delay = delay;
+        self
+    }
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+    pub fn set_rng<R: RngCore + Send + 'static>(&mut self, rng: R) -> &mut Self {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+    /* Applies the boot/reset time delay, as after power on */
+    pub fn start_delay(&mut self) {
+        self.locked_until = Some(self.clock.now() + self.delay);
+    }
+    pub fn unlocked_level(&self) -> Option<u8> {
+        self.unlocked_level
+    }
+    pub fn failed_attempts(&self) -> u8 {
+        self.failed_attempts
+    }
+    /* Locks the ECU again, e.g. on session change */
+    pub fn lock(&mut self) {
+        self.unlocked_level = None;
+        self.pending_seed = None;
+    }
+    /* Handles a complete SecurityAccess request and returns the response,
+     * None for a positive response suppressed by the sub-function */
+    pub fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let response = self.respond(request);
+        let suppressed = request.get(1).is_some_and(|sub_function| {
+            sub_function & SUPPRESS_POSITIVE_RESPONSE != 0
+        });
+        match response.first() {
+            Some(&NEGATIVE_RESPONSE_SID) => Some(response),
+            _ => (!suppressed).then_some(response),
+        }
+    }
+    fn respond(&mut self, request: &[u8]) -> Vec<u8> {
+        let sid = ServiceId::SecurityAccess as u8;
+        let negative = |nrc: NegativeResponseCode| vec![0x7F, sid, nrc as u8];
+        let (level, data) = match request {
+            [_, sub_function, data @ ..] => (sub_function & !SUPPRESS_POSITIVE_RESPONSE, data),
+            _ => return negative(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat),
+        };
+        if level % 2 == 1 {
+            if !self.levels.contains(&level) {
+                return negative(NegativeResponseCode::SubFunctionNotSupported);
+            }
+            if !data.is_empty() {
+                return negative(NegativeResponseCode::IncorrectMessageLengthOrInvalidFormat);
+            }
+            if self.locked_until.is_some_and(|until| self.clock.now() < until) {
+                return negative(NegativeResponseCode::RequiredTimeDelayNotExpired);
+            }
+            self.locked_until = None;
+            let mut response = vec![sid + POSITIVE_RESPONSE_OFFSET, level];
+            if self.unlocked_level == Some(level) {
+                response.resize(2 + self.seed_length, 0);
+                return response;
+            }
+            /* An all zero seed means unlocked already, so it is drawn again */
+            let mut seed = vec![0; self.seed_length];
+            self.fill_seed(&mut seed);
+            while !seed.is_empty() && seed.iter().all(|byte| *byte == 0) {
+                self.fill_seed(&mut seed);
+            }
+            response.extend_from_slice(&seed);
+            self.pending_seed = Some((level, seed));
+            response
+        } else {
+            let seed_level = level.wrapping_sub(1);
+            if !self.levels.contains(&seed_level) {
+                return negative(NegativeResponseCode::SubFunctionNotSupported);
+            }
+            let seed = match self.pending_seed.take() {
+                Some((pending_level, seed)) if pending_level == seed_level => seed,
+                _ => return negative(NegativeResponseCode::RequestSequenceError),
+            };
+            let expected = self.provider.compute_key(self.ecu_address, seed_level, &seed);
+            if expected.is_ok_and(|key| key == data) {
+                self.failed_attempts = 0;
+                self.unlocked_level = Some(seed_level);
+                return vec![sid + POSITIVE_RESPONSE_OFFSET, level];
+            }
+            self.failed_attempts += 1;
+            if self.failed_attempts >= self.max_attempts {
+                self.failed_attempts = 0;
+                self.start_delay();
+                return negative(NegativeResponseCode::ExceededNumberOfAttempts);
+            }
+            negative(NegativeResponseCode::InvalidKey)
+        }
+    }
+    fn fill_seed(&mut self, seed: &mut [u8]) {
+        match self.rng.as_mut() {
+            Some(rng) => rng.fill_bytes(seed),
+            None => rand::thread_rng().fill_bytes(seed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use rand::rngs::mock::StepRng;
+
+    #[test]
+    fn xor_mask_key() {
+        let provider = XorMaskProvider::new(&[0xFF, 0x00]);
+        assert_eq!(provider.compute_key(0x1000, 1, &[0x12, 0x34, 0x56]).unwrap(), vec![0xED, 0x34, 0xA9]);
+    }
+    #[test]
+    fn table_and_registry_dispatch() {
+        let mut table = TableProvider::new();
+        table.insert(0x1000, 0x11, &[1, 2], &[3, 4]);
+        let mut registry = ProviderRegistry::new();
+        registry.register(0x1000, 0x11, table).set_fallback(XorMaskProvider::new(&[0x01]));
+        assert_eq!(registry.compute_key(0x1000, 0x11, &[1, 2]).unwrap(), vec![3, 4]);
+        assert_eq!(
+            registry.compute_key(0x1000, 0x11, &[9, 9]).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(registry.compute_key(0x2000, 0x01, &[1, 2]).unwrap(), vec![0, 3]);
+    }
+    #[test]
+    fn external_process_key() {
+        let mut provider = ExternalProcessProvider::new("sh");
+        provider.arg("-c").arg("echo \"$3$2\"").arg("seedkey");
+        assert_eq!(provider.compute_key(0x1000, 0x01, &[0xAB, 0xCD]).unwrap(), vec![0xAB, 0xCD, 0x01]);
+    }
+    #[cfg(all(unix, feature = "key-library"))]
+    #[test]
+    fn shared_library_key() {
+        const SOURCE: &str = "
+            int GenerateKeyEx(const unsigned char* seed, unsigned int seed_size, unsigned int level,
+                              const char* variant, unsigned char* key, unsigned int max_key_size,
+                              unsigned int* key_size) {
+                if (seed_size + 1 > max_key_size) return 1;
+                for (unsigned int i = 0; i < seed_size; i++) key[i] = seed[i] ^ variant[0];
+                key[seed_size] = level;
+                *key_size = seed_size + 1;
+                return 0;
+            }";
+        let directory = std::env::temp_dir().join(format!("doip-seedkey-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let (source, library) = (directory.join("seedkey.c"), directory.join("libseedkey.so"));
+        std::fs::write(&source, SOURCE).unwrap();
+        let status = Command::new("cc").args(["-shared", "-fPIC", "-o"]).arg(&library).arg(&source).status();
+        assert!(status.unwrap().success());
+
+        let mut provider = SharedLibraryProvider::load(&library).unwrap();
+        provider.set_variant("Z").unwrap();
+        assert_eq!(provider.compute_key(0x1000, 0x11, &[0x12, 0x34]).unwrap(), vec![0x48, 0x6E, 0x11]);
+        provider.set_max_key_length(2);
+        assert!(provider.compute_key(0x1000, 0x11, &[0x12, 0x34]).is_err());
+        assert!(SharedLibraryProvider::load(&source).is_err());
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+    #[test]
+    fn server_unlocks_with_valid_key() {
+        let provider = XorMaskProvider::new(&[0x5A]);
+        let mut server = SecurityAccessServer::new(0x1000, provider.clone());
+        let response = server.handle(&[0x27, 0x01]).unwrap();
+        assert_eq!(&response[..2], &[0x67, 0x01]);
+        let mut request = vec![0x27, 0x02];
+        request.extend(provider.compute_key(0x1000, 0x01, &response[2..]).unwrap());
+        assert_eq!(server.handle(&request), Some(vec![0x67, 0x02]));
+        assert_eq!(server.unlocked_level(), Some(0x01));
+        assert_eq!(server.handle(&[0x27, 0x01]), Some(vec![0x67, 0x01, 0, 0, 0, 0]));
+    }
+    #[test]
+    fn server_suppresses_positive_response() {
+        let provider = XorMaskProvider::new(&[0x5A]);
+        let mut server = SecurityAccessServer::new(0x1000, provider.clone());
+        server.set_levels(&[0x01, 0x7D]).unwrap();
+        assert!(matches!(server.set_levels(&[0x01, 0x7F]), Err(UdsError::InvalidSecurityLevel(0x7F))));
+        /* Suppressing the seed makes little sense, but is accepted as for any sub-function */
+        assert_eq!(server.handle(&[0x27, 0x81]), None);
+        let response = server.handle(&[0x27, 0x01]).unwrap();
+        let mut request = vec![0x27, 0x82];
+        request.extend(provider.compute_key(0x1000, 0x01, &response[2..]).unwrap());
+        assert_eq!(server.handle(&request), None);
+        assert_eq!(server.unlocked_level(), Some(0x01));
+        /* Negative responses are sent regardless */
+        assert_eq!(server.handle(&[0x27, 0x82, 0, 0, 0, 0]), Some(vec![0x7F, 0x27, 0x24]));
+        /* 0xFD is requestSeed 0x7D with suppressed response, 0x80 is sendKey 0x00 of no level */
+        assert_eq!(server.handle(&[0x27, 0xFD]), None);
+        assert_eq!(server.handle(&[0x27, 0x80, 0, 0, 0, 0]), Some(vec![0x7F, 0x27, 0x12]));
+    }
+    #[test]
+    fn server_locks_out_after_failed_attempts() {
+        let clock = Arc::new(ManualClock::new());
+        let mut server = SecurityAccessServer::new(0x1000, XorMaskProvider::new(&[0x5A]));
+        server.set_attempt_limit(2, Duration::from_secs(10)).set_clock(clock.clone());
+        server.handle(&[0x27, 0x01]).unwrap();
+        assert_eq!(server.handle(&[0x27, 0x02, 0, 0, 0, 0]), Some(vec![0x7F, 0x27, 0x35]));
+        assert_eq!(server.failed_attempts(), 1);
+        server.handle(&[0x27, 0x01]).unwrap();
+        assert_eq!(server.handle(&[0x27, 0x02, 0, 0, 0, 0]), Some(vec![0x7F, 0x27, 0x36]));
+        assert_eq!(server.handle(&[0x27, 0x01]), Some(vec![0x7F, 0x27, 0x37]));
+        clock.advance(Duration::from_millis(9999));
+        assert_eq!(server.handle(&[0x27, 0x01]), Some(vec![0x7F, 0x27, 0x37]));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(server.handle(&[0x27, 0x01]).unwrap().len(), 6);
+    }
+    #[test]
+    fn server_draws_seed_from_rng() {
+        let mut server = SecurityAccessServer::new(0x1000, XorMaskProvider::new(&[0x5A]));
+        /* The first draw is all zero and is drawn again */
+        server.set_rng(StepRng::new(0, 0x0101_0101));
+        assert_eq!(server.handle(&[0x27, 0x01]), Some(vec![0x67, 0x01, 1, 1, 1, 1]));
+        assert_eq!(server.handle(&[0x27, 0x02, 0x5B, 0x5B, 0x5B, 0x5B]), Some(vec![0x67, 0x02]));
+    }
+    #[test]
+    fn server_unlocks_with_empty_seed() {
+        let mut server = SecurityAccessServer::new(0x1000, XorMaskProvider::new(&[0x5A]));
+        server.set_seed_length(0);
+        assert_eq!(server.handle(&[0x27, 0x01]), Some(vec![0x67, 0x01]));
+        assert_eq!(server.handle(&[0x27, 0x02]), Some(vec![0x67, 0x02]));
+        assert_eq!(server.unlocked_level(), Some(0x01));
+    }
+    #[test]
+    fn server_rejects_key_without_seed() {
+        let mut server = SecurityAccessServer::new(0x1000, XorMaskProvider::new(&[0x5A]));
+        assert_eq!(server.handle(&[0x27, 0x02, 0, 0, 0, 0]), Some(vec![0x7F, 0x27, 0x24]));
+        assert_eq!(server.handle(&[0x27, 0x03]), Some(vec![0x7F, 0x27, 0x12]));
+        assert_eq!(server.handle(&[0x27, 0x04, 0, 0, 0, 0]), Some(vec![0x7F, 0x27, 0x12]));
+    }
+}